@@ -50,6 +50,11 @@ fn generate_manpage() {
                 .long("--conf")
                 .help("Path to read configuration file from."),
         )
+        .option(
+            Opt::new("format")
+                .long("--log-format")
+                .help("Format of log output, either text or json."),
+        )
         .custom(
             Section::new("configuration")
                 .paragraph("\
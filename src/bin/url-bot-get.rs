@@ -4,6 +4,11 @@ URL munching IRC bot, web page title fetching tool.
 Retrieve the title or some content from web addresses, a debugging
 tool for `url-bot-rs`.
 
+If no URL is given, or the URL is `-`, newline separated URLs are read from
+stdin, and `<url>\t<title>` is printed for each, blank lines and lines
+starting with `#` are ignored. With JSON output, one object is printed for
+each URL.
+
 Usage:
     url-bot-rs [options] [-v...] [<url>]
 
@@ -15,10 +20,21 @@ Options:
     -u=<val> --user-agent=<val>   Specify user-agent.
     -l=<val> --accept-lang=<val>  Specify accept-lang.
     -t=<val> --timeout=<val>      Specify request timeout.
+    --total-timeout=<val>         Specify timeout across all redirections.
+    --connect-timeout=<val>       Specify connection timeout.
     -r=<val> --redirect=<val>     Specify redirection limit.
+    --cookie-limit=<val>          Specify the max number of cookies held.
+    --no-cookies                  Don't store or send cookies.
+    --http=<ver>                  Specify HTTP version, auto (HTTP/1.1) or http2.
+    --ca-cert=<path>              Use an additional root certificate (PEM).
+    --insecure                    Don't verify TLS certificates, DANGEROUS.
+    --local-address=<ip>          Make requests from a local IP address.
     --metadata=<val>              Enable metadata [default: true].
     --mime=<val>                  Enable mime reporting [default: true].
     --curl                        Behave like curl, post page content to stdout.
+    --dump-file=<path>            Write page content to a file.
+    --headers                     Print final URL, status and response headers.
+    -o=<fmt> --output=<fmt>       Output format, text or json [default: text].
 ";
 
 #[derive(Debug, Deserialize, Default, Clone)]
@@ -29,10 +45,34 @@ pub struct Args {
     flag_user_agent: Option<String>,
     flag_accept_lang: Option<String>,
     flag_timeout: Option<u64>,
+    flag_total_timeout: Option<u64>,
+    flag_connect_timeout: Option<u64>,
     flag_redirect: Option<u8>,
+    flag_cookie_limit: Option<usize>,
+    flag_no_cookies: bool,
+    flag_http: Option<HttpVersion>,
+    flag_ca_cert: Option<PathBuf>,
+    flag_insecure: bool,
+    flag_local_address: Option<IpAddr>,
     flag_metadata: bool,
     flag_mime: bool,
     flag_curl: bool,
+    flag_dump_file: Option<PathBuf>,
+    flag_headers: bool,
+    flag_output: Output,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Output {
+    Text,
+    Json,
+}
+
+impl Default for Output {
+    fn default() -> Self {
+        Output::Text
+    }
 }
 
 extern crate url_bot_rs;
@@ -57,16 +97,22 @@ extern crate log;
 extern crate atty;
 extern crate stderrlog;
 extern crate scraper;
+extern crate serde_json;
 
 use url_bot_rs::VERSION;
 use url_bot_rs::config::Rtd;
-use url_bot_rs::http::{Session, get_title};
+use url_bot_rs::http::{Session, RequestParams, HttpVersion, get_title, get_title_info};
 use url_bot_rs::message::add_scheme_for_tld;
 
 use docopt::Docopt;
 use stderrlog::{Timestamp, ColorChoice};
 use atty::{is, Stream};
 use std::process;
+use std::fs::File;
+use std::io::{self, Write, BufRead};
+use std::path::PathBuf;
+use std::net::IpAddr;
+use std::time::Instant;
 
 const MIN_VERBOSITY: usize = 2;
 
@@ -87,6 +133,8 @@ fn main() {
         .modules(vec![
             "url_bot_rs::config",
             "url_bot_rs::http",
+            "url_bot_rs::cookies",
+            "url_bot_rs::sites",
         ])
         .timestamp(Timestamp::Off)
         .quiet(args.flag_quiet)
@@ -100,29 +148,91 @@ fn main() {
     rtd.conf.features.report_metadata = args.flag_metadata;
     rtd.conf.features.report_mime = args.flag_mime;
 
-    // set session properties for the request
-    let mut session = Session::new();
+    // set session properties for requests
+    let params = request_params(&args);
+
+    let ret = if args.arg_url.is_empty() || args.arg_url == "-" {
+        get_stdin(&args, &rtd, &params)
+    } else if args.flag_output == Output::Json {
+        get_json(&args.arg_url, &rtd, &params)
+    } else {
+        get_single(&args, &rtd, &params)
+    };
+
+    process::exit(ret);
+}
+
+/// properties of requests, as overridden by the options given
+fn request_params(args: &Args) -> RequestParams {
+    let mut params = RequestParams::default();
     if let Some(v) = args.flag_timeout {
         info!("overriding timeout to {}s", v);
-        session.params.timeout_s = v;
+        params.timeout_s = v;
+    }
+    if let Some(v) = args.flag_total_timeout {
+        info!("overriding total timeout to {}s", v);
+        params.total_timeout_s = v;
+    }
+    if let Some(v) = args.flag_connect_timeout {
+        info!("overriding connection timeout to {}s", v);
+        params.connect_timeout_s = v;
     }
     if let Some(v) = args.flag_redirect {
         info!("overriding redirect limit to {}", v);
-        session.params.redirect_limit = v;
+        params.redirect_limit = v;
+    }
+    if let Some(v) = args.flag_cookie_limit {
+        info!("overriding cookie limit to {}", v);
+        params.cookie_limit = v;
+    }
+    if args.flag_no_cookies {
+        info!("disabling cookies");
+        params.use_cookies = false;
+    }
+    if let Some(v) = args.flag_http {
+        info!("overriding HTTP version to {:?}", v);
+        params.http_version = v;
+    }
+    if let Some(ref v) = args.flag_ca_cert {
+        info!("adding root certificate {}", v.display());
+        params.ca_cert = Some(v.clone());
     }
-    if let Some(v) = args.flag_user_agent {
+    if args.flag_insecure {
+        warn!("!!! TLS certificate verification is DISABLED !!!");
+        params.danger_accept_invalid_certs = true;
+    }
+    if let Some(v) = args.flag_local_address {
+        info!("making requests from {}", v);
+        params.local_address = Some(v);
+    }
+    if let Some(ref v) = args.flag_user_agent {
         info!("overriding user-agent to \"{}\"", v);
-        session.params.user_agent = v;
+        params.user_agent = v.to_string();
     }
-    if let Some(v) = args.flag_accept_lang {
+    if let Some(ref v) = args.flag_accept_lang {
         info!("overriding accept-lang to \"{}\"", v);
-        session.accept_lang(&v);
+        params.accept_lang = v.to_string();
     }
 
+    params
+}
+
+fn new_session(params: &RequestParams) -> Session {
+    let mut session = Session::new();
+    session.params = params.clone();
+    session
+}
+
+/// retrieve the title for a single URL given as an argument
+fn get_single(args: &Args, rtd: &Rtd, params: &RequestParams) -> i32 {
+    let mut session = new_session(params);
+
     // get short url, if applicable
-    let token = add_scheme_for_tld(&args.arg_url).unwrap_or(args.arg_url);
+    let token = add_scheme_for_tld(&args.arg_url)
+        .unwrap_or_else(|| args.arg_url.clone());
 
     // make request
+    let start = Instant::now();
     let mut resp = session
         .request(&token)
         .unwrap_or_else(|err| {
@@ -130,9 +240,34 @@ fn main() {
             process::exit(1);
         });
 
+    // print the final URL, status, and response headers
+    if args.flag_headers {
+        println!("URL: {}", session.url);
+        println!("Status: {}", resp.status());
+        for (k, v) in resp.headers().iter() {
+            println!("{}: {}", k, v.to_str().unwrap_or("(non-ASCII value)"));
+        }
+        println!();
+    }
+
+    // write page content to a file if specified, or stdout in curl mode
+    let mut stdout = io::stdout();
+    let mut file = args.flag_dump_file.as_ref().map(|path| {
+        File::create(path).unwrap_or_else(|err| {
+            error!("Error creating {}: {}", path.display(), err);
+            process::exit(1);
+        })
+    });
+    let dump: Option<&mut dyn Write> = match file {
+        Some(ref mut f) => Some(f),
+        None if args.flag_curl => Some(&mut stdout),
+        None => None,
+    };
+
     // output
-    let ret = match get_title(&mut resp, &rtd, args.flag_curl) {
+    match get_title(&mut resp, rtd, dump) {
         Ok(t) => {
+            debug!("resolved in {:?}", start.elapsed());
             if !args.flag_curl { println!("{}", t) };
             0
         },
@@ -140,7 +275,54 @@ fn main() {
             error!("Error getting title: {}", e);
             1
         },
-    };
+    }
+}
 
-    process::exit(ret);
+/// retrieve the title for a URL, and print the outcome as JSON
+fn get_json(url: &str, rtd: &Rtd, params: &RequestParams) -> i32 {
+    let token = add_scheme_for_tld(url).unwrap_or_else(|| url.to_string());
+    let mut info = get_title_info(&token, rtd, params);
+    info.url = url.to_string();
+
+    println!("{}", serde_json::to_string(&info).unwrap());
+
+    if info.error.is_some() { 1 } else { 0 }
+}
+
+/// retrieve titles for URLs read line by line from stdin
+fn get_stdin(args: &Args, rtd: &Rtd, params: &RequestParams) -> i32 {
+    let mut ret = 0;
+    let stdin = io::stdin();
+
+    for line in stdin.lock().lines() {
+        let line = line.unwrap_or_else(|err| {
+            error!("Error reading stdin: {}", err);
+            process::exit(1);
+        });
+
+        let url = line.trim();
+        if url.is_empty() || url.starts_with('#') {
+            continue;
+        }
+
+        if args.flag_output == Output::Json {
+            ret |= get_json(url, rtd, params);
+            continue;
+        }
+
+        let token = add_scheme_for_tld(url).unwrap_or_else(|| url.to_string());
+        let title = new_session(params)
+            .request(&token)
+            .and_then(|mut resp| get_title(&mut resp, rtd, None));
+
+        match title {
+            Ok(t) => println!("{}\t{}", url, t),
+            Err(e) => {
+                println!("{}\terror: {}", url, e);
+                ret = 1;
+            },
+        };
+    }
+
+    ret
 }
@@ -35,11 +35,19 @@ use url_bot_rs::VERSION;
 use url_bot_rs::sqlite::Database;
 use url_bot_rs::config::Rtd;
 use url_bot_rs::message::handle_message;
+use url_bot_rs::metrics;
+use url_bot_rs::http::startup_check;
+use url_bot_rs::outbox::Outbox;
+use url_bot_rs::logging::{self, LogFormat};
 
 use docopt::Docopt;
 use irc::client::prelude::*;
 use std::process;
 use std::path::PathBuf;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::{cmp, thread};
+use std::time::{Duration, Instant};
 use stderrlog::{Timestamp, ColorChoice};
 use atty::{is, Stream};
 
@@ -51,12 +59,14 @@ Usage:
     url-bot-rs [options] [-v...] [--db=PATH]
 
 Options:
-    -h --help       Show this help message.
-    --version       Print version.
-    -v --verbose    Show extra information.
-    -d --db=PATH    Use a sqlite database at PATH.
-    -c --conf=PATH  Use configuration file at PATH.
-    -t --timestamp  Force timestamps.
+    -h --help         Show this help message.
+    --version         Print version.
+    -v --verbose      Show extra information.
+    -d --db=PATH      Use a sqlite database at PATH.
+    -c --conf=PATH    Use configuration file at PATH.
+    -t --timestamp    Force timestamps.
+    --log-format=FMT  Log format, text or json.
+    --dry-run         Resolve URLs, but only log responses.
 ";
 
 #[derive(Debug, Deserialize, Default)]
@@ -65,10 +75,15 @@ pub struct Args {
     flag_db: Option<PathBuf>,
     flag_conf: Option<PathBuf>,
     flag_timestamp: bool,
+    flag_log_format: Option<LogFormat>,
+    flag_dry_run: bool,
 }
 
 const MIN_VERBOSITY: usize = 2;
 
+/// time a connection must stay up for the reconnection delay to be reset
+const STABLE_CONNECTION_S: u64 = 60;
+
 fn main() {
     // parse command line arguments with docopt
     let args: Args = Docopt::new(USAGE)
@@ -85,19 +100,30 @@ fn main() {
     if args.flag_timestamp { timestamp = Timestamp::Second };
 
     // start logger
-    stderrlog::new()
+    logging::init(stderrlog::new()
         .module(module_path!())
         .modules(vec![
             "url_bot_rs::message",
             "url_bot_rs::config",
             "url_bot_rs::http",
+            "url_bot_rs::cookies",
+            "url_bot_rs::sites",
+            "url_bot_rs::metrics",
+            "url_bot_rs::outbox",
+            "url_bot_rs::webhook",
+            "url_bot_rs::gemini",
+            "url_bot_rs::nick",
+            "url_bot_rs::unix",
         ])
         .verbosity(args.flag_verbose + MIN_VERBOSITY)
         .timestamp(timestamp)
-        .color(coloured_output)
-        .init()
+        .color(coloured_output))
         .unwrap();
 
+    if let Some(format) = args.flag_log_format {
+        logging::set_format(format);
+    }
+
     // get a run-time configuration data structure
     let mut rtd: Rtd = Rtd::new()
         .conf(&args.flag_conf)
@@ -108,17 +134,28 @@ fn main() {
             process::exit(1);
         });
 
+    // command line overrides the configured log format
+    logging::set_format(args.flag_log_format.unwrap_or(rtd.conf.params.log_format));
+
+    if args.flag_dry_run {
+        rtd.conf.features.dry_run = true;
+    }
+    if rtd.conf.features.dry_run {
+        warn!("Dry run, responses are logged, but not sent");
+    }
+
     info!("Using configuration: {}", rtd.paths.conf.display());
     if args.flag_verbose > 0 {
         println!("\n[features]\n{}", rtd.conf.features);
         println!("[parameters]\n{}", rtd.conf.params);
         println!("[database]\n{}", rtd.conf.database);
+        println!("[server]\n{}", rtd.conf.server);
     }
 
     // open the sqlite database for logging
     let db = if let Some(ref path) = rtd.paths.db {
         info!("Using database: {}", path.display());
-        Database::open(path).unwrap_or_else(|err| {
+        Database::open(path, &rtd.conf.database).unwrap_or_else(|err| {
             error!("Database error: {}", err);
             process::exit(1);
         })
@@ -127,24 +164,92 @@ fn main() {
         Database::open_in_memory().unwrap()
     };
 
-    // create IRC reactor
-    let mut reactor = IrcReactor::new().unwrap();
-    let client = reactor
-        .prepare_client_and_connect(&rtd.conf.client)
-        .unwrap_or_else(|err| {
-        error!("IRC prepare error: {}", err);
-        process::exit(1);
-    });
-    client.identify().unwrap();
+    if rtd.conf.params.danger_accept_invalid_certs {
+        warn!("!!! TLS certificate verification is DISABLED !!!");
+        warn!("danger_accept_invalid_certs is set, use for testing only");
+    }
+
+    // check that titles can be retrieved, if enabled
+    check_resolution(&rtd);
+
+    // serve prometheus metrics
+    if rtd.conf.features.metrics {
+        let port = rtd.conf.params.metrics_port;
+        metrics::serve(port).unwrap_or_else(|err| {
+            error!("Metrics server error: {}", err);
+            process::exit(1);
+        });
+        info!("Serving metrics on port {}", port);
+    }
+
+    // rate limit outgoing messages
+    let server = rtd.conf.server.clone();
+    if server.send_rate > 0.0 {
+        rtd.outbox = Some(Outbox::new(server.send_rate, server.send_burst));
+    }
+
+    // run-time data and the database persist across reconnections
+    let rtd = Rc::new(RefCell::new(rtd));
+    let db = Rc::new(db);
+    let mut delay = server.reconnect_delay_s;
+
+    loop {
+        match connect(&rtd, &db) {
+            Ok(mut reactor) => {
+                let connected = Instant::now();
+                match reactor.run() {
+                    Ok(()) => warn!("IRC connection closed"),
+                    Err(err) => error!("IRC client error: {}", err),
+                };
+
+                // only reset the delay if the connection wasn't dropped at
+                // once, so that a server doing so isn't hammered
+                if connected.elapsed() >= Duration::from_secs(STABLE_CONNECTION_S) {
+                    delay = server.reconnect_delay_s;
+                }
+            },
+            Err(err) => error!("IRC connection error: {}", err),
+        };
+
+        info!("Reconnecting in {}s", delay);
+        thread::sleep(Duration::from_secs(delay));
+        delay = cmp::min(delay * 2, server.reconnect_delay_max_s);
+    }
+}
+
+/// resolve the startup check URL, if enabled, exiting on failure in strict
+/// mode
+fn check_resolution(rtd: &Rtd) {
+    if !rtd.conf.features.startup_check {
+        return;
+    }
+
+    match startup_check(rtd) {
+        Ok(resolved) => info!("Startup check succeeded: {}", resolved.title),
+        Err(err) => {
+            error!("Startup check failed, {}", err);
+            if rtd.conf.features.startup_check_strict {
+                process::exit(1);
+            }
+        },
+    }
+}
+
+/// connect to the IRC server, and register a message handler
+fn connect(rtd: &Rc<RefCell<Rtd>>, db: &Rc<Database>) -> irc::error::Result<IrcReactor> {
+    // create IRC reactor, connecting using the current configuration, so
+    // that any channels joined at run time are rejoined
+    let mut reactor = IrcReactor::new()?;
+    let conf = rtd.borrow().conf.client.clone();
+    let client = reactor.prepare_client_and_connect(&conf)?;
+    client.identify()?;
 
     // register handler
+    let (rtd, db) = (Rc::clone(rtd), Rc::clone(db));
     reactor.register_client_with_handler(client, move |client, message| {
-        handle_message(client, &message, &mut rtd, &db);
+        handle_message(client, &message, &mut rtd.borrow_mut(), &db);
         Ok(())
     });
 
-    reactor.run().unwrap_or_else(|err| {
-        error!("IRC client error: {}", err);
-        process::exit(1);
-    });
+    Ok(reactor)
 }
@@ -10,21 +10,154 @@ use std::path::{Path, PathBuf};
 use irc::client::data::Config as IrcConfig;
 use failure::Error;
 use std::fmt;
+use std::net::IpAddr;
+use std::time::Instant;
+use url::Url;
+use regex::Regex;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use directories::{ProjectDirs, BaseDirs};
 
 use super::buildinfo;
+use super::logging::LogFormat;
+use super::http::{self, BasicAuth, HttpVersion};
+use super::message::{Failures, SplitUrl, Spoiler};
+use super::outbox::Outbox;
+use super::emoji::EmojiMode;
 
 // serde structures defining the configuration file structure
-#[derive(Default, Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone)]
 #[serde(default)]
 pub struct Features {
     pub report_metadata: bool,
     pub report_mime: bool,
     pub mask_highlights: bool,
+    pub mask_title_highlights: bool,
     pub send_notice: bool,
     pub history: bool,
     pub invite: bool,
     pub autosave: bool,
+    pub metrics: bool,
+    pub use_cookies: bool,
+    pub block_downgrades: bool,
+    pub https_only: bool,
+    pub persist_cookies: bool,
+    pub follow_meta_refresh: bool,
+    pub report_errors: bool,
+    pub spoiler_mode: bool,
+    pub combine_replies: bool,
+    pub site_handlers: bool,
+    pub report_language: bool,
+    pub refang: bool,
+    pub strip_colors: bool,
+    pub strip_colors_queries: bool,
+    pub webhook: bool,
+    pub discord_webhook: bool,
+    pub unicode_hosts: bool,
+    pub flag_mixed_scripts: bool,
+    pub use_canonical: bool,
+    pub dry_run: bool,
+    pub commands: bool,
+    pub first_title_only: bool,
+    pub fallback_to_host: bool,
+    pub assume_scheme: bool,
+    pub gemini: bool,
+    pub startup_check: bool,
+    pub startup_check_strict: bool,
+    pub show_title_mismatch: bool,
+    pub log_redirect_chain: bool,
+    pub unix_sockets: bool,
+    pub rejoin_split_urls: bool,
+    pub conditional_requests: bool,
+    pub trim_site_suffix: bool,
+    pub retry_trailing_slash: bool,
+    pub report_cooldown_errors: bool,
+    pub emoji_mode: EmojiMode,
+    /// URL schemes to retrieve titles for, only http and https, and gemini,
+    /// if enabled, are currently supported by `resolve_url`
+    pub allowed_schemes: Vec<String>,
+    /// mime types, or types alone, for which metadata and mime types are
+    /// reported, all types if empty
+    pub report_types: Vec<String>,
+    /// mime types, or types alone, of responses which are downloaded, all
+    /// types if empty
+    pub content_type_allowlist: Vec<String>,
+    /// regular expressions matching titles of pages which require a login,
+    /// e.g. `(?i)^log in`, such titles are annotated
+    pub paywall_patterns: Vec<String>,
+    /// nicks whose messages are the only ones resolved, all if empty
+    pub allowed_nicks: Vec<String>,
+    /// whether URLs posted in these channels are logged, in place of `history`
+    pub history_channels: BTreeMap<String, bool>,
+    /// whether formatting codes are removed in these channels, in place of
+    /// `strip_colors`
+    pub strip_colors_channels: BTreeMap<String, bool>,
+    /// prefixes of replies, in place of `⤷`, by mime type, or by the type
+    /// alone, e.g. `image`
+    pub type_prefixes: BTreeMap<String, String>,
+}
+
+impl Default for Features {
+    fn default() -> Self {
+        Self {
+            report_metadata: false,
+            report_mime: false,
+            mask_highlights: false,
+            mask_title_highlights: false,
+            send_notice: false,
+            history: false,
+            invite: false,
+            autosave: false,
+            metrics: false,
+            use_cookies: true,
+            block_downgrades: false,
+            https_only: false,
+            persist_cookies: false,
+            follow_meta_refresh: false,
+            report_errors: false,
+            spoiler_mode: false,
+            combine_replies: false,
+            site_handlers: false,
+            report_language: false,
+            refang: false,
+            strip_colors: false,
+            strip_colors_queries: false,
+            webhook: false,
+            discord_webhook: false,
+            unicode_hosts: false,
+            flag_mixed_scripts: false,
+            use_canonical: false,
+            dry_run: false,
+            commands: false,
+            first_title_only: false,
+            fallback_to_host: false,
+            assume_scheme: true,
+            gemini: false,
+            startup_check: false,
+            startup_check_strict: false,
+            show_title_mismatch: false,
+            log_redirect_chain: false,
+            unix_sockets: false,
+            rejoin_split_urls: false,
+            conditional_requests: false,
+            trim_site_suffix: false,
+            retry_trailing_slash: false,
+            report_cooldown_errors: false,
+            emoji_mode: EmojiMode::Raw,
+            allowed_schemes: vec!["http".to_string(), "https".to_string()],
+            report_types: vec![],
+            content_type_allowlist: vec![
+                "text/*".to_string(),
+                "application/xhtml+xml".to_string(),
+                "image/*".to_string(),
+                "application/pdf".to_string(),
+            ],
+            paywall_patterns: vec![],
+            allowed_nicks: vec![],
+            history_channels: BTreeMap::new(),
+            strip_colors_channels: BTreeMap::new(),
+            type_prefixes: BTreeMap::new(),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -33,12 +166,16 @@ pub struct Database {
     pub path: String,
     #[serde(rename = "type")]
     pub db_type: String,
+    pub busy_timeout_ms: u64,
+    pub wal: bool,
 }
 impl Default for Database {
     fn default() -> Self {
         Self {
             path: "".to_string(),
             db_type: "in-memory".to_string(),
+            busy_timeout_ms: 5000,
+            wal: false,
         }
     }
 }
@@ -47,14 +184,127 @@ impl Default for Database {
 #[serde(default)]
 pub struct Parameters {
     pub url_limit: u8,
+    pub accept: String,
     pub accept_lang: String,
+    pub metrics_port: u16,
+    pub log_format: LogFormat,
+    pub mask_nicks: Vec<String>,
+    pub bot_nicks: String,
+    pub preferred_nick: String,
+    pub nickserv_password: String,
+    pub admins: Vec<String>,
+    pub unsafe_chars: String,
+    pub chunk_size_kb: u64,
+    pub max_title_len: usize,
+    pub max_url_length: usize,
+    pub max_gif_frames: usize,
+    pub max_image_pixels: u64,
+    pub title_template: String,
+    pub min_size_report_bytes: u64,
+    pub max_content_length_mb: u64,
+    pub cookie_limit: usize,
+    pub header_size_limit_kb: usize,
+    pub error_template: String,
+    pub prepost_template: String,
+    pub spoiler_timeout_s: u64,
+    pub split_url_timeout_ms: u64,
+    pub failure_limit: u32,
+    pub failure_cooldown_secs: u64,
+    pub total_timeout_s: u64,
+    pub connect_timeout_s: u64,
+    pub read_timeout_s: u64,
+    pub http_version: HttpVersion,
+    pub ca_cert: String,
+    pub danger_accept_invalid_certs: bool,
+    pub force_identity_encoding: bool,
+    pub github_token: String,
+    pub imgur_client_id: String,
+    pub local_address: String,
+    pub webhook_url: String,
+    pub discord_webhook_url: String,
+    pub normalize_urls: bool,
+    pub startup_check_url: String,
+    /// IP addresses to use in place of DNS resolution, for the given hosts
+    pub host_overrides: BTreeMap<String, String>,
+    /// HTTP Basic authentication credentials, for the given hosts
+    pub basic_auth: BTreeMap<String, BasicAuth>,
+    /// headers sent with each request, by name
+    pub extra_headers: BTreeMap<String, String>,
+    /// paths of Unix domain sockets to connect to for the given hosts
+    pub unix_sockets: BTreeMap<String, String>,
+    /// `url_limit`, for the given channels
+    pub url_limit_channels: BTreeMap<String, u8>,
 }
 
 impl Default for Parameters {
     fn default() -> Self {
         Self {
             url_limit: 10,
-            accept_lang: "en".to_string()
+            accept: http::ACCEPT.to_string(),
+            accept_lang: "en".to_string(),
+            metrics_port: 9186,
+            log_format: LogFormat::Text,
+            mask_nicks: vec![],
+            bot_nicks: "".to_string(),
+            preferred_nick: "".to_string(),
+            nickserv_password: "".to_string(),
+            admins: vec![],
+            unsafe_chars: r#"{}|\^~[]`<>""#.to_string(),
+            chunk_size_kb: 100,
+            max_title_len: 0,
+            max_url_length: 2048,
+            max_gif_frames: 1000,
+            max_image_pixels: 100_000_000,
+            title_template: "{prefix} {title}".to_string(),
+            min_size_report_bytes: 0,
+            max_content_length_mb: 1024,
+            cookie_limit: 64,
+            header_size_limit_kb: 128,
+            error_template: "⤷ failed to retrieve title: {error}".to_string(),
+            prepost_template: "{title} → {time} {user} ({channel})".to_string(),
+            spoiler_timeout_s: 600,
+            split_url_timeout_ms: 2000,
+            failure_limit: 3,
+            failure_cooldown_secs: 0,
+            total_timeout_s: 30,
+            connect_timeout_s: 10,
+            read_timeout_s: 10,
+            http_version: HttpVersion::Auto,
+            ca_cert: "".to_string(),
+            danger_accept_invalid_certs: false,
+            force_identity_encoding: true,
+            github_token: "".to_string(),
+            imgur_client_id: "".to_string(),
+            local_address: "".to_string(),
+            webhook_url: "".to_string(),
+            discord_webhook_url: "".to_string(),
+            normalize_urls: false,
+            startup_check_url: "https://example.com/".to_string(),
+            host_overrides: BTreeMap::new(),
+            basic_auth: BTreeMap::new(),
+            extra_headers: BTreeMap::new(),
+            unix_sockets: BTreeMap::new(),
+            url_limit_channels: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct Server {
+    pub reconnect_delay_s: u64,
+    pub reconnect_delay_max_s: u64,
+    pub send_rate: f64,
+    pub send_burst: u32,
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Self {
+            reconnect_delay_s: 10,
+            reconnect_delay_max_s: 600,
+            send_rate: 1.0,
+            send_burst: 4,
         }
     }
 }
@@ -66,6 +316,7 @@ pub struct Conf {
     #[serde(rename = "parameters")]
     pub params: Parameters,
     pub database: Database,
+    pub server: Server,
     #[serde(rename = "connection")]
     pub client: IrcConfig,
 }
@@ -74,8 +325,53 @@ impl Conf {
     /// load configuration TOML from a file
     pub fn load(path: impl AsRef<Path>) -> Result<Self, Error> {
         let conf = fs::read_to_string(path.as_ref())?;
-        let conf: Conf = toml::de::from_str(&conf)?;
-        Ok(conf)
+        Self::parse(&conf)
+    }
+
+    /// parse configuration TOML, in which channels may be given either as
+    /// names, or as tables with the channel's key
+    fn parse(conf: &str) -> Result<Self, Error> {
+        let mut conf: toml::Value = toml::de::from_str(conf)?;
+        move_channel_keys(&mut conf)?;
+        Ok(conf.try_into()?)
+    }
+
+    /// check values which can't be checked when deserialising
+    pub fn validate(&self) -> Result<(), Error> {
+        Patterns::new(self)?;
+
+        let addr = &self.params.local_address;
+        if !addr.is_empty() {
+            addr.parse::<IpAddr>().map_err(|err| {
+                format_err!("invalid local_address \"{}\": {}", addr, err)
+            })?;
+        }
+
+        let webhook = &self.params.webhook_url;
+        if self.features.webhook {
+            webhook.parse::<Url>().map_err(|err| {
+                format_err!("invalid webhook_url \"{}\": {}", webhook, err)
+            })?;
+        }
+
+        for (name, value) in &self.params.extra_headers {
+            http::check_extra_header(name, value).map_err(|err| {
+                format_err!("invalid extra header \"{}\": {}", name, err)
+            })?;
+        }
+
+        if self.server.reconnect_delay_s == 0 {
+            bail!("invalid reconnect_delay_s, must be at least 1");
+        }
+
+        let discord = &self.params.discord_webhook_url;
+        if self.features.discord_webhook {
+            discord.parse::<Url>().map_err(|err| {
+                format_err!("invalid discord_webhook_url \"{}\": {}", discord, err)
+            })?;
+        }
+
+        Ok(())
     }
 
     /// write configuration to a file
@@ -104,12 +400,58 @@ impl Conf {
     }
 }
 
+/// Replace channels given as tables, `{ name = "#chan", key = "secret" }`, with
+/// their names, adding their keys to `channel_keys`, which are used by the IRC
+/// client when joining channels
+fn move_channel_keys(conf: &mut toml::Value) -> Result<(), Error> {
+    let connection = match conf.get_mut("connection").and_then(|c| c.as_table_mut()) {
+        Some(connection) => connection,
+        None => return Ok(()),
+    };
+
+    let mut keys = vec![];
+    if let Some(channels) = connection.get_mut("channels").and_then(|c| c.as_array_mut()) {
+        for chan in channels.iter_mut() {
+            let name = match chan.as_table() {
+                Some(table) => table.get("name")
+                    .and_then(|n| n.as_str())
+                    .ok_or_else(|| format_err!("channel without a name"))?
+                    .to_string(),
+                None => continue,
+            };
+
+            match chan.get("key").map(|k| k.as_str()) {
+                Some(Some(key)) => keys.push((name.clone(), key.to_string())),
+                Some(None) => bail!("invalid key for channel {}", name),
+                None => (),
+            }
+
+            *chan = toml::Value::String(name);
+        }
+    }
+
+    if keys.is_empty() {
+        return Ok(());
+    }
+
+    let channel_keys = connection.entry("channel_keys".to_string())
+        .or_insert_with(|| toml::Value::Table(toml::value::Table::new()))
+        .as_table_mut()
+        .ok_or_else(|| format_err!("channel_keys must be a table"))?;
+    for (name, key) in keys {
+        channel_keys.insert(name, toml::Value::String(key));
+    }
+
+    Ok(())
+}
+
 impl Default for Conf {
     fn default() -> Self {
         Self {
             features: Features::default(),
             params: Parameters::default(),
             database: Database::default(),
+            server: Server::default(),
             client: IrcConfig {
                 nickname: Some("url-bot-rs".to_string()),
                 alt_nicks: Some(vec!["url-bot-rs_".to_string()]),
@@ -146,6 +488,54 @@ pub struct Rtd {
     /// configuration file data
     pub conf: Conf,
     pub history: bool,
+    /// titles withheld in spoiler mode, by channel
+    pub spoilers: HashMap<String, Spoiler>,
+    /// the starts of URLs which may continue in the next message, by channel
+    /// and nick
+    pub split_urls: HashMap<(String, String), SplitUrl>,
+    /// consecutive failures to resolve URLs, by URL
+    pub failures: HashMap<String, Failures>,
+    /// channels in which resolution has been disabled by an admin, lower-case
+    pub disabled: HashSet<String>,
+    /// queue of outgoing messages, if rate limited
+    pub outbox: Option<Outbox>,
+    /// nick in use, as given by the server, which may differ from that
+    /// chosen by the client, after nick recovery
+    pub nick: Option<String>,
+    /// time at which the bot was started
+    pub started: Option<Instant>,
+    /// regular expressions from the configuration
+    pub patterns: Patterns,
+}
+
+/// regular expressions from the configuration, compiled once it's loaded
+#[derive(Default, Clone)]
+pub struct Patterns {
+    /// `params.bot_nicks`, if set
+    pub bot_nicks: Option<Regex>,
+    /// `features.paywall_patterns`
+    pub paywall: Vec<Regex>,
+}
+
+impl Patterns {
+    pub fn new(conf: &Conf) -> Result<Self, Error> {
+        let pattern = &conf.params.bot_nicks;
+        let bot_nicks = if pattern.is_empty() {
+            None
+        } else {
+            Some(Regex::new(pattern).map_err(|err| {
+                format_err!("invalid bot_nicks \"{}\": {}", pattern, err)
+            })?)
+        };
+
+        let paywall = conf.features.paywall_patterns.iter()
+            .map(|pattern| Regex::new(pattern).map_err(|err| {
+                format_err!("invalid paywall pattern \"{}\": {}", pattern, err)
+            }))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Patterns { bot_nicks, paywall })
+    }
 }
 
 #[derive(Default, Clone)]
@@ -156,7 +546,10 @@ pub struct Paths {
 
 impl Rtd {
     pub fn new() -> Self {
-        Rtd::default()
+        Rtd {
+            started: Some(Instant::now()),
+            ..Rtd::default()
+        }
     }
 
     pub fn db(&mut self, path: Option<PathBuf>) -> &mut Self {
@@ -189,6 +582,8 @@ impl Rtd {
 
         // load config file
         self.conf = Conf::load(&self.paths.conf)?;
+        self.conf.validate()?;
+        self.patterns = Patterns::new(&self.conf)?;
 
         // get db path, and history
         self.set_db_info();
@@ -206,10 +601,16 @@ impl Rtd {
     fn set_db_info(&mut self) {
         let dirs = ProjectDirs::from("org", "", "url-bot-rs").unwrap();
 
+        // history may be enabled only for some channels
+        let channel_history = self.conf.features.history_channels
+            .values()
+            .any(|&enabled| enabled);
+
         let (hist_enabled, db_path) = if let Some(ref path) = self.paths.db {
             // enable history when db path given as CLI argument
+            self.conf.features.history = true;
             (true, Some(PathBuf::from(path)))
-        } else if !self.conf.features.history {
+        } else if !self.conf.features.history && !channel_history {
             // no path specified on CLI, and history disabled in configuration
             (false, None)
         } else if !self.conf.database.path.is_empty() {
@@ -238,7 +639,7 @@ macro_rules! impl_display {
         })+
     }
 }
-impl_display!(Features, Parameters, Database);
+impl_display!(Features, Parameters, Database, Server);
 
 fn create_dir_if_missing(dir: &Path) -> Result<bool, Error> {
     let pdir = dir.to_str().unwrap();
@@ -289,6 +690,106 @@ mod tests {
         assert_eq!(default, example);
     }
 
+    #[test]
+    fn validate_local_address() {
+        let mut conf = Conf::default();
+        assert!(conf.validate().is_ok());
+
+        for addr in &["127.0.0.1", "::1"] {
+            conf.params.local_address = addr.to_string();
+            assert!(conf.validate().is_ok());
+        }
+
+        conf.params.local_address = "localhost".to_string();
+        let err = conf.validate().unwrap_err();
+        assert!(err.to_string().starts_with("invalid local_address \"localhost\""));
+    }
+
+    #[test]
+    fn parse_channels_with_keys() {
+        let conf = Conf::parse("[connection]
+            channels = [\"#a\", \"#b\"]").unwrap();
+        assert_eq!(conf.client.channels(), vec!["#a", "#b"]);
+        assert_eq!(conf.client.channel_key("#a"), None);
+
+        let conf = Conf::parse("[connection]
+            channels = [{ name = \"#a\" }, { name = \"#b\", key = \"secret\" }]
+            [connection.channel_keys]
+            \"#c\" = \"other\"").unwrap();
+        assert_eq!(conf.client.channels(), vec!["#a", "#b"]);
+        assert_eq!(conf.client.channel_key("#a"), None);
+        assert_eq!(conf.client.channel_key("#b"), Some("secret"));
+        assert_eq!(conf.client.channel_key("#c"), Some("other"));
+
+        let conf = Conf::parse("[[connection.channels]]
+            name = \"#b\"
+            key = \"secret\"").unwrap();
+        assert_eq!(conf.client.channels(), vec!["#b"]);
+        assert_eq!(conf.client.channel_key("#b"), Some("secret"));
+
+        assert!(Conf::parse("[connection]
+            channels = [{ key = \"secret\" }]").is_err());
+        assert!(Conf::parse("[connection]
+            channels = [{ name = \"#a\", key = 1 }]").is_err());
+    }
+
+    #[test]
+    fn validate_webhook_url() {
+        let mut conf = Conf::default();
+        conf.features.webhook = true;
+        assert!(conf.validate().is_err());
+
+        conf.params.webhook_url = "http://127.0.0.1/hook".to_string();
+        assert!(conf.validate().is_ok());
+
+        conf.features.discord_webhook = true;
+        assert!(conf.validate().is_err());
+
+        conf.params.discord_webhook_url = "https://discord.com/api/webhooks/1/a".to_string();
+        assert!(conf.validate().is_ok());
+    }
+
+    #[test]
+    fn validate_extra_headers() {
+        let mut conf = Conf::default();
+        conf.params.extra_headers.insert("DNT".to_string(), "1".to_string());
+        assert!(conf.validate().is_ok());
+
+        conf.params.extra_headers.insert("Connection".to_string(), "close".to_string());
+        assert!(conf.validate().is_err());
+    }
+
+    #[test]
+    fn validate_reconnect_delay() {
+        let mut conf = Conf::default();
+        conf.server.reconnect_delay_s = 1;
+        assert!(conf.validate().is_ok());
+
+        conf.server.reconnect_delay_s = 0;
+        assert!(conf.validate().is_err());
+    }
+
+    #[test]
+    fn validate_bot_nicks() {
+        let mut conf = Conf::default();
+        conf.params.bot_nicks = "^bot|bot$".to_string();
+        assert!(conf.validate().is_ok());
+
+        conf.params.bot_nicks = "(bot".to_string();
+        assert!(conf.validate().is_err());
+    }
+
+    #[test]
+    fn validate_paywall_patterns() {
+        let mut conf = Conf::default();
+        conf.features.paywall_patterns = vec!["(?i)^log in".to_string()];
+        assert!(conf.validate().is_ok());
+
+        conf.features.paywall_patterns.push("[subscribe".to_string());
+        let err = conf.validate().unwrap_err();
+        assert!(err.to_string().starts_with("invalid paywall pattern \"[subscribe\""));
+    }
+
     #[test]
     fn test_expand_tilde() {
         let homedir: PathBuf = BaseDirs::new()
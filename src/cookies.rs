@@ -0,0 +1,258 @@
+/*
+ * Cookie storage and matching, loosely following RFC 6265
+ * https://tools.ietf.org/html/rfc6265
+ *
+ */
+use cookie::Cookie;
+use url::{Url, Host};
+use time::{self, Tm};
+
+use super::sqlite::StoredCookie;
+
+/// a cookie held by a session, along with its scope
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionCookie {
+    pub name: String,
+    pub value: String,
+    pub domain: String,
+    pub path: String,
+    /// set without a Domain attribute, so sent only to the host which set it
+    pub host_only: bool,
+    pub secure: bool,
+    /// absolute expiry time, or `None` for a cookie lasting the session
+    pub expires: Option<Tm>,
+}
+
+impl SessionCookie {
+    /// Parse a Set-Cookie header received in response to a request for `url`,
+    /// (RFC 6265 section 5.3). Cookies setting a domain which the request
+    /// host doesn't belong to are rejected.
+    pub fn parse(set_cookie: &str, url: &Url, now: Tm) -> Option<Self> {
+        let c = Cookie::parse(set_cookie).ok()?;
+        let host = url.host_str()?.to_lowercase();
+
+        // Max-Age takes precedence over Expires
+        let expires = match (c.max_age(), c.expires()) {
+            (Some(age), _) => Some(now + age),
+            (None, Some(tm)) => Some(tm),
+            (None, None) => None,
+        };
+
+        let domain = c.domain()
+            .map(|d| d.trim_start_matches('.').to_lowercase())
+            .filter(|d| !d.is_empty());
+
+        let (domain, host_only) = match domain {
+            Some(d) => {
+                if !domain_match(url, &d) {
+                    debug!("rejected cookie {} for domain {}", c.name(), d);
+                    return None;
+                }
+                (d, false)
+            },
+            None => (host, true),
+        };
+
+        let path = match c.path() {
+            Some(p) if p.starts_with('/') => p.to_string(),
+            _ => default_path(url.path()),
+        };
+
+        Some(Self {
+            name: c.name().to_string(),
+            value: c.value().to_string(),
+            domain,
+            path,
+            host_only,
+            secure: c.secure().unwrap_or(false),
+            expires,
+        })
+    }
+
+    pub fn is_expired(&self, now: Tm) -> bool {
+        self.expires
+            .map(|e| e.to_timespec() <= now.to_timespec())
+            .unwrap_or(false)
+    }
+
+    /// whether a new cookie should replace this one
+    pub fn same_key(&self, other: &SessionCookie) -> bool {
+        self.name == other.name
+            && self.domain == other.domain
+            && self.path == other.path
+    }
+
+    /// whether the cookie should be sent with a request for `url`
+    pub fn matches(&self, url: &Url, now: Tm) -> bool {
+        let host = url.host_str().unwrap_or_default().to_lowercase();
+
+        let domain_ok = if self.host_only {
+            host == self.domain
+        } else {
+            domain_match(url, &self.domain)
+        };
+
+        domain_ok
+            && path_match(url.path(), &self.path)
+            && (!self.secure || url.scheme() == "https")
+            && !self.is_expired(now)
+    }
+
+    /// Convert to the form in which cookies are persisted, cookies lasting
+    /// only for the session aren't persisted.
+    pub fn to_stored(&self) -> Option<StoredCookie> {
+        let expires = self.expires?;
+
+        let mut c = Cookie::build(self.name.clone(), self.value.clone())
+            .path(self.path.clone())
+            .secure(self.secure)
+            .expires(expires)
+            .finish();
+        if !self.host_only { c.set_domain(self.domain.clone()); }
+
+        Some(StoredCookie {
+            domain: self.domain.clone(),
+            path: self.path.clone(),
+            name: self.name.clone(),
+            cookie: c.to_string(),
+            expires: expires.to_timespec().sec,
+        })
+    }
+
+    pub fn from_stored(stored: &StoredCookie) -> Option<Self> {
+        let c = Cookie::parse(stored.cookie.as_str()).ok()?;
+
+        Some(Self {
+            name: stored.name.clone(),
+            value: c.value().to_string(),
+            domain: stored.domain.clone(),
+            path: stored.path.clone(),
+            host_only: c.domain().is_none(),
+            secure: c.secure().unwrap_or(false),
+            expires: Some(time::at_utc(time::Timespec::new(stored.expires, 0))),
+        })
+    }
+}
+
+/// domain matching (RFC 6265 section 5.1.3), IP addresses only match exactly
+pub fn domain_match(url: &Url, domain: &str) -> bool {
+    let host = url.host_str().unwrap_or_default().to_lowercase();
+
+    match url.host() {
+        Some(Host::Domain(_)) => {
+            host == domain || host.ends_with(&format!(".{}", domain))
+        },
+        _ => host == domain,
+    }
+}
+
+/// path matching (RFC 6265 section 5.1.4)
+pub fn path_match(path: &str, cookie_path: &str) -> bool {
+    path == cookie_path
+        || (path.starts_with(cookie_path) && (
+            cookie_path.ends_with('/')
+            || path[cookie_path.len()..].starts_with('/')
+        ))
+}
+
+/// the default cookie path for a request path (RFC 6265 section 5.1.4)
+fn default_path(path: &str) -> String {
+    if !path.starts_with('/') {
+        return "/".to_string();
+    }
+
+    match path.rfind('/') {
+        Some(0) | None => "/".to_string(),
+        Some(i) => path[..i].to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn now() -> Tm {
+        time::at_utc(time::Timespec::new(1_000_000, 0))
+    }
+
+    fn url(u: &str) -> Url {
+        Url::parse(u).unwrap()
+    }
+
+    fn parse(c: &str, u: &str) -> Option<SessionCookie> {
+        SessionCookie::parse(c, &url(u), now())
+    }
+
+    #[test]
+    fn test_cookie_domain() {
+        let c = parse("a=1", "http://www.example.com/").unwrap();
+        assert!(c.host_only);
+        assert!(c.matches(&url("http://www.example.com/"), now()));
+        assert!(!c.matches(&url("http://sub.www.example.com/"), now()));
+        assert!(!c.matches(&url("http://example.com/"), now()));
+
+        let c = parse("a=1; Domain=.Example.com", "http://www.example.com/")
+            .unwrap();
+        assert!(!c.host_only);
+        assert_eq!(c.domain, "example.com");
+        assert!(c.matches(&url("http://example.com/"), now()));
+        assert!(c.matches(&url("http://a.b.example.com/"), now()));
+        assert!(!c.matches(&url("http://badexample.com/"), now()));
+        assert!(!c.matches(&url("http://example.org/"), now()));
+
+        // domains the request host doesn't belong to are rejected
+        assert!(parse("a=1; Domain=example.org", "http://example.com/").is_none());
+        assert!(parse("a=1; Domain=www.example.com", "http://example.com/").is_none());
+        assert!(parse("a=1; Domain=0.0.1", "http://127.0.0.1/").is_none());
+    }
+
+    #[test]
+    fn test_cookie_path() {
+        let c = parse("a=1", "http://z.zzz/foo/bar").unwrap();
+        assert_eq!(c.path, "/foo");
+        assert_eq!(parse("a=1", "http://z.zzz/foo").unwrap().path, "/");
+        assert_eq!(parse("a=1; Path=x", "http://z.zzz/").unwrap().path, "/");
+
+        let c = parse("a=1; Path=/foo", "http://z.zzz/").unwrap();
+        assert!(c.matches(&url("http://z.zzz/foo"), now()));
+        assert!(c.matches(&url("http://z.zzz/foo/bar"), now()));
+        assert!(!c.matches(&url("http://z.zzz/foobar"), now()));
+        assert!(!c.matches(&url("http://z.zzz/"), now()));
+
+        assert!(path_match("/foo/bar", "/foo/"));
+        assert!(path_match("/", "/"));
+    }
+
+    #[test]
+    fn test_cookie_secure_and_expiry() {
+        let c = parse("a=1; Secure", "https://z.zzz/").unwrap();
+        assert!(c.matches(&url("https://z.zzz/"), now()));
+        assert!(!c.matches(&url("http://z.zzz/"), now()));
+
+        let c = parse("a=1; Max-Age=60", "http://z.zzz/").unwrap();
+        assert_eq!(c.expires.unwrap().to_timespec().sec, 1_000_060);
+        assert!(c.matches(&url("http://z.zzz/"), now()));
+        assert!(!c.matches(&url("http://z.zzz/"), now() + time::Duration::seconds(60)));
+
+        let c = parse("a=1; Expires=Wed, 21 Oct 2015 07:28:00 GMT", "http://z.zzz/")
+            .unwrap();
+        assert!(c.is_expired(now() + time::Duration::days(365 * 50)));
+        assert!(!c.is_expired(now()));
+        assert!(parse("a=1; Max-Age=0", "http://z.zzz/").unwrap().is_expired(now()));
+    }
+
+    #[test]
+    fn test_cookie_stored_round_trip() {
+        assert!(parse("a=1", "http://z.zzz/").unwrap().to_stored().is_none());
+
+        for c in &[
+            parse("a=1; Max-Age=60", "http://www.z.zzz/x/y").unwrap(),
+            parse("b=2; Domain=z.zzz; Secure; Max-Age=60", "https://www.z.zzz/").unwrap(),
+        ] {
+            let stored = c.to_stored().unwrap();
+            assert_eq!(stored.expires, 1_000_060);
+            assert!(!stored.cookie.contains("Max-Age"));
+            assert_eq!(&SessionCookie::from_stored(&stored).unwrap(), c);
+        }
+    }
+}
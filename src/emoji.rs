@@ -0,0 +1,103 @@
+/*
+ * Emoji in titles, which are sent as they are, stripped, or replaced by
+ * shortcodes, e.g. `:tada:`, as set by `features.emoji_mode`
+ *
+ * Shortcodes are those used by GitHub, from the `emojis` crate's data.
+ *
+ */
+use emojis::{Emoji, SkinTone};
+use itertools::Itertools;
+use unicode_segmentation::UnicodeSegmentation;
+
+/// how emoji in titles are sent
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum EmojiMode {
+    Raw,
+    Strip,
+    /// replaced by shortcodes
+    Shortcode,
+}
+
+impl Default for EmojiMode {
+    fn default() -> Self {
+        EmojiMode::Raw
+    }
+}
+
+const VARIATION_SELECTOR: char = '\u{FE0F}';
+
+/// Apply an emoji mode to a title
+pub fn apply(title: &str, mode: EmojiMode) -> String {
+    if mode == EmojiMode::Raw || !title.graphemes(true).any(|g| emoji(g).is_some()) {
+        return title.to_string();
+    }
+
+    let converted: String = title.graphemes(true)
+        .map(|g| match (emoji(g), mode) {
+            (None, _) => g.to_string(),
+            (Some(e), EmojiMode::Shortcode) => shortcode(e).unwrap_or_else(|| g.to_string()),
+            _ => String::new(),
+        })
+        .collect();
+
+    // emoji stripped from between words leave spaces to be collapsed, and a
+    // title of only emoji is given in shortcodes, rather than left empty
+    match mode {
+        EmojiMode::Strip => match converted.split_whitespace().join(" ") {
+            ref stripped if stripped.is_empty() => apply(title, EmojiMode::Shortcode),
+            stripped => stripped,
+        },
+        _ => converted,
+    }
+}
+
+/// the emoji a grapheme is, including sequences of emoji joined with
+/// zero-width joiners, with skin tones, and flags, symbols shown as text by
+/// default, e.g. `©`, only being emoji with a variation selector
+fn emoji(grapheme: &str) -> Option<&'static Emoji> {
+    emojis::get(grapheme).filter(|e| {
+        grapheme.contains(VARIATION_SELECTOR) || !e.as_str().contains(VARIATION_SELECTOR)
+    })
+}
+
+/// the shortcode of an emoji, that of its default skin tone if it has none
+fn shortcode(emoji: &Emoji) -> Option<String> {
+    emoji.shortcode()
+        .or_else(|| emoji.with_skin_tone(SkinTone::Default).and_then(Emoji::shortcode))
+        .map(|name| format!(":{}:", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn emoji_modes() {
+        let title = "🦀 Rust 1.35 released 🎉";
+        assert_eq!(apply(title, EmojiMode::Raw), title);
+        assert_eq!(apply(title, EmojiMode::Strip), "Rust 1.35 released");
+        assert_eq!(apply(title, EmojiMode::Shortcode), ":crab: Rust 1.35 released :tada:");
+
+        // with variation selectors, skin tones, flags, and sequences
+        let title = "Great ❤️ work 👍🏽 from 🇬🇧 👨‍👩‍👧";
+        assert_eq!(apply(title, EmojiMode::Strip), "Great work from");
+        assert_eq!(apply(title, EmojiMode::Shortcode),
+            "Great :heart: work :+1: from :gb: :family_man_woman_girl:");
+
+        assert_eq!(apply("a 🦑 b", EmojiMode::Shortcode), "a :squid: b");
+        assert_eq!(apply("🦑", EmojiMode::Strip), ":squid:");
+        assert_eq!(apply("press 1️⃣", EmojiMode::Strip), "press");
+        assert_eq!(apply("press 1️⃣", EmojiMode::Shortcode), "press :one:");
+    }
+
+    #[test]
+    fn text_symbols_kept() {
+        for title in &["★★★★☆ 4/5", "done ✓", "Ⓒ 2019 — all rights reserved",
+            "© 2019", "a ↔ b", "a  b"]
+        {
+            assert_eq!(apply(title, EmojiMode::Strip), *title);
+            assert_eq!(apply(title, EmojiMode::Shortcode), *title);
+        }
+    }
+}
@@ -0,0 +1,336 @@
+/*
+ * Gemini titles, from the first heading of a gemtext document
+ * https://gemini.circumlunar.space/docs/specification.html
+ *
+ * Gemini servers commonly use self-signed certificates, which clients trust
+ * on first use, so certificates aren't verified.
+ *
+ */
+use std::cmp;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+use failure::Error;
+use native_tls::TlsConnector;
+use url::{Host, Url};
+
+use super::config::Rtd;
+use super::http::{RequestParams, ResolveError, Resolved};
+use super::title::parse_gemtext_title;
+
+const PORT: u16 = 1965;
+
+/// max length of the meta field of a response header
+const META_MAX: u64 = 1024;
+
+/// a response header, with the body of successful responses
+#[derive(Debug, PartialEq)]
+struct Response {
+    status: u8,
+    meta: String,
+    body: Vec<u8>,
+}
+
+/// the time limits of requests
+struct Timeouts {
+    connect: Duration,
+    read: Duration,
+    /// the time limit of the whole resolution, if any
+    deadline: Option<Instant>,
+}
+
+/// Resolve a gemini URL, following redirections, to the first heading of the
+/// gemtext document, responses of other types are an error
+pub fn resolve(url: &str, rtd: &Rtd) -> Result<Resolved, Error> {
+    let start = Instant::now();
+    let params = RequestParams::from(rtd);
+    let timeouts = Timeouts {
+        connect: Duration::from_secs(params.connect_timeout_s),
+        read: Duration::from_secs(params.timeout_s),
+        deadline: match params.total_timeout_s {
+            0 => None,
+            s => Some(start + Duration::from_secs(s)),
+        },
+    };
+    let max_bytes = rtd.conf.params.chunk_size_kb * 1024;
+    let mut url = Url::parse(url)?;
+    let mut redirects = vec![];
+
+    for _ in 0..=params.redirect_limit {
+        redirects.push(url.to_string());
+        let resp = request(&url, &timeouts, max_bytes).map_err(|err| {
+            match timeouts.deadline {
+                Some(d) if Instant::now() >= d => {
+                    ResolveError::Timeout(params.total_timeout_s).into()
+                },
+                _ => err,
+            }
+        })?;
+        debug!("<{}> → [{} {}]", url, resp.status, resp.meta);
+
+        match resp.status / 10 {
+            2 => {
+                let mime = gemini_mime(&resp.meta);
+                if mime != "text/gemini" {
+                    return Err(ResolveError::IgnoredType(mime).into());
+                }
+
+                let body = String::from_utf8_lossy(&resp.body);
+                let title = parse_gemtext_title(&body).ok_or(ResolveError::NoTitle)?;
+                let elapsed = start.elapsed();
+                return Ok(Resolved {
+                    title, canonical: None, mime: Some(mime), elapsed, redirects
+                });
+            },
+            3 => {
+                url = url.join(&resp.meta)?;
+                if url.scheme() != "gemini" {
+                    bail!("Refusing redirection to <{}>", url);
+                }
+            },
+            _ => return Err(ResolveError::GeminiStatus(resp.status, resp.meta).into()),
+        }
+    }
+
+    Err(ResolveError::TooManyRedirects(params.redirect_limit).into())
+}
+
+/// the mime type of a successful response, without parameters, which is
+/// text/gemini if not given
+fn gemini_mime(meta: &str) -> String {
+    let mime = meta.split(';').next().unwrap_or_default().trim();
+
+    if mime.is_empty() {
+        "text/gemini".to_string()
+    } else {
+        mime.to_ascii_lowercase()
+    }
+}
+
+/// make a request, reading up to `max_bytes` of the body
+fn request(url: &Url, timeouts: &Timeouts, max_bytes: u64) -> Result<Response, Error> {
+    let (host, addr) = host_addr(url)?;
+
+    let connect_timeout = time_left(timeouts.connect, timeouts.deadline)?;
+    let stream = Connection {
+        stream: TcpStream::connect_timeout(&addr, connect_timeout)?,
+        timeout: timeouts.read,
+        deadline: timeouts.deadline,
+    };
+
+    let connector = TlsConnector::builder()
+        .danger_accept_invalid_certs(true)
+        .danger_accept_invalid_hostnames(true)
+        .build()?;
+    let mut stream = connector.connect(&host, stream)
+        .map_err(|err| format_err!("TLS error: {}", err))?;
+
+    stream.write_all(format!("{}\r\n", url).as_bytes())?;
+    read_response(stream, max_bytes)
+}
+
+/// the host of a URL, IPv6 addresses without brackets, and the address to
+/// connect to
+fn host_addr(url: &Url) -> Result<(String, SocketAddr), Error> {
+    let host = match url.host() {
+        Some(Host::Ipv6(addr)) => addr.to_string(),
+        Some(host) => host.to_string(),
+        None => bail!("No host in <{}>", url),
+    };
+    let addr = url.with_default_port(|_| Ok(PORT))?
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| format_err!("Can't resolve {}", host))?;
+
+    Ok((host, addr))
+}
+
+/// a connection, each read or write on which is limited to `timeout`, or to
+/// the time left before the deadline, if sooner
+#[derive(Debug)]
+struct Connection {
+    stream: TcpStream,
+    timeout: Duration,
+    deadline: Option<Instant>,
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.set_read_timeout(Some(time_left(self.timeout, self.deadline)?))?;
+        self.stream.read(buf)
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.set_write_timeout(Some(time_left(self.timeout, self.deadline)?))?;
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// a timeout, limited to the time left before a deadline, which is an error
+/// once it's passed
+fn time_left(timeout: Duration, deadline: Option<Instant>) -> io::Result<Duration> {
+    let deadline = match deadline {
+        Some(deadline) => deadline,
+        None => return Ok(timeout),
+    };
+
+    let now = Instant::now();
+    if now >= deadline {
+        return Err(io::Error::new(io::ErrorKind::TimedOut, "deadline reached"));
+    }
+    Ok(cmp::min(timeout, deadline - now))
+}
+
+/// read a response header, `<STATUS> <META>\r\n`, and the body of a successful
+/// response
+fn read_response(stream: impl Read, max_bytes: u64) -> Result<Response, Error> {
+    let mut reader = BufReader::new(stream);
+
+    let mut header = String::new();
+    reader.by_ref().take(META_MAX + 5).read_line(&mut header)?;
+    if !header.ends_with("\r\n") {
+        bail!("Invalid gemini response header");
+    }
+    let header = header.trim_end();
+
+    let status = header.get(..2)
+        .filter(|s| s.chars().all(|c| c.is_ascii_digit()))
+        .and_then(|s| s.parse::<u8>().ok())
+        .ok_or_else(|| format_err!("Invalid gemini response status: {}", header))?;
+    let meta = header[2..].trim().to_string();
+
+    // servers close the connection at the end of the body, some without
+    // closing the TLS session, so an error after some content is ignored
+    let mut body = Vec::new();
+    if status / 10 == 2 {
+        if let Err(err) = reader.take(max_bytes).read_to_end(&mut body) {
+            if body.is_empty() {
+                return Err(err.into());
+            }
+            debug!("error reading response: {}", err);
+        }
+    }
+
+    Ok(Response { status, meta, body })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+    use native_tls::{Identity, TlsAcceptor};
+
+    fn response(data: &[u8]) -> Result<Response, Error> {
+        read_response(data, 1024)
+    }
+
+    #[test]
+    fn read_gemini_responses() {
+        assert_eq!(response(b"20 text/gemini\r\n# Title\n").unwrap(), Response {
+            status: 20,
+            meta: "text/gemini".to_string(),
+            body: b"# Title\n".to_vec(),
+        });
+        assert_eq!(response(b"31 /moved\r\nignored").unwrap(), Response {
+            status: 31,
+            meta: "/moved".to_string(),
+            body: vec![],
+        });
+        assert_eq!(response(b"20\r\n").unwrap().meta, "");
+        assert_eq!(read_response(&b"20 \r\n0123456789"[..], 4).unwrap().body, b"0123");
+
+        assert!(response(b"20 text/gemini\n").is_err());
+        assert!(response(b"2x text/gemini\r\n").is_err());
+        assert!(response(b"").is_err());
+        let long = format!("20 {}\r\n", "x".repeat(2000));
+        assert!(response(long.as_bytes()).is_err());
+
+        assert_eq!(gemini_mime(""), "text/gemini");
+        assert_eq!(gemini_mime("Text/Gemini; lang=en"), "text/gemini");
+        assert_eq!(gemini_mime("image/png"), "image/png");
+    }
+
+    #[test]
+    fn gemini_host_addresses() {
+        let parse = |url| host_addr(&Url::parse(url).unwrap());
+
+        assert_eq!(parse("gemini://127.0.0.1/").unwrap(),
+            ("127.0.0.1".to_string(), "127.0.0.1:1965".parse().unwrap()));
+        assert_eq!(parse("gemini://[::1]:1966/").unwrap(),
+            ("::1".to_string(), "[::1]:1966".parse().unwrap()));
+        assert!(parse("gemini:text").is_err());
+    }
+
+    #[test]
+    fn resolve_gemini_urls() {
+        let pkcs12 = fs::read("./test/tls/gemini.p12").unwrap();
+        let identity = Identity::from_pkcs12(&pkcs12, "url-bot").unwrap();
+        let acceptor = TlsAcceptor::new(identity).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:28518").unwrap();
+
+        let server_thread = thread::spawn(move || {
+            for stream in listener.incoming().take(4) {
+                let mut stream = acceptor.accept(stream.unwrap()).unwrap();
+                let mut request = String::new();
+                BufReader::new(&mut stream).read_line(&mut request).unwrap();
+
+                let resp: &[u8] = match request.as_str() {
+                    "gemini://127.0.0.1:28518/\r\n" => b"31 /page\r\n",
+                    "gemini://127.0.0.1:28518/page\r\n" => b"20 text/gemini; lang=en\r\n\
+                        ```\n# not a heading\n```\n## Subheading\n# Gemini title\n",
+                    "gemini://127.0.0.1:28518/image\r\n" => b"20 image/png\r\n\x89PNG",
+                    _ => b"51 Not found\r\n",
+                };
+                stream.write_all(resp).unwrap();
+                stream.shutdown().unwrap();
+            }
+        });
+
+        let rtd = Rtd::default();
+        let resolved = resolve("gemini://127.0.0.1:28518/", &rtd).unwrap();
+        assert_eq!(resolved.title, "Gemini title");
+        assert_eq!(resolved.mime.unwrap(), "text/gemini");
+        assert_eq!(resolved.redirects, vec![
+            "gemini://127.0.0.1:28518/", "gemini://127.0.0.1:28518/page",
+        ]);
+
+        let err = |url| resolve(url, &rtd).unwrap_err().downcast::<ResolveError>().unwrap();
+        assert_eq!(err("gemini://127.0.0.1:28518/image"),
+            ResolveError::IgnoredType("image/png".to_string()));
+        assert_eq!(err("gemini://127.0.0.1:28518/missing"),
+            ResolveError::GeminiStatus(51, "Not found".to_string()));
+
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn gemini_total_timeout() {
+        // a server which accepts a connection, but never responds
+        let listener = TcpListener::bind("127.0.0.1:28536").unwrap();
+        let (tx, rx) = mpsc::channel::<()>();
+        let server_thread = thread::spawn(move || {
+            let (_stream, _) = listener.accept().unwrap();
+            rx.recv().ok();
+        });
+
+        let mut rtd = Rtd::default();
+        rtd.conf.params.total_timeout_s = 1;
+        rtd.conf.params.read_timeout_s = 10;
+        let start = Instant::now();
+        let err = resolve("gemini://127.0.0.1:28536/", &rtd).unwrap_err();
+        assert_eq!(err.downcast::<ResolveError>().unwrap(), ResolveError::Timeout(1));
+        assert!(start.elapsed() < Duration::from_secs(3));
+
+        drop(tx);
+        server_thread.join().unwrap();
+    }
+}
@@ -1,32 +1,187 @@
-use std::time::Duration;
+use std::cmp;
+use std::time::{Duration, Instant};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Mutex;
+use std::net::IpAddr;
+use std::fs;
+use std::path::PathBuf;
 use itertools::Itertools;
 use failure::Error;
-use reqwest::{Client, header, RedirectPolicy, Response};
-use cookie::Cookie;
-use std::io::Read;
+use reqwest::{self, Client, Certificate, header, RedirectPolicy, Response, StatusCode};
+use std::io::{Read, Write};
 use mime::{Mime, IMAGE, TEXT, HTML};
-use humansize::{FileSize, file_size_opts as options};
 use toml;
+use log::Level;
+use url::Url;
+use time;
 
 use super::config::Rtd;
 use super::buildinfo;
-use super::sqlite::{Database, UrlError, ErrorInfo};
-use super::title::{parse_title, get_mime, get_image_metadata};
+use super::sqlite::{Database, UrlError, ErrorInfo, StoredValidator};
+use super::cookies::SessionCookie;
+use super::title::{
+    parse_title, parse_first_title, parse_canonical, parse_meta_refresh, parse_language,
+    language_tag, get_mime, get_image_metadata, report_type, allowed_type,
+    disposition_filename, is_textual, title_mismatch, trim_site_suffix
+};
+use super::metrics;
+use super::logging::Event;
+use super::sites;
+use super::gemini;
+use super::unix;
 
-const CHUNK_BYTES: u64 = 100 * 1024; // 100kB
-const CHUNKS_MAX: u64 = 10; // 1000kB
+const CHUNKS_MAX: u64 = 10;
+const FIRST_READ_BYTES: u64 = 4 * 1024;
+const META_REFRESH_MAX_S: u64 = 5;
+
+/// the default Accept header, preferring HTML, as sent by browsers
+pub const ACCEPT: &str = "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8";
+
+/// headers which can't be set with `extra_headers`, being either set by the
+/// session, or specific to a connection
+pub const RESERVED_HEADERS: &[&str] = &[
+    "host", "connection", "keep-alive", "proxy-connection", "upgrade", "te",
+    "trailer", "transfer-encoding", "content-length", "cookie", "user-agent",
+    "accept", "accept-language", "accept-encoding",
+];
+
+/// parts of the names of headers likely to hold credentials, extra headers
+/// with such names are only sent to the host first requested
+const SENSITIVE_HEADER_PARTS: &[&str] = &[
+    "auth", "key", "token", "secret", "session", "password",
+];
 
 lazy_static! {
     static ref USER_AGENT: String = format!(
         "Mozilla/5.0 url-bot-rs/{}", buildinfo::PKG_VERSION
     );
+
+    /// clients shared by sessions with the same settings, so that connections
+    /// to a host are reused by later sessions
+    static ref CLIENTS: Mutex<HashMap<ClientSettings, Client>> = Mutex::new(HashMap::new());
+}
+
+/// the parameters of a session with which its client is built
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct ClientSettings {
+    timeout: Duration,
+    connect_timeout: Duration,
+    http_version: HttpVersion,
+    ca_cert: Option<PathBuf>,
+    danger_accept_invalid_certs: bool,
+    local_address: Option<IpAddr>,
+    force_identity_encoding: bool,
+}
+
+impl ClientSettings {
+    /// build a client with these settings
+    fn build(&self) -> Result<Client, Error> {
+        // follow only one redirection
+        let redirect = RedirectPolicy::custom(|attempt| {
+            if attempt.previous().len() == 1 {
+                attempt.stop()
+            } else {
+                attempt.follow()
+            }
+        });
+
+        let mut builder = Client::builder()
+            .gzip(!self.force_identity_encoding)
+            .redirect(redirect)
+            .connect_timeout(self.connect_timeout)
+            .timeout(self.timeout);
+
+        if self.http_version == HttpVersion::Http2 {
+            builder = builder.h2_prior_knowledge();
+        }
+
+        if let Some(ref path) = self.ca_cert {
+            let pem = fs::read(path).map_err(|err| {
+                format_err!("can't read {}: {}", path.display(), err)
+            })?;
+            builder = builder.add_root_certificate(Certificate::from_pem(&pem)?);
+        }
+
+        if self.danger_accept_invalid_certs {
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+
+        if let Some(addr) = self.local_address {
+            builder = builder.local_address(addr);
+        }
+
+        Ok(builder.build()?)
+    }
+}
+
+/// preferred HTTP version for requests
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum HttpVersion {
+    /// HTTP/1.1, as HTTP/2 isn't negotiated over TLS (ALPN) with native-tls
+    Auto,
+    /// HTTP/2 only, assuming the server supports it
+    Http2,
+}
+
+impl Default for HttpVersion {
+    fn default() -> Self {
+        HttpVersion::Auto
+    }
+}
+
+/// HTTP Basic authentication credentials
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct BasicAuth {
+    pub username: String,
+    pub password: String,
 }
 
+#[derive(Clone)]
 pub struct RequestParams {
     pub user_agent: String,
+    /// timeout of each request
     pub timeout_s: u64,
+    /// timeout of connecting to a host, within `timeout_s`
+    pub connect_timeout_s: u64,
+    /// timeout of the whole resolution, across redirections, none if 0
+    pub total_timeout_s: u64,
     pub redirect_limit: u8,
-    pub accept_lang: String
+    pub accept: String,
+    pub accept_lang: String,
+    /// max number of cookies held by a session, of which a single response
+    /// may set half
+    pub cookie_limit: usize,
+    /// max total size of response headers, across all redirections
+    pub header_size_limit: usize,
+    /// IP addresses to connect to in place of resolving these host names
+    pub host_overrides: BTreeMap<String, String>,
+    /// credentials sent only with requests to these hosts
+    pub basic_auth: BTreeMap<String, BasicAuth>,
+    /// headers sent with every request, other than those which appear to hold
+    /// credentials, sent only before any redirection to another host
+    pub extra_headers: BTreeMap<String, String>,
+    /// Unix domain sockets to connect to in place of these hosts
+    pub unix_sockets: BTreeMap<String, PathBuf>,
+    pub http_version: HttpVersion,
+    /// additional root certificate, in PEM format
+    pub ca_cert: Option<PathBuf>,
+    /// disable TLS certificate verification, for testing only
+    pub danger_accept_invalid_certs: bool,
+    /// local address from which requests are made
+    pub local_address: Option<IpAddr>,
+    /// request content without compression, otherwise the encoding is
+    /// negotiated, and compressed content decoded
+    pub force_identity_encoding: bool,
+    /// store cookies set by responses, and send them with requests
+    pub use_cookies: bool,
+    /// refuse redirections from https to http
+    pub block_downgrades: bool,
+    /// refuse to make requests over plain http
+    pub https_only: bool,
+    /// retry a request failing with 404 Not Found once, with a trailing slash
+    /// appended to a path without an extension
+    pub retry_trailing_slash: bool,
 }
 
 impl Default for RequestParams {
@@ -34,8 +189,65 @@ impl Default for RequestParams {
         RequestParams {
             user_agent: USER_AGENT.to_string(),
             timeout_s: 10,
+            connect_timeout_s: 10,
+            total_timeout_s: 30,
             redirect_limit: 10,
+            accept: ACCEPT.to_string(),
             accept_lang: "en".to_string(),
+            cookie_limit: 64,
+            header_size_limit: 128 * 1024, // 128kB
+            host_overrides: BTreeMap::new(),
+            basic_auth: BTreeMap::new(),
+            extra_headers: BTreeMap::new(),
+            unix_sockets: BTreeMap::new(),
+            http_version: HttpVersion::Auto,
+            ca_cert: None,
+            danger_accept_invalid_certs: false,
+            local_address: None,
+            force_identity_encoding: true,
+            use_cookies: true,
+            block_downgrades: false,
+            https_only: false,
+            retry_trailing_slash: false,
+        }
+    }
+}
+
+impl<'a> From<&'a Rtd> for RequestParams {
+    fn from(rtd: &Rtd) -> Self {
+        let params = &rtd.conf.params;
+        let ca_cert = Some(&params.ca_cert)
+            .filter(|p| !p.is_empty())
+            .map(PathBuf::from);
+        let unix_sockets = params.unix_sockets
+            .iter()
+            .filter(|_| rtd.conf.features.unix_sockets)
+            .map(|(host, path)| (host.clone(), PathBuf::from(path)))
+            .collect();
+
+        Self {
+            accept: params.accept.clone(),
+            accept_lang: params.accept_lang.clone(),
+            timeout_s: params.read_timeout_s,
+            cookie_limit: params.cookie_limit,
+            header_size_limit: params.header_size_limit_kb * 1024,
+            connect_timeout_s: params.connect_timeout_s,
+            total_timeout_s: params.total_timeout_s,
+            host_overrides: params.host_overrides.clone(),
+            basic_auth: params.basic_auth.clone(),
+            extra_headers: params.extra_headers.clone(),
+            unix_sockets,
+            http_version: params.http_version,
+            ca_cert,
+            danger_accept_invalid_certs: params.danger_accept_invalid_certs,
+            // validated when the configuration is loaded
+            local_address: params.local_address.parse().ok(),
+            force_identity_encoding: params.force_identity_encoding,
+            use_cookies: rtd.conf.features.use_cookies,
+            block_downgrades: rtd.conf.features.block_downgrades,
+            https_only: rtd.conf.features.https_only,
+            retry_trailing_slash: rtd.conf.features.retry_trailing_slash,
+            ..Self::default()
         }
     }
 }
@@ -43,8 +255,22 @@ impl Default for RequestParams {
 #[derive(Default)]
 pub struct Session {
     pub url: String,
-    pub cookies: Vec<String>,
-    pub request_count: u8,
+    pub cookies: Vec<SessionCookie>,
+    pub request_count: u32,
+    /// total size of response headers received
+    pub header_bytes: usize,
+    /// time at which the first request was made
+    pub started: Option<Instant>,
+    /// host of the first request, to which sensitive extra headers are sent
+    pub origin_host: Option<String>,
+    /// URLs requested, in order, including those of redirections
+    pub urls: Vec<String>,
+    /// URLs requested, without fragments, each with the cookies sent, a
+    /// repeated request being a redirection loop
+    visited: HashSet<(String, String)>,
+    /// validators of a page from an earlier resolution, sent when the page is
+    /// requested, for a `304 Not Modified` response if it's unchanged
+    pub validator: Option<StoredValidator>,
     pub params: RequestParams,
 }
 
@@ -58,87 +284,159 @@ impl Session {
         self
     }
 
+    /// Get a client configured with the session's parameters, shared with
+    /// other sessions with the same parameters, built if there is none
+    pub fn client(&self) -> Result<Client, Error> {
+        let settings = self.client_settings();
+        let mut clients = CLIENTS.lock().unwrap_or_else(|err| err.into_inner());
+        if let Some(client) = clients.get(&settings) {
+            return Ok(client.clone());
+        }
+
+        let client = settings.build()?;
+        clients.insert(settings, client.clone());
+        Ok(client)
+    }
+
+    fn client_settings(&self) -> ClientSettings {
+        let timeout = Duration::from_secs(self.params.timeout_s);
+        ClientSettings {
+            timeout,
+            connect_timeout: self.connect_timeout(timeout),
+            http_version: self.params.http_version,
+            ca_cert: self.params.ca_cert.clone(),
+            danger_accept_invalid_certs: self.params.danger_accept_invalid_certs,
+            local_address: self.params.local_address,
+            force_identity_encoding: self.params.force_identity_encoding,
+        }
+    }
+
     /// Make a request attempting to conform to RFC 6265
     /// https://tools.ietf.org/html/rfc6265
     pub fn request(&mut self, url: &str) -> Result<Response, Error> {
-        // follow only one redirection
-        let redirect = RedirectPolicy::custom(|attempt| {
-            if attempt.previous().len() == 1 {
-                attempt.stop()
-            } else {
-                attempt.follow()
-            }
-        });
-
-        let client = Client::builder()
-            .gzip(false)
-            .redirect(redirect)
-            .timeout(Duration::from_secs(self.params.timeout_s))
-            .build()?;
+        let client = self.client()?;
+        let timeout = Duration::from_secs(self.params.timeout_s);
 
+        // a meta refresh redirection may also downgrade the scheme
+        if self.url.is_empty() {
+            self.origin_host = Url::parse(url)?.host_str().map(String::from);
+        } else {
+            self.check_redirection(url)?;
+        }
         self.url = url.to_string();
+        self.check_scheme()?;
+
+        // the deadline applies to all requests made by the session, including
+        // those following meta refresh redirections
+        let started = *self.started.get_or_insert_with(Instant::now);
+        let deadline = match self.params.total_timeout_s {
+            0 => None,
+            s => Some(started + Duration::from_secs(s)),
+        };
 
         loop {
-            // generate cookie header
-            let cookie_string: String = self.cookies
-                .iter()
-                .map(|s| s.parse::<Cookie>().ok())
-                .flatten()
-                .map(|c| format!("{}={}", c.name(), c.value()))
-                .intersperse("; ".to_string())
-                .collect();
+            // limit requests over Unix sockets to the time remaining, those
+            // made by the shared client are checked once they're complete
+            let timeout = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(self.timed_out());
+                    }
+                    cmp::min(timeout, deadline - now)
+                },
+                None => timeout,
+            };
+
+            // apply any host override
+            let (request_url, host) = self.override_host()?;
 
             // set request headers and make request
-            let resp = client.get(&self.url)
-                .header(header::COOKIE, cookie_string)
+            let cookies = if self.params.use_cookies {
+                self.cookie_header()?
+            } else {
+                String::new()
+            };
+            self.check_loop(&cookies)?;
+            self.urls.push(self.url.clone());
+            let mut request = client.get(request_url);
+            if let Some(host) = host {
+                request = request.header(header::HOST, host);
+            }
+            if self.params.use_cookies {
+                request = request.header(header::COOKIE, cookies);
+            }
+            // credentials are looked up for each request, so that they aren't
+            // sent on after a redirection to another host
+            if let Some(auth) = self.basic_auth()? {
+                debug!("sending credentials for {}", auth.username);
+                request = request.basic_auth(&auth.username, Some(&auth.password));
+            }
+            request = request
+                .headers(self.extra_headers()?)
+                .headers(self.conditional_headers())
                 .header(header::USER_AGENT, self.params.user_agent.as_str())
-                .header(header::ACCEPT_LANGUAGE, self.params.accept_lang.as_str())
-                .header(header::ACCEPT_ENCODING, "identity")
-                .send()?;
+                .header(header::ACCEPT, self.params.accept.as_str())
+                .header(header::ACCEPT_LANGUAGE, self.params.accept_lang.as_str());
+            if self.params.force_identity_encoding {
+                request = request.header(header::ACCEPT_ENCODING, "identity");
+            }
+            let request = request.build()?;
+            let resp = match self.unix_socket()?.cloned() {
+                Some(path) => {
+                    debug!("connecting to {}", path.display());
+                    unix::send(&path, &request, timeout, self.params.header_size_limit)
+                },
+                None => client.execute(request).map_err(|err| self.request_error(err)),
+            };
+            if deadline.map_or(false, |d| Instant::now() >= d) {
+                return Err(self.timed_out());
+            }
+            let resp = resp?;
 
             debug!("[{}] <{}> → [{:?} {}]",
                 self.request_count, self.url, resp.version(), resp.status());
 
-            if resp.status().is_redirection() {
-                // get new cookies from response headers
-                let mut new_cookies: Vec<String> = resp.headers()
-                    .get_all(header::SET_COOKIE)
-                    .iter()
-                    .map(|c| c.to_str().ok().and_then(|s| s.parse().ok()))
-                    .flatten()
-                    .filter(|c| !self.cookies.contains(c))
-                    .take(32) // max 32 new cookies per request
-                    .collect();
+            // limit the total size of headers received
+            self.header_bytes += resp.headers()
+                .iter()
+                .map(|(k, v)| k.as_str().len() + v.len() + 4)
+                .sum::<usize>();
+            if self.header_bytes > self.params.header_size_limit {
+                let limit = self.params.header_size_limit;
+                return Err(ResolveError::HeadersTooLarge(limit).into());
+            }
 
-                // debug print cookie information
-                if !new_cookies.is_empty() {
-                    trace!("Received cookies:");
-                    new_cookies
-                        .iter()
-                        .map(|s| s.parse::<Cookie>().ok())
-                        .flatten()
-                        .for_each(|c| trace!("{} = {}", c.name(), c.value()));
-                    debug!("added {} cookies", new_cookies.len());
-                };
+            // add any new cookies to the session
+            if self.params.use_cookies {
+                self.add_cookies(&resp);
+            }
 
-                // add cookies to session
-                self.cookies.append(&mut new_cookies);
+            if resp.status() == StatusCode::NOT_MODIFIED && self.validator.is_some() {
+                debug!("<{}> not modified", self.url);
+                return Ok(resp);
+            }
 
+            else if resp.status().is_redirection() {
                 // get redirection location
                 let redirected_url = resp.headers().get(header::LOCATION)
                     .and_then(|u| u.to_str().ok())
                     .and_then(|u| u.parse::<String>().ok());
 
                 match redirected_url {
-                    Some(url) => self.url = url,
-                    None => bail!("Can't get redirection URL"),
+                    Some(url) => {
+                        self.check_redirection(&url)?;
+                        self.url = url;
+                        self.check_scheme()?;
+                    },
+                    None => return Err(ResolveError::NoLocation.into()),
                 };
 
                 // limit the number of redirections
                 self.request_count += 1;
-                if self.request_count > self.params.redirect_limit {
-                    bail!("Too many redirects, max {}",
-                        self.params.redirect_limit);
+                if self.request_count > u32::from(self.params.redirect_limit) {
+                    let limit = self.params.redirect_limit;
+                    return Err(ResolveError::TooManyRedirects(limit).into());
                 }
             }
 
@@ -149,12 +447,357 @@ impl Session {
                 return Ok(resp);
             }
 
+            else if let Some(url) = self.with_trailing_slash(resp.status()) {
+                debug!("retrying with a trailing slash <{}>", url);
+                self.url = url;
+            }
+
             else {
-                let r = resp.error_for_status()?;
-                bail!("Unhandled request status: {}", r.status());
+                return Err(ResolveError::HttpStatus(resp.status()).into());
+            }
+        }
+    }
+
+    /// Check that the current URL may be requested, if `https_only` is set
+    fn check_scheme(&self) -> Result<(), Error> {
+        let url = Url::parse(&self.url)?;
+
+        if self.params.https_only && url.scheme() != "https" {
+            return Err(ResolveError::HttpsOnly(self.url.clone()).into());
+        }
+
+        Ok(())
+    }
+
+    /// Check that a redirection from the current URL doesn't downgrade from
+    /// https to http, if `block_downgrades` is set
+    fn check_redirection(&self, to: &str) -> Result<(), Error> {
+        if !self.params.block_downgrades {
+            return Ok(());
+        }
+
+        let from = Url::parse(&self.url)?;
+        let to = Url::parse(to)?;
+        if from.scheme() == "https" && to.scheme() == "http" {
+            return Err(ResolveError::Downgrade(to.into_string()).into());
+        }
+
+        Ok(())
+    }
+
+    /// Check that the current URL hasn't been requested before with the same
+    /// cookies, as a redirection loop would continue to the redirection limit,
+    /// while a site may redirect back once it has set a cookie
+    fn check_loop(&mut self, cookies: &str) -> Result<(), Error> {
+        let mut url = Url::parse(&self.url)?;
+        url.set_fragment(None);
+
+        if !self.visited.insert((url.into_string(), cookies.to_string())) {
+            return Err(ResolveError::RedirectLoop(self.url.clone()).into());
+        }
+
+        Ok(())
+    }
+
+    /// The current URL with a trailing slash, to retry a request failing with
+    /// a status of 404, if `retry_trailing_slash` is set, and the path doesn't
+    /// already end with a slash, or have an extension, e.g. `/file.html`
+    fn with_trailing_slash(&self, status: StatusCode) -> Option<String> {
+        if !self.params.retry_trailing_slash || status != StatusCode::NOT_FOUND {
+            return None;
+        }
+
+        let mut url = Url::parse(&self.url).ok()?;
+        let path = url.path().to_string();
+        let name = path.rsplit('/').next().unwrap_or_default();
+        if name.is_empty() || name.contains('.') {
+            return None;
+        }
+
+        url.set_path(&format!("{}/", path));
+        Some(url.into_string())
+    }
+
+    /// `If-None-Match` and `If-Modified-Since` headers, if the current URL is
+    /// that of the page of the session's validator
+    fn conditional_headers(&self) -> header::HeaderMap {
+        let mut headers = header::HeaderMap::new();
+        let validator = match self.validator {
+            Some(ref v) if v.page_url == self.url => v,
+            _ => return headers,
+        };
+
+        let value = |v: &Option<String>| v.as_ref()
+            .and_then(|v| header::HeaderValue::from_str(v).ok());
+        if let Some(etag) = value(&validator.etag) {
+            headers.insert(header::IF_NONE_MATCH, etag);
+        }
+        if let Some(date) = value(&validator.last_modified) {
+            headers.insert(header::IF_MODIFIED_SINCE, date);
+        }
+
+        headers
+    }
+
+    /// the time limit of connecting to a host, within that of a request
+    fn connect_timeout(&self, timeout: Duration) -> Duration {
+        cmp::min(Duration::from_secs(self.params.connect_timeout_s), timeout)
+    }
+
+    /// an error making a request, a timeout being a `ConnectTimeout` if it
+    /// occurred while connecting, otherwise a `RequestTimeout`
+    fn request_error(&self, err: reqwest::Error) -> Error {
+        let connecting = err.get_ref()
+            .and_then(|e| e.downcast_ref::<hyper::Error>())
+            .map_or(false, hyper::Error::is_connect);
+
+        match (err.is_timeout(), connecting) {
+            (true, true) => ResolveError::ConnectTimeout(self.params.connect_timeout_s).into(),
+            (true, false) => ResolveError::RequestTimeout(self.params.timeout_s).into(),
+            _ => err.into(),
+        }
+    }
+
+    fn timed_out(&self) -> Error {
+        ResolveError::Timeout(self.params.total_timeout_s).into()
+    }
+
+    /// Get the URL to request, with the host replaced by an IP address if
+    /// overridden, along with the original host, to be sent as a header
+    fn override_host(&self) -> Result<(Url, Option<String>), Error> {
+        let mut url = Url::parse(&self.url)?;
+
+        let ip = match url.host_str().and_then(|h| self.params.host_overrides.get(h)) {
+            Some(ip) => ip.parse::<IpAddr>()?,
+            None => return Ok((url, None)),
+        };
+
+        let host = match url.port() {
+            Some(port) => format!("{}:{}", url.host_str().unwrap(), port),
+            None => url.host_str().unwrap().to_string(),
+        };
+
+        debug!("connecting to {} for {}", ip, host);
+        url.set_ip_host(ip).map_err(|_| format_err!("can't override host"))?;
+
+        Ok((url, Some(host)))
+    }
+
+    /// The Unix domain socket configured for the host of the current URL, if
+    /// any, to which the request is made over HTTP
+    fn unix_socket(&self) -> Result<Option<&PathBuf>, Error> {
+        let url = Url::parse(&self.url)?;
+
+        Ok(url.host_str().and_then(|h| self.params.unix_sockets.get(h)))
+    }
+
+    /// The credentials configured for the host of the current URL, if any
+    fn basic_auth(&self) -> Result<Option<&BasicAuth>, Error> {
+        let url = Url::parse(&self.url)?;
+
+        Ok(url.host_str().and_then(|h| self.params.basic_auth.get(h)))
+    }
+
+    /// The extra headers sent to the current URL, those which appear to hold
+    /// credentials are omitted after a redirection to another host
+    fn extra_headers(&self) -> Result<header::HeaderMap, Error> {
+        let url = Url::parse(&self.url)?;
+        let same_host = url.host_str() == self.origin_host.as_ref().map(String::as_str);
+
+        let mut headers = header::HeaderMap::new();
+        for (name, value) in &self.params.extra_headers {
+            if !same_host && is_sensitive_header(name) {
+                debug!("not sending {} header to another host", name);
+                continue;
+            }
+            let name = header::HeaderName::from_bytes(name.as_bytes())?;
+            headers.insert(name, value.parse()?);
+        }
+
+        Ok(headers)
+    }
+
+    /// Generate a cookie header for the current URL, containing only cookies
+    /// whose domain and path match, and which haven't expired
+    fn cookie_header(&self) -> Result<String, Error> {
+        let url = Url::parse(&self.url)?;
+        let now = time::now_utc();
+
+        let mut cookies: Vec<&SessionCookie> = self.cookies
+            .iter()
+            .filter(|c| c.matches(&url, now))
+            .collect();
+
+        // cookies with longer paths are listed first
+        cookies.sort_by(|a, b| b.path.len().cmp(&a.path.len()));
+
+        Ok(cookies
+            .iter()
+            .map(|c| format!("{}={}", c.name, c.value))
+            .intersperse("; ".to_string())
+            .collect())
+    }
+
+    /// Add cookies set by a response to the session, replacing any existing
+    /// cookies with the same name, domain and path
+    fn add_cookies(&mut self, resp: &Response) {
+        let url = match Url::parse(&self.url) {
+            Ok(u) => u,
+            Err(_) => return,
+        };
+        let now = time::now_utc();
+
+        // get new cookies from response headers
+        let new_cookies: Vec<SessionCookie> = resp.headers()
+            .get_all(header::SET_COOKIE)
+            .iter()
+            .filter_map(|c| c.to_str().ok())
+            .filter_map(|s| SessionCookie::parse(s, &url, now))
+            .filter(|c| !self.cookies.contains(c))
+            .take(cmp::max(self.params.cookie_limit / 2, 1))
+            .collect();
+
+        // debug print cookie information
+        if !new_cookies.is_empty() {
+            trace!("Received cookies:");
+            new_cookies
+                .iter()
+                .for_each(|c| trace!("{} = {} ({}{})", c.name, c.value, c.domain, c.path));
+            debug!("added {} cookies", new_cookies.len());
+        };
+
+        // add cookies to session, up to the limit
+        let limit = self.params.cookie_limit;
+        for cookie in new_cookies {
+            self.cookies.retain(|c| !c.same_key(&cookie));
+            // an expired cookie only removes any stored with the same key
+            if cookie.is_expired(now) {
+                continue;
+            }
+            if self.cookies.len() >= limit {
+                warn!("cookie limit ({}) reached, ignoring further cookies", limit);
+                break;
             }
+            self.cookies.push(cookie);
+        }
+    }
+}
+
+/// A means of making requests, for the retrieval of titles, implemented by
+/// `Session`, which may be replaced, e.g. with canned responses in tests
+pub trait HttpFetcher {
+    /// Request a URL, following any redirections, to a successful response
+    fn fetch(&mut self, url: &str) -> Result<Response, Error>;
+
+    /// Count a redirection found in a page, e.g. a meta refresh, which is to
+    /// be fetched, failing if there have been too many
+    fn redirected(&mut self) -> Result<(), Error>;
+
+    /// The URLs requested, in order, including those of redirections
+    fn urls(&self) -> &[String];
+}
+
+impl HttpFetcher for Session {
+    fn fetch(&mut self, url: &str) -> Result<Response, Error> {
+        self.request(url)
+    }
+
+    fn redirected(&mut self) -> Result<(), Error> {
+        self.request_count += 1;
+        if self.request_count > u32::from(self.params.redirect_limit) {
+            let limit = self.params.redirect_limit;
+            return Err(ResolveError::TooManyRedirects(limit).into());
         }
+
+        Ok(())
+    }
+
+    fn urls(&self) -> &[String] {
+        &self.urls
+    }
+}
+
+/// load persisted cookies for the host of a URL into a session
+fn load_cookies(session: &mut Session, url: &str, db: &Database) {
+    let host = match Url::parse(url).ok().and_then(|u| u.host_str().map(str::to_lowercase)) {
+        Some(h) => h,
+        None => return,
+    };
+
+    match db.get_cookies(&host) {
+        Ok(cookies) => {
+            debug!("loaded {} stored cookies for {}", cookies.len(), host);
+            session.cookies.extend(
+                cookies.iter().filter_map(SessionCookie::from_stored)
+            );
+        },
+        Err(e) => error!("database error: {}", e),
+    };
+}
+
+/// persist the cookies held by a session, expired cookies are removed from
+/// the database when cookies are next loaded
+fn save_cookies(session: &Session, db: &Database) {
+    session.cookies
+        .iter()
+        .filter_map(SessionCookie::to_stored)
+        .for_each(|c| db.save_cookie(&c).unwrap_or_else(|e| {
+            error!("database error: {}", e);
+        }));
+}
+
+/// load the validators of the page last resolved from a URL into a session
+fn load_validator(session: &mut Session, url: &str, db: &Database) {
+    match db.get_validator(url) {
+        Ok(validator) => session.validator = validator,
+        Err(e) => error!("database error: {}", e),
+    };
+}
+
+/// store the validators of a page, if it has any, with its details
+fn save_validator(session: &Session, url: &str, resp: &Response,
+    page: &Result<Page, Error>, db: &Database)
+{
+    let page = match *page {
+        Ok(ref page) => page,
+        Err(_) => return,
+    };
+
+    let value = |name| resp.headers().get(name)
+        .and_then(|v: &header::HeaderValue| v.to_str().ok())
+        .map(String::from);
+    let etag = value(header::ETAG);
+    let last_modified = value(header::LAST_MODIFIED);
+    if etag.is_none() && last_modified.is_none() {
+        return;
     }
+
+    let validator = StoredValidator {
+        url: url.to_string(),
+        page_url: session.urls.last().cloned().unwrap_or_default(),
+        etag,
+        last_modified,
+        title: page.title.clone(),
+        canonical: page.canonical.clone(),
+        mime: page.mime.clone(),
+    };
+
+    db.save_validator(&validator).unwrap_or_else(|e| {
+        error!("database error: {}", e);
+    });
+}
+
+/// the page stored with a session's validator, following a `304 Not Modified`
+/// response
+fn not_modified(session: &Session) -> Result<Page, Error> {
+    let validator = session.validator.as_ref()
+        .ok_or_else(|| format_err!("Not modified, without a stored page"))?;
+
+    Ok(Page {
+        title: validator.title.clone(),
+        canonical: validator.canonical.clone(),
+        mime: validator.mime.clone(),
+    })
 }
 
 fn log_error(rtd: &Rtd, db: &Database, url: &str, err: &Error, resp: &Response) {
@@ -181,107 +824,595 @@ fn log_error(rtd: &Rtd, db: &Database, url: &str, err: &Error, resp: &Response)
     info!("added error record to database")
 }
 
-pub fn resolve_url(url: &str, rtd: &Rtd, db: &Database) -> Result<String, Error> {
-    let mut resp = Session::new()
-        .accept_lang(&rtd.conf.params.accept_lang)
-        .request(url)?;
-
-    match get_title(&mut resp, rtd, false) {
-        Ok(title) => Ok(title),
-        Err(err) => {
-            log_error(&rtd, &db, url, &err, &resp);
-            Err(err)
-        },
+/// Check an extra header can be sent, that it's valid, and not reserved
+pub fn check_extra_header(name: &str, value: &str) -> Result<(), Error> {
+    if RESERVED_HEADERS.iter().any(|h| h.eq_ignore_ascii_case(name)) {
+        bail!("{} is set by the bot", name);
     }
-}
+    header::HeaderName::from_bytes(name.as_bytes())?;
+    header::HeaderValue::from_str(value)?;
 
-pub fn get_title(resp: &mut Response, rtd: &Rtd, dump: bool) -> Result<String, Error> {
-    // get content type
-    let content_type = resp.headers().get(header::CONTENT_TYPE)
-        .and_then(|typ| typ.to_str().ok())
-        .and_then(|typ| typ.parse::<Mime>().ok());
+    Ok(())
+}
 
-    // get content length and human-readable size
-    let len = resp.content_length().unwrap_or(0);
-    let size = len.file_size(options::CONVENTIONAL).unwrap_or_default();
+/// Whether a header appears to hold credentials, judging by its name
+fn is_sensitive_header(name: &str) -> bool {
+    let name = name.to_lowercase();
+    SENSITIVE_HEADER_PARTS.iter().any(|part| name.contains(part))
+}
 
-    // debug printing
-    trace!("Response headers:");
-    resp.headers().iter().for_each(|(k, v)| {
-        trace!("[{}] {}", k, v.to_str().unwrap());
-    });
+/// a failure to resolve a URL, other than an error making a request, or
+/// reading a response
+#[derive(Debug, Fail, PartialEq)]
+pub enum ResolveError {
+    /// the time limit of the whole resolution, in seconds, was reached
+    #[fail(display = "Resolution timed out, max {}s", _0)]
+    Timeout(u64),
+    /// the time limit of a request, in seconds, was reached
+    #[fail(display = "Request timed out, max {}s", _0)]
+    RequestTimeout(u64),
+    /// the time limit of connecting to a host, in seconds, was reached
+    #[fail(display = "Connection timed out, max {}s", _0)]
+    ConnectTimeout(u64),
+    #[fail(display = "Too many redirects, max {}", _0)]
+    TooManyRedirects(u8),
+    #[fail(display = "Redirect loop detected at <{}>", _0)]
+    RedirectLoop(String),
+    #[fail(display = "Can't get redirection URL")]
+    NoLocation,
+    /// a URL refused with `https_only`
+    #[fail(display = "Refusing to request <{}>, only https is allowed", _0)]
+    HttpsOnly(String),
+    /// a redirection refused with `block_downgrades`
+    #[fail(display = "Refusing redirection from https to http <{}>", _0)]
+    Downgrade(String),
+    /// the limit of the total size of response headers, in bytes, was reached
+    #[fail(display = "Response headers too large, max {}B", _0)]
+    HeadersTooLarge(usize),
+    /// a response which is neither successful, nor a redirection
+    #[fail(display = "Unhandled request status: {}", _0)]
+    HttpStatus(StatusCode),
+    /// a gemini response which is neither successful, nor a redirection,
+    /// with its meta field
+    #[fail(display = "Gemini request failed: {} {}", _0, _1)]
+    GeminiStatus(u8, String),
+    /// no title could be found in a response
+    #[fail(display = "failed to parse title")]
+    NoTitle,
+    /// a response of a type not in `features.content_type_allowlist`
+    #[fail(display = "ignored content type {}", _0)]
+    IgnoredType(String),
+}
 
-    // vector to hold page content, which is progressively built from chunks of
-    // downloaded data until a title is found (up to CHUNKS_MAX chunks)
-    let mut body = Vec::new();
+impl ResolveError {
+    /// the kind of failure, as a label, e.g. for metrics
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            ResolveError::Timeout(_) => "timeout",
+            ResolveError::RequestTimeout(_) => "request_timeout",
+            ResolveError::ConnectTimeout(_) => "connect_timeout",
+            ResolveError::TooManyRedirects(_) => "too_many_redirects",
+            ResolveError::RedirectLoop(_) => "redirect_loop",
+            ResolveError::NoLocation => "no_location",
+            ResolveError::HttpsOnly(_) | ResolveError::Downgrade(_) => "blocked",
+            ResolveError::HeadersTooLarge(_) => "headers_too_large",
+            ResolveError::HttpStatus(_) => "http_status",
+            ResolveError::GeminiStatus(..) => "gemini_status",
+            ResolveError::NoTitle => "no_title",
+            ResolveError::IgnoredType(_) => "ignored_type",
+        }
+    }
+}
 
-    for i in 1..=CHUNKS_MAX {
-        // download a chunk
-        let mut chunk = Vec::new();
-        resp.take(CHUNK_BYTES).read_to_end(&mut chunk)?;
+/// the kind of an error resolving a URL, `other` for errors making requests,
+/// or reading responses, e.g. a connection being refused
+pub fn error_kind(err: &Error) -> &'static str {
+    err.downcast_ref::<ResolveError>().map_or("other", ResolveError::kind)
+}
 
-        // print downloaded chunk
-        if dump { print!("{}", String::from_utf8_lossy(&chunk)); }
+/// a meta refresh redirection found while retrieving a title
+#[derive(Debug, Fail)]
+#[fail(display = "Meta refresh to {}", _0)]
+pub struct MetaRefresh(pub String);
 
-        // append to downloaded content (move)
-        body.append(&mut chunk);
+/// Get a page from a response, following any meta refresh redirection with a
+/// further request, counted towards the fetcher's redirection limit
+fn follow_page<F: HttpFetcher>(fetcher: &mut F, mut resp: Response, rtd: &Rtd)
+    -> (Response, Result<Page, Error>)
+{
+    loop {
+        let err = match get_page(&mut resp, rtd, None) {
+            Ok(page) => return (resp, Ok(page)),
+            Err(err) => err,
+        };
 
-        // get title or metadata
-        let contents = String::from_utf8_lossy(&body);
-        let title = match content_type.clone() {
-            None => parse_title(&contents),
-            Some(mime) => {
-                match (mime.type_(), mime.subtype()) {
-                    (TEXT, HTML) => parse_title(&contents),
-                    (IMAGE, _) => parse_title(&contents)
-                        .or_else(|| get_image_metadata(&rtd, &body))
-                        .or_else(|| get_mime(&rtd, &mime, &size)),
-                    _ => parse_title(&contents)
-                        .or_else(|| get_mime(&rtd, &mime, &size)),
-                }
-            },
+        let url = match err.downcast::<MetaRefresh>() {
+            Ok(MetaRefresh(url)) => url,
+            Err(err) => return (resp, Err(err)),
         };
 
-        match title {
-            Some(t) => {
-                trace!("title found in {} chunks ({} B)", i, i * CHUNK_BYTES);
-                return Ok(t)
-            },
-            None => continue,
+        if let Err(err) = fetcher.redirected() {
+            return (resp, Err(err));
         }
+
+        debug!("following meta refresh to <{}>", url);
+        resp = match fetcher.fetch(&url) {
+            Ok(r) => r,
+            Err(err) => return (resp, Err(err)),
+        };
     }
+}
 
-    bail!("failed to parse title");
+/// the result of resolving a URL
+#[derive(Debug)]
+pub struct Resolved {
+    pub title: String,
+    /// canonical URL of the page, if found, and enabled
+    pub canonical: Option<String>,
+    /// mime type of the content, without parameters, if given
+    pub mime: Option<String>,
+    /// time taken to follow any redirections, and retrieve the title
+    pub elapsed: Duration,
+    /// URLs requested, from the URL resolved, through any redirections
+    pub redirects: Vec<String>,
 }
 
-#[cfg(test)]
-mod tests {
-    extern crate tiny_http;
+/// Resolve a URL, optionally with a database, in which errors are logged (if
+/// history is enabled), and cookies are persisted (if enabled)
+pub fn resolve_url(url: &str, rtd: &Rtd, db: Option<&Database>) -> Result<Resolved, Error> {
+    let timer = metrics::RESOLVE_TIME.start_timer();
+    let (res, event) = fetch_title(url, rtd, db);
+    timer.observe_duration();
 
-    use super::*;
-    use std::fs::File;
-    use std::path::{Path, PathBuf};
-    use std::{thread, time};
-    use self::tiny_http::{Response, Header};
-    use std::sync::mpsc;
+    match res {
+        Ok(_) => metrics::RESOLVE_SUCCESS.inc(),
+        Err(ref err) => {
+            metrics::RESOLVE_FAILURE.inc();
+            metrics::RESOLVE_ERRORS.with_label_values(&[error_kind(err)]).inc();
+        },
+    };
 
-    #[test]
-    fn resolve_urls() {
-        let rtd: Rtd = Rtd::default();
-        let db = Database::open_in_memory().unwrap();
-        resolve_url("https://youtube.com",  &rtd, &db).unwrap();
-        resolve_url("https://google.co.uk", &rtd, &db).unwrap();
-    }
+    event.log(module_path!(), Level::Info);
 
-    #[test]
-    fn resolve_locally_served_files() {
-        let mut rtd: Rtd = Rtd::default();
+    res
+}
 
-        // metadata and mime disabled
-        rtd.conf.features.report_metadata = false;
-        rtd.conf.features.report_mime = false;
+/// resolve `startup_check_url`, to check that titles can be retrieved
+pub fn startup_check(rtd: &Rtd) -> Result<Resolved, Error> {
+    let url = &rtd.conf.params.startup_check_url;
 
-        for t in vec!(
+    resolve_url(url, rtd, None)
+        .map_err(|err| format_err!("failed to resolve <{}>: {}", url, err))
+}
+
+/// Resolve a URL with the given fetcher, without a site handler, or logging,
+/// e.g. to retrieve titles from canned responses
+pub fn resolve_with<F: HttpFetcher>(fetcher: &mut F, url: &str, rtd: &Rtd)
+    -> Result<Resolved, Error>
+{
+    let start = Instant::now();
+    let resp = fetcher.fetch(url)?;
+    let page = follow_page(fetcher, resp, rtd).1?;
+
+    Ok(Resolved {
+        title: page.title,
+        canonical: page.canonical,
+        mime: page.mime,
+        elapsed: start.elapsed(),
+        redirects: fetcher.urls().to_vec(),
+    })
+}
+
+fn fetch_title<'a>(url: &'a str, rtd: &Rtd, db: Option<&Database>)
+    -> (Result<Resolved, Error>, Event<'a>)
+{
+    let start = Instant::now();
+    let mut event = Event::new(url);
+
+    // use a site handler, if there is one, falling back to the page title
+    if rtd.conf.features.site_handlers {
+        if let Some(title) = sites::site_title(url, rtd) {
+            let elapsed = start.elapsed();
+            let resolved = Resolved {
+                title, canonical: None, mime: None, elapsed, redirects: vec![]
+            };
+            return (Ok(resolved), event.duration(elapsed));
+        }
+    }
+
+    // gemini URLs are resolved over the gemini protocol, if enabled
+    let is_gemini = Url::parse(url).map(|u| u.scheme() == "gemini");
+    if rtd.conf.features.gemini && is_gemini.unwrap_or(false) {
+        let res = gemini::resolve(url, rtd);
+        event = event.duration(start.elapsed());
+        if let Err(ref err) = res { event = event.error(err); }
+        return (res, event);
+    }
+
+    let persist = db.filter(|_| {
+        rtd.conf.features.use_cookies && rtd.conf.features.persist_cookies
+    });
+    let mut session = Session::new();
+    session.params = RequestParams::from(rtd);
+    if let Some(db) = persist { load_cookies(&mut session, url, db); }
+    let conditional = db.filter(|_| rtd.conf.features.conditional_requests);
+    if let Some(db) = conditional { load_validator(&mut session, url, db); }
+
+    let res = match session.request(url) {
+        Ok(ref resp) if resp.status() == StatusCode::NOT_MODIFIED => {
+            if let Some(db) = persist { save_cookies(&session, db); }
+            event = event.status(resp.status().as_u16());
+            not_modified(&session)
+        },
+        Ok(resp) => {
+            // requests following a meta refresh aren't conditional
+            session.validator = None;
+            let (resp, res) = follow_page(&mut session, resp, rtd);
+            if let Some(db) = persist { save_cookies(&session, db); }
+            if let Some(db) = conditional { save_validator(&session, url, &resp, &res, db); }
+            event = event.status(resp.status().as_u16());
+            res.map_err(|err| {
+                if let Some(db) = db { log_error(&rtd, db, url, &err, &resp); }
+                err
+            })
+        },
+        Err(err) => Err(err),
+    };
+
+    let elapsed = start.elapsed();
+    event = event.duration(elapsed);
+    if let Err(ref err) = res {
+        event = event.error(err);
+    }
+
+    let res = res.map(|page| Resolved {
+        title: page.title,
+        canonical: page.canonical,
+        mime: page.mime,
+        elapsed,
+        redirects: session.urls,
+    });
+
+    (res, event)
+}
+
+/// the outcome of retrieving a title, along with details of the response
+#[derive(Debug, Default, Serialize)]
+pub struct TitleInfo {
+    pub url: String,
+    pub title: Option<String>,
+    pub final_url: Option<String>,
+    pub status: Option<u16>,
+    pub error: Option<String>,
+}
+
+/// Retrieve a title, without logging errors, reporting the final URL and
+/// status of the response
+pub fn get_title_info(url: &str, rtd: &Rtd, params: &RequestParams) -> TitleInfo {
+    let mut info = TitleInfo { url: url.to_string(), ..TitleInfo::default() };
+    let mut session = Session::new();
+    session.params = params.clone();
+
+    let title = session.request(url).and_then(|resp| {
+        let (resp, page) = follow_page(&mut session, resp, rtd);
+        info.final_url = Some(session.url.clone());
+        info.status = Some(resp.status().as_u16());
+        page.map(|p| p.title)
+    });
+
+    match title {
+        Ok(t) => info.title = Some(t),
+        Err(e) => info.error = Some(e.to_string()),
+    };
+
+    info
+}
+
+/// Get a title from a response, optionally writing the downloaded content to
+/// `dump`
+pub fn get_title(resp: &mut Response, rtd: &Rtd, dump: Option<&mut dyn Write>)
+    -> Result<String, Error>
+{
+    get_page(resp, rtd, dump).map(|page| page.title)
+}
+
+/// details of a page found in a response
+#[derive(Debug)]
+pub struct Page {
+    pub title: String,
+    /// canonical URL, resolved against the URL of the response, if enabled
+    pub canonical: Option<String>,
+    /// mime type of the content, without parameters, if given
+    pub mime: Option<String>,
+}
+
+/// Get a title and other details of a page from a response, optionally
+/// writing the downloaded content to `dump`
+pub fn get_page(resp: &mut Response, rtd: &Rtd, mut dump: Option<&mut dyn Write>)
+    -> Result<Page, Error>
+{
+    // get content type
+    let content_type = resp.headers().get(header::CONTENT_TYPE)
+        .and_then(|typ| typ.to_str().ok())
+        .and_then(|typ| typ.parse::<Mime>().ok());
+
+    // don't download types which are of no interest
+    if let Some(ref mime) = content_type {
+        if !allowed_type(&rtd, mime) {
+            let essence = format!("{}/{}", mime.type_(), mime.subtype());
+            return Err(ResolveError::IgnoredType(essence).into());
+        }
+    }
+
+    // get content length, if given, and plausible
+    let max_len = rtd.conf.params.max_content_length_mb * 1024 * 1024;
+    let len = resp.content_length().filter(|&len| {
+        let plausible = max_len == 0 || len <= max_len;
+        if !plausible {
+            warn!("ignoring implausible Content-Length: {}", len);
+        }
+        plausible
+    });
+
+    // the name of a downloaded file, reported along with the mime type
+    let filename = resp.headers().get(header::CONTENT_DISPOSITION)
+        .and_then(|d| d.to_str().ok())
+        .and_then(disposition_filename);
+    let filename = filename.as_ref().map(String::as_str);
+
+    // debug printing
+    trace!("Response headers:");
+    resp.headers().iter().for_each(|(k, v)| {
+        trace!("[{}] {}", k, v.to_str().unwrap());
+    });
+
+    // follow meta refresh redirections, if enabled
+    let follow_refresh = rtd.conf.features.follow_meta_refresh;
+
+    // the language given by the Content-Language header, used if the page
+    // doesn't declare its language
+    let report_language = rtd.conf.features.report_language;
+    let header_language = resp.headers().get(header::CONTENT_LANGUAGE)
+        .and_then(|lang| lang.to_str().ok())
+        .and_then(language_tag);
+
+    // size of each downloaded chunk
+    let chunk_bytes = rtd.conf.params.chunk_size_kb * 1024;
+
+    // a title can only be found in the head of a HTML document
+    let is_html = content_type.as_ref()
+        .map(|mime| (mime.type_(), mime.subtype()) == (TEXT, HTML))
+        .unwrap_or(false);
+    let is_page = is_html || content_type.is_none();
+
+    // the title of a page is usually within the first few hundred bytes, so
+    // pages are read in small pieces at first, doubling in size up to the
+    // chunk size, other content is read a chunk at a time
+    let mut read_bytes = if is_page {
+        cmp::min(FIRST_READ_BYTES, chunk_bytes)
+    } else {
+        chunk_bytes
+    };
+
+    // vector to hold page content, which is progressively built from reads of
+    // downloaded data until a title is found (up to CHUNKS_MAX chunks)
+    let mut body = Vec::new();
+    let max_bytes = CHUNKS_MAX * chunk_bytes;
+
+    for i in 1.. {
+        // download the next piece, at the end of the content if it's short
+        let mut chunk = Vec::new();
+        let want = cmp::min(read_bytes, max_bytes - body.len() as u64);
+        resp.take(want).read_to_end(&mut chunk)?;
+        let done = (chunk.len() as u64) < want || body.len() as u64 + want >= max_bytes;
+        read_bytes = cmp::min(read_bytes * 2, chunk_bytes);
+
+        // dump downloaded chunk
+        if let Some(ref mut w) = dump { w.write_all(&chunk)?; }
+
+        // append to downloaded content (move)
+        body.append(&mut chunk);
+
+        // get title or metadata
+        let contents = String::from_utf8_lossy(&body);
+
+        // check for a meta refresh redirection in the first chunk
+        if follow_refresh && body.len() as u64 <= chunk_bytes {
+            if let Some((delay, target)) = parse_meta_refresh(&contents) {
+                if delay <= META_REFRESH_MAX_S {
+                    let url = resp.url().join(&target)?;
+                    return Err(MetaRefresh(url.into_string()).into());
+                }
+            }
+        }
+
+        // titles are only looked for in text, not in binary content
+        let page_title = if !is_textual(content_type.as_ref(), &body) {
+            None
+        } else if rtd.conf.features.first_title_only {
+            parse_first_title(&contents)
+        } else {
+            parse_title(&contents)
+        };
+
+        let title = match content_type.clone() {
+            None => page_title,
+            Some(mime) => {
+                let report = report_type(&rtd, &mime);
+                match (mime.type_(), mime.subtype()) {
+                    (TEXT, HTML) => page_title,
+                    (IMAGE, _) => page_title
+                        .or_else(|| get_image_metadata(&rtd, &body).filter(|_| report))
+                        .or_else(|| get_mime(&rtd, &mime, len, filename).filter(|_| report)),
+                    _ => page_title
+                        .or_else(|| get_mime(&rtd, &mime, len, filename).filter(|_| report)),
+                }
+            },
+        };
+
+        match title {
+            Some(title) => {
+                trace!("title found in {} reads ({} B)", i, body.len());
+
+                let title = if is_page {
+                    let title = trim_site_suffix(&rtd, resp.url(), title);
+                    title_mismatch(&rtd, &contents, title)
+                } else {
+                    title
+                };
+
+                // annotate the title with the language of the page
+                let title = if report_language && is_page {
+                    match parse_language(&contents).or_else(|| header_language.clone()) {
+                        Some(lang) => format!("[{}] {}", lang, title),
+                        None => title,
+                    }
+                } else {
+                    title
+                };
+
+                // get the canonical URL
+                let canonical = if rtd.conf.features.use_canonical {
+                    parse_canonical(&contents)
+                        .and_then(|c| resp.url().join(&c).ok())
+                        .map(|c| c.into_string())
+                } else {
+                    None
+                };
+
+                let mime = content_type.as_ref()
+                    .map(|m| format!("{}/{}", m.type_(), m.subtype()));
+
+                return Ok(Page { title, canonical, mime })
+            },
+            None if is_html && end_of_head(&contents) => {
+                trace!("end of head found in {} reads, no title", i);
+                break;
+            },
+            None if done => break,
+            None => continue,
+        }
+    }
+
+    // report the host of a page without a title, if enabled
+    match resp.url().host_str() {
+        Some(host) if rtd.conf.features.fallback_to_host => {
+            debug!("no title found, falling back to host");
+            let mime = content_type.as_ref()
+                .map(|m| format!("{}/{}", m.type_(), m.subtype()));
+            Ok(Page { title: host.to_string(), canonical: None, mime })
+        },
+        _ => Err(ResolveError::NoTitle.into()),
+    }
+}
+
+/// Check whether the end of the head of a HTML document has been seen
+fn end_of_head(contents: &str) -> bool {
+    let contents = contents.to_ascii_lowercase();
+    contents.contains("</head>") || contents.contains("<body")
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tiny_http;
+    extern crate tempfile;
+
+    use super::*;
+    use std::fs::File;
+    use std::path::{Path, PathBuf};
+    use std::{thread, time};
+    use self::tiny_http::{Response, Header};
+    use std::sync::mpsc;
+    use std::io::{Seek, SeekFrom};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use serde_json::{self, Value};
+    use hyper;
+    use super::super::logging::{self, LogFormat};
+    use reqwest::async::ResponseBuilderExt;
+
+    /// canned responses to requests, by URL
+    #[derive(Default)]
+    struct MockFetcher {
+        pages: HashMap<&'static str, (&'static str, &'static str)>,
+        urls: Vec<String>,
+    }
+
+    impl MockFetcher {
+        fn page(mut self, url: &'static str, mime: &'static str, body: &'static str) -> Self {
+            self.pages.insert(url, (mime, body));
+            self
+        }
+    }
+
+    impl HttpFetcher for MockFetcher {
+        fn fetch(&mut self, url: &str) -> Result<super::Response, Error> {
+            self.urls.push(url.to_string());
+            let (mime, body) = self.pages.get(url)
+                .ok_or_else(|| format_err!("Unhandled request status: 404 Not Found"))?;
+
+            let resp = hyper::Response::builder()
+                .url(Url::parse(url)?)
+                .header(header::CONTENT_TYPE, *mime)
+                .body(*body)?;
+            Ok(super::Response::from(resp))
+        }
+
+        fn redirected(&mut self) -> Result<(), Error> {
+            if self.urls.len() > 2 {
+                bail!("Too many redirects, max 2");
+            }
+            Ok(())
+        }
+
+        fn urls(&self) -> &[String] {
+            &self.urls
+        }
+    }
+
+    #[test]
+    fn resolve_with_mock_fetcher() {
+        let mut rtd = Rtd::default();
+        rtd.conf.features.follow_meta_refresh = true;
+        rtd.conf.features.use_canonical = true;
+        let mock = || MockFetcher::default()
+            .page("http://a.zzz/", "text/html",
+                r#"<meta http-equiv="refresh" content="0; url=http://a.zzz/page">"#)
+            .page("http://a.zzz/page", "text/html; charset=utf-8",
+                r#"<title>Page</title><link rel="canonical" href="/canonical">"#)
+            .page("http://a.zzz/file", "application/octet-stream", "\0\0")
+            .page("http://a.zzz/loop", "text/html",
+                r#"<meta http-equiv="refresh" content="0; url=http://a.zzz/loop">"#);
+
+        let mut fetcher = mock();
+        let resolved = resolve_with(&mut fetcher, "http://a.zzz/", &rtd).unwrap();
+        assert_eq!(resolved.title, "Page");
+        assert_eq!(resolved.canonical.unwrap(), "http://a.zzz/canonical");
+        assert_eq!(resolved.mime.unwrap(), "text/html");
+        assert_eq!(resolved.redirects, vec!["http://a.zzz/", "http://a.zzz/page"]);
+
+        let err = |url| resolve_with(&mut mock(), url, &rtd).unwrap_err().to_string();
+        assert_eq!(err("http://a.zzz/missing"), "Unhandled request status: 404 Not Found");
+        assert_eq!(err("http://a.zzz/loop"), "Too many redirects, max 2");
+        assert!(resolve_with(&mut mock(), "http://a.zzz/file", &rtd).is_err());
+    }
+
+    #[test]
+    fn resolve_urls() {
+        let rtd: Rtd = Rtd::default();
+        let db = Database::open_in_memory().unwrap();
+        resolve_url("https://youtube.com",  &rtd, Some(&db)).unwrap();
+        resolve_url("https://google.co.uk", &rtd, Some(&db)).unwrap();
+    }
+
+    #[test]
+    fn resolve_locally_served_files() {
+        let mut rtd: Rtd = Rtd::default();
+
+        // metadata and mime disabled
+        rtd.conf.features.report_metadata = false;
+        rtd.conf.features.report_mime = false;
+
+        for t in vec!(
             "./test/img/test.gif",
             "./test/other/test.txt",
             "./test/other/test.pdf",
@@ -289,23 +1420,1396 @@ mod tests {
             assert!(serve_resolve(PathBuf::from(t), &rtd).is_err());
         }
 
-        // metadata and mime enabled
-        rtd.conf.features.report_metadata = true;
-        rtd.conf.features.report_mime = true;
+        // metadata and mime enabled
+        rtd.conf.features.report_metadata = true;
+        rtd.conf.features.report_mime = true;
+
+        for t in vec!(
+            ("./test/img/test.png", "image/png 800×400"),
+            ("./test/img/test.jpg", "image/jpeg 400×200"),
+            ("./test/img/test.gif", "image/gif 1920×1080"),
+            ("./test/html/basic.html", "basic"),
+            ("./test/other/test.txt", "text/plain; charset=utf8 16B"),
+            ("./test/other/test.pdf", "application/pdf 1.31KB"),
+        ) {
+            assert_eq!(
+                serve_resolve(PathBuf::from(t.0), &rtd).unwrap(),
+                String::from(t.1)
+            )
+        }
+
+        // metadata and mime enabled for images only
+        rtd.conf.features.report_types = vec!["image/*".to_string()];
+
+        assert_eq!(
+            serve_resolve(PathBuf::from("./test/img/test.png"), &rtd).unwrap(),
+            "image/png 800×400"
+        );
+        assert!(serve_resolve(PathBuf::from("./test/other/test.pdf"), &rtd).is_err());
+    }
+
+    #[test]
+    fn resolution_event_as_json() {
+        let server_thread = thread::spawn(move || {
+            let server = tiny_http::Server::http("0.0.0.0:28483").unwrap();
+            let rq = server.recv().unwrap();
+            let path = Path::new("./test/html/basic.html");
+            rq.respond(Response::from_file(File::open(path).unwrap())).unwrap();
+        });
+
+        thread::sleep(time::Duration::from_millis(100));
+        let db = Database::open_in_memory().unwrap();
+        let url = "http://0.0.0.0:28483/test";
+        let mut res = None;
+        let logged = logging::capture(LogFormat::Json, || {
+            res = Some(resolve_url(url, &Rtd::default(), Some(&db)));
+        });
+        server_thread.join().unwrap();
+
+        // each line logged is a JSON object, one of which is the event
+        let lines: Vec<Value> = logged.iter()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        let v = lines.iter().find(|v| v.get("status").is_some()).unwrap();
+
+        assert_eq!(res.unwrap().unwrap().title, "basic");
+        assert!(v["timestamp"].is_string());
+        assert_eq!(v["level"], "info");
+        assert_eq!(v["target"], "url_bot_rs::http");
+        assert_eq!(v["url"], url);
+        assert_eq!(v["status"], 200);
+        assert!(v["duration"].is_u64());
+        assert!(v.get("error").is_none());
+    }
+
+    #[test]
+    fn dump_response_to_file() {
+        let server_thread = thread::spawn(move || {
+            let server = tiny_http::Server::http("0.0.0.0:28485").unwrap();
+            let rq = server.recv().unwrap();
+            let path = Path::new("./test/html/basic.html");
+            rq.respond(Response::from_file(File::open(path).unwrap())).unwrap();
+        });
+
+        thread::sleep(time::Duration::from_millis(100));
+        let mut resp = Session::new()
+            .request("http://0.0.0.0:28485/test")
+            .unwrap();
+
+        let mut file = tempfile::tempfile().unwrap();
+        let title = get_title(&mut resp, &Rtd::default(), Some(&mut file));
+        server_thread.join().unwrap();
+        assert_eq!(title.unwrap(), "basic");
+
+        let mut dumped = String::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_string(&mut dumped).unwrap();
+
+        let mut expected = String::new();
+        File::open("./test/html/basic.html")
+            .unwrap()
+            .read_to_string(&mut expected)
+            .unwrap();
+
+        assert_eq!(dumped, expected);
+    }
+
+    #[test]
+    fn title_info_as_json() {
+        let server_thread = thread::spawn(move || {
+            let server = tiny_http::Server::http("0.0.0.0:28486").unwrap();
+            let rq = server.recv().unwrap();
+            let path = Path::new("./test/html/basic.html");
+            rq.respond(Response::from_file(File::open(path).unwrap())).unwrap();
+        });
+
+        thread::sleep(time::Duration::from_millis(100));
+        let url = "http://0.0.0.0:28486/test";
+        let params = RequestParams::default();
+        let info = get_title_info(url, &Rtd::default(), &params);
+        server_thread.join().unwrap();
+
+        let v = serde_json::to_value(&info).unwrap();
+        assert_eq!(v["url"], url);
+        assert_eq!(v["title"], "basic");
+        assert_eq!(v["final_url"], url);
+        assert_eq!(v["status"], 200);
+        assert_eq!(v["error"], Value::Null);
+        assert_eq!(v.as_object().unwrap().len(), 5);
+
+        // a failed request
+        let info = get_title_info("http://0.0.0.0:1/", &Rtd::default(), &params);
+        let v = serde_json::to_value(&info).unwrap();
+        assert_eq!(v["title"], Value::Null);
+        assert_eq!(v["status"], Value::Null);
+        assert!(v["error"].is_string());
+    }
+
+    #[test]
+    fn cookies_not_sent_to_other_domains() {
+        let (tx, rx) = mpsc::channel();
+        let server_thread = thread::spawn(move || {
+            let server = tiny_http::Server::http("0.0.0.0:28488").unwrap();
+
+            for (cookies, location) in &[
+                (vec!("a=1", "b=2; Path=/other", "c=3; Max-Age=0"),
+                    Some("http://127.0.0.1:28488/next")),
+                (vec!("d=4; Domain=localhost"),
+                    Some("http://localhost:28488/final")),
+                (vec!(), None),
+            ] {
+                let rq = server.recv().unwrap();
+                let host = rq.headers().iter()
+                    .find(|h| h.field.equiv("Host"))
+                    .map(|h| h.value.to_string())
+                    .unwrap();
+                let sent = rq.headers().iter()
+                    .find(|h| h.field.equiv("Cookie"))
+                    .map(|h| h.value.to_string())
+                    .unwrap_or_default();
+                tx.send((host, sent)).unwrap();
+
+                let mut resp = match location {
+                    Some(l) => Response::from_data(vec!())
+                        .with_status_code(302)
+                        .with_header(Header::from_bytes("Location", *l).unwrap()),
+                    None => Response::from_data(
+                        ::std::fs::read("./test/html/basic.html").unwrap()),
+                };
+                for c in cookies {
+                    resp.add_header(Header::from_bytes("Set-Cookie", *c).unwrap());
+                }
+                rq.respond(resp).unwrap();
+            }
+        });
+
+        thread::sleep(time::Duration::from_millis(100));
+        let mut session = Session::new();
+        let resp = session.request("http://127.0.0.1:28488/start");
+        server_thread.join().unwrap();
+
+        let sent: Vec<(String, String)> = rx.iter().collect();
+        assert_eq!(sent, vec!(
+            ("127.0.0.1:28488".to_string(), "".to_string()),
+            ("127.0.0.1:28488".to_string(), "a=1".to_string()),
+            ("localhost:28488".to_string(), "".to_string()),
+        ));
+        assert!(resp.is_ok());
+
+        // the cookie for a foreign domain was rejected, and the expired cookie
+        // wasn't stored
+        let names: Vec<&str> = session.cookies.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, vec!["a", "b"]);
+    }
+
+    #[test]
+    fn cookies_disabled() {
+        let (tx, rx) = mpsc::channel();
+        let server_thread = thread::spawn(move || {
+            let server = tiny_http::Server::http("0.0.0.0:28505").unwrap();
+
+            for location in &[Some("http://127.0.0.1:28505/next"), None] {
+                let rq = server.recv().unwrap();
+                let sent = rq.headers().iter()
+                    .any(|h| h.field.equiv("Cookie"));
+                tx.send(sent).unwrap();
+
+                let resp = match location {
+                    Some(l) => Response::from_data(vec!())
+                        .with_status_code(302)
+                        .with_header(Header::from_bytes("Location", *l).unwrap()),
+                    None => Response::from_data(
+                        ::std::fs::read("./test/html/basic.html").unwrap()),
+                };
+                let resp = resp.with_header(
+                    Header::from_bytes("Set-Cookie", "a=1; Max-Age=60").unwrap());
+                rq.respond(resp).unwrap();
+            }
+        });
+
+        thread::sleep(time::Duration::from_millis(100));
+        let mut rtd = Rtd::default();
+        rtd.conf.features.use_cookies = false;
+        let mut session = Session::new();
+        session.params = RequestParams::from(&rtd);
+        let resp = session.request("http://127.0.0.1:28505/start");
+        server_thread.join().unwrap();
+
+        // no Cookie header is sent, even after a cookie is set
+        let sent: Vec<bool> = rx.iter().collect();
+        assert_eq!(sent, vec!(false, false));
+        assert!(resp.is_ok());
+        assert!(session.cookies.is_empty());
+    }
+
+    #[test]
+    fn basic_auth_for_host() {
+        let (tx, rx) = mpsc::channel();
+        let server_thread = thread::spawn(move || {
+            let server = tiny_http::Server::http("0.0.0.0:28506").unwrap();
+
+            for location in &[
+                Some("http://127.0.0.1:28506/next"),
+                Some("http://localhost:28506/final"),
+                None,
+            ] {
+                let rq = server.recv().unwrap();
+                let auth = rq.headers().iter()
+                    .find(|h| h.field.equiv("Authorization"))
+                    .map(|h| h.value.to_string());
+                tx.send(auth).unwrap();
+
+                let resp = match location {
+                    Some(l) => Response::from_data(vec!())
+                        .with_status_code(302)
+                        .with_header(Header::from_bytes("Location", *l).unwrap()),
+                    None => Response::from_data(
+                        ::std::fs::read("./test/html/basic.html").unwrap()),
+                };
+                rq.respond(resp).unwrap();
+            }
+        });
+
+        thread::sleep(time::Duration::from_millis(100));
+        let mut rtd = Rtd::default();
+        rtd.conf.params.basic_auth.insert("127.0.0.1".to_string(), BasicAuth {
+            username: "user".to_string(),
+            password: "pass".to_string(),
+        });
+        let mut session = Session::new();
+        session.params = RequestParams::from(&rtd);
+        let resp = session.request("http://127.0.0.1:28506/start");
+        server_thread.join().unwrap();
+
+        // credentials aren't sent after a redirection to another host
+        let sent: Vec<Option<String>> = rx.iter().collect();
+        let auth = Some("Basic dXNlcjpwYXNz".to_string());
+        assert_eq!(sent, vec!(auth.clone(), auth, None));
+        assert!(resp.is_ok());
+    }
+
+    #[test]
+    fn send_extra_headers() {
+        let (tx, rx) = mpsc::channel();
+        let server_thread = thread::spawn(move || {
+            let server = tiny_http::Server::http("0.0.0.0:28524").unwrap();
+
+            for location in &[Some("http://localhost:28524/final"), None] {
+                let rq = server.recv().unwrap();
+                let mut headers: Vec<String> = rq.headers().iter()
+                    .filter(|h| h.field.equiv("DNT") || h.field.equiv("X-Api-Key"))
+                    .map(|h| format!("{}: {}", h.field, h.value))
+                    .collect();
+                headers.sort();
+                tx.send(headers).unwrap();
+
+                let resp = match location {
+                    Some(l) => Response::from_data(vec!())
+                        .with_status_code(302)
+                        .with_header(Header::from_bytes("Location", *l).unwrap()),
+                    None => Response::from_data(
+                        ::std::fs::read("./test/html/basic.html").unwrap()),
+                };
+                rq.respond(resp).unwrap();
+            }
+        });
+
+        thread::sleep(time::Duration::from_millis(100));
+        let mut rtd = Rtd::default();
+        rtd.conf.params.extra_headers.insert("DNT".to_string(), "1".to_string());
+        rtd.conf.params.extra_headers.insert("X-Api-Key".to_string(), "abc".to_string());
+        let resolved = resolve_url("http://127.0.0.1:28524/start", &rtd, None);
+        server_thread.join().unwrap();
+        assert_eq!(resolved.unwrap().title, "basic");
+
+        // the key isn't sent after a redirection to another host
+        let sent: Vec<Vec<String>> = rx.iter().collect();
+        assert_eq!(sent, vec![
+            vec!["dnt: 1".to_string(), "x-api-key: abc".to_string()],
+            vec!["dnt: 1".to_string()],
+        ]);
+    }
+
+    #[test]
+    fn extra_header_checks() {
+        assert!(check_extra_header("DNT", "1").is_ok());
+        assert!(check_extra_header("Sec-GPC", "1").is_ok());
+        assert!(check_extra_header("Host", "z.zzz").is_err());
+        assert!(check_extra_header("user-agent", "curl").is_err());
+        assert!(check_extra_header("Bad Header", "1").is_err());
+        assert!(check_extra_header("X-Value", "a\nb").is_err());
+
+        assert!(is_sensitive_header("Authorization"));
+        assert!(is_sensitive_header("X-API-Key"));
+        assert!(is_sensitive_header("X-Auth-Token"));
+        assert!(!is_sensitive_header("DNT"));
+    }
+
+    #[test]
+    fn ignore_types_not_allowed() {
+        let server_thread = thread::spawn(move || {
+            let server = tiny_http::Server::http("0.0.0.0:28507").unwrap();
+            let rq = server.recv().unwrap();
+            let header = Header::from_bytes("Content-Type",
+                "application/octet-stream").unwrap();
+            let resp = Response::from_data(vec![0; 64 * 1024]).with_header(header);
+            let _ = rq.respond(resp);
+        });
+
+        thread::sleep(time::Duration::from_millis(100));
+        let rtd = Rtd::default();
+        let mut session = Session::new();
+        let mut resp = session.request("http://127.0.0.1:28507/").unwrap();
+
+        // nothing is downloaded
+        let mut dump = vec![];
+        let err = get_page(&mut resp, &rtd, Some(&mut dump)).err().unwrap();
+        drop(resp);
+        server_thread.join().unwrap();
+
+        assert_eq!(err.downcast::<ResolveError>().unwrap(),
+            ResolveError::IgnoredType("application/octet-stream".to_string()));
+        assert!(dump.is_empty());
+    }
+
+    #[test]
+    fn resolve_error_kinds() {
+        let server_thread = thread::spawn(move || {
+            let server = tiny_http::Server::http("0.0.0.0:28530").unwrap();
+
+            while let Ok(Some(rq)) = server.recv_timeout(time::Duration::from_secs(2)) {
+                let location = match rq.url() {
+                    "/loop" => Some("http://0.0.0.0:28530/loop".to_string()),
+                    url if url.starts_with("/redirect/") => {
+                        let hop: usize = url[10..].parse().unwrap();
+                        Some(format!("http://0.0.0.0:28530/redirect/{}", hop + 1))
+                    },
+                    _ => None,
+                };
+
+                let resp = match rq.url() {
+                    _ if location.is_some() => Response::from_data(vec!())
+                        .with_status_code(302)
+                        .with_header(Header::from_bytes("Location", location.unwrap()).unwrap()),
+                    "/nolocation" => Response::from_data(vec!()).with_status_code(302),
+                    "/big" => Response::from_data(vec!())
+                        .with_header(Header::from_bytes("X-Big", "x".repeat(128)).unwrap()),
+                    "/empty" => Response::from_string("<html></html>")
+                        .with_header(Header::from_bytes("Content-Type", "text/html").unwrap()),
+                    _ => Response::from_data(vec!()).with_status_code(404),
+                };
+                rq.respond(resp).unwrap();
+            }
+        });
+
+        thread::sleep(time::Duration::from_millis(100));
+        let request = |session: &mut Session, url: &str| {
+            let err = session.request(url).err().unwrap();
+            assert_eq!(err.downcast_ref::<ResolveError>().map(ResolveError::kind),
+                Some(error_kind(&err)));
+            err.downcast::<ResolveError>().unwrap()
+        };
+
+        assert_eq!(request(&mut Session::new(), "http://0.0.0.0:28530/missing"),
+            ResolveError::HttpStatus(StatusCode::NOT_FOUND));
+        assert_eq!(request(&mut Session::new(), "http://0.0.0.0:28530/nolocation"),
+            ResolveError::NoLocation);
+
+        assert_eq!(request(&mut Session::new(), "http://0.0.0.0:28530/loop"),
+            ResolveError::RedirectLoop("http://0.0.0.0:28530/loop".to_string()));
+
+        let mut session = Session::new();
+        session.params.redirect_limit = 1;
+        assert_eq!(request(&mut session, "http://0.0.0.0:28530/redirect/0"),
+            ResolveError::TooManyRedirects(1));
+
+        let mut session = Session::new();
+        session.params.header_size_limit = 64;
+        assert_eq!(request(&mut session, "http://0.0.0.0:28530/big"),
+            ResolveError::HeadersTooLarge(64));
+
+        let mut session = Session::new();
+        session.params.https_only = true;
+        let err = request(&mut session, "http://0.0.0.0:28530/");
+        assert_eq!(err, ResolveError::HttpsOnly("http://0.0.0.0:28530/".to_string()));
+        assert_eq!(err.kind(), "blocked");
+
+        let mut session = Session::new();
+        session.params.block_downgrades = true;
+        session.url = "https://example.com/".to_string();
+        let err = session.check_redirection("http://example.com/").unwrap_err();
+        assert_eq!(err.downcast::<ResolveError>().unwrap(),
+            ResolveError::Downgrade("http://example.com/".to_string()));
+
+        let err = resolve_url("http://0.0.0.0:28530/empty", &Rtd::default(), None).unwrap_err();
+        assert_eq!(err.downcast::<ResolveError>().unwrap(), ResolveError::NoTitle);
+
+        // errors making requests aren't a ResolveError
+        let err = Session::new().request("http://0.0.0.0:1/").unwrap_err();
+        assert_eq!(error_kind(&err), "other");
+
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn distrust_inflated_content_length() {
+        use std::io::BufRead;
+        use std::net::TcpListener;
+
+        // a server sending less content than advertised, keeping connections
+        // open until the test is done
+        let (tx, rx) = mpsc::channel::<()>();
+        let listener = TcpListener::bind("127.0.0.1:28508").unwrap();
+        let server_thread = thread::spawn(move || {
+            let mut streams = vec![];
+            for stream in listener.incoming().take(2) {
+                let mut stream = stream.unwrap();
+                let mut reader = ::std::io::BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 2 {
+                    line.clear();
+                }
+
+                stream.write_all(b"HTTP/1.1 200 OK\r\n\
+                    Content-Type: application/pdf\r\n\
+                    Content-Length: 5000000000000\r\n\r\n").unwrap();
+                stream.write_all(&[0; 8 * 1024]).unwrap();
+                streams.push(stream);
+            }
+            rx.recv().unwrap();
+        });
+
+        let mut rtd = Rtd::default();
+        rtd.conf.features.report_mime = true;
+        rtd.conf.params.chunk_size_kb = 1;
+        let url = "http://127.0.0.1:28508/";
+
+        // only the first chunk of the content is downloaded
+        assert_eq!(resolve_url(url, &rtd, None).unwrap().title, "application/pdf");
+
+        rtd.conf.params.max_content_length_mb = 0;
+        assert_eq!(resolve_url(url, &rtd, None).unwrap().title,
+            "application/pdf 4.55TB");
+
+        tx.send(()).unwrap();
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn limit_cookies_and_headers() {
+        let server_thread = thread::spawn(move || {
+            let server = tiny_http::Server::http("0.0.0.0:28489").unwrap();
+
+            for rq in server.incoming_requests().take(7) {
+                let hop: usize = rq.url()[1..].parse().unwrap();
+                let mut resp = if hop < 5 {
+                    let next = format!("http://0.0.0.0:28489/{}", hop + 1);
+                    Response::from_data(vec!())
+                        .with_status_code(302)
+                        .with_header(Header::from_bytes("Location", next).unwrap())
+                } else {
+                    Response::from_data(
+                        ::std::fs::read("./test/html/basic.html").unwrap())
+                };
+                for i in 0..20 {
+                    let c = format!("c{}_{}=x", hop, i);
+                    resp.add_header(Header::from_bytes("Set-Cookie", c).unwrap());
+                }
+                rq.respond(resp).unwrap();
+            }
+        });
+
+        thread::sleep(time::Duration::from_millis(100));
+        let mut session = Session::new();
+        session.params.cookie_limit = 50;
+        assert!(session.request("http://0.0.0.0:28489/0").is_ok());
+        assert_eq!(session.cookies.len(), 50);
+
+        // a single response may only set half of the limit
+        let mut session = Session::new();
+        session.params.cookie_limit = 10;
+        assert!(session.request("http://0.0.0.0:28489/5").is_ok());
+        assert_eq!(session.cookies.len(), 5);
+        server_thread.join().unwrap();
+
+        // headers too large
+        let server_thread = thread::spawn(move || {
+            let server = tiny_http::Server::http("0.0.0.0:28490").unwrap();
+            let rq = server.recv().unwrap();
+            let big = Header::from_bytes("X-Big", vec![b'x'; 2000]).unwrap();
+            rq.respond(Response::from_data(vec!()).with_header(big)).unwrap();
+        });
+
+        thread::sleep(time::Duration::from_millis(100));
+        let mut session = Session::new();
+        session.params.header_size_limit = 1000;
+        let err = session.request("http://0.0.0.0:28490/").unwrap_err();
+        server_thread.join().unwrap();
+        assert!(err.to_string().starts_with("Response headers too large"));
+    }
+
+    #[test]
+    fn follow_meta_refresh() {
+        let server_thread = thread::spawn(move || {
+            let server = tiny_http::Server::http("0.0.0.0:28491").unwrap();
+
+            for rq in server.incoming_requests().take(3) {
+                let path = match rq.url() {
+                    "/refresh" => "./test/html/refresh.html",
+                    _ => "./test/html/basic.html",
+                };
+                let file = File::open(path).unwrap();
+                rq.respond(Response::from_file(file)).unwrap();
+            }
+        });
+
+        thread::sleep(time::Duration::from_millis(100));
+        let db = Database::open_in_memory().unwrap();
+        let mut rtd = Rtd::default();
+        let url = "http://0.0.0.0:28491/refresh";
+
+        assert_eq!(resolve_url(url, &rtd, Some(&db)).unwrap().title, "Redirecting");
+
+        rtd.conf.features.follow_meta_refresh = true;
+        assert_eq!(resolve_url(url, &rtd, Some(&db)).unwrap().title, "basic");
+        server_thread.join().unwrap();
+
+        // the redirection limit applies
+        let mut session = Session::new();
+        session.params.redirect_limit = 0;
+        let server_thread = thread::spawn(move || {
+            let server = tiny_http::Server::http("0.0.0.0:28492").unwrap();
+            let rq = server.recv().unwrap();
+            let file = File::open("./test/html/refresh.html").unwrap();
+            rq.respond(Response::from_file(file)).unwrap();
+        });
+        thread::sleep(time::Duration::from_millis(100));
+        let resp = session.request("http://0.0.0.0:28492/refresh").unwrap();
+        let (_, res) = follow_page(&mut session, resp, &rtd);
+        server_thread.join().unwrap();
+        assert!(res.unwrap_err().to_string().starts_with("Too many redirects"));
+    }
+
+    #[test]
+    fn canonical_url() {
+        let server_thread = thread::spawn(move || {
+            let server = tiny_http::Server::http("0.0.0.0:28493").unwrap();
+
+            for rq in server.incoming_requests().take(2) {
+                let file = File::open("./test/html/canonical.html").unwrap();
+                rq.respond(Response::from_file(file)).unwrap();
+            }
+        });
+
+        thread::sleep(time::Duration::from_millis(100));
+        let db = Database::open_in_memory().unwrap();
+        let mut rtd = Rtd::default();
+        let url = "http://0.0.0.0:28493/article?id=1&utm_source=z";
+
+        let resolved = resolve_url(url, &rtd, Some(&db)).unwrap();
+        assert_eq!(resolved.title, "canonical");
+        assert_eq!(resolved.canonical, None);
+
+        rtd.conf.features.use_canonical = true;
+        let resolved = resolve_url(url, &rtd, Some(&db)).unwrap();
+        server_thread.join().unwrap();
+        assert_eq!(resolved.title, "canonical");
+        assert_eq!(
+            resolved.canonical,
+            Some(String::from("http://0.0.0.0:28493/article?id=1"))
+        );
+    }
+
+    #[test]
+    fn resolve_without_database() {
+        let server_thread = thread::spawn(move || {
+            let server = tiny_http::Server::http("0.0.0.0:28502").unwrap();
+
+            for rq in server.incoming_requests().take(2) {
+                let body = match rq.url() {
+                    "/basic" => "<head><title>basic</title></head><body></body>",
+                    _ => "<head></head><body></body>",
+                };
+                let resp = Response::from_string(body)
+                    .with_header(Header::from_bytes(&b"Content-Type"[..], &b"text/html"[..])
+                        .unwrap())
+                    .with_header(Header::from_bytes(&b"Set-Cookie"[..], &b"a=1; Max-Age=60"[..])
+                        .unwrap());
+                rq.respond(resp).unwrap();
+            }
+        });
+
+        thread::sleep(time::Duration::from_millis(100));
+        let mut rtd = Rtd::default();
+        rtd.conf.features.history = true;
+        rtd.conf.features.persist_cookies = true;
+
+        let url = "http://0.0.0.0:28502/basic";
+        assert_eq!(resolve_url(url, &rtd, None).unwrap().title, "basic");
+        let url = "http://0.0.0.0:28502/untitled";
+        assert!(resolve_url(url, &rtd, None).is_err());
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn no_titles_from_binary_content() {
+        let server_thread = thread::spawn(move || {
+            let server = tiny_http::Server::http("0.0.0.0:28516").unwrap();
+
+            for rq in server.incoming_requests().take(3) {
+                let mut data = vec![0, 1, 2, 0xff];
+                data.extend_from_slice(b"<title>bogus</title>");
+                data.resize(2048, 0);
+                let mut resp = Response::from_data(data);
+                if rq.url() == "/bin" {
+                    resp = resp.with_header(Header::from_bytes("Content-Type",
+                        "application/octet-stream").unwrap());
+                }
+                rq.respond(resp).unwrap();
+            }
+        });
+
+        thread::sleep(time::Duration::from_millis(100));
+        let mut rtd = Rtd::default();
+        rtd.conf.features.content_type_allowlist = vec![];
+
+        let url = "http://127.0.0.1:28516/bin";
+        assert!(resolve_url(url, &rtd, None).is_err());
+        let untyped = "http://127.0.0.1:28516/untyped";
+        assert!(resolve_url(untyped, &rtd, None).is_err());
+
+        rtd.conf.features.report_mime = true;
+        assert_eq!(resolve_url(url, &rtd, None).unwrap().title,
+            "application/octet-stream 2KB");
+
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn report_disposition_filename() {
+        let server_thread = thread::spawn(move || {
+            let server = tiny_http::Server::http("0.0.0.0:28515").unwrap();
+
+            for rq in server.incoming_requests().take(2) {
+                let file = File::open("./test/other/test.pdf").unwrap();
+                let ctype = Header::from_bytes("Content-Type", "application/pdf").unwrap();
+                let mut resp = Response::from_file(file).with_header(ctype);
+                if rq.url() == "/download" {
+                    resp = resp.with_header(Header::from_bytes("Content-Disposition",
+                        "attachment; filename*=UTF-8''%E2%82%AC%20report.pdf").unwrap());
+                }
+                rq.respond(resp).unwrap();
+            }
+        });
+
+        thread::sleep(time::Duration::from_millis(100));
+        let mut rtd = Rtd::default();
+        rtd.conf.features.report_mime = true;
+
+        let url = "http://127.0.0.1:28515/download";
+        assert_eq!(resolve_url(url, &rtd, None).unwrap().title,
+            "€ report.pdf (application/pdf 1.31KB)");
+        let url = "http://127.0.0.1:28515/view";
+        assert_eq!(resolve_url(url, &rtd, None).unwrap().title, "application/pdf 1.31KB");
+
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn omit_size_without_content_length() {
+        let server_thread = thread::spawn(move || {
+            let server = tiny_http::Server::http("0.0.0.0:28501").unwrap();
+            let rq = server.recv().unwrap();
+            let file = File::open("./test/other/test.txt").unwrap();
+            let header = Header::from_bytes(&b"Content-Type"[..], &b"text/plain"[..])
+                .unwrap();
+
+            // no data length, so the response is chunked
+            let resp = Response::new(200.into(), vec![header], file, None, None);
+            rq.respond(resp).unwrap();
+        });
+
+        thread::sleep(time::Duration::from_millis(100));
+        let db = Database::open_in_memory().unwrap();
+        let mut rtd = Rtd::default();
+        rtd.conf.features.report_mime = true;
+
+        let resolved = resolve_url("http://0.0.0.0:28501/", &rtd, Some(&db)).unwrap();
+        server_thread.join().unwrap();
+        assert_eq!(resolved.title, "text/plain");
+    }
+
+    #[test]
+    fn report_page_language() {
+        let server_thread = thread::spawn(move || {
+            let server = tiny_http::Server::http("0.0.0.0:28500").unwrap();
+            let html = Header::from_bytes(&b"Content-Type"[..], &b"text/html"[..])
+                .unwrap();
+            let lang = Header::from_bytes(&b"Content-Language"[..], &b"fr, en"[..])
+                .unwrap();
+
+            for rq in server.incoming_requests().take(4) {
+                let path = format!("./test/html{}.html", rq.url());
+                let resp = Response::from_file(File::open(path).unwrap())
+                    .with_header(html.clone())
+                    .with_header(lang.clone());
+                rq.respond(resp).unwrap();
+            }
+        });
+
+        thread::sleep(time::Duration::from_millis(100));
+        let db = Database::open_in_memory().unwrap();
+        let mut rtd = Rtd::default();
+        let lang = "http://0.0.0.0:28500/lang";
+        let basic = "http://0.0.0.0:28500/basic";
+
+        assert_eq!(resolve_url(lang, &rtd, Some(&db)).unwrap().title, "Titel");
+        assert_eq!(resolve_url(basic, &rtd, Some(&db)).unwrap().title, "basic");
+
+        // the lang attribute takes precedence over the header
+        rtd.conf.features.report_language = true;
+        assert_eq!(resolve_url(lang, &rtd, Some(&db)).unwrap().title, "[de] Titel");
+        assert_eq!(resolve_url(basic, &rtd, Some(&db)).unwrap().title, "[fr] basic");
+
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn resolve_with_host_override() {
+        let (tx, rx) = mpsc::channel();
+        let server_thread = thread::spawn(move || {
+            let server = tiny_http::Server::http("0.0.0.0:28494").unwrap();
+            let rq = server.recv().unwrap();
+            let host = rq.headers().iter()
+                .find(|h| h.field.equiv("Host"))
+                .map(|h| h.value.to_string())
+                .unwrap();
+            tx.send(host).unwrap();
+            let file = File::open("./test/html/basic.html").unwrap();
+            rq.respond(Response::from_file(file)).unwrap();
+        });
+
+        thread::sleep(time::Duration::from_millis(100));
+        let db = Database::open_in_memory().unwrap();
+        let mut rtd = Rtd::default();
+        rtd.conf.params.host_overrides
+            .insert("url-bot.invalid".to_string(), "127.0.0.1".to_string());
+
+        let res = resolve_url("http://url-bot.invalid:28494/test", &rtd, Some(&db));
+        server_thread.join().unwrap();
+        assert_eq!(res.unwrap().title, "basic");
+        assert_eq!(rx.recv().unwrap(), "url-bot.invalid:28494");
+    }
+
+    #[test]
+    fn fall_back_to_host() {
+        let server_thread = thread::spawn(move || {
+            let server = tiny_http::Server::http("0.0.0.0:28512").unwrap();
+
+            for rq in server.incoming_requests().take(2) {
+                let file = File::open("./test/html/empty-title.html").unwrap();
+                let header = Header::from_bytes("Content-Type", "text/html").unwrap();
+                rq.respond(Response::from_file(file).with_header(header)).unwrap();
+            }
+        });
+
+        thread::sleep(time::Duration::from_millis(100));
+        let mut rtd = Rtd::default();
+        let url = "http://127.0.0.1:28512/empty";
+
+        assert!(resolve_url(url, &rtd, None).is_err());
+
+        rtd.conf.features.fallback_to_host = true;
+        let resolved = resolve_url(url, &rtd, None).unwrap();
+        assert_eq!(resolved.title, "127.0.0.1");
+        assert_eq!(resolved.mime.unwrap(), "text/html");
+
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn check_resolution_on_startup() {
+        let server_thread = thread::spawn(move || {
+            let server = tiny_http::Server::http("0.0.0.0:28519").unwrap();
+
+            for rq in server.incoming_requests().take(2) {
+                match rq.url() {
+                    "/canary" => {
+                        let file = File::open("./test/html/basic.html").unwrap();
+                        rq.respond(Response::from_file(file)).unwrap();
+                    },
+                    _ => rq.respond(Response::empty(404)).unwrap(),
+                };
+            }
+        });
+
+        thread::sleep(time::Duration::from_millis(100));
+        let mut rtd = Rtd::default();
+
+        rtd.conf.params.startup_check_url = "http://127.0.0.1:28519/canary".to_string();
+        assert_eq!(startup_check(&rtd).unwrap().title, "basic");
+
+        rtd.conf.params.startup_check_url = "http://127.0.0.1:28519/gone".to_string();
+        let err = startup_check(&rtd).unwrap_err().to_string();
+        assert!(err.starts_with("failed to resolve <http://127.0.0.1:28519/gone>: "));
+
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn read_pages_incrementally() {
+        let server_thread = thread::spawn(move || {
+            let server = tiny_http::Server::http("0.0.0.0:28513").unwrap();
+
+            for rq in server.incoming_requests().take(2) {
+                let mut page = b"<html><head><title>early</title></head><body>".to_vec();
+                page.resize(1024 * 1024, b'x');
+                let ctype = match rq.url() {
+                    "/html" => "text/html",
+                    _ => "text/plain",
+                };
+                let header = Header::from_bytes("Content-Type", ctype).unwrap();
+
+                // the client stops reading early, closing the connection
+                let _ = rq.respond(Response::from_data(page).with_header(header));
+            }
+        });
+
+        thread::sleep(time::Duration::from_millis(100));
+        let rtd = Rtd::default();
+
+        let get = |url| {
+            let mut resp = Session::new().request(url).unwrap();
+            let mut dump = Vec::new();
+            let res = get_title(&mut resp, &rtd, Some(&mut dump));
+            (res, dump.len())
+        };
+
+        // only the first read of the page is needed for the title
+        let (res, len) = get("http://127.0.0.1:28513/html");
+        assert_eq!(res.unwrap(), "early");
+        assert_eq!(len as u64, FIRST_READ_BYTES);
+
+        // other content is read a chunk at a time, up to the limit
+        let (res, len) = get("http://127.0.0.1:28513/txt");
+        assert_eq!(res.unwrap(), "early");
+        assert_eq!(len, 100 * 1024);
+
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn stop_at_end_of_head() {
+        let server_thread = thread::spawn(move || {
+            let server = tiny_http::Server::http("0.0.0.0:28495").unwrap();
+
+            for rq in server.incoming_requests().take(2) {
+                let file = File::open("./test/html/untitled.html").unwrap();
+                let ctype = match rq.url() {
+                    "/html" => "text/html",
+                    _ => "text/plain",
+                };
+                let header = Header::from_bytes("Content-Type", ctype).unwrap();
+                rq.respond(Response::from_file(file).with_header(header)).unwrap();
+            }
+        });
+
+        thread::sleep(time::Duration::from_millis(100));
+        let mut rtd = Rtd::default();
+        rtd.conf.params.chunk_size_kb = 1;
+
+        let get = |url| {
+            let mut resp = Session::new().request(url).unwrap();
+            let mut dump = Vec::new();
+            let res = get_title(&mut resp, &rtd, Some(&mut dump));
+            (res, dump.len())
+        };
+
+        // only the first chunk of the html is downloaded
+        let (res, len) = get("http://0.0.0.0:28495/html");
+        assert!(res.is_err());
+        assert_eq!(len, 1024);
+
+        // other content is downloaded in full
+        let (res, len) = get("http://0.0.0.0:28495/txt");
+        server_thread.join().unwrap();
+        assert_eq!(res.unwrap(), "too late");
+        assert!(len > 1024);
+    }
+
+    #[test]
+    fn http_version_preference() {
+        let server_thread = thread::spawn(move || {
+            let server = tiny_http::Server::http("0.0.0.0:28496").unwrap();
+
+            for rq in server.incoming_requests().take(1) {
+                let file = File::open("./test/html/basic.html").unwrap();
+                rq.respond(Response::from_file(file)).unwrap();
+            }
+        });
+
+        thread::sleep(time::Duration::from_millis(100));
+        let url = "http://0.0.0.0:28496/test";
+
+        let mut session = Session::new();
+        let resp = session.request(url).unwrap();
+        assert_eq!(resp.version(), reqwest::Version::HTTP_11);
+        server_thread.join().unwrap();
+
+        // the test server doesn't support HTTP/2
+        let server_thread = thread::spawn(move || {
+            let server = tiny_http::Server::http("0.0.0.0:28497").unwrap();
+            server.recv_timeout(time::Duration::from_secs(2)).ok();
+        });
+
+        thread::sleep(time::Duration::from_millis(100));
+        let mut session = Session::new();
+        session.params.http_version = HttpVersion::Http2;
+        session.params.timeout_s = 1;
+        assert!(session.request("http://0.0.0.0:28497/test").is_err());
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn detect_redirect_loops() {
+        let server_thread = thread::spawn(move || {
+            let server = tiny_http::Server::http("0.0.0.0:28527").unwrap();
+
+            // redirect from a to b, and back, setting a cookie on the first
+            // request, so that the second request to a differs
+            for (i, rq) in server.incoming_requests().take(5).enumerate() {
+                let next = match rq.url() {
+                    "/a" => "http://0.0.0.0:28527/b#x",
+                    _ => "http://0.0.0.0:28527/a",
+                };
+                let mut resp = Response::from_data(vec!())
+                    .with_status_code(302)
+                    .with_header(Header::from_bytes("Location", next).unwrap());
+                if i == 0 {
+                    resp.add_header(Header::from_bytes("Set-Cookie", "c=1").unwrap());
+                }
+                rq.respond(resp).unwrap();
+            }
+        });
+
+        thread::sleep(time::Duration::from_millis(100));
+        let mut session = Session::new();
+        let err = session.request("http://0.0.0.0:28527/a").unwrap_err();
+        assert_eq!(err.to_string(), "Redirect loop detected at <http://0.0.0.0:28527/b#x>");
+        assert_eq!(session.urls, vec![
+            "http://0.0.0.0:28527/a",
+            "http://0.0.0.0:28527/b#x",
+            "http://0.0.0.0:28527/a",
+        ]);
+
+        // without cookies, the loop is detected at the first repeated request
+        let mut session = Session::new();
+        session.params.use_cookies = false;
+        let err = session.request("http://0.0.0.0:28527/a").unwrap_err();
+        assert_eq!(err.to_string(), "Redirect loop detected at <http://0.0.0.0:28527/a>");
+        assert_eq!(session.urls.len(), 2);
+
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn limit_total_resolution_time() {
+        let server_thread = thread::spawn(move || {
+            let server = tiny_http::Server::http("0.0.0.0:28509").unwrap();
+
+            // redirect slowly, until the client gives up
+            while let Ok(Some(rq)) = server.recv_timeout(time::Duration::from_secs(2)) {
+                thread::sleep(time::Duration::from_millis(400));
+                let hop: usize = rq.url()[1..].parse().unwrap();
+                let next = format!("http://0.0.0.0:28509/{}", hop + 1);
+                let resp = Response::from_data(vec!())
+                    .with_status_code(302)
+                    .with_header(Header::from_bytes("Location", next).unwrap());
+                let _ = rq.respond(resp);
+            }
+        });
+
+        thread::sleep(time::Duration::from_millis(100));
+        let mut session = Session::new();
+        session.params.total_timeout_s = 1;
+        let start = Instant::now();
+        let err = session.request("http://0.0.0.0:28509/0").err().unwrap();
+        let elapsed = start.elapsed();
+
+        assert_eq!(err.to_string(), "Resolution timed out, max 1s");
+        assert!(elapsed >= time::Duration::from_secs(1));
+        assert!(elapsed < time::Duration::from_millis(1500));
+        assert!(session.request_count < 5);
+
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn refuse_downgrades() {
+        let mut session = Session::new();
+        session.url = "https://z.zzz/".to_string();
+
+        assert!(session.check_redirection("http://z.zzz/").is_ok());
+        session.params.block_downgrades = true;
+        let err = session.check_redirection("http://z.zzz/a").err().unwrap();
+        assert_eq!(err.to_string(), "Refusing redirection from https to http <http://z.zzz/a>");
+        assert!(session.check_redirection("https://z.zzz/").is_ok());
+
+        session.url = "http://z.zzz/".to_string();
+        assert!(session.check_redirection("http://z.zzz/").is_ok());
+        assert!(session.check_scheme().is_ok());
+
+        session.params.https_only = true;
+        assert!(session.check_scheme().is_err());
+        session.url = "https://z.zzz/".to_string();
+        assert!(session.check_scheme().is_ok());
+    }
+
+    #[test]
+    fn refuse_plain_http() {
+        let server_thread = thread::spawn(move || {
+            let server = tiny_http::Server::http("0.0.0.0:28510").unwrap();
+            let rq = server.recv().unwrap();
+            let location = Header::from_bytes("Location",
+                "http://0.0.0.0:28510/next").unwrap();
+            rq.respond(Response::empty(302).with_header(location)).unwrap();
+        });
+
+        thread::sleep(time::Duration::from_millis(100));
+        let mut rtd = Rtd::default();
+        rtd.conf.features.https_only = true;
+        let mut session = Session::new();
+        session.params = RequestParams::from(&rtd);
+
+        // refused without making a request
+        let err = session.request("http://0.0.0.0:28510/").err().unwrap();
+        assert_eq!(err.to_string(),
+            "Refusing to request <http://0.0.0.0:28510/>, only https is allowed");
+        assert_eq!(session.request_count, 0);
+
+        // a redirection from http isn't a downgrade
+        rtd.conf.features.https_only = false;
+        rtd.conf.features.block_downgrades = true;
+        let mut session = Session::new();
+        session.params = RequestParams::from(&rtd);
+        session.params.redirect_limit = 0;
+        let err = session.request("http://0.0.0.0:28510/").err().unwrap();
+        assert_eq!(err.to_string(), "Too many redirects, max 0");
+
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn request_from_local_address() {
+        let (tx, rx) = mpsc::channel();
+        let server_thread = thread::spawn(move || {
+            let server = tiny_http::Server::http("0.0.0.0:28503").unwrap();
+            let rq = server.recv().unwrap();
+            tx.send(rq.remote_addr().ip()).unwrap();
+            let file = File::open("./test/html/basic.html").unwrap();
+            rq.respond(Response::from_file(file)).unwrap();
+        });
+
+        thread::sleep(time::Duration::from_millis(100));
+        let mut rtd = Rtd::default();
+        rtd.conf.params.local_address = "127.0.0.2".to_string();
+
+        let mut session = Session::new();
+        session.params = RequestParams::from(&rtd);
+        session.request("http://127.0.0.1:28503/test").unwrap();
+        server_thread.join().unwrap();
+        assert_eq!(rx.recv().unwrap(), "127.0.0.2".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn reuse_connections() {
+        use std::io::BufRead;
+        use std::net::TcpListener;
+
+        // a server accepting only one connection, over which both requests
+        // must be made
+        let listener = TcpListener::bind("127.0.0.1:28517").unwrap();
+        let server_thread = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = ::std::io::BufReader::new(stream.try_clone().unwrap());
+            let body = "<title>reused</title>";
+
+            for _ in 0..2 {
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 2 {
+                    line.clear();
+                }
+                write!(stream, "HTTP/1.1 200 OK\r\n\
+                    Content-Type: text/html\r\n\
+                    Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+            }
+        });
+
+        let rtd = Rtd::default();
+        for path in &["a", "b"] {
+            let url = format!("http://127.0.0.1:28517/{}", path);
+            assert_eq!(resolve_url(&url, &rtd, None).unwrap().title, "reused");
+        }
+
+        server_thread.join().unwrap();
+    }
 
-        for t in vec!(
-            ("./test/img/test.png", "image/png 800×400"),
-            ("./test/img/test.jpg", "image/jpeg 400×200"),
-            ("./test/img/test.gif", "image/gif 1920×1080"),
-            ("./test/html/basic.html", "basic"),
-            ("./test/other/test.txt", "text/plain; charset=utf8 16B"),
-            ("./test/other/test.pdf", "application/pdf 1.31KB"),
-        ) {
-            assert_eq!(
-                serve_resolve(PathBuf::from(t.0), &rtd).unwrap(),
-                String::from(t.1)
-            )
+    #[test]
+    fn client_with_ca_cert() {
+        let mut session = Session::new();
+        session.params.ca_cert = Some(PathBuf::from("./test/tls/ca.pem"));
+        assert!(session.client().is_ok());
+
+        session.params.danger_accept_invalid_certs = true;
+        assert!(session.client().is_ok());
+
+        session.params.ca_cert = Some(PathBuf::from("./test/html/basic.html"));
+        assert!(session.client().is_err());
+
+        session.params.ca_cert = Some(PathBuf::from("./test/tls/missing.pem"));
+        assert!(session.client().is_err());
+    }
+
+    #[test]
+    fn send_configured_accept_header() {
+        let server_thread = thread::spawn(move || {
+            let server = tiny_http::Server::http("0.0.0.0:28522").unwrap();
+            let rq = server.recv().unwrap();
+            let accept: Vec<String> = rq.headers()
+                .iter()
+                .filter(|h| h.field.equiv("Accept"))
+                .map(|h| h.value.to_string())
+                .collect();
+
+            let file = File::open("./test/html/basic.html").unwrap();
+            rq.respond(Response::from_file(file)).unwrap();
+            accept
+        });
+
+        thread::sleep(time::Duration::from_millis(100));
+        let mut rtd = Rtd::default();
+        rtd.conf.params.accept = "text/html".to_string();
+        resolve_url("http://127.0.0.1:28522/", &rtd, None).unwrap();
+
+        assert_eq!(server_thread.join().unwrap(), vec!["text/html"]);
+    }
+
+    #[test]
+    fn negotiate_encoding() {
+        let server_thread = thread::spawn(move || {
+            let server = tiny_http::Server::http("0.0.0.0:28529").unwrap();
+
+            server.incoming_requests().take(2).map(|rq| {
+                let encoding = rq.headers()
+                    .iter()
+                    .find(|h| h.field.equiv("Accept-Encoding"))
+                    .map(|h| h.value.to_string());
+
+                let file = File::open("./test/html/basic.html").unwrap();
+                rq.respond(Response::from_file(file)).unwrap();
+                encoding
+            }).collect::<Vec<_>>()
+        });
+
+        thread::sleep(time::Duration::from_millis(100));
+        let mut rtd = Rtd::default();
+        let url = "http://127.0.0.1:28529/";
+        assert_eq!(resolve_url(url, &rtd, None).unwrap().title, "basic");
+
+        rtd.conf.params.force_identity_encoding = false;
+        assert_eq!(resolve_url(url, &rtd, None).unwrap().title, "basic");
+
+        let encodings = server_thread.join().unwrap();
+        assert_eq!(encodings[0].as_ref().unwrap(), "identity");
+        assert_ne!(encodings[1].as_ref().map(String::as_str), Some("identity"));
+    }
+
+    #[test]
+    fn request_params_from_config() {
+        let mut rtd = Rtd::default();
+        assert_eq!(RequestParams::from(&rtd).ca_cert, None);
+
+        rtd.conf.params.ca_cert = "/etc/ca.pem".to_string();
+        rtd.conf.params.accept_lang = "de".to_string();
+        let params = RequestParams::from(&rtd);
+        assert_eq!(params.ca_cert, Some(PathBuf::from("/etc/ca.pem")));
+        assert_eq!(params.accept_lang, "de");
+        assert_eq!(params.user_agent, RequestParams::default().user_agent);
+    }
+
+    #[test]
+    fn persist_cookies_between_sessions() {
+        let (tx, rx) = mpsc::channel();
+        let server_thread = thread::spawn(move || {
+            let server = tiny_http::Server::http("0.0.0.0:28487").unwrap();
+            let path = Path::new("./test/html/basic.html");
+
+            for cookies in &[
+                vec!("keep=1; Max-Age=3600", "session=2", "gone=3; Max-Age=0"),
+                vec!(),
+            ] {
+                let rq = server.recv().unwrap();
+                let sent = rq.headers().iter()
+                    .find(|h| h.field.equiv("Cookie"))
+                    .map(|h| h.value.to_string())
+                    .unwrap_or_default();
+                tx.send(sent).unwrap();
+
+                let mut resp = Response::from_file(File::open(path).unwrap());
+                for c in cookies {
+                    resp.add_header(Header::from_bytes("Set-Cookie", *c).unwrap());
+                }
+                rq.respond(resp).unwrap();
+            }
+        });
+
+        thread::sleep(time::Duration::from_millis(100));
+        let mut rtd = Rtd::default();
+        rtd.conf.features.persist_cookies = true;
+        let db = Database::open_in_memory().unwrap();
+        let url = "http://0.0.0.0:28487/test";
+
+        resolve_url(url, &rtd, Some(&db)).unwrap();
+        assert_eq!(rx.recv().unwrap(), "");
+        assert_eq!(db.get_cookies("0.0.0.0").unwrap().len(), 1);
+
+        resolve_url(url, &rtd, Some(&db)).unwrap();
+        assert_eq!(rx.recv().unwrap(), "keep=1");
+        server_thread.join().unwrap();
+
+        assert!(db.get_cookies("other.host").unwrap().is_empty());
+    }
+
+    #[test]
+    fn connect_timeout_settings() {
+        let mut session = Session::new();
+        session.params.timeout_s = 5;
+        session.params.connect_timeout_s = 1;
+        let settings = session.client_settings();
+        assert_eq!(settings.timeout, time::Duration::from_secs(5));
+        assert_eq!(settings.connect_timeout, time::Duration::from_secs(1));
+
+        // connecting is limited to the timeout of the whole request
+        session.params.connect_timeout_s = 10;
+        let settings = session.client_settings();
+        assert_eq!(settings.connect_timeout, time::Duration::from_secs(5));
+    }
+
+    #[test]
+    #[ignore] // needs a network on which 10.255.255.1 is unreachable
+    fn connect_timeout() {
+        // an unroutable address, connecting to which times out
+        let mut session = Session::new();
+        session.params.connect_timeout_s = 1;
+        let start = Instant::now();
+        let err = session.request("http://10.255.255.1/").unwrap_err();
+        assert_eq!(err.downcast::<ResolveError>().unwrap(), ResolveError::ConnectTimeout(1));
+        assert!(start.elapsed() < time::Duration::from_secs(5));
+    }
+
+    #[test]
+    fn retry_with_trailing_slash() {
+        let server_thread = thread::spawn(move || {
+            let server = tiny_http::Server::http("0.0.0.0:28533").unwrap();
+
+            while let Ok(Some(rq)) = server.recv_timeout(time::Duration::from_secs(2)) {
+                let resp = match rq.url() {
+                    url if url.starts_with("/foo/") => Response::from_string("foo").boxed(),
+                    _ => Response::from_data(vec!()).with_status_code(404).boxed(),
+                };
+                rq.respond(resp).unwrap();
+            }
+        });
+
+        thread::sleep(time::Duration::from_millis(100));
+        let not_found = |session: &mut Session, url| {
+            let err = session.request(url).err().unwrap();
+            err.downcast::<ResolveError>().unwrap() == ResolveError::HttpStatus(StatusCode::NOT_FOUND)
+        };
+        assert!(not_found(&mut Session::new(), "http://0.0.0.0:28533/foo"));
+
+        let mut session = Session::new();
+        session.params.retry_trailing_slash = true;
+        let resp = session.request("http://0.0.0.0:28533/foo?a=b").unwrap();
+        assert_eq!(resp.url().as_str(), "http://0.0.0.0:28533/foo/?a=b");
+        assert_eq!(session.urls, vec![
+            "http://0.0.0.0:28533/foo?a=b", "http://0.0.0.0:28533/foo/?a=b",
+        ]);
+
+        // retried only once, and not for paths with extensions
+        for url in &["http://0.0.0.0:28533/bar", "http://0.0.0.0:28533/foo.html"] {
+            let mut session = Session::new();
+            session.params.retry_trailing_slash = true;
+            assert!(not_found(&mut session, url));
+            assert_eq!(session.urls.len(), if url.ends_with("bar") { 2 } else { 1 });
         }
+
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn reuse_titles_of_unmodified_pages() {
+        let (tx, rx) = mpsc::channel();
+        let server_thread = thread::spawn(move || {
+            let server = tiny_http::Server::http("0.0.0.0:28532").unwrap();
+            let path = Path::new("./test/html/basic.html");
+
+            for rq in server.incoming_requests().take(4) {
+                let validators = {
+                    let header = |name: &'static str| rq.headers().iter()
+                        .find(|h| h.field.equiv(name))
+                        .map(|h| h.value.to_string());
+                    (header("If-None-Match"), header("If-Modified-Since"))
+                };
+                tx.send(validators.clone()).unwrap();
+
+                let resp = match rq.url() {
+                    "/start" => Response::from_data(vec!())
+                        .with_status_code(302)
+                        .with_header(Header::from_bytes("Location",
+                            "http://0.0.0.0:28532/page").unwrap())
+                        .boxed(),
+                    _ if validators.0.is_some() => Response::from_data(vec!())
+                        .with_status_code(304)
+                        .boxed(),
+                    _ => Response::from_file(File::open(path).unwrap())
+                        .with_header(Header::from_bytes("ETag", "\"v1\"").unwrap())
+                        .with_header(Header::from_bytes("Last-Modified",
+                            "Sat, 01 Jun 2019 00:00:00 GMT").unwrap())
+                        .boxed(),
+                };
+                rq.respond(resp).unwrap();
+            }
+        });
+
+        thread::sleep(time::Duration::from_millis(100));
+        let mut rtd = Rtd::default();
+        rtd.conf.features.conditional_requests = true;
+        let db = Database::open_in_memory().unwrap();
+        let url = "http://0.0.0.0:28532/start";
+
+        let resolved = resolve_url(url, &rtd, Some(&db)).unwrap();
+        assert_eq!(resolved.title, "basic");
+        assert_eq!(rx.recv().unwrap(), (None, None));
+        assert_eq!(rx.recv().unwrap(), (None, None));
+
+        let validator = db.get_validator(url).unwrap().unwrap();
+        assert_eq!(validator.page_url, "http://0.0.0.0:28532/page");
+        assert_eq!(validator.etag.unwrap(), "\"v1\"");
+
+        // the page isn't modified, so the stored title is reused
+        let resolved = resolve_url(url, &rtd, Some(&db)).unwrap();
+        assert_eq!(resolved.title, "basic");
+        assert_eq!(resolved.redirects, vec![url, "http://0.0.0.0:28532/page"]);
+        assert_eq!(rx.recv().unwrap(), (None, None));
+        assert_eq!(rx.recv().unwrap(), (
+            Some("\"v1\"".to_string()),
+            Some("Sat, 01 Jun 2019 00:00:00 GMT".to_string()),
+        ));
+        server_thread.join().unwrap();
+
+        // a 304 response is an error without stored validators
+        assert!(not_modified(&Session::new()).is_err());
     }
 
     fn get_ctype(path: &Path) -> &'static str {
@@ -327,9 +2831,15 @@ mod tests {
     }
 
     // Spin up a local http server, and resolve the url served
+    static SERVE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
     fn serve_resolve(path: PathBuf, rtd: &Rtd) -> Result<String, Error> {
+        // a server is started on a new port for each file, since a connection
+        // to a previous server, whose requests go unanswered, may be reused
+        let port = 28600 + SERVE_COUNT.fetch_add(1, Ordering::SeqCst);
+        let addr = format!("0.0.0.0:{}", port);
         let server_thread = thread::spawn(move || {
-            let server = tiny_http::Server::http("0.0.0.0:28482").unwrap();
+            let server = tiny_http::Server::http(addr).unwrap();
             loop {
                 let rq = server.recv().unwrap();
                 if rq.url() == "/test" {
@@ -348,9 +2858,39 @@ mod tests {
 
         thread::sleep(time::Duration::from_millis(100));
         let db = Database::open_in_memory().unwrap();
-        let res = resolve_url("http://0.0.0.0:28482/test", &rtd, &db);
+        let res = resolve_url(&format!("http://0.0.0.0:{}/test", port), &rtd, Some(&db));
+        server_thread.join().unwrap();
+        res.map(|r| r.title)
+    }
+
+    #[test]
+    fn resolve_timing_includes_redirections() {
+        let server_thread = thread::spawn(move || {
+            let server = tiny_http::Server::http("0.0.0.0:28484").unwrap();
+            for _ in 0..2 {
+                let rq = server.recv().unwrap();
+                if rq.url() == "/redirect" {
+                    thread::sleep(time::Duration::from_millis(250));
+                    let location = Header::from_bytes("Location",
+                        "http://0.0.0.0:28484/test").unwrap();
+                    let resp = Response::empty(302).with_header(location);
+                    rq.respond(resp).unwrap();
+                } else {
+                    let path = Path::new("./test/html/basic.html");
+                    let resp = Response::from_file(File::open(path).unwrap());
+                    rq.respond(resp).unwrap();
+                }
+            }
+        });
+
+        thread::sleep(time::Duration::from_millis(100));
+        let db = Database::open_in_memory().unwrap();
+        let url = "http://0.0.0.0:28484/redirect";
+        let resolved = resolve_url(url, &Rtd::default(), Some(&db)).unwrap();
         server_thread.join().unwrap();
-        res
+
+        assert_eq!(resolved.title, "basic");
+        assert!(resolved.elapsed >= time::Duration::from_millis(250));
     }
 
     // Spin up a local http server, extract and verify request headers in the
@@ -365,7 +2905,7 @@ mod tests {
             Header::from_bytes("user-agent",
                 format!("Mozilla/5.0 url-bot-rs/{}", buildinfo::PKG_VERSION)
             ).unwrap(),
-            Header::from_bytes("accept", "*/*").unwrap(),
+            Header::from_bytes("accept", ACCEPT).unwrap(),
             Header::from_bytes("cookie", "").unwrap(),
             Header::from_bytes("accept-language", "en").unwrap(),
             Header::from_bytes("accept-encoding", "identity").unwrap(),
@@ -391,7 +2931,7 @@ mod tests {
 
         thread::sleep(time::Duration::from_millis(100));
         let db = Database::open_in_memory().unwrap();
-        resolve_url("http://0.0.0.0:28282/test", &Rtd::default(), &db).unwrap();
+        resolve_url("http://0.0.0.0:28282/test", &Rtd::default(), Some(&db)).unwrap();
         let request_headers = rx.recv().unwrap();
 
         println!("Headers in request:\n{:?}", request_headers);
@@ -1,6 +1,15 @@
+//! URL munching IRC bot, and the library it is built from.
+//!
+//! The core of the bot may be used by other projects: `resolve_url` retrieves
+//! the title of a web page, configured by an `Rtd`, optionally recording it in
+//! a history `Database`, and `parse_title` finds the title in a HTML document.
+//! `resolve_with` does the same with responses from any `HttpFetcher`, e.g.
+//! canned responses, for testing or benchmarking.
+//! The `url-bot-rs` and `url-bot-get` binaries are built on the same API.
 extern crate irc;
 extern crate rusqlite;
 extern crate docopt;
+extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 extern crate itertools;
@@ -25,6 +34,14 @@ extern crate log;
 extern crate atty;
 extern crate stderrlog;
 extern crate phf;
+extern crate serde_json;
+extern crate url;
+#[macro_use]
+extern crate prometheus;
+extern crate tiny_http;
+extern crate native_tls;
+extern crate hyper;
+extern crate emojis;
 
 pub mod sqlite;
 pub mod http;
@@ -32,10 +49,25 @@ pub mod title;
 pub mod config;
 pub mod message;
 pub mod tld;
+pub mod cookies;
+pub mod metrics;
+pub mod outbox;
+pub mod sites;
+pub mod logging;
+pub mod webhook;
+pub mod gemini;
+pub mod nick;
+pub mod unix;
+pub mod emoji;
 pub mod buildinfo {
    include!(concat!(env!("OUT_DIR"), "/built.rs"));
 }
 
+pub use http::{resolve_url, resolve_with, HttpFetcher, Resolved};
+pub use title::parse_title;
+pub use config::{Conf, Rtd};
+pub use sqlite::Database;
+
 lazy_static! {
     pub static ref VERSION: String = format!(
         "v{}{} (build: {})",
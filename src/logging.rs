@@ -0,0 +1,292 @@
+/*
+ * Logging
+ *
+ * Log records may be rendered either as human-readable text (by stderrlog),
+ * or as JSON objects, one per line, for ingestion into log pipelines.
+ *
+ */
+use std::fmt;
+#[cfg(test)]
+use std::cell::RefCell;
+#[cfg(test)]
+use std::sync::Once;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use log::{self, Log, Level, LevelFilter, Metadata, Record, SetLoggerError};
+use stderrlog::StdErrLog;
+use serde_json;
+use time;
+
+static JSON: AtomicBool = AtomicBool::new(false);
+
+#[cfg(test)]
+thread_local! {
+    /// the format of, and lines logged on, a thread capturing its log
+    static CAPTURED: RefCell<Option<(LogFormat, Vec<String>)>> = RefCell::new(None);
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl Default for LogFormat {
+    fn default() -> Self {
+        LogFormat::Text
+    }
+}
+
+/// set the format used to render log records
+pub fn set_format(format: LogFormat) {
+    JSON.store(format == LogFormat::Json, Ordering::Relaxed);
+}
+
+fn format() -> LogFormat {
+    #[cfg(test)]
+    {
+        if let Some(format) = CAPTURED.with(|c| c.borrow().as_ref().map(|c| c.0)) {
+            return format;
+        }
+    }
+
+    if JSON.load(Ordering::Relaxed) {
+        LogFormat::Json
+    } else {
+        LogFormat::Text
+    }
+}
+
+/// logger wrapping stderrlog, which determines which records are enabled,
+/// and renders records when text output is selected
+struct Logger {
+    text: StdErrLog,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.text.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        match format() {
+            LogFormat::Text => self.text.log(record),
+            LogFormat::Json => {
+                if !self.enabled(record.metadata()) {
+                    return;
+                }
+                let line = LogLine::new(
+                    record.level(),
+                    record.target(),
+                    record.args().to_string(),
+                );
+                write_line(&line.to_json());
+            },
+        }
+    }
+
+    fn flush(&self) {
+        self.text.flush();
+    }
+}
+
+/// install the logger, stderrlog settings still govern filtering
+pub fn init(text: &StdErrLog) -> Result<(), SetLoggerError> {
+    log::set_max_level(LevelFilter::Trace);
+    log::set_boxed_logger(Box::new(Logger { text: text.clone() }))
+}
+
+fn write_line(line: &str) {
+    #[cfg(test)]
+    {
+        let captured = CAPTURED.with(|c| {
+            c.borrow_mut().as_mut().map(|c| c.1.push(line.to_string())).is_some()
+        });
+        if captured {
+            return;
+        }
+    }
+
+    let stderr = io::stderr();
+    let mut handle = stderr.lock();
+    writeln!(handle, "{}", line).ok();
+}
+
+/// logger installed in tests, capturing records logged on threads capturing
+/// their log, and ignoring others
+#[cfg(test)]
+struct CaptureLogger;
+
+#[cfg(test)]
+impl Log for CaptureLogger {
+    fn enabled(&self, _: &Metadata) -> bool {
+        CAPTURED.with(|c| c.borrow().is_some())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let message = record.args().to_string();
+        match format() {
+            LogFormat::Text => write_line(&message),
+            LogFormat::Json => {
+                write_line(&LogLine::new(record.level(), record.target(), message).to_json());
+            },
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Capture the lines logged on the current thread, at any level, while
+/// running a function, rendered in a format, text lines being the messages
+#[cfg(test)]
+pub fn capture<F: FnOnce()>(format: LogFormat, f: F) -> Vec<String> {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+        log::set_max_level(LevelFilter::Trace);
+        log::set_boxed_logger(Box::new(CaptureLogger)).ok();
+    });
+
+    CAPTURED.with(|c| *c.borrow_mut() = Some((format, vec![])));
+    f();
+    CAPTURED.with(|c| c.borrow_mut().take()).map_or_else(Vec::new, |c| c.1)
+}
+
+/// an event relating to the resolution of a URL
+#[derive(Debug, Default, Serialize)]
+pub struct Event<'a> {
+    pub url: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    /// duration in milliseconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl<'a> Event<'a> {
+    pub fn new(url: &'a str) -> Self {
+        Self { url, ..Self::default() }
+    }
+
+    pub fn status(mut self, status: u16) -> Self {
+        self.status = Some(status);
+        self
+    }
+
+    pub fn duration(mut self, duration: Duration) -> Self {
+        let ms = duration.as_secs() * 1000
+            + u64::from(duration.subsec_millis());
+        self.duration = Some(ms);
+        self
+    }
+
+    pub fn error(mut self, error: impl fmt::Display) -> Self {
+        self.error = Some(error.to_string());
+        self
+    }
+
+    /// log the event, in either text or JSON form
+    pub fn log(&self, target: &str, level: Level) {
+        if !log_enabled!(target: target, level) {
+            return;
+        }
+        match format() {
+            LogFormat::Text => log!(target: target, level, "{}", self),
+            LogFormat::Json => write_line(&self.to_json(target, level)),
+        }
+    }
+
+    /// render the event as a JSON log line
+    pub fn to_json(&self, target: &str, level: Level) -> String {
+        let mut line = LogLine::new(level, target, self.to_string());
+        line.event = Some(self);
+        line.to_json()
+    }
+}
+
+impl<'a> fmt::Display for Event<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<{}>", self.url)?;
+        if let Some(s) = self.status { write!(f, " [{}]", s)?; }
+        if let Some(d) = self.duration { write!(f, " {}ms", d)?; }
+        if let Some(ref e) = self.error { write!(f, " {}", e)?; }
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+struct LogLine<'a> {
+    timestamp: String,
+    level: &'a str,
+    target: &'a str,
+    message: String,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    event: Option<&'a Event<'a>>,
+}
+
+impl<'a> LogLine<'a> {
+    fn new(level: Level, target: &'a str, message: String) -> Self {
+        Self {
+            timestamp: time::now_utc().rfc3339().to_string(),
+            level: level_str(level),
+            target,
+            message,
+            event: None,
+        }
+    }
+
+    fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+fn level_str(level: Level) -> &'static str {
+    match level {
+        Level::Error => "error",
+        Level::Warn => "warn",
+        Level::Info => "info",
+        Level::Debug => "debug",
+        Level::Trace => "trace",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    #[test]
+    fn event_renders_as_text() {
+        let event = Event::new("http://z.zzz/")
+            .status(404)
+            .duration(Duration::from_millis(1500))
+            .error("Not Found");
+
+        assert_eq!(event.to_string(), "<http://z.zzz/> [404] 1500ms Not Found");
+        assert_eq!(Event::new("http://z.zzz/").to_string(), "<http://z.zzz/>");
+    }
+
+    #[test]
+    fn event_renders_as_json() {
+        let event = Event::new("http://z.zzz/")
+            .status(200)
+            .duration(Duration::from_millis(12));
+
+        let json = event.to_json("url_bot_rs::http", Level::Info);
+        let v: Value = serde_json::from_str(&json).unwrap();
+
+        assert!(v["timestamp"].is_string());
+        assert_eq!(v["level"], "info");
+        assert_eq!(v["target"], "url_bot_rs::http");
+        assert_eq!(v["url"], "http://z.zzz/");
+        assert_eq!(v["status"], 200);
+        assert_eq!(v["duration"], 12);
+        assert!(v.get("error").is_none());
+    }
+}
@@ -1,32 +1,69 @@
 use irc::client::prelude::*;
+use irc::proto::message::Tag;
 use std::iter;
+use std::fmt;
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
 use unicode_segmentation::UnicodeSegmentation;
 use reqwest::Url;
-use regex::Regex;
+use url::{Host, Position};
+use url::idna;
+use regex::{self, Captures, Regex};
 
-use super::http::resolve_url;
-use super::sqlite::{Database, NewLogEntry};
+use failure::Error;
+
+use super::http::{resolve_url, ResolveError, Resolved};
+use super::sqlite::{ChannelStats, Database, LogEntry, NewLogEntry, PrevPost};
 use super::config::Rtd;
 use super::tld::TLD;
+use super::metrics;
+use super::outbox::Outgoing;
+use super::webhook;
+use super::nick;
+use super::emoji;
+use super::VERSION;
+use serde_json;
+use time::{self, Tm};
 
 pub fn handle_message(client: &IrcClient, message: &Message, rtd: &mut Rtd, db: &Database) {
     trace!("{:?}", message.command);
+    nick::handle(client, message, rtd);
 
     match message.command {
         Command::KICK(ref chan, ref nick, _) => kick(client, rtd, chan, nick),
         Command::INVITE(ref nick, ref chan) => invite(client, rtd, nick, chan),
         Command::PRIVMSG(ref target, ref msg) => {
-            privmsg(client, message, rtd, db, target, msg)
+            // ignore the bot's own messages, and those from other bots
+            let nick = message.source_nickname().unwrap_or_default();
+            if is_from_bot(message, nick, nick::own_nick(client, rtd), rtd) {
+                debug!("ignoring message from bot {}", nick);
+                return;
+            }
+
+            match strip_ctcp(msg) {
+                Some(msg) => if is_enabled(message, rtd, target, msg)
+                    && !stats_command(client, message, rtd, db, target, msg)
+                    && !info_command(client, message, rtd, target, msg)
+                    && !title_command(client, message, rtd, db, target, msg)
+                {
+                    if is_allowed_nick(nick, rtd) {
+                        privmsg(client, message, rtd, db, target, msg)
+                    } else {
+                        debug!("ignoring message from {}, not an allowed nick", nick);
+                    }
+                },
+                None => debug!("ignoring CTCP message"),
+            }
         },
         _ => return,
     };
 }
 
 fn kick(client: &IrcClient, rtd: &mut Rtd, chan: &str, nick: &str) {
-    if !rtd.conf.features.autosave {
+    if !autosave(rtd) {
         return;
     }
-    if nick != client.current_nickname() {
+    if nick != nick::own_nick(client, rtd) {
         return;
     }
 
@@ -45,7 +82,7 @@ fn invite(client: &IrcClient, rtd: &mut Rtd, nick: &str, chan: &str) {
     if !rtd.conf.features.invite {
         return;
     }
-    if nick != client.current_nickname() {
+    if nick != nick::own_nick(client, rtd) {
         return;
     }
 
@@ -58,7 +95,7 @@ fn invite(client: &IrcClient, rtd: &mut Rtd, nick: &str, chan: &str) {
 
     info!("joined {}", chan);
 
-    if !rtd.conf.features.autosave {
+    if !autosave(rtd) {
         return;
     }
 
@@ -71,45 +108,84 @@ fn invite(client: &IrcClient, rtd: &mut Rtd, nick: &str, chan: &str) {
     info!("configuration saved");
 }
 
-fn privmsg(client: &IrcClient, message: &Message, rtd: &Rtd, db: &Database, target: &str, msg: &str) {
+/// whether the configuration is saved on changes to the channels joined, which
+/// it isn't in dry run mode
+fn autosave(rtd: &Rtd) -> bool {
+    rtd.conf.features.autosave && !rtd.conf.features.dry_run
+}
+
+fn privmsg(client: &IrcClient, message: &Message, rtd: &mut Rtd, db: &Database, target: &str, msg: &str) {
     let is_chanmsg = target.starts_with('#');
     let user = message.source_nickname().unwrap();
     let mut num_processed = 0;
+    let url_limit = url_limit(rtd, target);
+    let spoiler_mode = rtd.conf.features.spoiler_mode && is_chanmsg;
+    let combine = rtd.conf.features.combine_replies;
+    let mut replies = vec![];
 
-    // look at each space-separated message token
-    for token in msg.split_whitespace() {
-        // the token must not contain unsafe characters
-        if contains_unsafe_chars(token) {
-            continue;
-        }
+    // reveal a title withheld in spoiler mode
+    if spoiler_mode && msg.trim() == SPOILER_COMMAND {
+        let timeout = Duration::from_secs(rtd.conf.params.spoiler_timeout_s);
+        match take_spoiler(&mut rtd.spoilers, target, timeout, Instant::now()) {
+            Some(msg) => respond(client, message, rtd, target, &msg),
+            None => debug!("no title to reveal in {}", target),
+        };
+        return;
+    }
 
-        // get a full URL for tokens without a scheme
-        let maybe_token = add_scheme_for_tld(token);
-        let token = maybe_token
-            .as_ref()
-            .map_or(token, String::as_str);
+    // join the start of a URL, split from the end of the user's last message
+    let joined = rejoin_split_url(rtd, target, user, msg, Instant::now());
+    let msg = joined.as_ref().map_or(msg, String::as_str);
 
-        // the token must be a valid url
-        let url = match token.parse::<Url>() {
-            Ok(url) => url,
-            _ => continue,
-        };
+    // look at each URL found in the message
+    for found in find_urls(msg, rtd) {
+        let token = found.url.as_str();
 
-        // the scheme must be http or https
-        if !["http", "https"].contains(&url.scheme()) {
+        // limit the number of processed URLs
+        if at_url_limit(num_processed, url_limit) {
+            skip(token, &Skip::UrlLimit(url_limit));
             continue;
         }
 
         info!("RESOLVE <{}>", token);
+        metrics::URLS_PROCESSED.inc();
 
         // try to get the title from the url
-        let title = match resolve_url(token, rtd, db) {
-            Ok(title) => title,
+        let (url, resolved) = match resolve_unless_failing(&found, rtd, db, Instant::now()) {
+            Ok(resolved) => resolved,
             Err(err) => {
                 error!("{:?}", err);
+
+                // report the failure, if enabled
+                if let Some(msg) = error_response(&err, token, rtd) {
+                    if combine {
+                        replies.push(msg);
+                    } else {
+                        respond(client, message, rtd, target, &msg);
+                    }
+                    num_processed += 1;
+                }
+
                 continue
             },
         };
+        let title = resolved.title;
+        let prefix = type_prefix(
+            resolved.mime.as_ref().map(String::as_str),
+            &rtd.conf.features.type_prefixes,
+        );
+
+        // use the canonical URL of the page, if found, for history
+        let token = match resolved.canonical {
+            Some(ref canonical) => {
+                debug!("canonical URL <{}>", canonical);
+                canonical.as_str()
+            },
+            None => url.as_str(),
+        };
+        let normalized = history_url(token, rtd);
+        let token = normalized.as_str();
+        let chain = redirect_chain(&resolved.redirects, rtd);
 
         // create a log entry struct
         let entry = NewLogEntry {
@@ -117,38 +193,48 @@ fn privmsg(client: &IrcClient, message: &Message, rtd: &Rtd, db: &Database, targ
             url: token,
             user,
             channel: target,
+            duration_ms: Some(resolved.elapsed.as_millis() as i64),
+            redirect_chain: chain.as_ref().map(String::as_str),
         };
+        webhook::notify(rtd, &entry);
 
         // check for pre-post
-        let pre_post = if rtd.history {
+        let pre_post = if history_enabled(rtd, target) {
             db.check_prepost(token)
         } else {
             Ok(None)
         };
 
+        let title = display_title(client, &title, &url, target, rtd);
+        let title = expand_template(
+            &rtd.conf.params.title_template,
+            prefix,
+            &title,
+            strip_colors(rtd, target),
+        );
+
         // generate response string
-        let mut msg = match pre_post {
-            Ok(Some(previous_post)) => {
-                let user = if rtd.conf.features.mask_highlights {
-                    create_non_highlighting_name(&previous_post.user)
-                } else {
-                    previous_post.user
-                };
-                format!("⤷ {} → {} {} ({})",
-                    title,
-                    previous_post.time_created,
-                    user,
-                    previous_post.channel
+        let msg = match pre_post {
+            Ok(Some(mut previous_post)) => {
+                metrics::HISTORY_HITS.inc();
+                if rtd.conf.features.mask_highlights {
+                    previous_post.user = create_non_highlighting_name(&previous_post.user);
+                }
+                expand_prepost(
+                    &rtd.conf.params.prepost_template,
+                    &title,
+                    &previous_post,
+                    time::now(),
                 )
             },
             Ok(None) => {
                 // add new log entry to database
-                if rtd.history && is_chanmsg {
+                if history_enabled(rtd, target) && is_chanmsg && !rtd.conf.features.dry_run {
                     if let Err(err) = db.add_log(&entry) {
                         error!("SQL error: {}", err);
                     }
                 }
-                format!("⤷ {}", title)
+                title
             },
             Err(err) => {
                 error!("SQL error: {}", err);
@@ -156,158 +242,2346 @@ fn privmsg(client: &IrcClient, message: &Message, rtd: &Rtd, db: &Database, targ
             },
         };
 
-        // limit response length, see RFC1459
-        msg = utf8_truncate(&msg, 510);
-
-        // log
-        info!("{}", msg);
-
-        // send the IRC response
-        let target = message.response_target().unwrap_or(target);
-        if rtd.conf.features.send_notice && is_chanmsg {
-            client.send_notice(target, &msg).unwrap()
+        // send the IRC response, withhold it in spoiler mode, or hold it to
+        // be combined with the other replies
+        if spoiler_mode {
+            info!("withholding title in {}", target);
+            rtd.spoilers.insert(target.to_string(), Spoiler::new(msg));
+        } else if combine {
+            replies.push(msg);
         } else {
-            client.send_privmsg(target, &msg).unwrap()
+            respond(client, message, rtd, target, &msg);
         }
 
-        // limit the number of processed URLs
         num_processed += 1;
-        if num_processed == rtd.conf.params.url_limit {
-            break;
-        }
     };
+
+    // send the combined replies
+    for msg in combine_replies(&replies, MAX_MSG_LEN) {
+        respond(client, message, rtd, target, &msg);
+    }
+}
+
+/// The max number of URLs processed in a message to a target, as set for the
+/// channel in `params.url_limit_channels`, or by `url_limit`
+fn url_limit(rtd: &Rtd, target: &str) -> u8 {
+    rtd.conf.params.url_limit_channels
+        .iter()
+        .find(|(channel, _)| channel.eq_ignore_ascii_case(target))
+        .map_or(rtd.conf.params.url_limit, |(_, &limit)| limit)
+}
+
+/// whether `url_limit` URLs in a message have been processed, after which any
+/// further URLs are skipped
+fn at_url_limit(num_processed: u8, url_limit: u8) -> bool {
+    url_limit > 0 && num_processed >= url_limit
 }
 
-// regex for unsafe characters, as defined in RFC 1738
-const RE_UNSAFE_CHARS: &str = r#"[{}|\\^~\[\]`<>"]"#;
+/// the form of a title to be displayed
+fn display_title(client: &IrcClient, title: &str, url: &str, target: &str, rtd: &Rtd) -> String {
+    let title = emoji::apply(title, rtd.conf.features.emoji_mode);
 
-/// does the token contain characters not permitted by RFC 1738
-fn contains_unsafe_chars(token: &str) -> bool {
-    lazy_static! {
-        static ref UNSAFE: Regex = Regex::new(RE_UNSAFE_CHARS).unwrap();
+    // limit the displayed length of the title
+    let title = match rtd.conf.params.max_title_len {
+        0 => title,
+        n => truncate_graphemes(&title, n),
+    };
+
+    // note pages which appear to be behind a login or paywall
+    let title = if login_required(&title, rtd) {
+        format!("[login required] {}", title)
+    } else {
+        title
+    };
+
+    // mask the nicks of channel members appearing in the title
+    let title = if rtd.conf.features.mask_title_highlights && target.starts_with('#') {
+        mask_nicks_in(&title, &channel_nicks(client, target, rtd))
+    } else {
+        title
+    };
+
+    // warn of hosts which may be spoofing another
+    match url.parse::<Url>().ok().and_then(|u| mixed_script_host(&u)) {
+        Some(ref host) if rtd.conf.features.flag_mixed_scripts => {
+            format!("{} ⚠ mixed-script host {}", title, host)
+        },
+        _ => title,
     }
-    UNSAFE.is_match(token)
 }
 
-/// create a name that doesn't trigger highlight regexes
-fn create_non_highlighting_name(name: &str) -> String {
-    let mut graphemes = name.graphemes(true);
-    let first = graphemes.next();
+/// check whether a title matches one of `paywall_patterns`
+fn login_required(title: &str, rtd: &Rtd) -> bool {
+    rtd.patterns.paywall.iter().any(|re| re.is_match(title))
+}
 
-    first
-        .into_iter()
-        .chain(iter::once("\u{200C}"))
-        .chain(graphemes)
-        .collect()
+/// resolve a URL found in a message, where https was assumed for a token
+/// without a scheme, fall back to http, returns the URL resolved
+fn resolve_found(found: &FoundUrl, rtd: &Rtd, db: &Database) -> Result<(String, Resolved), Error> {
+    let token = found.url.as_str();
+
+    // errors and cookies aren't stored in dry run mode
+    let db = Some(db).filter(|_| !rtd.conf.features.dry_run);
+    let fallback = if found.assumed_scheme {
+        Some(token.replacen("https://", "http://", 1))
+    } else {
+        None
+    };
+
+    resolve_url(token, rtd, db)
+        .map(|resolved| (token.to_string(), resolved))
+        .or_else(|err| match fallback {
+            Some(url) => {
+                info!("RESOLVE <{}>", url);
+                resolve_url(&url, rtd, db).map(|r| (url, r))
+            },
+            None => Err(err),
+        })
 }
 
-/// truncate to a maximum number of bytes, taking UTF-8 into account
-fn utf8_truncate(s: &str, n: usize) -> String {
-    s.char_indices()
-        .take_while(|(len, c)| len + c.len_utf8() <= n)
-        .map(|(_, c)| c)
-        .collect()
+/// IRC formatting codes, which may be used in `params.title_template`
+const FORMATTING: &[(&str, &str)] = &[
+    ("{bold}", "\u{2}"),
+    ("{italic}", "\u{1D}"),
+    ("{underline}", "\u{1F}"),
+    ("{reverse}", "\u{16}"),
+    ("{reset}", "\u{F}"),
+];
+
+lazy_static! {
+    /// a colour code, `{color:fg}` or `{color:fg,bg}`
+    static ref COLOR: Regex = Regex::new(r"\{color:(\d{1,2})(?:,(\d{1,2}))?\}").unwrap();
+    /// IRC formatting codes in messages, with the colours of colour codes
+    static ref FORMATTING_CODES: Regex = Regex::new(concat!(
+        r"\x03(?:\d{1,2}(?:,\d{1,2})?)?",
+        r"|\x04(?:[[:xdigit:]]{6}(?:,[[:xdigit:]]{6})?)?",
+        r"|[\x02\x0F\x11\x16\x1D\x1E\x1F]",
+    )).unwrap();
+    /// the start of a URL, which may continue in the next message
+    static ref SPLIT_URL: Regex = Regex::new(r"(?i)^https?:/{0,2}\S*$").unwrap();
+    /// placeholders in `params.prepost_template`
+    static ref PREPOST_FIELD: Regex = Regex::new(r"\{(title|time|user|channel|ago)\}").unwrap();
 }
 
-/// if a token has a recognised TLD, but no scheme, add one
-pub fn add_scheme_for_tld(token: &str) -> Option<String> {
-    if token.parse::<Url>().is_err() {
-        let new_token = format!("http://{}", token);
+/// Expand `params.title_template`, replacing `{prefix}` and `{title}`, and
+/// formatting codes, e.g. `{bold}` or `{color:4}`, which are removed instead
+/// if `strip` is set
+fn expand_template(template: &str, prefix: &str, title: &str, strip: bool) -> String {
+    let template = FORMATTING
+        .iter()
+        .fold(template.to_string(), |t, (code, ctrl)| {
+            t.replace(code, if strip { "" } else { ctrl })
+        });
 
-        if let Ok(url) = new_token.parse::<Url>() {
-            if !url.domain()?.contains('.') {
-                return None;
-            }
+    let template = COLOR.replace_all(&template, |caps: &Captures| {
+        if strip {
+            return String::new();
+        }
+        let code = |c: &str| format!("{:02}", c.parse::<u8>().unwrap_or_default());
+        match caps.get(2) {
+            Some(bg) => format!("\u{3}{},{}", code(&caps[1]), code(bg.as_str())),
+            None => format!("\u{3}{}", code(&caps[1])),
+        }
+    });
 
-            // reject email addresses
-            if url.username() != "" {
-                return None;
-            }
+    // the title is substituted last, so that it isn't expanded
+    template
+        .replace("{prefix}", prefix)
+        .replace("{title}", title)
+}
 
-            let tld = url.domain()?
-                .split('.')
-                .last()?;
+/// Expand `params.prepost_template`, for a title posted before, in one pass,
+/// so that placeholders in the values substituted, e.g. a title, are kept
+fn expand_prepost(template: &str, title: &str, post: &PrevPost, now: Tm) -> String {
+    PREPOST_FIELD
+        .replace_all(template, |caps: &Captures| match &caps[1] {
+            "title" => title.to_string(),
+            "time" => post.time_created.clone(),
+            "user" => post.user.clone(),
+            "channel" => post.channel.clone(),
+            _ => time_ago(&post.time_created, now),
+        })
+        .into_owned()
+}
 
-            if TLD.contains(tld) {
-                return Some(new_token);
-            }
+/// the time since a post was made, at a time as stored in the database, in
+/// local time, e.g. `3 days ago`, or the time as stored if it can't be parsed
+fn time_ago(time_created: &str, now: Tm) -> String {
+    // stored times have no offset, so both are compared as if in UTC
+    let local_secs = |tm: Tm| tm.to_timespec().sec + i64::from(tm.tm_utcoff);
+
+    match time::strptime(time_created, "%a %b %e %T %Y") {
+        Ok(then) => relative_time(local_secs(now) - local_secs(then)),
+        Err(_) => time_created.to_string(),
+    }
+}
+
+/// a human readable duration in the past, in the largest whole unit
+fn relative_time(secs: i64) -> String {
+    const UNITS: &[(&str, i64)] = &[
+        ("year", 365 * 24 * 60 * 60),
+        ("month", 30 * 24 * 60 * 60),
+        ("day", 24 * 60 * 60),
+        ("hour", 60 * 60),
+        ("minute", 60),
+    ];
+
+    UNITS.iter()
+        .map(|&(unit, len)| (unit, secs / len))
+        .find(|&(_, n)| n > 0)
+        .map(|(unit, n)| format!("{} {}{} ago", n, unit, if n == 1 { "" } else { "s" }))
+        .unwrap_or_else(|| "just now".to_string())
+}
+
+/// the prefix of replies, where none is configured for the content type
+const DEFAULT_PREFIX: &str = "⤷";
+
+/// the prefix of a reply, for the mime type of the content of a URL, that for
+/// the full mime type is preferred over that for the type alone
+fn type_prefix<'a>(mime: Option<&str>, prefixes: &'a BTreeMap<String, String>) -> &'a str {
+    mime
+        .and_then(|mime| prefixes.get(mime)
+            .or_else(|| mime.split('/').next().and_then(|t| prefixes.get(t))))
+        .map_or(DEFAULT_PREFIX, String::as_str)
+}
+
+/// maximum length of an IRC message, in bytes, see RFC1459
+const MAX_MSG_LEN: usize = 510;
+
+/// join replies into as few lines as possible, each no longer than `max`
+/// bytes, replies which are too long by themselves are left on their own line
+fn combine_replies(replies: &[String], max: usize) -> Vec<String> {
+    let mut lines: Vec<String> = vec![];
+
+    for reply in replies {
+        let fits = lines.last()
+            .map_or(false, |line| line.len() + 1 + reply.len() <= max);
+
+        if fits {
+            let line = lines.last_mut().unwrap();
+            line.push(' ');
+            line.push_str(reply);
+        } else {
+            lines.push(reply.clone());
         }
     }
 
-    None
+    lines
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// Spoiler mode
+///
+/// With `features.spoiler_mode` enabled, the titles of URLs posted in a channel
+/// aren't posted. The title of the last URL posted in each channel is held,
+/// and is revealed in the channel when a user sends the command `!title`,
+/// with no arguments. A held title is discarded once it has been revealed,
+/// when a newer URL is posted in the channel, or once it is older than
+/// `params.spoiler_timeout_s` seconds. Held titles aren't persisted across
+/// restarts. Private messages are unaffected.
+const SPOILER_COMMAND: &str = "!title";
 
-    #[test]
-    fn test_utf8_truncate() {
-        assert_eq!("", utf8_truncate("", 10));
-        assert_eq!("", utf8_truncate("", 1));
-        assert_eq!(" ", utf8_truncate("  ", 1));
-        assert_eq!("\u{2665}", utf8_truncate("\u{2665}", 4));
-        assert_eq!("\u{2665}", utf8_truncate("\u{2665}", 3));
-        assert_eq!("", utf8_truncate("\u{2665}", 2));
-        assert_eq!("\u{0306}\u{0306}", utf8_truncate("\u{0306}\u{0306}", 4));
-        assert_eq!("\u{0306}", utf8_truncate("\u{0306}\u{0306}", 2));
-        assert_eq!("\u{0306}", utf8_truncate("\u{0306}", 2));
-        assert_eq!("", utf8_truncate("\u{0306}", 1));
-        assert_eq!("hello ", utf8_truncate("hello \u{1F603} world!", 9));
+/// a response withheld in spoiler mode
+#[derive(Debug, Clone)]
+pub struct Spoiler {
+    msg: String,
+    time: Instant,
+}
+
+impl Spoiler {
+    fn new(msg: String) -> Self {
+        Self { msg, time: Instant::now() }
     }
+}
 
-    #[test]
-    fn test_create_non_highlighting_name() {
-        assert_eq!("\u{200C}", create_non_highlighting_name(""));
-        assert_eq!("f\u{200C}oo", create_non_highlighting_name("foo"));
-        assert_eq!("b\u{200C}ar", create_non_highlighting_name("bar"));
-        assert_eq!("b\u{200C}az", create_non_highlighting_name("baz"));
+/// take the response withheld for a channel, if it hasn't expired
+fn take_spoiler(spoilers: &mut HashMap<String, Spoiler>, chan: &str,
+    timeout: Duration, now: Instant) -> Option<String>
+{
+    spoilers.remove(chan)
+        .filter(|s| now.duration_since(s.time) <= timeout)
+        .map(|s| s.msg)
+}
+
+/// consecutive failures to resolve a URL
+#[derive(Debug, Clone)]
+pub struct Failures {
+    count: u32,
+    /// time of the last failure, and its error
+    last: Instant,
+    error: String,
+}
+
+/// the last error resolving a URL, skipped after failing repeatedly
+#[derive(Debug, Fail)]
+#[fail(display = "{}", _0)]
+struct CooledDown(String);
+
+/// Resolve a URL found in a message, unless it's failed to resolve
+/// `params.failure_limit` times in a row, the last within
+/// `params.failure_cooldown_secs`, in which case the last error is returned
+fn resolve_unless_failing(found: &FoundUrl, rtd: &mut Rtd, db: &Database, now: Instant)
+    -> Result<(String, Resolved), Error>
+{
+    let cooldown = Duration::from_secs(rtd.conf.params.failure_cooldown_secs);
+    if cooldown.as_secs() == 0 {
+        return resolve_found(found, rtd, db);
     }
 
-    #[test]
-    fn test_contains_unsafe_chars() {
-        for c in &['{', '}', '|', '\\', '^', '~', '[', ']', '`', '<', '>', '"']
+    let url = &found.url;
+    if let Some(failures) = rtd.failures.get(url) {
+        if failures.count >= rtd.conf.params.failure_limit
+            && now.duration_since(failures.last) < cooldown
         {
-            assert!(contains_unsafe_chars(&format!("http://z/{}", c)));
+            skip(url, &Skip::Cooldown(failures.count));
+            return Err(CooledDown(failures.error.clone()).into());
         }
-        assert_eq!(contains_unsafe_chars("http://z.zzz/"), false);
     }
 
-    #[test]
-    fn test_add_scheme_for_tld() {
-        // appears to be a URL, and has a valid TLD
-        assert!(add_scheme_for_tld("docs.rs").is_some());
-        assert!(add_scheme_for_tld("nomnomnom.xyz").is_some());
-        assert!(add_scheme_for_tld("endless.horse").is_some());
-        assert!(add_scheme_for_tld("google.co.uk").is_some());
-        assert!(add_scheme_for_tld("notreal.co.uk/#banana").is_some());
-        assert!(add_scheme_for_tld("notreal.co.uk/?banana=3").is_some());
+    let res = resolve_found(found, rtd, db);
+    match res {
+        Ok(_) => {
+            rtd.failures.remove(url);
+        },
+        Err(ref err) => {
+            // forget failures of other URLs, before the cooldown
+            rtd.failures.retain(|_, f| now.duration_since(f.last) < cooldown);
 
-        // return value is as expected
-        assert_eq!(
-            Some(String::from("http://nomnomnom.xyz")),
-            add_scheme_for_tld("nomnomnom.xyz")
-        );
-        assert_eq!(
-            Some(String::from("http://google.co.uk")),
-            add_scheme_for_tld("google.co.uk")
-        );
+            let failures = rtd.failures.entry(url.to_string()).or_insert(Failures {
+                count: 0, last: now, error: String::new(),
+            });
+            failures.count += 1;
+            failures.last = now;
+            failures.error = err.to_string();
+        },
+    };
 
-        // already a valid URL
-        assert!(add_scheme_for_tld("http://nomnomnom.xyz").is_none());
-        assert!(add_scheme_for_tld("http://endless.horse").is_none());
+    res
+}
 
-        // not a recognised TLD
-        assert!(add_scheme_for_tld("abc.cheese").is_none());
-        assert!(add_scheme_for_tld("abc.limes").is_none());
+/// the start of a URL, ending a message, which may continue in the next
+#[derive(Debug, Clone)]
+pub struct SplitUrl {
+    start: String,
+    time: Instant,
+}
 
-        // recognised TLD, but incomplete as a URL
-        assert!(add_scheme_for_tld("xyz").is_none());
-        assert!(add_scheme_for_tld("uk").is_none());
-        assert!(add_scheme_for_tld("horse").is_none());
+/// If enabled, join the start of a URL ending the user's previous message in
+/// the channel, if sent within the timeout, with the first word of a message,
+/// if the result is a URL with a host, returning the message with the URL
+/// joined, and hold the start of any URL ending the message
+fn rejoin_split_url(rtd: &mut Rtd, target: &str, user: &str, msg: &str, now: Instant)
+    -> Option<String>
+{
+    if !rtd.conf.features.rejoin_split_urls {
+        return None;
+    }
 
-        // don't resolve email addresses
-        assert!(add_scheme_for_tld("test@gmail.com").is_none());
-        assert!(add_scheme_for_tld("word.word@gmail.com").is_none());
+    let key = (target.to_lowercase(), user.to_lowercase());
+    let timeout = Duration::from_millis(rtd.conf.params.split_url_timeout_ms);
+
+    let joined = rtd.split_urls.remove(&key)
+        .filter(|split| now.duration_since(split.time) <= timeout)
+        .and_then(|split| join_split_url(&split.start, msg, rtd));
+    if let Some(ref joined) = joined {
+        debug!("joined URL split across messages: {}", joined);
+    }
+
+    // hold the start of a URL ending the message, which isn't itself a URL
+    let start = {
+        let msg = joined.as_ref().map_or(msg, String::as_str);
+        let last = msg.split_whitespace().last().unwrap_or_default();
+        Some(last)
+            .filter(|last| SPLIT_URL.is_match(last))
+            .filter(|last| check_token(last, rtd, &mut vec![]).is_err())
+            .map(String::from)
+    };
+    if let Some(start) = start {
+        debug!("holding the start of a split URL: {}", start);
+        rtd.split_urls.insert(key, SplitUrl { start, time: now });
+    }
+
+    joined
+}
+
+/// the message, with its first word joined to the start of a URL split from
+/// the previous message, if the result is a URL with a host name
+fn join_split_url(start: &str, msg: &str, rtd: &Rtd) -> Option<String> {
+    // a continuation follows directly from the start of the URL
+    if msg.starts_with(char::is_whitespace) {
+        return None;
+    }
+
+    let rest = msg.split_whitespace().next()?;
+    let joined = format!("{}{}", start, rest);
+
+    let found = check_token(&joined, rtd, &mut vec![]).ok()?;
+    let url = found.url.parse::<Url>().ok()?;
+    if !url.host_str().map_or(false, |h| h.contains('.')) {
+        return None;
+    }
+
+    Some(format!("{}{}", start, msg))
+}
+
+/// send a response to a message
+fn respond(client: &IrcClient, message: &Message, rtd: &Rtd, target: &str, msg: &str) {
+    let is_chanmsg = target.starts_with('#');
+
+    // limit response length
+    let msg = utf8_truncate(msg, MAX_MSG_LEN);
+
+    // log, and only log in dry run mode
+    if rtd.conf.features.dry_run {
+        info!("DRY RUN, not sending to {}: {}", target, msg);
+        return;
+    }
+    info!("{}", msg);
+
+    // send the IRC response, via the outgoing queue if rate limited
+    let out = Outgoing {
+        client: client.clone(),
+        target: message.response_target().unwrap_or(target).to_string(),
+        msg,
+        notice: rtd.conf.features.send_notice && is_chanmsg,
+    };
+
+    match rtd.outbox {
+        Some(ref outbox) => outbox.send(out),
+        None => out.send(),
+    };
+}
+
+/// the response reporting a failure to resolve a URL, if enabled, failures due
+/// to no title being found, or to an ignored content type, aren't reported
+fn error_response(err: &Error, url: &str, rtd: &Rtd) -> Option<String> {
+    if !rtd.conf.features.report_errors {
+        return None;
+    }
+    match err.downcast_ref::<ResolveError>() {
+        Some(ResolveError::NoTitle) | Some(ResolveError::IgnoredType(_)) => return None,
+        _ => (),
+    };
+    if err.downcast_ref::<CooledDown>().is_some() && !rtd.conf.features.report_cooldown_errors {
+        return None;
+    }
+
+    let url = if rtd.conf.features.unicode_hosts {
+        unicode_url(url)
+    } else {
+        url.to_string()
+    };
+
+    let msg = rtd.conf.params.error_template
+        .replace("{url}", &url)
+        .replace("{error}", &err.to_string());
+
+    Some(msg)
+}
+
+/// Whether URLs posted to a target are logged, and checked for preposts, as
+/// set for the channel in `features.history_channels`, or by `history`
+fn history_enabled(rtd: &Rtd, target: &str) -> bool {
+    let channel = rtd.conf.features.history_channels
+        .iter()
+        .find(|(channel, _)| channel.eq_ignore_ascii_case(target))
+        .map(|(_, &enabled)| enabled);
+
+    rtd.history && channel.unwrap_or(rtd.conf.features.history)
+}
+
+/// Whether formatting codes are removed from replies to a target, as set for
+/// the channel in `features.strip_colors_channels`, or by `strip_colors`, and
+/// in queries, also by `strip_colors_queries`
+fn strip_colors(rtd: &Rtd, target: &str) -> bool {
+    let features = &rtd.conf.features;
+    if !target.starts_with('#') {
+        return features.strip_colors || features.strip_colors_queries;
+    }
+
+    features.strip_colors_channels
+        .iter()
+        .find(|(channel, _)| channel.eq_ignore_ascii_case(target))
+        .map_or(features.strip_colors, |(_, &strip)| strip)
+}
+
+/// Whether a message is from the bot itself, or from another bot, identified
+/// by a nick matching `params.bot_nicks`, or by the IRCv3 `bot` message tag
+fn is_from_bot(message: &Message, nick: &str, own_nick: &str, rtd: &Rtd) -> bool {
+    if nick.eq_ignore_ascii_case(own_nick) {
+        return true;
+    }
+
+    let tagged = message.tags.as_ref().map_or(false, |tags| {
+        tags.iter().any(|Tag(k, _)| k == "bot" || k == "draft/bot")
+    });
+
+    let matched = rtd.patterns.bot_nicks.as_ref()
+        .map_or(false, |re| re.is_match(nick));
+
+    tagged || matched
+}
+
+/// Whether URLs in messages from a nick are resolved, those from any nick if
+/// `features.allowed_nicks` is empty
+fn is_allowed_nick(nick: &str, rtd: &Rtd) -> bool {
+    let allowed = &rtd.conf.features.allowed_nicks;
+    allowed.is_empty() || allowed.iter().any(|n| n.eq_ignore_ascii_case(nick))
+}
+
+/// command enabling or disabling resolution in a channel
+const TOGGLE_COMMAND: &str = "!urlbot";
+
+/// Whether a message should be resolved, handling `!urlbot on|off` commands
+/// from admins, which enable or disable resolution in the channel, commands
+/// aren't resolved, nor are messages in channels where the bot is disabled
+fn is_enabled(message: &Message, rtd: &mut Rtd, target: &str, msg: &str) -> bool {
+    let chan = target.to_lowercase();
+
+    let enable = match toggle_command(msg) {
+        Some(enable) => enable,
+        None => return !rtd.disabled.contains(&chan),
+    };
+
+    if !chan.starts_with('#') || !is_admin(message, &rtd.conf.params.admins) {
+        debug!("ignoring unauthorised command from {:?}", message.prefix);
+        return false;
+    }
+
+    if enable {
+        info!("enabled in {}", target);
+        rtd.disabled.remove(&chan);
+    } else {
+        info!("disabled in {}", target);
+        rtd.disabled.insert(chan);
+    }
+
+    false
+}
+
+/// parse `!urlbot on` or `!urlbot off`, returning whether to enable the bot
+fn toggle_command(msg: &str) -> Option<bool> {
+    let words: Vec<&str> = msg.split_whitespace().collect();
+
+    match words[..] {
+        [TOGGLE_COMMAND, "on"] => Some(true),
+        [TOGGLE_COMMAND, "off"] => Some(false),
+        _ => None,
+    }
+}
+
+/// command reporting statistics of the URLs posted in a channel
+const STATS_COMMAND: &str = "!stats";
+
+/// respond to `!stats` in a channel, if commands are enabled, returns whether
+/// the message was the command
+fn stats_command(client: &IrcClient, message: &Message, rtd: &Rtd, db: &Database, target: &str, msg: &str) -> bool {
+    if !rtd.conf.features.commands || msg.trim() != STATS_COMMAND {
+        return false;
+    }
+
+    if !target.starts_with('#') {
+        debug!("ignoring {} outside of a channel", STATS_COMMAND);
+        return true;
+    }
+
+    match db.channel_stats(target) {
+        Ok(stats) => respond(client, message, rtd, target, &stats_response(&stats, target, rtd)),
+        Err(err) => error!("SQL error: {}", err),
+    };
+
+    true
+}
+
+fn stats_response(stats: &ChannelStats, channel: &str, rtd: &Rtd) -> String {
+    let mut msg = format!("{} URLs logged, {} in {}", stats.total, stats.channel, channel);
+
+    if let Some((ref user, count)) = stats.top_poster {
+        let user = if rtd.conf.features.mask_highlights {
+            create_non_highlighting_name(user)
+        } else {
+            user.to_string()
+        };
+        msg.push_str(&format!(", top poster {} ({})", user, count));
+    }
+    if let Some((ref host, count)) = stats.top_host {
+        msg.push_str(&format!(", most linked {} ({})", host, count));
+    }
+
+    msg
+}
+
+/// commands reporting the version of the bot, and how long it's been running
+const VERSION_COMMAND: &str = "!version";
+const UPTIME_COMMAND: &str = "!uptime";
+
+/// respond to `!version` or `!uptime`, if commands are enabled, returns whether
+/// the message was one of the commands
+fn info_command(client: &IrcClient, message: &Message, rtd: &Rtd, target: &str, msg: &str) -> bool {
+    if !rtd.conf.features.commands {
+        return false;
+    }
+
+    let response = match msg.trim() {
+        VERSION_COMMAND => version_response(),
+        UPTIME_COMMAND => {
+            let uptime = rtd.started.map_or(Duration::from_secs(0), |t| t.elapsed());
+            uptime_response(uptime)
+        },
+        _ => return false,
+    };
+    respond(client, message, rtd, target, &response);
+
+    true
+}
+
+/// respond to `!title` in a channel, if commands are enabled, with the title of
+/// the URL logged most recently in the channel, returns whether the message was
+/// the command, which isn't handled here while a title is withheld in spoiler
+/// mode, to be revealed instead
+fn title_command(client: &IrcClient, message: &Message, rtd: &Rtd, db: &Database, target: &str, msg: &str) -> bool {
+    if !rtd.conf.features.commands || msg.trim() != SPOILER_COMMAND {
+        return false;
+    }
+
+    if !target.starts_with('#') {
+        debug!("ignoring {} outside of a channel", SPOILER_COMMAND);
+        return true;
+    }
+
+    let timeout = Duration::from_secs(rtd.conf.params.spoiler_timeout_s);
+    let withheld = rtd.spoilers.get(target).map_or(false, |s| s.time.elapsed() <= timeout);
+    if rtd.conf.features.spoiler_mode && withheld {
+        return false;
+    }
+
+    match db.last_entry(target) {
+        Ok(Some(entry)) => respond(client, message, rtd, target, &title_response(client, &entry, target, rtd)),
+        Ok(None) => debug!("no title logged in {}", target),
+        Err(err) => error!("SQL error: {}", err),
+    };
+
+    true
+}
+
+/// the title of a logged post, as it would be displayed when posted
+fn title_response(client: &IrcClient, entry: &LogEntry, target: &str, rtd: &Rtd) -> String {
+    let title = display_title(client, &entry.title, &entry.url, target, rtd);
+
+    expand_template(
+        &rtd.conf.params.title_template,
+        type_prefix(None, &rtd.conf.features.type_prefixes),
+        &title,
+        strip_colors(rtd, target),
+    )
+}
+
+fn version_response() -> String {
+    format!("url-bot-rs {}", *VERSION)
+}
+
+/// the uptime of the bot, in days, hours, and minutes
+fn uptime_response(uptime: Duration) -> String {
+    let secs = uptime.as_secs();
+    let units = [
+        ("day", secs / (24 * 60 * 60)),
+        ("hour", secs / (60 * 60) % 24),
+        ("minute", secs / 60 % 60),
+    ];
+    let parts: Vec<String> = units.iter()
+        .skip_while(|&&(_, n)| n == 0)
+        .map(|&(unit, n)| format!("{} {}{}", n, unit, if n == 1 { "" } else { "s" }))
+        .collect();
+
+    if parts.is_empty() {
+        format!("up {} seconds", secs)
+    } else {
+        format!("up {}", parts.join(", "))
+    }
+}
+
+/// whether the sender of a message is in `params.admins`, matching either the
+/// nick, or for entries containing `!` or `@`, the hostmask
+fn is_admin(message: &Message, admins: &[String]) -> bool {
+    let prefix = match message.prefix {
+        Some(ref prefix) => prefix,
+        None => return false,
+    };
+    let nick = message.source_nickname().unwrap_or_default();
+
+    admins.iter().any(|admin| {
+        if admin.contains('!') || admin.contains('@') {
+            mask_matches(admin, prefix)
+        } else {
+            admin.eq_ignore_ascii_case(nick)
+        }
+    })
+}
+
+/// match a hostmask against a pattern with `*` and `?` wildcards, ignoring case
+fn mask_matches(pattern: &str, mask: &str) -> bool {
+    let pattern = regex::escape(pattern)
+        .replace(r"\*", ".*")
+        .replace(r"\?", ".");
+
+    Regex::new(&format!("(?i)^{}$", pattern))
+        .map(|re| re.is_match(mask))
+        .unwrap_or(false)
+}
+
+/// Remove CTCP ACTION (`/me`) markers from a message, `None` is returned
+/// for other CTCP messages, which are ignored
+fn strip_ctcp(msg: &str) -> Option<&str> {
+    if !msg.starts_with('\u{1}') {
+        return Some(msg);
+    }
+
+    let msg = msg.trim_start_matches('\u{1}').trim_end_matches('\u{1}');
+    if msg.starts_with("ACTION ") {
+        Some(&msg["ACTION ".len()..])
+    } else if msg == "ACTION" {
+        Some("")
+    } else {
+        None
+    }
+}
+
+/// a URL found in a message
+#[derive(Debug, PartialEq)]
+struct FoundUrl {
+    url: String,
+    /// https was assumed, for a token without a scheme
+    assumed_scheme: bool,
+}
+
+/// the reason a token isn't resolved
+#[derive(Debug, PartialEq)]
+enum Skip {
+    UnsafeChars,
+    NotUrl,
+    Scheme(String),
+    TooLong(usize),
+    Duplicate,
+    /// failed to resolve a number of times in a row, within the cooldown
+    Cooldown(u32),
+    /// `url_limit` URLs in the message were already processed
+    UrlLimit(u8),
+}
+
+impl fmt::Display for Skip {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Skip::UnsafeChars => write!(f, "contains unsafe characters"),
+            Skip::NotUrl => write!(f, "not a URL"),
+            Skip::Scheme(ref s) => write!(f, "scheme {} not allowed", s),
+            Skip::TooLong(n) => write!(f, "too long, {} characters", n),
+            Skip::Duplicate => write!(f, "already found in message"),
+            Skip::Cooldown(n) => write!(f, "failed {} times", n),
+            Skip::UrlLimit(n) => write!(f, "url_limit of {} reached", n),
+        }
+    }
+}
+
+/// find the URLs to be resolved in a message, in order, omitting any
+/// equivalent to a URL found earlier in the message
+fn find_urls(msg: &str, rtd: &Rtd) -> Vec<FoundUrl> {
+    let (found, skipped) = scan_tokens(msg, rtd);
+
+    for (token, reason) in skipped {
+        skip(&token, &reason);
+    }
+
+    found
+}
+
+/// log a token, or URL, being skipped
+fn skip(token: &str, reason: &Skip) {
+    debug!("skipping <{}>: {}", token, reason);
+}
+
+/// the URLs found in a message, and the tokens skipped, with the reason
+fn scan_tokens(msg: &str, rtd: &Rtd) -> (Vec<FoundUrl>, Vec<(String, Skip)>) {
+    let mut found = vec![];
+    let mut skipped = vec![];
+    let mut seen = vec![];
+
+    // look at each space-separated message token
+    for token in msg.split_whitespace() {
+        match check_token(token, rtd, &mut seen) {
+            Ok(url) => found.push(url),
+            Err(reason) => skipped.push((token.to_string(), reason)),
+        }
+    }
+
+    (found, skipped)
+}
+
+/// get the URL to be resolved from a message token, if any, `seen` holds the
+/// URLs found earlier in the message
+fn check_token(token: &str, rtd: &Rtd, seen: &mut Vec<String>) -> Result<FoundUrl, Skip> {
+    // remove any formatting codes, e.g. colours, then surrounding punctuation
+    let unformatted = FORMATTING_CODES.replace_all(token, "");
+    let token = trim_punctuation(&unformatted);
+
+    // refang defanged URLs, e.g. hxxp://example[.]com
+    let refanged = if rtd.conf.features.refang {
+        refang(token)
+    } else {
+        None
+    };
+    let token = refanged
+        .as_ref()
+        .map_or(token, String::as_str);
+
+    // the token must not contain unsafe characters
+    if contains_unsafe_chars(token, &rtd.conf.params.unsafe_chars) {
+        return Err(Skip::UnsafeChars);
+    }
+
+    // get a full URL for tokens without a scheme
+    let maybe_token = if rtd.conf.features.assume_scheme {
+        assume_scheme(token)
+    } else {
+        None
+    };
+    let token = maybe_token
+        .as_ref()
+        .map_or(token, String::as_str);
+
+    // the token must be a valid url
+    let url = token.parse::<Url>().map_err(|_| Skip::NotUrl)?;
+
+    // the scheme must be one of those allowed
+    if !is_allowed_scheme(&url, rtd) {
+        return Err(Skip::Scheme(url.scheme().to_string()));
+    }
+
+    // the URL must not be too long, e.g. a data URI
+    let max_len = rtd.conf.params.max_url_length;
+    let len = token.chars().count();
+    if max_len > 0 && len > max_len {
+        return Err(Skip::TooLong(len));
+    }
+
+    // skip URLs already found in the message
+    let key = dedup_key(&url);
+    if seen.contains(&key) {
+        return Err(Skip::Duplicate);
+    }
+    seen.push(key);
+
+    Ok(FoundUrl {
+        url: token.to_string(),
+        assumed_scheme: maybe_token.is_some(),
+    })
+}
+
+/// patterns used to defang URLs, and their replacements
+const DEFANGS: &[(&str, &str)] = &[
+    ("[://]", "://"),
+    ("[:]", ":"),
+    ("[.]", "."),
+    ("(.)", "."),
+    ("{.}", "."),
+    ("[dot]", "."),
+    ("(dot)", "."),
+    ("[DOT]", "."),
+    ("(DOT)", "."),
+];
+
+/// undo common ways of defanging a URL, e.g. `hxxp://example[.]com` or
+/// `example(dot)com`, returning the refanged token, if it was defanged
+fn refang(token: &str) -> Option<String> {
+    let mut refanged = DEFANGS
+        .iter()
+        .fold(token.to_string(), |t, (from, to)| t.replace(from, to));
+
+    let lower = refanged.to_ascii_lowercase();
+    for (from, to) in &[("hxxps:", "https:"), ("hxxp:", "http:")] {
+        if lower.starts_with(from) {
+            refanged = format!("{}{}", to, &refanged[from.len()..]);
+            break;
+        }
+    }
+
+    if refanged == token {
+        None
+    } else {
+        Some(refanged)
+    }
+}
+
+/// a form of a URL shared by trivially equivalent URLs, parsing already
+/// lower-cases the host and removes any default port, trailing slashes are
+/// removed from the path
+fn dedup_key(url: &Url) -> String {
+    let mut url = url.clone();
+    let path = url.path().trim_end_matches('/').to_string();
+    url.set_path(&path);
+    url.into_string()
+}
+
+/// the form of a URL stored in, and looked up in, the history database,
+/// without any fragment, and as `dedup_key`, if `normalize_urls` is enabled
+fn history_url(token: &str, rtd: &Rtd) -> String {
+    if !rtd.conf.params.normalize_urls {
+        return token.to_string();
+    }
+
+    match token.parse::<Url>() {
+        Ok(mut url) => {
+            url.set_fragment(None);
+            dedup_key(&url)
+        },
+        Err(_) => token.to_string(),
+    }
+}
+
+/// the URLs requested in resolving a URL, as a JSON array, to be logged, if
+/// `features.log_redirect_chain` is enabled
+fn redirect_chain(urls: &[String], rtd: &Rtd) -> Option<String> {
+    if !rtd.conf.features.log_redirect_chain || urls.is_empty() {
+        return None;
+    }
+
+    serde_json::to_string(urls).ok()
+}
+
+/// the URL with an internationalized domain name in its Unicode form, rather
+/// than punycode, e.g. `http://例え.jp/` for `http://xn--r8jz45g.jp/`
+fn unicode_url(token: &str) -> String {
+    let url = match token.parse::<Url>() {
+        Ok(url) => url,
+        Err(_) => return token.to_string(),
+    };
+
+    match url.host() {
+        Some(Host::Domain(domain)) if domain.contains("xn--") => {
+            let (host, res) = idna::domain_to_unicode(domain);
+            if res.is_err() {
+                return token.to_string();
+            }
+            format!("{}{}{}",
+                &url[..Position::BeforeHost],
+                host,
+                &url[Position::AfterHost..]
+            )
+        },
+        _ => token.to_string(),
+    }
+}
+
+/// scripts of letters which are commonly confused with each other
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Script {
+    Latin,
+    Greek,
+    Cyrillic,
+}
+
+fn script(c: char) -> Option<Script> {
+    match c as u32 {
+        0x41..=0x5A | 0x61..=0x7A | 0xC0..=0x24F | 0x1E00..=0x1EFF => Some(Script::Latin),
+        0x370..=0x3FF | 0x1F00..=0x1FFF => Some(Script::Greek),
+        0x400..=0x52F => Some(Script::Cyrillic),
+        _ => None,
+    }
+}
+
+/// The punycode host of a URL, if a label of the host mixes Latin, Greek or
+/// Cyrillic letters, e.g. a Cyrillic `а` in `pаypal.com`, as in homograph
+/// attacks. Mixes of other scripts, e.g. Latin and Han, are common, and
+/// aren't flagged.
+fn mixed_script_host(url: &Url) -> Option<String> {
+    let domain = url.domain()?;
+    let (unicode, _) = idna::domain_to_unicode(domain);
+
+    let mixed = unicode.split('.').any(|label| {
+        let mut scripts = label.chars().filter_map(script);
+        match scripts.next() {
+            Some(first) => scripts.any(|s| s != first),
+            None => false,
+        }
+    });
+
+    if mixed {
+        Some(domain.to_string())
+    } else {
+        None
+    }
+}
+
+/// does the token contain unsafe characters, e.g. those not permitted by
+/// RFC 1738, ignoring the brackets enclosing an IPv6 host
+fn contains_unsafe_chars(token: &str, unsafe_chars: &str) -> bool {
+    strip_ipv6_host(token)
+        .chars()
+        .any(|c| unsafe_chars.contains(c))
+}
+
+/// is the URL's scheme in the configured list of allowed schemes
+fn is_allowed_scheme(url: &Url, rtd: &Rtd) -> bool {
+    rtd.conf.features.allowed_schemes
+        .iter()
+        .any(|s| s.eq_ignore_ascii_case(url.scheme()))
+}
+
+/// remove punctuation surrounding a URL, e.g. in `(http://z.zzz/).`
+///
+/// Closing brackets are only trimmed when they are unbalanced within the
+/// token, so that URLs which legitimately end in a bracket are preserved.
+fn trim_punctuation(token: &str) -> &str {
+    let mut token = token.trim_start_matches(|c| "([{<\"'".contains(c));
+
+    loop {
+        let trimmed = token.trim_end_matches(|c| ".,;:!?\"'".contains(c));
+
+        let opener = match trimmed.chars().last() {
+            Some(')') => '(',
+            Some(']') => '[',
+            Some('}') => '{',
+            Some('>') => '<',
+            _ => return trimmed,
+        };
+
+        let closer = trimmed.chars().last().unwrap();
+        if trimmed.matches(opener).count() >= trimmed.matches(closer).count() {
+            return trimmed;
+        }
+
+        token = &trimmed[..trimmed.len() - closer.len_utf8()];
+    }
+}
+
+/// remove a bracketed IPv6 literal host from a URL
+fn strip_ipv6_host(token: &str) -> String {
+    let is_ipv6 = token.parse::<Url>().ok().map_or(false, |url| {
+        match url.host() {
+            Some(Host::Ipv6(_)) => true,
+            _ => false,
+        }
+    });
+
+    match (is_ipv6, token.find('['), token.find(']')) {
+        (true, Some(s), Some(e)) if s < e => {
+            format!("{}{}", &token[..s], &token[e + 1..])
+        },
+        _ => token.to_string(),
+    }
+}
+
+/// create a name that doesn't trigger highlight regexes
+fn create_non_highlighting_name(name: &str) -> String {
+    let mut graphemes = name.graphemes(true);
+    let first = graphemes.next();
+
+    first
+        .into_iter()
+        .chain(iter::once("\u{200C}"))
+        .chain(graphemes)
+        .collect()
+}
+
+/// nicks present in a channel, along with any configured nicks
+fn channel_nicks(client: &IrcClient, chan: &str, rtd: &Rtd) -> Vec<String> {
+    client.list_users(chan)
+        .unwrap_or_default()
+        .iter()
+        .map(|u| u.get_nickname().to_string())
+        .chain(rtd.conf.params.mask_nicks.iter().cloned())
+        .collect()
+}
+
+/// mask any of the given nicks appearing as whole words in some text
+fn mask_nicks_in(text: &str, nicks: &[String]) -> String {
+    nicks
+        .iter()
+        .filter(|n| !n.is_empty())
+        .fold(text.to_string(), |text, nick| mask_nick_in(&text, nick))
+}
+
+/// mask case-insensitive, whole word, occurrences of a nick in some text
+fn mask_nick_in(text: &str, nick: &str) -> String {
+    let is_word_char = |c: Option<char>| {
+        c.map_or(false, |c| c.is_alphanumeric() || c == '_')
+    };
+
+    let lower = text.to_ascii_lowercase();
+    let mut masked = String::with_capacity(text.len());
+    let mut last = 0;
+
+    for (start, _) in lower.match_indices(&nick.to_ascii_lowercase()) {
+        let end = start + nick.len();
+        if is_word_char(text[..start].chars().next_back())
+            || is_word_char(text[end..].chars().next())
+        {
+            continue;
+        }
+        masked.push_str(&text[last..start]);
+        masked.push_str(&create_non_highlighting_name(&text[start..end]));
+        last = end;
+    }
+
+    masked.push_str(&text[last..]);
+    masked
+}
+
+/// truncate to a maximum number of bytes, taking UTF-8 into account
+fn utf8_truncate(s: &str, n: usize) -> String {
+    s.char_indices()
+        .take_while(|(len, c)| len + c.len_utf8() <= n)
+        .map(|(_, c)| c)
+        .collect()
+}
+
+/// truncate a string to at most n graphemes, ending with an ellipsis if
+/// truncated
+fn truncate_graphemes(s: &str, n: usize) -> String {
+    if s.graphemes(true).count() <= n {
+        return s.to_string();
+    }
+
+    let truncated: String = s.graphemes(true).take(n - 1).collect();
+    format!("{}…", truncated.trim_end())
+}
+
+/// if a token has a recognised TLD, but no scheme, add one
+pub fn add_scheme_for_tld(token: &str) -> Option<String> {
+    if token.parse::<Url>().is_err() {
+        let new_token = format!("http://{}", token);
+
+        if let Ok(url) = new_token.parse::<Url>() {
+            if !url.domain()?.contains('.') {
+                return None;
+            }
+
+            // reject email addresses
+            if url.username() != "" {
+                return None;
+            }
+
+            let tld = url.domain()?
+                .split('.')
+                .last()?;
+
+            if TLD.contains(tld) {
+                return Some(new_token);
+            }
+        }
+    }
+
+    None
+}
+
+// top level domains which are unlikely to be found at the end of a sentence
+const COMMON_TLDS: &[&str] = &[
+    "com", "net", "org", "edu", "gov", "mil", "int", "info", "biz",
+];
+
+/// if a token without a scheme looks like a URL, add https
+///
+/// To avoid treating run-together sentences as URLs, the token must also
+/// start with `www.`, include a path, query or fragment, or have a country
+/// code, or other common, top level domain.
+pub fn assume_scheme(token: &str) -> Option<String> {
+    let url = add_scheme_for_tld(token)?.parse::<Url>().ok()?;
+    let domain = url.domain()?;
+    let tld = domain.split('.').last()?;
+
+    if domain.starts_with("www.")
+        || token.contains('/')
+        || url.query().is_some()
+        || url.fragment().is_some()
+        || tld.len() == 2
+        || COMMON_TLDS.contains(&tld)
+    {
+        Some(format!("https://{}", token))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tiny_http;
+
+    use super::*;
+    use std::fs::File;
+    use std::sync::mpsc;
+    use std::thread;
+    use super::super::config::Patterns;
+    use super::super::outbox::Outbox;
+    use super::super::logging::{self, LogFormat};
+    use buildinfo;
+
+    /// a client with a mock connection, which isn't connected to a server
+    fn mock_client() -> IrcClient {
+        IrcClient::from_config(Config {
+            nickname: Some("url-bot".to_string()),
+            server: Some("irc.test.net".to_string()),
+            use_mock_connection: Some(true),
+            ..Config::default()
+        }).unwrap()
+    }
+
+    fn basic_response() -> tiny_http::Response<File> {
+        tiny_http::Response::from_file(File::open("./test/html/basic.html").unwrap())
+    }
+
+    /// serve `basic.html` on a port, for a number of requests, listening
+    /// before returning
+    fn serve_basic(port: u16, requests: usize) -> thread::JoinHandle<()> {
+        let server = tiny_http::Server::http(("0.0.0.0", port)).unwrap();
+
+        thread::spawn(move || {
+            for rq in server.incoming_requests().take(requests) {
+                rq.respond(basic_response()).unwrap();
+            }
+        })
+    }
+
+    #[test]
+    fn test_utf8_truncate() {
+        assert_eq!("", utf8_truncate("", 10));
+        assert_eq!("", utf8_truncate("", 1));
+        assert_eq!(" ", utf8_truncate("  ", 1));
+        assert_eq!("\u{2665}", utf8_truncate("\u{2665}", 4));
+        assert_eq!("\u{2665}", utf8_truncate("\u{2665}", 3));
+        assert_eq!("", utf8_truncate("\u{2665}", 2));
+        assert_eq!("\u{0306}\u{0306}", utf8_truncate("\u{0306}\u{0306}", 4));
+        assert_eq!("\u{0306}", utf8_truncate("\u{0306}\u{0306}", 2));
+        assert_eq!("\u{0306}", utf8_truncate("\u{0306}", 2));
+        assert_eq!("", utf8_truncate("\u{0306}", 1));
+        assert_eq!("hello ", utf8_truncate("hello \u{1F603} world!", 9));
+    }
+
+    #[test]
+    fn test_rejoin_split_urls() {
+        let mut rtd = Rtd::default();
+        let now = Instant::now();
+        let later = now + Duration::from_millis(500);
+
+        // disabled by default
+        assert_eq!(rejoin_split_url(&mut rtd, "#chan", "user", "see https://exa", now), None);
+        assert_eq!(rejoin_split_url(&mut rtd, "#chan", "user", "mple.com/page", later), None);
+
+        rtd.conf.features.rejoin_split_urls = true;
+        let mut rejoin = |msg, nick, time| rejoin_split_url(&mut rtd, "#chan", nick, msg, time);
+
+        // split after the scheme, or within it
+        assert_eq!(rejoin("see https://", "user", now), None);
+        assert_eq!(rejoin("example.com/page thanks", "User", later).unwrap(),
+            "https://example.com/page thanks");
+        assert_eq!(rejoin("see http:", "user", now), None);
+        assert_eq!(rejoin("//example.com/", "user", later).unwrap(), "http://example.com/");
+
+        // only from the same user, and within the timeout
+        assert_eq!(rejoin("see https://", "user", now), None);
+        assert_eq!(rejoin("example.com/", "other", later), None);
+        assert_eq!(rejoin("example.com/", "user", later).unwrap(), "https://example.com/");
+        assert_eq!(rejoin("example.com/", "user", later), None);
+        assert_eq!(rejoin("see https://", "user", now), None);
+        assert_eq!(rejoin("example.com/", "user", now + Duration::from_secs(3)), None);
+    }
+
+    #[test]
+    fn test_rejoin_split_url_false_positives() {
+        let mut rtd = Rtd::default();
+        rtd.conf.features.rejoin_split_urls = true;
+        let now = Instant::now();
+        let mut rejoin = |msg| rejoin_split_url(&mut rtd, "#chan", "user", msg, now);
+
+        // complete URLs aren't held
+        assert_eq!(rejoin("see https://example.com/"), None);
+        assert_eq!(rejoin("page"), None);
+
+        // nor joined with words which don't continue them
+        assert_eq!(rejoin("URLs start with https://"), None);
+        assert_eq!(rejoin("is the scheme"), None);
+        assert_eq!(rejoin("prefixed with http:"), None);
+        assert_eq!(rejoin(" example.com"), None);
+        assert_eq!(rejoin("the https:"), None);
+        assert_eq!(rejoin("https://example.com/"), None);
+        assert_eq!(rejoin("end https"), None);
+        assert_eq!(rejoin("s://example.com/"), None);
+    }
+
+    #[test]
+    fn test_take_spoiler() {
+        let mut spoilers = HashMap::new();
+        let timeout = Duration::from_secs(600);
+        let now = Instant::now();
+
+        assert_eq!(take_spoiler(&mut spoilers, "#chan", timeout, now), None);
+
+        spoilers.insert("#chan".to_string(), Spoiler::new("⤷ a".to_string()));
+        spoilers.insert("#other".to_string(), Spoiler::new("⤷ b".to_string()));
+        let now = Instant::now();
+
+        // revealed once
+        assert_eq!(
+            take_spoiler(&mut spoilers, "#chan", timeout, now),
+            Some("⤷ a".to_string())
+        );
+        assert_eq!(take_spoiler(&mut spoilers, "#chan", timeout, now), None);
+
+        // expired
+        let later = now + Duration::from_secs(601);
+        assert_eq!(take_spoiler(&mut spoilers, "#other", timeout, later), None);
+        assert!(spoilers.is_empty());
+    }
+
+    #[test]
+    fn test_error_response() {
+        let mut rtd = Rtd::default();
+        let url = "http://z.zzz/";
+        let err = || format_err!("Connection refused");
+
+        // silent
+        assert_eq!(error_response(&err(), url, &rtd), None);
+
+        // reporting
+        rtd.conf.features.report_errors = true;
+        assert_eq!(
+            error_response(&err(), url, &rtd),
+            Some("⤷ failed to retrieve title: Connection refused".to_string())
+        );
+        assert_eq!(error_response(&ResolveError::NoTitle.into(), url, &rtd), None);
+        let ignored = ResolveError::IgnoredType("video/mp4".to_string());
+        assert_eq!(error_response(&ignored.into(), url, &rtd), None);
+
+        rtd.conf.params.error_template = "{url} {error} {url}".to_string();
+        assert_eq!(
+            error_response(&err(), url, &rtd),
+            Some("http://z.zzz/ Connection refused http://z.zzz/".to_string())
+        );
+    }
+
+    #[test]
+    fn test_unicode_hosts() {
+        let mut rtd = Rtd::default();
+
+        // Unicode hosts are requested as punycode
+        let found = find_urls("see http://例え.jp/", &rtd);
+        assert_eq!(found[0].url, "http://例え.jp/");
+        let url = found[0].url.parse::<Url>().unwrap();
+        assert_eq!(url.host_str(), Some("xn--r8jz45g.jp"));
+
+        // and punycode hosts displayed as Unicode
+        assert_eq!(unicode_url(url.as_str()), "http://例え.jp/");
+        assert_eq!(unicode_url("http://xn--r8jz45g.jp/a?b#c"), "http://例え.jp/a?b#c");
+        assert_eq!(unicode_url("http://user@xn--r8jz45g.jp:8080/"),
+            "http://user@例え.jp:8080/");
+        assert_eq!(unicode_url("http://z.zzz/"), "http://z.zzz/");
+        assert_eq!(unicode_url("http://[::1]/"), "http://[::1]/");
+        assert_eq!(unicode_url("not a url"), "not a url");
+
+        rtd.conf.features.report_errors = true;
+        rtd.conf.params.error_template = "{url}".to_string();
+        let err = format_err!("Connection refused");
+        assert_eq!(error_response(&err, "http://xn--r8jz45g.jp/", &rtd).unwrap(),
+            "http://xn--r8jz45g.jp/");
+        rtd.conf.features.unicode_hosts = true;
+        assert_eq!(error_response(&err, "http://xn--r8jz45g.jp/", &rtd).unwrap(),
+            "http://例え.jp/");
+    }
+
+    #[test]
+    fn test_mixed_script_host() {
+        let host = |u: &str| mixed_script_host(&u.parse::<Url>().unwrap());
+
+        // Cyrillic "а" in place of a Latin "a"
+        assert_eq!(host("http://pаypal.com/"), Some("xn--pypal-4ve.com".to_string()));
+        assert_eq!(host("http://xn--pypal-4ve.com/"), Some("xn--pypal-4ve.com".to_string()));
+
+        assert_eq!(host("http://paypal.com/"), None);
+        assert_eq!(host("http://例え.jp/"), None);
+        assert_eq!(host("http://bücher.de/"), None);
+        assert_eq!(host("http://пример.рф/"), None);
+        assert_eq!(host("http://ドメインexample.jp/"), None);
+        assert_eq!(host("http://127.0.0.1/"), None);
+    }
+
+    #[test]
+    fn test_login_required() {
+        let mut rtd = Rtd::default();
+        assert!(!login_required("Log in to continue", &rtd));
+
+        rtd.conf.features.paywall_patterns = vec![
+            "(?i)^log ?in".to_string(),
+            "Subscribe to read".to_string(),
+        ];
+        rtd.patterns = Patterns::new(&rtd.conf).unwrap();
+        assert!(login_required("Log in to continue", &rtd));
+        assert!(login_required("LOGIN | Example", &rtd));
+        assert!(login_required("Subscribe to read | The Paper", &rtd));
+        assert!(!login_required("How to log in to a server", &rtd));
+    }
+
+    #[test]
+    fn test_skipped_tokens() {
+        let rtd = Rtd::default();
+        let (found, skipped) = scan_tokens(
+            "see http://z.zzz/ http://z.zzz http://z.zzz/{} ftp://z.zzz/ :", &rtd);
+
+        assert_eq!(found.len(), 1);
+        assert_eq!(skipped, vec![
+            ("see".to_string(), Skip::NotUrl),
+            ("http://z.zzz".to_string(), Skip::Duplicate),
+            ("http://z.zzz/{}".to_string(), Skip::UnsafeChars),
+            ("ftp://z.zzz/".to_string(), Skip::Scheme("ftp".to_string())),
+            (":".to_string(), Skip::NotUrl),
+        ]);
+
+        let reasons: Vec<String> = skipped.iter().map(|(_, r)| r.to_string()).collect();
+        assert_eq!(reasons, vec![
+            "not a URL",
+            "already found in message",
+            "contains unsafe characters",
+            "scheme ftp not allowed",
+            "not a URL",
+        ]);
+
+        // each is logged, with its reason
+        let msg = "see http://z.zzz/ http://z.zzz http://z.zzz/{} ftp://z.zzz/ :";
+        let logged = logging::capture(LogFormat::Text, || { find_urls(msg, &rtd); });
+        assert_eq!(logged, vec![
+            "skipping <see>: not a URL",
+            "skipping <http://z.zzz>: already found in message",
+            "skipping <http://z.zzz/{}>: contains unsafe characters",
+            "skipping <ftp://z.zzz/>: scheme ftp not allowed",
+            "skipping <:>: not a URL",
+        ]);
+
+        // as are URLs failing repeatedly, and those over the url_limit
+        let client = mock_client();
+        let db = Database::open_in_memory().unwrap();
+        let (outbox, _sent) = Outbox::capture();
+        let mut rtd = Rtd::default();
+        rtd.outbox = Some(outbox);
+        rtd.conf.features.report_errors = true;
+        rtd.conf.features.report_cooldown_errors = true;
+        rtd.conf.params.url_limit = 1;
+        rtd.conf.params.failure_limit = 1;
+        rtd.conf.params.failure_cooldown_secs = 60;
+        for url in &["http://a.zzz/", "http://b.zzz/"] {
+            rtd.failures.insert(url.to_string(), Failures {
+                count: 1, last: Instant::now(), error: "failed".to_string(),
+            });
+        }
+
+        let message: Message = ":user!u@h PRIVMSG #chan :http://a.zzz/ http://b.zzz/"
+            .parse().unwrap();
+        let logged = logging::capture(LogFormat::Text, || {
+            handle_message(&client, &message, &mut rtd, &db);
+        });
+        let skipped: Vec<&String> = logged.iter().filter(|l| l.starts_with("skipping")).collect();
+        assert_eq!(skipped, vec![
+            "skipping <http://a.zzz/>: failed 1 times",
+            "skipping <http://b.zzz/>: url_limit of 1 reached",
+        ]);
+    }
+
+    #[test]
+    fn test_find_urls() {
+        let rtd = Rtd::default();
+        let urls = |msg| find_urls(msg, &rtd)
+            .into_iter()
+            .map(|f| f.url)
+            .collect::<Vec<String>>();
+
+        assert!(urls("").is_empty());
+        assert!(urls("no urls here").is_empty());
+        assert_eq!(
+            urls("http://z.zzz/a http://z.zzz/b"),
+            vec!["http://z.zzz/a", "http://z.zzz/b"]
+        );
+
+        // duplicates within a message are skipped
+        assert_eq!(urls("http://z.zzz/a http://z.zzz/a"), vec!["http://z.zzz/a"]);
+        assert_eq!(
+            urls("http://z.zzz/a (http://Z.ZZZ/a) http://z.zzz:80/a/"),
+            vec!["http://z.zzz/a"]
+        );
+        assert_eq!(urls("http://z.zzz http://z.zzz/"), vec!["http://z.zzz"]);
+
+        // different schemes, ports and queries aren't equivalent
+        assert_eq!(
+            urls("http://z.zzz/ https://z.zzz/ http://z.zzz:8080/ http://z.zzz/?a"),
+            vec!["http://z.zzz/", "https://z.zzz/", "http://z.zzz:8080/", "http://z.zzz/?a"]
+        );
+
+        // assumed schemes
+        let found = find_urls("www.example.com https://www.example.com", &rtd);
+        assert_eq!(found, vec![FoundUrl {
+            url: "https://www.example.com".to_string(),
+            assumed_scheme: true,
+        }]);
+    }
+
+    #[test]
+    fn test_expand_prepost() {
+        let post = PrevPost {
+            user: "{title}".to_string(),
+            time_created: "Tue Oct 13 12:00:00 2026".to_string(),
+            channel: "#chan".to_string(),
+            redirect_chain: None,
+        };
+        let now = time::strptime("Wed Oct 14 13:00:00 2026", "%c").unwrap();
+        let expand = |t| expand_prepost(t, "⤷ {user}", &post, now);
+
+        assert_eq!(expand("{title} → {time} {user} ({channel})"),
+            "⤷ {user} → Tue Oct 13 12:00:00 2026 {title} (#chan)");
+        assert_eq!(expand("{title} (posted {ago} by {user})"),
+            "⤷ {user} (posted 1 day ago by {title})");
+        assert_eq!(expand("{title} {unknown}"), "⤷ {user} {unknown}");
+    }
+
+    #[test]
+    fn test_time_ago() {
+        let now = time::strptime("Wed Oct 14 13:00:00 2026", "%c").unwrap();
+        assert_eq!(time_ago("Wed Oct 14 12:58:30 2026", now), "1 minute ago");
+        assert_eq!(time_ago("Sun Oct  4 13:00:00 2026", now), "10 days ago");
+        assert_eq!(time_ago("Wed Oct 14 13:00:00 2026", now), "just now");
+        assert_eq!(time_ago("invalid", now), "invalid");
+
+        assert_eq!(relative_time(-5), "just now");
+        assert_eq!(relative_time(59), "just now");
+        assert_eq!(relative_time(2 * 60 * 60 + 59), "2 hours ago");
+        assert_eq!(relative_time(45 * 24 * 60 * 60), "1 month ago");
+        assert_eq!(relative_time(800 * 24 * 60 * 60), "2 years ago");
+    }
+
+    #[test]
+    fn test_expand_template() {
+        let expand = |t, strip| expand_template(t, "⤷", "a {bold} title", strip);
+
+        assert_eq!(expand("{prefix} {title}", false), "⤷ a {bold} title");
+        assert_eq!(
+            expand("{prefix} {bold}{title}{reset}", false),
+            "⤷ \u{2}a {bold} title\u{F}"
+        );
+        assert_eq!(
+            expand("{italic}{underline}{reverse}{title}", false),
+            "\u{1D}\u{1F}\u{16}a {bold} title"
+        );
+        assert_eq!(
+            expand("{color:4}{prefix}{reset} {color:12,1}{title}", false),
+            "\u{3}04⤷\u{F} \u{3}12,01a {bold} title"
+        );
+        assert_eq!(expand("{color:x}{title}", false), "{color:x}a {bold} title");
+
+        // formatting codes are removed if stripped
+        assert_eq!(
+            expand("{color:4}{prefix}{reset} {bold}{title}{color:1,2}", true),
+            "⤷ a {bold} title"
+        );
+    }
+
+    #[test]
+    fn test_is_from_bot() {
+        let mut rtd = Rtd::default();
+        let msg: Message = ":url-bot!u@h PRIVMSG #chan :http://z.zzz/".parse().unwrap();
+        let tagged: Message = "@bot :other!u@h PRIVMSG #chan :http://z.zzz/"
+            .parse()
+            .unwrap();
+
+        // the bot's own messages are ignored
+        assert!(is_from_bot(&msg, "url-bot", "url-bot", &rtd));
+        assert!(is_from_bot(&msg, "URL-Bot", "url-bot", &rtd));
+        assert!(!is_from_bot(&msg, "someone", "url-bot", &rtd));
+
+        // as are those from nicks matching the pattern, or tagged as bots
+        assert!(is_from_bot(&tagged, "other", "url-bot", &rtd));
+        rtd.conf.params.bot_nicks = "(?i)bot$".to_string();
+        rtd.patterns = Patterns::new(&rtd.conf).unwrap();
+        assert!(is_from_bot(&msg, "AnotherBot", "url-bot", &rtd));
+        assert!(!is_from_bot(&msg, "botanist", "url-bot", &rtd));
+    }
+
+    #[test]
+    fn test_is_admin() {
+        let msg: Message = ":Op!op@admin.zzz PRIVMSG #chan :!urlbot off".parse().unwrap();
+        let admins = |a: &[&str]| -> Vec<String> {
+            a.iter().map(|s| s.to_string()).collect()
+        };
+
+        assert!(!is_admin(&msg, &admins(&[])));
+        assert!(is_admin(&msg, &admins(&["op"])));
+        assert!(is_admin(&msg, &admins(&["someone", "*!*@admin.zzz"])));
+        assert!(is_admin(&msg, &admins(&["op!op@*"])));
+        assert!(is_admin(&msg, &admins(&["?p!*@ADMIN.zzz"])));
+        assert!(!is_admin(&msg, &admins(&["someone"])));
+        assert!(!is_admin(&msg, &admins(&["*!*@other.zzz"])));
+        assert!(!is_admin(&msg, &admins(&["*!*@admin"])));
+        assert!(!is_admin(&msg, &admins(&["*!*@admin?zzz.zzz"])));
+
+        let server: Message = "PRIVMSG #chan :!urlbot off".parse().unwrap();
+        assert!(!is_admin(&server, &admins(&["*"])));
+    }
+
+    #[test]
+    fn test_toggle_enabled() {
+        let mut rtd = Rtd::default();
+        rtd.conf.params.admins = vec!["op".to_string()];
+        let from = |nick: &str, msg: &str| -> Message {
+            format!(":{}!u@h PRIVMSG #chan :{}", nick, msg).parse().unwrap()
+        };
+        let url = "http://z.zzz/";
+
+        assert!(is_enabled(&from("user", url), &mut rtd, "#chan", url));
+
+        // unauthorised commands are ignored
+        let off = "!urlbot off";
+        assert!(!is_enabled(&from("user", off), &mut rtd, "#chan", off));
+        assert!(is_enabled(&from("user", url), &mut rtd, "#chan", url));
+
+        // disabled only in the channel
+        assert!(!is_enabled(&from("op", off), &mut rtd, "#Chan", off));
+        assert!(!is_enabled(&from("user", url), &mut rtd, "#chan", url));
+        assert!(is_enabled(&from("user", url), &mut rtd, "#other", url));
+
+        let on = " !urlbot  on ";
+        assert!(!is_enabled(&from("user", on), &mut rtd, "#chan", on));
+        assert!(!is_enabled(&from("user", url), &mut rtd, "#chan", url));
+        assert!(!is_enabled(&from("op", on), &mut rtd, "#chan", on));
+        assert!(is_enabled(&from("user", url), &mut rtd, "#chan", url));
+
+        // not in private messages
+        assert!(!is_enabled(&from("op", off), &mut rtd, "url-bot", off));
+        assert!(is_enabled(&from("op", url), &mut rtd, "url-bot", url));
+
+        assert_eq!(toggle_command("!urlbot"), None);
+        assert_eq!(toggle_command("!urlbot off now"), None);
+        assert_eq!(toggle_command("!urlbotoff"), None);
+    }
+
+    #[test]
+    fn test_allowed_nicks() {
+        let server_thread = serve_basic(28523, 3);
+
+        let client = mock_client();
+        let db = Database::open_in_memory().unwrap();
+        let (outbox, sent) = Outbox::capture();
+        let mut rtd = Rtd::default();
+        rtd.outbox = Some(outbox);
+
+        let message = |nick: &str| -> Message {
+            format!(":{}!u@h PRIVMSG #chan :see http://127.0.0.1:28523/", nick)
+                .parse().unwrap()
+        };
+
+        // anyone's URLs are resolved without an allowlist
+        handle_message(&client, &message("someone"), &mut rtd, &db);
+        assert_eq!(sent.try_recv().unwrap().msg, "⤷ basic");
+
+        rtd.conf.features.allowed_nicks = vec!["Trusted".to_string()];
+        handle_message(&client, &message("someone"), &mut rtd, &db);
+        assert!(sent.try_recv().is_err());
+        handle_message(&client, &message("trusted"), &mut rtd, &db);
+        assert_eq!(sent.try_recv().unwrap().msg, "⤷ basic");
+
+        rtd.conf.features.allowed_nicks = vec![];
+        handle_message(&client, &message("someone"), &mut rtd, &db);
+        assert_eq!(sent.try_recv().unwrap().msg, "⤷ basic");
+
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn test_log_redirect_chain() {
+        let server_thread = thread::spawn(move || {
+            let server = tiny_http::Server::http("0.0.0.0:28525").unwrap();
+
+            for _ in 0..6 {
+                let rq = server.recv().unwrap();
+                let location = match rq.url() {
+                    "/a" | "/a?x" => "http://127.0.0.1:28525/b",
+                    "/b" => "http://localhost:28525/c",
+                    _ => "",
+                };
+                let resp = basic_response();
+                if location.is_empty() {
+                    rq.respond(resp).unwrap();
+                } else {
+                    let header = tiny_http::Header::from_bytes("Location", location).unwrap();
+                    rq.respond(resp.with_status_code(302).with_header(header)).unwrap();
+                }
+            }
+        });
+
+        let client = mock_client();
+        let db = Database::open_in_memory().unwrap();
+        let (outbox, _sent) = Outbox::capture();
+        let mut rtd = Rtd::default();
+        rtd.history = true;
+        rtd.conf.features.history = true;
+        rtd.outbox = Some(outbox);
+
+        thread::sleep(Duration::from_millis(100));
+        let post = |url: &str| -> Message {
+            format!(":user!u@h PRIVMSG #chan :{}", url).parse().unwrap()
+        };
+
+        // the chain is only stored if enabled
+        handle_message(&client, &post("http://127.0.0.1:28525/a"), &mut rtd, &db);
+        let prev = db.check_prepost("http://127.0.0.1:28525/a").unwrap().unwrap();
+        assert_eq!(prev.redirect_chain, None);
+
+        rtd.conf.features.log_redirect_chain = true;
+        handle_message(&client, &post("http://127.0.0.1:28525/a?x"), &mut rtd, &db);
+        let prev = db.check_prepost("http://127.0.0.1:28525/a?x").unwrap().unwrap();
+        let chain: Vec<String> = serde_json::from_str(&prev.redirect_chain.unwrap()).unwrap();
+        assert_eq!(chain, vec![
+            "http://127.0.0.1:28525/a?x",
+            "http://127.0.0.1:28525/b",
+            "http://localhost:28525/c",
+        ]);
+
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn test_history_channels() {
+        let server_thread = serve_basic(28526, 3);
+
+        let client = mock_client();
+        let db = Database::open_in_memory().unwrap();
+        let (outbox, sent) = Outbox::capture();
+        let mut rtd = Rtd::default();
+        rtd.history = true;
+        rtd.outbox = Some(outbox);
+        rtd.conf.features.history_channels.insert("#Public".to_string(), true);
+
+        let post = |chan: &str, url: &str| -> Message {
+            format!(":user!u@h PRIVMSG {} :{}", chan, url).parse().unwrap()
+        };
+
+        // URLs are logged only in channels with history enabled
+        handle_message(&client, &post("#private", "http://127.0.0.1:28526/a"), &mut rtd, &db);
+        assert_eq!(sent.try_recv().unwrap().msg, "⤷ basic");
+        assert!(db.check_prepost("http://127.0.0.1:28526/a").unwrap().is_none());
+
+        handle_message(&client, &post("#public", "http://127.0.0.1:28526/b"), &mut rtd, &db);
+        assert_eq!(sent.try_recv().unwrap().msg, "⤷ basic");
+        assert!(db.check_prepost("http://127.0.0.1:28526/b").unwrap().is_some());
+
+        // nor are preposts reported in others
+        handle_message(&client, &post("#private", "http://127.0.0.1:28526/b"), &mut rtd, &db);
+        assert_eq!(sent.try_recv().unwrap().msg, "⤷ basic");
+
+        assert!(!history_enabled(&rtd, "#other"));
+        rtd.conf.features.history = true;
+        assert!(history_enabled(&rtd, "#other"));
+        rtd.conf.features.history_channels.insert("#other".to_string(), false);
+        assert!(!history_enabled(&rtd, "#other"));
+
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn test_strip_colors() {
+        let mut rtd = Rtd::default();
+        assert!(!strip_colors(&rtd, "#chan"));
+        assert!(!strip_colors(&rtd, "url-bot"));
+
+        rtd.conf.features.strip_colors_queries = true;
+        assert!(!strip_colors(&rtd, "#chan"));
+        assert!(strip_colors(&rtd, "url-bot"));
+
+        rtd.conf.features.strip_colors = true;
+        rtd.conf.features.strip_colors_queries = false;
+        rtd.conf.features.strip_colors_channels.insert("#Colorful".to_string(), false);
+        assert!(strip_colors(&rtd, "#chan"));
+        assert!(!strip_colors(&rtd, "#colorful"));
+        assert!(strip_colors(&rtd, "url-bot"));
+    }
+
+    #[test]
+    fn test_url_limit_channels() {
+        let server_thread = serve_basic(28531, 6);
+
+        let client = mock_client();
+        let db = Database::open_in_memory().unwrap();
+        let (outbox, sent) = Outbox::capture();
+        let mut rtd = Rtd::default();
+        rtd.outbox = Some(outbox);
+        rtd.conf.params.url_limit = 2;
+        rtd.conf.params.url_limit_channels.insert("#Tech".to_string(), 3);
+        rtd.conf.params.url_limit_channels.insert("#social".to_string(), 1);
+
+        let msg = "http://127.0.0.1:28531/a http://127.0.0.1:28531/b \
+            http://127.0.0.1:28531/c http://127.0.0.1:28531/d";
+        let mut replies = |chan: &str| {
+            let message = format!(":user!u@h PRIVMSG {} :{}", chan, msg).parse().unwrap();
+            handle_message(&client, &message, &mut rtd, &db);
+            sent.try_iter().count()
+        };
+
+        assert_eq!(replies("#tech"), 3);
+        assert_eq!(replies("#social"), 1);
+        assert_eq!(replies("#other"), 2);
+
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn test_failure_cooldown() {
+        let (tx, rx) = mpsc::channel();
+        let server_thread = thread::spawn(move || {
+            let server = tiny_http::Server::http("0.0.0.0:28535").unwrap();
+
+            while let Ok(Some(rq)) = server.recv_timeout(Duration::from_secs(2)) {
+                tx.send(rq.url().to_string()).unwrap();
+                rq.respond(tiny_http::Response::from_data(vec!()).with_status_code(500)).unwrap();
+            }
+        });
+
+        let client = mock_client();
+        let db = Database::open_in_memory().unwrap();
+        let (outbox, sent) = Outbox::capture();
+        let mut rtd = Rtd::default();
+        rtd.outbox = Some(outbox);
+        rtd.conf.features.report_errors = true;
+        rtd.conf.params.error_template = "{error}".to_string();
+        rtd.conf.params.failure_limit = 2;
+        rtd.conf.params.failure_cooldown_secs = 60;
+
+        thread::sleep(Duration::from_millis(100));
+        let url = "http://127.0.0.1:28535/fail";
+        let msg: Message = format!(":user!u@h PRIVMSG #chan :{}", url).parse().unwrap();
+        let error = "Unhandled request status: 500 Internal Server Error";
+
+        // the URL is requested until it's failed twice
+        for _ in 0..2 {
+            handle_message(&client, &msg, &mut rtd, &db);
+            assert_eq!(sent.try_recv().unwrap().msg, error);
+            assert_eq!(rx.recv().unwrap(), "/fail");
+        }
+
+        // then skipped, reporting the last error only if enabled
+        handle_message(&client, &msg, &mut rtd, &db);
+        assert!(sent.try_recv().is_err());
+        rtd.conf.features.report_cooldown_errors = true;
+        handle_message(&client, &msg, &mut rtd, &db);
+        assert_eq!(sent.try_recv().unwrap().msg, error);
+        assert!(rx.try_recv().is_err());
+
+        // until the cooldown has passed, after which failures are counted again
+        let found = find_urls(url, &rtd).remove(0);
+        let later = rtd.failures[url].last + Duration::from_secs(61);
+        assert!(resolve_unless_failing(&found, &mut rtd, &db, later).is_err());
+        assert_eq!(rx.recv().unwrap(), "/fail");
+        assert_eq!(rtd.failures[url].count, 1);
+
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn test_dry_run() {
+        let server_thread = serve_basic(28514, 2);
+
+        let client = mock_client();
+        let db = Database::open_in_memory().unwrap();
+        let (outbox, sent) = Outbox::capture();
+        let mut rtd = Rtd::default();
+        rtd.history = true;
+        rtd.conf.features.history = true;
+        rtd.outbox = Some(outbox);
+        rtd.conf.features.dry_run = true;
+
+        let url = "http://127.0.0.1:28514/";
+        let message: Message = format!(":user!u@h PRIVMSG #chan :see {}", url)
+            .parse().unwrap();
+
+        // resolved, but neither sent, nor logged
+        handle_message(&client, &message, &mut rtd, &db);
+        assert!(sent.try_recv().is_err());
+        assert!(db.check_prepost(url).unwrap().is_none());
+
+        rtd.conf.features.dry_run = false;
+        handle_message(&client, &message, &mut rtd, &db);
+        assert_eq!(sent.try_recv().unwrap().msg, "⤷ basic");
+        assert!(db.check_prepost(url).unwrap().is_some());
+
+        // both requests were made
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn test_info_responses() {
+        let version = version_response();
+        assert!(version.starts_with(&format!("url-bot-rs v{}", buildinfo::PKG_VERSION)));
+        assert!(version.ends_with(&format!("(build: {})", buildinfo::PROFILE)));
+        assert!(!version.contains('\n'));
+
+        let uptime = |secs| uptime_response(Duration::from_secs(secs));
+        assert_eq!(uptime(12), "up 12 seconds");
+        assert_eq!(uptime(60), "up 1 minute");
+        assert_eq!(uptime(2 * 3600 + 5), "up 2 hours, 0 minutes");
+        assert_eq!(uptime(86400 + 3600 + 120), "up 1 day, 1 hour, 2 minutes");
+    }
+
+    #[test]
+    fn test_info_commands() {
+        let client = mock_client();
+        let db = Database::open_in_memory().unwrap();
+        let (outbox, sent) = Outbox::capture();
+        let mut rtd = Rtd::new();
+        rtd.outbox = Some(outbox);
+        let msg = |text: &str| -> Message {
+            format!(":user!u@h PRIVMSG #chan :{}", text).parse().unwrap()
+        };
+
+        // commands are ignored unless enabled
+        handle_message(&client, &msg("!version"), &mut rtd, &db);
+        assert!(sent.try_recv().is_err());
+
+        rtd.conf.features.commands = true;
+        handle_message(&client, &msg("!version"), &mut rtd, &db);
+        assert_eq!(sent.try_recv().unwrap().msg, version_response());
+        handle_message(&client, &msg(" !uptime "), &mut rtd, &db);
+        assert_eq!(sent.try_recv().unwrap().msg, "up 0 seconds");
+    }
+
+    #[test]
+    fn test_title_command() {
+        let client = mock_client();
+        let db = Database::open_in_memory().unwrap();
+        let (outbox, sent) = Outbox::capture();
+        let mut rtd = Rtd::default();
+        rtd.outbox = Some(outbox);
+        rtd.conf.features.commands = true;
+        let msg = |chan: &str| -> Message {
+            format!(":user!u@h PRIVMSG {} :!title", chan).parse().unwrap()
+        };
+        let post = |title, url, channel| NewLogEntry {
+            title, url, user: "user", channel, duration_ms: None, redirect_chain: None,
+        };
+
+        // nothing logged yet
+        handle_message(&client, &msg("#chan"), &mut rtd, &db);
+        assert!(sent.try_recv().is_err());
+
+        for e in &[
+            post("First title", "https://a.zzz/1", "#chan"),
+            post("Second title", "https://a.zzz/2", "#chan"),
+            post("Other title", "https://b.zzz/", "#other"),
+        ] {
+            db.add_log(e).unwrap();
+        }
+
+        handle_message(&client, &msg("#chan"), &mut rtd, &db);
+        assert_eq!(sent.try_recv().unwrap().msg, "⤷ Second title");
+        handle_message(&client, &msg("#other"), &mut rtd, &db);
+        assert_eq!(sent.try_recv().unwrap().msg, "⤷ Other title");
+        handle_message(&client, &msg("url-bot"), &mut rtd, &db);
+        assert!(sent.try_recv().is_err());
+
+        // titles are truncated, and sent as notices, as when posted
+        rtd.conf.params.max_title_len = 6;
+        rtd.conf.features.send_notice = true;
+        handle_message(&client, &msg("#chan"), &mut rtd, &db);
+        let out = sent.try_recv().unwrap();
+        assert_eq!(out.msg, "⤷ Secon…");
+        assert!(out.notice);
+
+        // a title withheld in spoiler mode is revealed instead
+        rtd.conf.features.spoiler_mode = true;
+        rtd.spoilers.insert("#chan".to_string(), Spoiler::new("⤷ Withheld".to_string()));
+        handle_message(&client, &msg("#chan"), &mut rtd, &db);
+        assert_eq!(sent.try_recv().unwrap().msg, "⤷ Withheld");
+        handle_message(&client, &msg("#chan"), &mut rtd, &db);
+        assert_eq!(sent.try_recv().unwrap().msg, "⤷ Secon…");
+    }
+
+    #[test]
+    fn test_stats_response() {
+        let mut rtd = Rtd::default();
+        let mut stats = ChannelStats { total: 6, channel: 0, ..ChannelStats::default() };
+        assert_eq!(stats_response(&stats, "#chan", &rtd), "6 URLs logged, 0 in #chan");
+
+        stats.channel = 4;
+        stats.top_poster = Some(("bob".to_string(), 2));
+        stats.top_host = Some(("b.zzz".to_string(), 3));
+        assert_eq!(stats_response(&stats, "#chan", &rtd),
+            "6 URLs logged, 4 in #chan, top poster bob (2), most linked b.zzz (3)");
+
+        rtd.conf.features.mask_highlights = true;
+        assert_eq!(stats_response(&stats, "#chan", &rtd),
+            "6 URLs logged, 4 in #chan, top poster b\u{200c}ob (2), most linked b.zzz (3)");
+    }
+
+    #[test]
+    fn test_strip_ctcp() {
+        assert_eq!(strip_ctcp("http://z.zzz/a"), Some("http://z.zzz/a"));
+        assert_eq!(
+            strip_ctcp("\u{1}ACTION likes http://z.zzz/a\u{1}"),
+            Some("likes http://z.zzz/a")
+        );
+        assert_eq!(strip_ctcp("\u{1}ACTION http://z.zzz/a"), Some("http://z.zzz/a"));
+        assert_eq!(strip_ctcp("\u{1}ACTION\u{1}"), Some(""));
+        assert_eq!(strip_ctcp("\u{1}VERSION\u{1}"), None);
+        assert_eq!(strip_ctcp("\u{1}PING http://z.zzz/a\u{1}"), None);
+
+        let rtd = Rtd::default();
+        let msg = strip_ctcp("\u{1}ACTION http://z.zzz/a http://z.zzz/b\u{1}").unwrap();
+        assert_eq!(
+            find_urls(msg, &rtd).into_iter().map(|f| f.url).collect::<Vec<_>>(),
+            vec!["http://z.zzz/a", "http://z.zzz/b"]
+        );
+    }
+
+    #[test]
+    fn test_refang() {
+        assert_eq!(refang("hxxp://x[.]com").unwrap(), "http://x.com");
+        assert_eq!(refang("HXXPS[://]x[.]com/a").unwrap(), "https://x.com/a");
+        assert_eq!(refang("x(dot)com").unwrap(), "x.com");
+        assert_eq!(refang("http[:]//a[DOT]x{.}com/(.)").unwrap(), "http://a.x.com/.");
+        assert_eq!(refang("http://x.com/"), None);
+        assert_eq!(refang("hxxpx://x.com/"), None);
+
+        let mut rtd = Rtd::default();
+        assert!(find_urls("hxxp://x[.]com x(dot)com", &rtd).is_empty());
+
+        rtd.conf.features.refang = true;
+        assert_eq!(find_urls("hxxp://x[.]com x(dot)com", &rtd), vec![
+            FoundUrl { url: "http://x.com".to_string(), assumed_scheme: false },
+            FoundUrl { url: "https://x.com".to_string(), assumed_scheme: true },
+        ]);
+    }
+
+    #[test]
+    fn test_truncate_graphemes() {
+        assert_eq!("", truncate_graphemes("", 1));
+        assert_eq!("hello", truncate_graphemes("hello", 5));
+        assert_eq!("hell…", truncate_graphemes("hello world", 5));
+        assert_eq!("hello…", truncate_graphemes("hello world", 7));
+        assert_eq!("…", truncate_graphemes("hello", 1));
+
+        // emoji, including multi-codepoint sequences
+        assert_eq!("\u{1F603}\u{1F603}", truncate_graphemes("\u{1F603}\u{1F603}", 2));
+        assert_eq!("\u{1F603}…", truncate_graphemes("\u{1F603}\u{1F603}\u{1F603}", 2));
+        let family = "\u{1F468}\u{200D}\u{1F469}\u{200D}\u{1F467}";
+        assert_eq!(
+            format!("{}…", family),
+            truncate_graphemes(&format!("{}{}{}", family, family, family), 2)
+        );
+        let flag = "\u{1F1EC}\u{1F1E7}";
+        assert_eq!(format!("{}…", flag), truncate_graphemes(&flag.repeat(3), 2));
+
+        // combining characters aren't separated from their base
+        assert_eq!("e\u{0301}e\u{0301}", truncate_graphemes("e\u{0301}e\u{0301}", 2));
+        assert_eq!("e\u{0301}…", truncate_graphemes("e\u{0301}e\u{0301}e\u{0301}", 2));
+        assert_eq!("a\u{0306}\u{0306}…", truncate_graphemes("a\u{0306}\u{0306}bc", 2));
+    }
+
+    #[test]
+    fn test_type_prefix() {
+        let mut prefixes = BTreeMap::new();
+        assert_eq!(type_prefix(Some("text/html"), &prefixes), "⤷");
+        assert_eq!(type_prefix(None, &prefixes), "⤷");
+
+        for (mime, prefix) in &[
+            ("text/html", "\u{1F4C4}"),
+            ("image", "\u{1F5BC}"),
+            ("video", "\u{1F39E}"),
+            ("application/pdf", "\u{1F4D5}"),
+            ("image/gif", "GIF"),
+        ] {
+            prefixes.insert(mime.to_string(), prefix.to_string());
+        }
+
+        assert_eq!(type_prefix(Some("text/html"), &prefixes), "\u{1F4C4}");
+        assert_eq!(type_prefix(Some("image/png"), &prefixes), "\u{1F5BC}");
+        assert_eq!(type_prefix(Some("image/gif"), &prefixes), "GIF");
+        assert_eq!(type_prefix(Some("video/mp4"), &prefixes), "\u{1F39E}");
+        assert_eq!(type_prefix(Some("application/pdf"), &prefixes), "\u{1F4D5}");
+        assert_eq!(type_prefix(Some("application/zip"), &prefixes), "⤷");
+        assert_eq!(type_prefix(Some("text/plain"), &prefixes), "⤷");
+        assert_eq!(type_prefix(None, &prefixes), "⤷");
+    }
+
+    #[test]
+    fn test_combine_replies() {
+        let replies = vec![String::from("⤷ foo"), String::from("⤷ bar")];
+        assert_eq!(combine_replies(&replies, 510), vec!["⤷ foo ⤷ bar"]);
+        assert!(combine_replies(&[], 510).is_empty());
+
+        // lines are split where combining would exceed the limit
+        let long = format!("⤷ {}", "a".repeat(300));
+        let replies = vec![long.clone(), long.clone(), "⤷ foo".into()];
+        assert_eq!(combine_replies(&replies, 510), vec![
+            long.clone(),
+            format!("{} ⤷ foo", long),
+        ]);
+        assert_eq!(combine_replies(&replies[1..], 308), vec![
+            long.clone(),
+            String::from("⤷ foo"),
+        ]);
+        assert_eq!(combine_replies(&replies[1..], 308 + 1 + 7), vec![
+            format!("{} ⤷ foo", long),
+        ]);
+    }
+
+    #[test]
+    fn test_create_non_highlighting_name() {
+        assert_eq!("\u{200C}", create_non_highlighting_name(""));
+        assert_eq!("f\u{200C}oo", create_non_highlighting_name("foo"));
+        assert_eq!("b\u{200C}ar", create_non_highlighting_name("bar"));
+        assert_eq!("b\u{200C}az", create_non_highlighting_name("baz"));
+    }
+
+    #[test]
+    fn test_mask_nicks_in() {
+        let nicks = vec![
+            String::from("foo"),
+            String::from("Bar"),
+            String::from("the"),
+            String::from(""),
+        ];
+
+        assert_eq!("", mask_nicks_in("", &nicks));
+        assert_eq!("f\u{200C}oo", mask_nicks_in("foo", &nicks));
+        assert_eq!("hello f\u{200C}oo and b\u{200C}ar!",
+            mask_nicks_in("hello foo and bar!", &nicks));
+        assert_eq!("F\u{200C}OO: f\u{200C}oo",
+            mask_nicks_in("FOO: foo", &nicks));
+        assert_eq!("t\u{200C}he other",
+            mask_nicks_in("the other", &nicks));
+
+        // only mask on word boundaries
+        assert_eq!("food barn there", mask_nicks_in("food barn there", &nicks));
+        assert_eq!("foo_bar", mask_nicks_in("foo_bar", &nicks));
+        assert_eq!("f\u{200C}oo-b\u{200C}ar", mask_nicks_in("foo-bar", &nicks));
+        assert_eq!("\u{2665}f\u{200C}oo\u{2665}",
+            mask_nicks_in("\u{2665}foo\u{2665}", &nicks));
+    }
+
+    #[test]
+    fn test_max_url_length() {
+        let mut rtd = Rtd::default();
+        let url = |len: usize| format!("http://z.zzz/{}", "a".repeat(len - 13));
+        assert_eq!(url(20).len(), 20);
+
+        rtd.conf.params.max_url_length = 20;
+        let (found, skipped) = scan_tokens(&format!("{} {}", url(20), url(21)), &rtd);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].url, url(20));
+        assert_eq!(skipped, vec![(url(21), Skip::TooLong(21))]);
+        assert_eq!(skipped[0].1.to_string(), "too long, 21 characters");
+
+        rtd.conf.params.max_url_length = 0;
+        assert_eq!(find_urls(&url(5000), &rtd).len(), 1);
+
+        // the default is generous
+        let rtd = Rtd::default();
+        assert_eq!(find_urls(&url(2048), &rtd).len(), 1);
+        assert!(find_urls(&url(2049), &rtd).is_empty());
+    }
+
+    #[test]
+    fn test_formatted_urls() {
+        let rtd = Rtd::default();
+        let msg = "see \u{3}04,01http://z.zzz/a\u{3} and \u{2}\u{1F}https://z.zzz/b\u{F}, \
+            \u{4}ff0000(http://z.zzz/c)\u{4} \u{3}12\u{1D}\u{3}";
+
+        assert_eq!(
+            find_urls(msg, &rtd).into_iter().map(|f| f.url).collect::<Vec<_>>(),
+            vec!["http://z.zzz/a", "https://z.zzz/b", "http://z.zzz/c"]
+        );
+    }
+
+    #[test]
+    fn test_is_allowed_scheme() {
+        let mut rtd = Rtd::default();
+        let url = |u: &str| u.parse::<Url>().unwrap();
+
+        assert!(is_allowed_scheme(&url("http://z.zzz/"), &rtd));
+        assert!(is_allowed_scheme(&url("HTTPS://z.zzz/"), &rtd));
+        assert!(!is_allowed_scheme(&url("ftp://z.zzz/"), &rtd));
+        assert!(!is_allowed_scheme(&url("mailto:z@z.zzz"), &rtd));
+
+        rtd.conf.features.allowed_schemes = vec!["ftp".to_string()];
+        assert!(is_allowed_scheme(&url("ftp://z.zzz/"), &rtd));
+        assert!(!is_allowed_scheme(&url("http://z.zzz/"), &rtd));
+
+        rtd.conf.features.allowed_schemes = vec![];
+        assert!(!is_allowed_scheme(&url("http://z.zzz/"), &rtd));
+    }
+
+    #[test]
+    fn test_history_url() {
+        let mut rtd = Rtd::default();
+        assert_eq!(history_url("http://z.zzz/a/#b", &rtd), "http://z.zzz/a/#b");
+
+        rtd.conf.params.normalize_urls = true;
+        let db = Database::open_in_memory().unwrap();
+        let entry = NewLogEntry {
+            title: "title",
+            url: &history_url("http://z.zzz/page", &rtd),
+            user: "user",
+            channel: "#chan",
+            duration_ms: None,
+            redirect_chain: None,
+        };
+        db.add_log(&entry).unwrap();
+
+        for url in &[
+            "http://z.zzz/page#section",
+            "http://z.zzz/page/",
+            "http://Z.ZZZ/page/#",
+        ] {
+            assert_eq!(history_url(url, &rtd), "http://z.zzz/page");
+            assert!(db.check_prepost(&history_url(url, &rtd)).unwrap().is_some());
+        }
+
+        assert_eq!(history_url("http://z.zzz/#top", &rtd), "http://z.zzz/");
+        assert_eq!(history_url("http://z.zzz/?q=1#a", &rtd), "http://z.zzz/?q=1");
+        assert_eq!(history_url("not a url", &rtd), "not a url");
+        assert!(db.check_prepost(&history_url("http://z.zzz/other", &rtd))
+            .unwrap().is_none());
+    }
+
+    #[test]
+    fn test_trim_punctuation() {
+        for (token, url) in &[
+            ("http://z.zzz/", "http://z.zzz/"),
+            ("(http://z.zzz/)", "http://z.zzz/"),
+            ("(https://example.com)", "https://example.com"),
+            ("https://example.com.", "https://example.com"),
+            ("https://example.com/,", "https://example.com/"),
+            ("https://example.com/?q=a!", "https://example.com/?q=a"),
+            ("(https://example.com/?a=b).", "https://example.com/?a=b"),
+            ("\"https://example.com/\"", "https://example.com/"),
+            ("<https://example.com/>", "https://example.com/"),
+            ("[https://example.com/]:", "https://example.com/"),
+            ("http://[::1]:8080/", "http://[::1]:8080/"),
+            ("(http://[::1]/)", "http://[::1]/"),
+            ("...", ""),
+            ("", ""),
+        ] {
+            assert_eq!(trim_punctuation(token), *url);
+        }
+
+        // URLs which legitimately end in a bracket
+        let wiki = "https://en.wikipedia.org/wiki/Rust_(programming_language)";
+        assert_eq!(trim_punctuation(wiki), wiki);
+        assert_eq!(trim_punctuation(&format!("({})", wiki)), wiki);
+        assert_eq!(trim_punctuation(&format!("{}.", wiki)), wiki);
+        assert_eq!(trim_punctuation(&format!("({}).", wiki)), wiki);
+    }
+
+    #[test]
+    fn test_contains_unsafe_chars() {
+        let unsafe_chars = Rtd::default().conf.params.unsafe_chars;
+        for c in &['{', '}', '|', '\\', '^', '~', '[', ']', '`', '<', '>', '"']
+        {
+            assert!(contains_unsafe_chars(&format!("http://z/{}", c), &unsafe_chars));
+        }
+        assert_eq!(contains_unsafe_chars("http://z.zzz/", &unsafe_chars), false);
+
+        // a configured set of unsafe characters
+        assert!(contains_unsafe_chars("http://z/~", "~"));
+        assert_eq!(contains_unsafe_chars("http://z/~", "{}"), false);
+        assert_eq!(contains_unsafe_chars("http://z/{}", ""), false);
+    }
+
+    #[test]
+    fn test_contains_unsafe_chars_ipv6() {
+        let unsafe_chars = Rtd::default().conf.params.unsafe_chars;
+
+        // brackets enclosing an IPv6 host are permitted
+        for url in &[
+            "http://[::1]/",
+            "http://[::1]:8080/",
+            "https://[2001:db8::ff00:42:8329]/index.html",
+            "http://user@[::1]:8080/a/b?c=d#e",
+        ] {
+            assert_eq!(contains_unsafe_chars(url, &unsafe_chars), false);
+        }
+
+        // but not elsewhere, or when the host isn't a valid IPv6 address
+        for url in &[
+            "http://[::1]:8080/[x]",
+            "http://[::1]/~",
+            "http://[::1]/<script>",
+            "http://[zz::zz]/",
+            "http://z.zzz/[::1]",
+        ] {
+            assert!(contains_unsafe_chars(url, &unsafe_chars));
+        }
+    }
+
+    #[test]
+    fn test_add_scheme_for_tld() {
+        // appears to be a URL, and has a valid TLD
+        assert!(add_scheme_for_tld("docs.rs").is_some());
+        assert!(add_scheme_for_tld("nomnomnom.xyz").is_some());
+        assert!(add_scheme_for_tld("endless.horse").is_some());
+        assert!(add_scheme_for_tld("google.co.uk").is_some());
+        assert!(add_scheme_for_tld("notreal.co.uk/#banana").is_some());
+        assert!(add_scheme_for_tld("notreal.co.uk/?banana=3").is_some());
+
+        // return value is as expected
+        assert_eq!(
+            Some(String::from("http://nomnomnom.xyz")),
+            add_scheme_for_tld("nomnomnom.xyz")
+        );
+        assert_eq!(
+            Some(String::from("http://google.co.uk")),
+            add_scheme_for_tld("google.co.uk")
+        );
+
+        // already a valid URL
+        assert!(add_scheme_for_tld("http://nomnomnom.xyz").is_none());
+        assert!(add_scheme_for_tld("http://endless.horse").is_none());
+
+        // not a recognised TLD
+        assert!(add_scheme_for_tld("abc.cheese").is_none());
+        assert!(add_scheme_for_tld("abc.limes").is_none());
+
+        // recognised TLD, but incomplete as a URL
+        assert!(add_scheme_for_tld("xyz").is_none());
+        assert!(add_scheme_for_tld("uk").is_none());
+        assert!(add_scheme_for_tld("horse").is_none());
+
+        // don't resolve email addresses
+        assert!(add_scheme_for_tld("test@gmail.com").is_none());
+        assert!(add_scheme_for_tld("word.word@gmail.com").is_none());
+    }
+
+    #[test]
+    fn test_assume_scheme() {
+        assert_eq!(
+            Some(String::from("https://www.x.com")),
+            assume_scheme("www.x.com")
+        );
+        assert_eq!(
+            Some(String::from("https://x.com/y")),
+            assume_scheme("x.com/y")
+        );
+        assert_eq!(
+            Some(String::from("https://endless.horse/")),
+            assume_scheme("endless.horse/")
+        );
+        assert_eq!(
+            Some(String::from("https://www.endless.horse")),
+            assume_scheme("www.endless.horse")
+        );
+        assert!(assume_scheme("example.com").is_some());
+        assert!(assume_scheme("docs.rs").is_some());
+        assert!(assume_scheme("google.co.uk").is_some());
+        assert!(assume_scheme("notreal.xyz?banana=3").is_some());
+        assert!(assume_scheme("notreal.xyz#banana").is_some());
+
+        // recognised TLD, but probably a sentence
+        assert!(assume_scheme("hello.world").is_none());
+        assert!(assume_scheme("endless.horse").is_none());
+
+        // not URLs
+        assert!(assume_scheme("http://x.com/y").is_none());
+        assert!(assume_scheme("abc.cheese/y").is_none());
+        assert!(assume_scheme("xyz").is_none());
+        assert!(assume_scheme("test@gmail.com").is_none());
     }
 }
@@ -0,0 +1,131 @@
+/*
+ * Prometheus metrics
+ *
+ */
+use std::thread;
+use failure::Error;
+use lazy_static;
+use prometheus::{self, Encoder, TextEncoder, IntCounter, IntCounterVec, Histogram};
+use tiny_http::{Server, Response, Header};
+
+lazy_static! {
+    pub static ref URLS_PROCESSED: IntCounter = register_int_counter!(
+        "url_bot_urls_processed_total",
+        "Total number of URLs processed"
+    ).unwrap();
+    pub static ref RESOLVE_SUCCESS: IntCounter = register_int_counter!(
+        "url_bot_resolve_success_total",
+        "Total number of successful URL resolutions"
+    ).unwrap();
+    pub static ref RESOLVE_FAILURE: IntCounter = register_int_counter!(
+        "url_bot_resolve_failure_total",
+        "Total number of failed URL resolutions"
+    ).unwrap();
+    pub static ref RESOLVE_ERRORS: IntCounterVec = register_int_counter_vec!(
+        "url_bot_resolve_errors_total",
+        "Total number of failed URL resolutions, by kind of failure",
+        &["kind"]
+    ).unwrap();
+    pub static ref RESOLVE_TIME: Histogram = register_histogram!(
+        "url_bot_resolve_duration_seconds",
+        "Time taken to resolve a URL, in seconds"
+    ).unwrap();
+    pub static ref HISTORY_HITS: IntCounter = register_int_counter!(
+        "url_bot_history_hits_total",
+        "Total number of URLs found in the history database"
+    ).unwrap();
+}
+
+/// register all metrics, so that they're reported before first use
+pub fn init() {
+    lazy_static::initialize(&URLS_PROCESSED);
+    lazy_static::initialize(&RESOLVE_SUCCESS);
+    lazy_static::initialize(&RESOLVE_FAILURE);
+    lazy_static::initialize(&RESOLVE_ERRORS);
+    lazy_static::initialize(&RESOLVE_TIME);
+    lazy_static::initialize(&HISTORY_HITS);
+}
+
+/// render all registered metrics in the Prometheus text format
+pub fn render() -> Vec<u8> {
+    let mut buf = Vec::new();
+    TextEncoder::new()
+        .encode(&prometheus::gather(), &mut buf)
+        .unwrap_or_else(|err| error!("error encoding metrics: {}", err));
+    buf
+}
+
+/// spawn a thread serving metrics over HTTP on the given port
+pub fn serve(port: u16) -> Result<thread::JoinHandle<()>, Error> {
+    let server = Server::http(("0.0.0.0", port))
+        .map_err(|err| format_err!("{}", err))?;
+
+    init();
+
+    let handle = thread::spawn(move || {
+        for rq in server.incoming_requests() {
+            let encoder = TextEncoder::new();
+            let header = Header::from_bytes(
+                "Content-Type", encoder.format_type()
+            ).unwrap();
+            let resp = Response::from_data(render()).with_header(header);
+
+            if let Err(err) = rq.respond(resp) {
+                error!("error serving metrics: {}", err);
+            }
+        }
+    });
+
+    Ok(handle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::io::Read;
+    use std::{thread, time};
+    use reqwest;
+    use super::super::config::Rtd;
+    use super::super::sqlite::Database;
+    use super::super::http::resolve_url;
+
+    #[test]
+    fn scrape_metrics_after_resolution() {
+        let server_thread = thread::spawn(move || {
+            let server = tiny_http::Server::http("0.0.0.0:28582").unwrap();
+            let rq = server.recv().unwrap();
+            let file = File::open("./test/html/basic.html").unwrap();
+            rq.respond(Response::from_file(file)).unwrap();
+        });
+
+        thread::sleep(time::Duration::from_millis(100));
+        serve(28583).unwrap();
+
+        let db = Database::open_in_memory().unwrap();
+        let rtd = Rtd::default();
+        resolve_url("http://0.0.0.0:28582/test", &rtd, Some(&db)).unwrap();
+        server_thread.join().unwrap();
+
+        let mut body = String::new();
+        reqwest::get("http://0.0.0.0:28583/metrics")
+            .unwrap()
+            .read_to_string(&mut body)
+            .unwrap();
+
+        // the value of a metric, which other tests may also have changed
+        let value = |name: &str| -> Option<u64> {
+            body.lines()
+                .find(|l| l.starts_with(&format!("{} ", name)))
+                .and_then(|l| l.split_whitespace().nth(1))
+                .and_then(|v| v.parse().ok())
+        };
+
+        assert!(body.contains("# TYPE url_bot_urls_processed_total counter"));
+        assert!(value("url_bot_urls_processed_total").is_some());
+        assert!(value("url_bot_resolve_failure_total").is_some());
+        assert!(value("url_bot_history_hits_total").is_some());
+        assert!(value("url_bot_resolve_success_total").unwrap() >= 1);
+        assert!(value("url_bot_resolve_duration_seconds_count").unwrap() >= 1);
+    }
+}
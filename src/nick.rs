@@ -0,0 +1,143 @@
+/*
+ * Nick recovery
+ *
+ * Where the preferred nick is in use when the bot connects, and it's given an
+ * alternative nick, the preferred nick is regained, by asking NickServ to
+ * GHOST the user holding it, if a password is configured, and changing to it
+ * once it's available, as checked with ISON when the holder quits or changes
+ * nick, and with each PING from the server.
+ *
+ */
+use irc::client::prelude::*;
+
+use super::config::Rtd;
+
+const NICKSERV: &str = "NickServ";
+
+/// track the bot's own nick, and attempt to regain the preferred nick
+pub fn handle(client: &IrcClient, message: &Message, rtd: &mut Rtd) {
+    let source = message.source_nickname().unwrap_or_default();
+
+    // the client doesn't track changes of nick other than its own
+    match message.command {
+        Command::Response(Response::RPL_WELCOME, ref args, _) => {
+            rtd.nick = args.first().cloned();
+        },
+        Command::NICK(ref nick) if source.eq_ignore_ascii_case(own_nick(client, rtd)) => {
+            info!("nick changed to {}", nick);
+            rtd.nick = Some(nick.to_string());
+        },
+        _ => (),
+    }
+
+    for command in recover(message, own_nick(client, rtd), rtd) {
+        if let Err(err) = client.send(command) {
+            error!("error sending nick recovery command: {}", err);
+        }
+    }
+}
+
+/// the nick the bot is using
+pub fn own_nick<'a>(client: &'a IrcClient, rtd: &'a Rtd) -> &'a str {
+    rtd.nick.as_ref().map_or(client.current_nickname(), String::as_str)
+}
+
+/// the commands, in response to a message, to regain `params.preferred_nick`,
+/// if it's set, and the bot is using another nick, `own_nick`
+fn recover(message: &Message, own_nick: &str, rtd: &Rtd) -> Vec<Command> {
+    let preferred = &rtd.conf.params.preferred_nick;
+    if preferred.is_empty() || preferred.eq_ignore_ascii_case(own_nick) {
+        return vec![];
+    }
+
+    let is_preferred = |nick: &str| nick.eq_ignore_ascii_case(preferred);
+    let is_nickserv = |nick: &str| nick.eq_ignore_ascii_case(NICKSERV);
+    let source = message.source_nickname().unwrap_or_default();
+    let ison = || vec![Command::ISON(vec![preferred.to_string()])];
+
+    match message.command {
+        Command::Response(Response::RPL_ENDOFMOTD, _, _) |
+        Command::Response(Response::ERR_NOMOTD, _, _) => {
+            let password = &rtd.conf.params.nickserv_password;
+            if password.is_empty() {
+                return ison();
+            }
+
+            info!("asking {} to ghost {}", NICKSERV, preferred);
+            let ghost = format!("GHOST {} {}", preferred, password);
+            vec![Command::PRIVMSG(NICKSERV.to_string(), ghost), ison().remove(0)]
+        },
+        // the preferred nick may have become available
+        Command::QUIT(_) | Command::NICK(_) if is_preferred(source) => ison(),
+        Command::NOTICE(..) if is_nickserv(source) => ison(),
+        Command::PING(..) => ison(),
+        Command::Response(Response::RPL_ISON, _, ref online) => {
+            let online = online.as_ref().map_or("", String::as_str);
+            if online.split_whitespace().any(is_preferred) {
+                return vec![];
+            }
+
+            info!("{} is available, changing nick", preferred);
+            vec![Command::NICK(preferred.to_string())]
+        },
+        Command::Response(Response::ERR_NOSUCHNICK, ref args, _)
+            if args.get(1).map_or(false, |n| is_nickserv(n)) =>
+        {
+            warn!("{} isn't present, waiting for {} to become available",
+                NICKSERV, preferred);
+            vec![]
+        },
+        _ => vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn recovery(message: &str, rtd: &Rtd) -> Vec<String> {
+        let message: Message = message.parse().unwrap();
+        recover(&message, "url-bot_", rtd)
+            .into_iter()
+            .map(|c| Message::from(c).to_string())
+            .collect()
+    }
+
+    #[test]
+    fn recover_preferred_nick() {
+        let mut rtd = Rtd::default();
+        let motd = ":srv 376 url-bot_ :End of MOTD";
+
+        // disabled without a preferred nick
+        assert!(recovery(motd, &rtd).is_empty());
+        assert!(recovery(":srv 303 url-bot_ :", &rtd).is_empty());
+
+        rtd.conf.params.preferred_nick = "url-bot".to_string();
+        assert_eq!(recovery(motd, &rtd), vec!["ISON url-bot\r\n"]);
+        assert!(recover(&motd.parse().unwrap(), "URL-bot", &rtd).is_empty());
+
+        rtd.conf.params.nickserv_password = "secret".to_string();
+        assert_eq!(recovery(motd, &rtd), vec![
+            "PRIVMSG NickServ :GHOST url-bot secret\r\n",
+            "ISON url-bot\r\n",
+        ]);
+
+        // the nick is checked when it may have become available
+        for message in &[
+            ":url-bot!u@h QUIT :bye",
+            ":url-bot!u@h NICK other",
+            ":NickServ!s@services NOTICE url-bot_ :url-bot has been ghosted.",
+            "PING :srv",
+        ] {
+            assert_eq!(recovery(message, &rtd), vec!["ISON url-bot\r\n"]);
+        }
+        assert!(recovery(":other!u@h QUIT :bye", &rtd).is_empty());
+
+        // and changed to once it is
+        assert_eq!(recovery(":srv 303 url-bot_ :", &rtd), vec!["NICK :url-bot\r\n"]);
+        assert!(recovery(":srv 303 url-bot_ :URL-bot", &rtd).is_empty());
+
+        // without NickServ, the bot waits for the nick to become available
+        assert!(recovery(":srv 401 url-bot_ NickServ :No such nick", &rtd).is_empty());
+    }
+}
@@ -0,0 +1,161 @@
+/*
+ * Outgoing message queue
+ *
+ * Responses are queued, and sent by a separate thread at a limited rate, so
+ * that the bot doesn't flood itself off the server when many URLs are posted
+ * in quick succession.
+ *
+ */
+use std::cmp;
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+use irc::client::prelude::*;
+
+/// token bucket rate limiter, allowing a burst of messages, after which
+/// messages are sent at a steady rate
+#[derive(Debug)]
+pub struct RateLimiter {
+    /// messages per second
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: f64, burst: u32, now: Instant) -> Self {
+        let burst = f64::from(cmp::max(burst, 1));
+        Self { rate, burst, tokens: burst, last: now }
+    }
+
+    /// take a message from the bucket at time `now`, returning the delay
+    /// before it may be sent
+    pub fn next(&mut self, now: Instant) -> Duration {
+        if now > self.last {
+            let elapsed = now.duration_since(self.last);
+            let elapsed = elapsed.as_secs() as f64
+                + f64::from(elapsed.subsec_nanos()) * 1e-9;
+            self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+            self.last = now;
+        }
+
+        self.tokens -= 1.0;
+
+        if self.tokens >= 0.0 {
+            Duration::from_millis(0)
+        } else {
+            Duration::from_millis((-self.tokens / self.rate * 1000.0).ceil() as u64)
+        }
+    }
+}
+
+/// a message to be sent
+pub struct Outgoing {
+    pub client: IrcClient,
+    pub target: String,
+    pub msg: String,
+    pub notice: bool,
+}
+
+impl Outgoing {
+    pub fn send(&self) {
+        let res = if self.notice {
+            self.client.send_notice(&self.target, &self.msg)
+        } else {
+            self.client.send_privmsg(&self.target, &self.msg)
+        };
+
+        if let Err(err) = res {
+            error!("error sending message: {}", err);
+        }
+    }
+}
+
+/// queue of outgoing messages, sent by a dedicated thread
+#[derive(Clone)]
+pub struct Outbox {
+    tx: Sender<Outgoing>,
+}
+
+impl Outbox {
+    /// spawn a thread sending queued messages, at up to `rate` messages per
+    /// second, after an initial burst
+    pub fn new(rate: f64, burst: u32) -> Self {
+        let (tx, rx) = mpsc::channel::<Outgoing>();
+
+        thread::spawn(move || {
+            let mut limiter = RateLimiter::new(rate, burst, Instant::now());
+
+            for out in rx {
+                let delay = limiter.next(Instant::now());
+                if delay > Duration::from_millis(0) {
+                    debug!("delaying message by {:?}", delay);
+                    thread::sleep(delay);
+                }
+                out.send();
+            }
+        });
+
+        Self { tx }
+    }
+
+    /// an outbox whose messages are received by `rx`, rather than sent
+    #[cfg(test)]
+    pub fn capture() -> (Self, mpsc::Receiver<Outgoing>) {
+        let (tx, rx) = mpsc::channel::<Outgoing>();
+        (Self { tx }, rx)
+    }
+
+    pub fn send(&self, out: Outgoing) {
+        if self.tx.send(out).is_err() {
+            error!("outgoing message queue closed");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    #[test]
+    fn limit_rate_after_burst() {
+        let t0 = Instant::now();
+        let mut limiter = RateLimiter::new(2.0, 3, t0);
+
+        // burst
+        for _ in 0..3 {
+            assert_eq!(limiter.next(t0), ms(0));
+        }
+
+        // then limited to the rate, accounting for time spent waiting
+        assert_eq!(limiter.next(t0), ms(500));
+        assert_eq!(limiter.next(t0 + ms(500)), ms(500));
+        assert_eq!(limiter.next(t0 + ms(1000)), ms(500));
+
+        // the message before is sent at 1500ms, after which the bucket is
+        // partially refilled
+        assert_eq!(limiter.next(t0 + ms(1750)), ms(250));
+        assert_eq!(limiter.next(t0 + ms(3000)), ms(0));
+
+        // refilled to no more than the burst size after a pause
+        let t1 = t0 + ms(60_000);
+        for _ in 0..3 {
+            assert_eq!(limiter.next(t1), ms(0));
+        }
+        assert_eq!(limiter.next(t1), ms(500));
+    }
+
+    #[test]
+    fn earlier_times_are_ignored() {
+        let t0 = Instant::now() + ms(1000);
+        let mut limiter = RateLimiter::new(1.0, 1, t0);
+
+        assert_eq!(limiter.next(t0 - ms(1000)), ms(0));
+        assert_eq!(limiter.next(t0 - ms(1000)), ms(1000));
+    }
+}
@@ -0,0 +1,253 @@
+/*
+ * GitHub repositories, issues and pull requests, using the REST API
+ * https://developer.github.com/v3/
+ *
+ */
+use std::sync::atomic::{AtomicI64, Ordering};
+use failure::Error;
+use reqwest::header::{self, HeaderMap, HeaderValue};
+use url::Url;
+use time;
+
+use super::super::config::Rtd;
+
+pub const API: &str = "https://api.github.com";
+
+/// time at which an exhausted API rate limit is reset, as a unix timestamp
+static RATE_LIMIT_RESET: AtomicI64 = AtomicI64::new(0);
+
+/// the GitHub resource a URL refers to
+#[derive(Debug, PartialEq)]
+enum Resource<'a> {
+    Repo(&'a str, &'a str),
+    Issue(&'a str, &'a str, u64),
+    Pull(&'a str, &'a str, u64),
+}
+
+impl<'a> Resource<'a> {
+    fn from_url(url: &'a Url) -> Option<Self> {
+        let segments: Vec<&str> = url.path_segments()?
+            .filter(|s| !s.is_empty())
+            .collect();
+
+        match segments[..] {
+            [owner, repo] => Some(Resource::Repo(owner, repo)),
+            [owner, repo, "issues", n] => {
+                Some(Resource::Issue(owner, repo, n.parse().ok()?))
+            },
+            [owner, repo, "pull", n] => {
+                Some(Resource::Pull(owner, repo, n.parse().ok()?))
+            },
+            _ => None,
+        }
+    }
+
+    /// the API path for the resource
+    fn path(&self) -> String {
+        match *self {
+            Resource::Repo(o, r) => format!("/repos/{}/{}", o, r),
+            Resource::Issue(o, r, n) => format!("/repos/{}/{}/issues/{}", o, r, n),
+            Resource::Pull(o, r, n) => format!("/repos/{}/{}/pulls/{}", o, r, n),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Repo {
+    full_name: String,
+    description: Option<String>,
+    language: Option<String>,
+    stargazers_count: u64,
+}
+
+#[derive(Deserialize)]
+struct Issue {
+    number: u64,
+    title: String,
+    state: String,
+}
+
+#[derive(Deserialize)]
+struct Pull {
+    number: u64,
+    title: String,
+    state: String,
+    merged: bool,
+}
+
+/// get a title for a GitHub URL, from the API at `api`, or `None` if the URL
+/// isn't one of a repository, issue or pull request
+pub fn title(url: &Url, rtd: &Rtd, api: &str) -> Result<Option<String>, Error> {
+    let resource = match Resource::from_url(url) {
+        Some(r) => r,
+        None => return Ok(None),
+    };
+
+    // don't make requests while the rate limit is exhausted
+    let now = time::now_utc().to_timespec().sec;
+    let reset = RATE_LIMIT_RESET.load(Ordering::Relaxed);
+    if now < reset {
+        bail!("GitHub API rate limit exceeded, resets in {}s", reset - now);
+    }
+
+    let mut headers = HeaderMap::new();
+    headers.insert(header::ACCEPT,
+        HeaderValue::from_static("application/vnd.github.v3+json"));
+    let token = &rtd.conf.params.github_token;
+    if !token.is_empty() {
+        headers.insert(header::AUTHORIZATION,
+            format!("token {}", token).parse()?);
+    }
+
+    let resp = super::get(&format!("{}{}", api, resource.path()), rtd, headers)?;
+
+    if let Some(reset) = rate_limit_reset(resp.headers()) {
+        warn!("GitHub API rate limit exhausted");
+        RATE_LIMIT_RESET.store(reset, Ordering::Relaxed);
+    }
+
+    let title = match resource {
+        Resource::Repo(..) => repo_title(&super::json(resp)?),
+        Resource::Issue(..) => issue_title(&super::json(resp)?),
+        Resource::Pull(..) => pull_title(&super::json(resp)?),
+    };
+
+    Ok(Some(title))
+}
+
+/// the time at which the rate limit is reset, if no requests remain
+fn rate_limit_reset(headers: &HeaderMap) -> Option<i64> {
+    let get = |name| headers.get(name)?.to_str().ok();
+
+    match get("x-ratelimit-remaining")?.parse::<u64>().ok()? {
+        0 => get("x-ratelimit-reset")?.parse().ok(),
+        _ => None,
+    }
+}
+
+fn repo_title(repo: &Repo) -> String {
+    let mut title = repo.full_name.clone();
+
+    if let Some(ref description) = repo.description {
+        if !description.is_empty() {
+            title.push_str(&format!(": {}", description));
+        }
+    }
+    if let Some(ref language) = repo.language {
+        title.push_str(&format!(" ({})", language));
+    }
+
+    format!("{} ★{}", title, repo.stargazers_count)
+}
+
+fn issue_title(issue: &Issue) -> String {
+    format!("#{} {} [{}]", issue.number, issue.title, issue.state)
+}
+
+fn pull_title(pull: &Pull) -> String {
+    let state = if pull.merged { "merged" } else { pull.state.as_str() };
+    format!("#{} {} [{}]", pull.number, pull.title, state)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tiny_http;
+
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+    use self::tiny_http::{Response, Header};
+
+    fn resource(url: &str) -> Option<String> {
+        let url = Url::parse(url).unwrap();
+        Resource::from_url(&url).map(|r| r.path())
+    }
+
+    #[test]
+    fn github_resources() {
+        assert_eq!(resource("https://github.com/o/r").unwrap(), "/repos/o/r");
+        assert_eq!(resource("https://github.com/o/r/").unwrap(), "/repos/o/r");
+        assert_eq!(resource("https://github.com/o/r/issues/42").unwrap(),
+            "/repos/o/r/issues/42");
+        assert_eq!(resource("https://github.com/o/r/pull/7").unwrap(),
+            "/repos/o/r/pulls/7");
+
+        assert!(resource("https://github.com/").is_none());
+        assert!(resource("https://github.com/o").is_none());
+        assert!(resource("https://github.com/o/r/issues").is_none());
+        assert!(resource("https://github.com/o/r/issues/x").is_none());
+        assert!(resource("https://github.com/o/r/pull/7/files").is_none());
+        assert!(resource("https://github.com/o/r/blob/master/README.md").is_none());
+    }
+
+    #[test]
+    fn github_rate_limit() {
+        let headers = |remaining: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert("x-ratelimit-remaining", remaining.parse().unwrap());
+            headers.insert("x-ratelimit-reset", "1550000000".parse().unwrap());
+            headers
+        };
+
+        assert_eq!(rate_limit_reset(&headers("0")), Some(1_550_000_000));
+        assert_eq!(rate_limit_reset(&headers("59")), None);
+        assert_eq!(rate_limit_reset(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn github_titles() {
+        let server_thread = thread::spawn(move || {
+            let server = tiny_http::Server::http("0.0.0.0:28498").unwrap();
+
+            for rq in server.incoming_requests().take(5) {
+                let auth = rq.headers()
+                    .iter()
+                    .any(|h| h.field.equiv("Authorization")
+                        && h.value.as_str() == "token abc");
+                assert!(auth);
+
+                let body = match rq.url() {
+                    "/repos/o/r" => r#"{"full_name": "o/r",
+                        "description": "a repo", "language": "Rust",
+                        "stargazers_count": 1234}"#,
+                    "/repos/o/bare" => r#"{"full_name": "o/bare",
+                        "description": null, "language": null,
+                        "stargazers_count": 0}"#,
+                    "/repos/o/r/issues/42" => r#"{"number": 42,
+                        "title": "Issue title", "state": "open"}"#,
+                    "/repos/o/r/pulls/7" => r#"{"number": 7,
+                        "title": "PR title", "state": "closed",
+                        "merged": true}"#,
+                    _ => {
+                        rq.respond(Response::empty(404)).unwrap();
+                        continue;
+                    },
+                };
+
+                let header = Header::from_bytes(&b"Content-Type"[..],
+                    &b"application/json"[..]).unwrap();
+                rq.respond(Response::from_string(body).with_header(header))
+                    .unwrap();
+            }
+        });
+
+        thread::sleep(Duration::from_millis(100));
+        let mut rtd = Rtd::default();
+        rtd.conf.params.github_token = "abc".to_string();
+        let api = "http://0.0.0.0:28498";
+        let title = |url| title(&Url::parse(url).unwrap(), &rtd, api);
+
+        assert_eq!(title("https://github.com/o/r").unwrap().unwrap(),
+            "o/r: a repo (Rust) ★1234");
+        assert_eq!(title("https://github.com/o/bare").unwrap().unwrap(),
+            "o/bare ★0");
+        assert_eq!(title("https://github.com/o/r/issues/42").unwrap().unwrap(),
+            "#42 Issue title [open]");
+        assert_eq!(title("https://github.com/o/r/pull/7").unwrap().unwrap(),
+            "#7 PR title [merged]");
+        assert!(title("https://github.com/o/missing").is_err());
+        assert!(title("https://github.com/o/r/tree/master").unwrap().is_none());
+
+        server_thread.join().unwrap();
+    }
+}
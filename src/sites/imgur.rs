@@ -0,0 +1,208 @@
+/*
+ * Imgur albums, using the API, which requires a registered client ID
+ * https://apidocs.imgur.com/
+ *
+ */
+use std::sync::atomic::{AtomicI64, Ordering};
+use failure::Error;
+use reqwest::{StatusCode, header::{self, HeaderMap}};
+use url::Url;
+use time;
+
+use super::super::config::Rtd;
+
+pub const API: &str = "https://api.imgur.com";
+
+/// time at which an exhausted API rate limit is reset, as a unix timestamp
+static RATE_LIMIT_RESET: AtomicI64 = AtomicI64::new(0);
+
+/// time to wait after being rate limited without being told for how long
+const RATE_LIMIT_WAIT_S: i64 = 60 * 60;
+
+#[derive(Deserialize)]
+struct Response {
+    data: Album,
+}
+
+#[derive(Deserialize)]
+struct Album {
+    title: Option<String>,
+    images_count: u64,
+}
+
+/// the API path for the album a URL refers to, from either an album URL,
+/// `/a/<id>`, or a gallery URL, `/gallery/<id>`, where the ID may follow a
+/// slug, e.g. `/a/some-title-<id>`
+fn album_path(url: &Url) -> Option<String> {
+    let segments: Vec<&str> = url.path_segments()?
+        .filter(|s| !s.is_empty())
+        .collect();
+
+    let (path, slug) = match segments[..] {
+        ["a", slug] => ("album", slug),
+        ["gallery", slug] => ("gallery/album", slug),
+        _ => return None,
+    };
+
+    let id = slug.rsplit('-').next()?;
+    if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return None;
+    }
+
+    Some(format!("/3/{}/{}", path, id))
+}
+
+/// get a title for an imgur URL, from the API at `api`, or `None` if the URL
+/// isn't one of an album, or no client ID is configured
+pub fn title(url: &Url, rtd: &Rtd, api: &str) -> Result<Option<String>, Error> {
+    let client_id = &rtd.conf.params.imgur_client_id;
+    if client_id.is_empty() {
+        return Ok(None);
+    }
+
+    let path = match album_path(url) {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+
+    // don't make requests while the rate limit is exhausted
+    let now = time::now_utc().to_timespec().sec;
+    let reset = RATE_LIMIT_RESET.load(Ordering::Relaxed);
+    if now < reset {
+        bail!("imgur API rate limit exceeded, resets in {}s", reset - now);
+    }
+
+    let mut headers = HeaderMap::new();
+    headers.insert(header::AUTHORIZATION, format!("Client-ID {}", client_id).parse()?);
+
+    let resp = super::get(&format!("{}{}", api, path), rtd, headers)?;
+
+    let limited = resp.status() == StatusCode::TOO_MANY_REQUESTS;
+    if let Some(reset) = rate_limit_reset(resp.headers(), limited, now) {
+        warn!("imgur API rate limit exhausted");
+        RATE_LIMIT_RESET.store(reset, Ordering::Relaxed);
+    }
+
+    let resp: Response = super::json(resp)?;
+    Ok(Some(album_title(&resp.data)))
+}
+
+/// the time at which the rate limit is reset, if no requests remain, or the
+/// request was refused, `limited`, at `now`
+fn rate_limit_reset(headers: &HeaderMap, limited: bool, now: i64) -> Option<i64> {
+    let get = |name| headers.get(name)?.to_str().ok()?.parse::<i64>().ok();
+
+    match get("x-ratelimit-userremaining") {
+        Some(0) => get("x-ratelimit-userreset"),
+        _ if limited => Some(now + RATE_LIMIT_WAIT_S),
+        _ => None,
+    }
+}
+
+fn album_title(album: &Album) -> String {
+    let title = album.title
+        .as_ref()
+        .map(|t| t.trim())
+        .filter(|t| !t.is_empty())
+        .unwrap_or("untitled album");
+
+    let plural = if album.images_count == 1 { "" } else { "s" };
+    format!("Imgur: {} ({} image{})", title, album.images_count, plural)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tiny_http;
+
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+    use self::tiny_http::{Response, Header};
+
+    fn path(url: &str) -> Option<String> {
+        album_path(&Url::parse(url).unwrap())
+    }
+
+    #[test]
+    fn imgur_albums() {
+        assert_eq!(path("https://imgur.com/a/AbC123").unwrap(), "/3/album/AbC123");
+        assert_eq!(path("https://imgur.com/a/some-title-AbC123/").unwrap(),
+            "/3/album/AbC123");
+        assert_eq!(path("https://imgur.com/gallery/AbC123").unwrap(),
+            "/3/gallery/album/AbC123");
+
+        assert!(path("https://imgur.com/").is_none());
+        assert!(path("https://imgur.com/AbC123").is_none());
+        assert!(path("https://imgur.com/a/").is_none());
+        assert!(path("https://imgur.com/a/title-").is_none());
+        assert!(path("https://imgur.com/a/AbC123/embed").is_none());
+        assert!(path("https://imgur.com/a/a%20b").is_none());
+    }
+
+    #[test]
+    fn imgur_rate_limit() {
+        let headers = |remaining: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert("x-ratelimit-userremaining", remaining.parse().unwrap());
+            headers.insert("x-ratelimit-userreset", "1550000000".parse().unwrap());
+            headers
+        };
+
+        assert_eq!(rate_limit_reset(&headers("0"), false, 1000), Some(1_550_000_000));
+        assert_eq!(rate_limit_reset(&headers("0"), true, 1000), Some(1_550_000_000));
+        assert_eq!(rate_limit_reset(&headers("12"), false, 1000), None);
+        assert_eq!(rate_limit_reset(&HeaderMap::new(), false, 1000), None);
+        assert_eq!(rate_limit_reset(&HeaderMap::new(), true, 1000), Some(4600));
+    }
+
+    #[test]
+    fn imgur_titles() {
+        let server_thread = thread::spawn(move || {
+            let server = tiny_http::Server::http("0.0.0.0:28520").unwrap();
+
+            for rq in server.incoming_requests().take(3) {
+                let auth = rq.headers()
+                    .iter()
+                    .any(|h| h.field.equiv("Authorization")
+                        && h.value.as_str() == "Client-ID abc");
+                assert!(auth);
+
+                let body = match rq.url() {
+                    "/3/album/AbC123" => r#"{"data": {"title": "Cats",
+                        "images_count": 12}, "success": true, "status": 200}"#,
+                    "/3/gallery/album/one" => r#"{"data": {"title": null,
+                        "images_count": 1}, "success": true, "status": 200}"#,
+                    _ => {
+                        rq.respond(Response::empty(404)).unwrap();
+                        continue;
+                    },
+                };
+
+                let header = Header::from_bytes(&b"Content-Type"[..],
+                    &b"application/json"[..]).unwrap();
+                rq.respond(Response::from_string(body).with_header(header))
+                    .unwrap();
+            }
+        });
+
+        thread::sleep(Duration::from_millis(100));
+        let mut rtd = Rtd::default();
+        let api = "http://0.0.0.0:28520";
+
+        // no requests are made without a client ID
+        assert!(title(&Url::parse("https://imgur.com/a/AbC123").unwrap(), &rtd, api)
+            .unwrap().is_none());
+
+        rtd.conf.params.imgur_client_id = "abc".to_string();
+        let title = |url| title(&Url::parse(url).unwrap(), &rtd, api);
+
+        assert_eq!(title("https://imgur.com/a/AbC123").unwrap().unwrap(),
+            "Imgur: Cats (12 images)");
+        assert_eq!(title("https://imgur.com/gallery/one").unwrap().unwrap(),
+            "Imgur: untitled album (1 image)");
+        assert!(title("https://imgur.com/a/missing").is_err());
+        assert!(title("https://imgur.com/AbC123").unwrap().is_none());
+
+        server_thread.join().unwrap();
+    }
+}
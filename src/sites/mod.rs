@@ -0,0 +1,76 @@
+/*
+ * Site handlers
+ *
+ * Titles for some sites are better produced from the site's API than from
+ * the HTML of the page. Where a handler fails, or doesn't recognise the URL,
+ * the title of the page is retrieved as usual.
+ *
+ */
+pub mod github;
+pub mod imgur;
+pub mod paste;
+pub mod reddit;
+pub mod wikipedia;
+
+use std::io::Read;
+use failure::Error;
+use reqwest::{header::{self, HeaderMap}, Response};
+use serde::de::DeserializeOwned;
+use serde_json;
+use url::Url;
+
+use super::config::Rtd;
+use super::http::{Session, RequestParams};
+
+/// maximum size of an API response
+const RESPONSE_MAX: u64 = 1024 * 1024;
+
+/// get a title using the handler for a URL's site, if there is one
+pub fn site_title(url: &str, rtd: &Rtd) -> Option<String> {
+    let url = Url::parse(url).ok()?;
+    let host = url.host_str()?.to_lowercase();
+
+    let res = match host.trim_start_matches("www.") {
+        "github.com" => github::title(&url, rtd, github::API),
+        "imgur.com" | "m.imgur.com" => imgur::title(&url, rtd, imgur::API),
+        "pastebin.com" => paste::title(&url, rtd, paste::PASTEBIN_RAW),
+        "gist.github.com" => paste::title(&url, rtd, paste::GIST_RAW),
+        "redd.it" => reddit::title(&url, rtd, reddit::API),
+        h if h == "reddit.com" || h.ends_with(".reddit.com") => {
+            reddit::title(&url, rtd, reddit::API)
+        },
+        h if h.ends_with(".wikipedia.org") => {
+            // use the API of the desktop site, for mobile site URLs
+            let base = format!("https://{}", h.replace(".m.wikipedia.org", ".wikipedia.org"));
+            wikipedia::title(&url, rtd, &base)
+        },
+        _ => return None,
+    };
+
+    res.unwrap_or_else(|err| {
+        warn!("site handler failed for <{}>: {}", url, err);
+        None
+    })
+}
+
+/// make an API request, using the configured request parameters
+fn get(url: &str, rtd: &Rtd, headers: HeaderMap) -> Result<Response, Error> {
+    let mut session = Session::new();
+    session.params = RequestParams::from(rtd);
+
+    let resp = session.client()?
+        .get(url)
+        .headers(headers)
+        .header(header::USER_AGENT, session.params.user_agent.as_str())
+        .send()?;
+
+    debug!("<{}> → [{}]", url, resp.status());
+
+    Ok(resp)
+}
+
+/// deserialise the body of a successful JSON response
+fn json<T: DeserializeOwned>(resp: Response) -> Result<T, Error> {
+    let resp = resp.error_for_status()?;
+    Ok(serde_json::from_reader(resp.take(RESPONSE_MAX))?)
+}
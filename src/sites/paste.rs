@@ -0,0 +1,261 @@
+/*
+ * Paste sites, Pastebin and GitHub Gists, titled with the language and first
+ * line of the raw paste, which are absent from the titles of their pages
+ *
+ */
+use std::io::Read;
+use std::path::Path;
+use failure::Error;
+use reqwest::header::HeaderMap;
+use url::Url;
+
+use super::super::config::Rtd;
+
+pub const PASTEBIN_RAW: &str = "https://pastebin.com";
+pub const GIST_RAW: &str = "https://gist.githubusercontent.com";
+
+/// max size of the start of a paste read, for its first line
+const RAW_MAX: u64 = 16 * 1024;
+
+/// max length of the first line, in characters
+const LINE_MAX: usize = 80;
+
+/// languages, by file extension
+const LANGUAGES: &[(&str, &str)] = &[
+    ("c", "c"), ("h", "c"), ("cc", "c++"), ("cpp", "c++"), ("hpp", "c++"),
+    ("cs", "c#"), ("css", "css"), ("go", "go"), ("hs", "haskell"),
+    ("html", "html"), ("java", "java"), ("js", "javascript"),
+    ("json", "json"), ("kt", "kotlin"), ("lua", "lua"), ("md", "markdown"),
+    ("nix", "nix"), ("php", "php"), ("pl", "perl"), ("py", "python"),
+    ("rb", "ruby"), ("rs", "rust"), ("sh", "shell"), ("sql", "sql"),
+    ("swift", "swift"), ("toml", "toml"), ("ts", "typescript"), ("xml", "xml"),
+    ("yaml", "yaml"), ("yml", "yaml"),
+];
+
+/// a paste a URL refers to
+#[derive(Debug, PartialEq)]
+enum Paste<'a> {
+    Pastebin(&'a str),
+    /// a gist, by owner and ID, with the extension of the file linked to
+    Gist(&'a str, &'a str, Option<&'a str>),
+}
+
+impl<'a> Paste<'a> {
+    fn from_url(url: &'a Url) -> Option<Self> {
+        let host = url.host_str()?.trim_start_matches("www.");
+        let segments: Vec<&str> = url.path_segments()?
+            .filter(|s| !s.is_empty())
+            .collect();
+        let is_id = |id: &str| !id.is_empty() && id.chars().all(|c| c.is_ascii_alphanumeric());
+
+        match (host, &segments[..]) {
+            ("pastebin.com", [id]) if is_id(id) => Some(Paste::Pastebin(id)),
+            ("gist.github.com", [owner, id]) if is_id(id) => {
+                // a file is linked to by a fragment, e.g. #file-main-rs
+                let ext = url.fragment()
+                    .filter(|f| f.starts_with("file-"))
+                    .and_then(|f| f.rsplit('-').next());
+                Some(Paste::Gist(owner, id, ext))
+            },
+            _ => None,
+        }
+    }
+
+    /// the path of the raw paste
+    fn raw_path(&self) -> String {
+        match *self {
+            Paste::Pastebin(id) => format!("/raw/{}", id),
+            Paste::Gist(owner, id, _) => format!("/{}/{}/raw", owner, id),
+        }
+    }
+
+    fn site(&self) -> &str {
+        match *self {
+            Paste::Pastebin(_) => "Pastebin",
+            Paste::Gist(..) => "Gist",
+        }
+    }
+}
+
+/// get a title for a paste URL, from the raw paste at `raw`, or `None` if the
+/// URL isn't one of a paste, or the paste isn't text
+pub fn title(url: &Url, rtd: &Rtd, raw: &str) -> Result<Option<String>, Error> {
+    let paste = match Paste::from_url(url) {
+        Some(paste) => paste,
+        None => return Ok(None),
+    };
+
+    let resp = super::get(&format!("{}{}", raw, paste.raw_path()), rtd, HeaderMap::new())?;
+    let mut content = Vec::new();
+    resp.error_for_status()?.take(RAW_MAX).read_to_end(&mut content)?;
+    if content.contains(&0) {
+        return Ok(None);
+    }
+    let content = String::from_utf8_lossy(&content);
+
+    let line = match first_line(&content) {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+
+    let ext = match paste {
+        Paste::Gist(_, _, ext) => ext,
+        _ => None,
+    };
+    let lang = ext.and_then(language).map(String::from);
+    let title = match lang.or_else(|| content_language(&content)) {
+        Some(lang) => format!("{} ({}): {}", paste.site(), lang, line),
+        None => format!("{}: {}", paste.site(), line),
+    };
+
+    Ok(Some(title))
+}
+
+/// the language of a file extension
+fn language(ext: &str) -> Option<&'static str> {
+    let ext = ext.to_lowercase();
+
+    LANGUAGES.iter()
+        .find(|(e, _)| *e == ext)
+        .map(|&(_, lang)| lang)
+}
+
+/// the language of a paste, from an interpreter line, e.g. `#!/bin/sh` or
+/// `#!/usr/bin/env python3`, or the start of a PHP or XML document
+fn content_language(content: &str) -> Option<String> {
+    let start = content.trim_start();
+
+    if start.starts_with("<?php") {
+        return Some("php".to_string());
+    }
+    if start.starts_with("<?xml") {
+        return Some("xml".to_string());
+    }
+
+    let interpreter = start.lines().next()?.trim_end();
+    if !interpreter.starts_with("#!") {
+        return None;
+    }
+    let mut words = interpreter[2..].split_whitespace();
+    let mut command = Path::new(words.next()?).file_name()?.to_str()?;
+    if command == "env" {
+        command = words.next()?;
+    }
+
+    // e.g. python3, or bash
+    let name = command.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.');
+    match name {
+        "sh" | "bash" | "zsh" | "dash" | "ksh" => Some("shell".to_string()),
+        "" => None,
+        name => Some(name.to_string()),
+    }
+}
+
+/// the first non-empty line of a paste, without control characters, truncated
+fn first_line(content: &str) -> Option<String> {
+    let line: String = content.lines()
+        .map(|l| l.chars().filter(|c| !c.is_control()).collect::<String>())
+        .map(|l| l.trim().to_string())
+        .find(|l| !l.is_empty())?;
+
+    if line.chars().count() <= LINE_MAX {
+        return Some(line);
+    }
+
+    let truncated: String = line.chars().take(LINE_MAX - 1).collect();
+    Some(format!("{}…", truncated.trim_end()))
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tiny_http;
+
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+    use self::tiny_http::Response;
+
+    fn paste(url: &str) -> Option<(String, String)> {
+        let url = Url::parse(url).unwrap();
+        Paste::from_url(&url).map(|p| (p.site().to_string(), p.raw_path()))
+    }
+
+    #[test]
+    fn paste_urls() {
+        let pastebin = || Some(("Pastebin".to_string(), "/raw/AbC123".to_string()));
+        assert_eq!(paste("https://pastebin.com/AbC123"), pastebin());
+        assert_eq!(paste("https://www.pastebin.com/AbC123/"), pastebin());
+        assert_eq!(paste("https://pastebin.com/raw/AbC123"), None);
+        assert_eq!(paste("https://pastebin.com/u/someone"), None);
+        assert_eq!(paste("https://pastebin.com/"), None);
+
+        let url = Url::parse("https://gist.github.com/user/abc123#file-main-rs").unwrap();
+        assert_eq!(Paste::from_url(&url), Some(Paste::Gist("user", "abc123", Some("rs"))));
+        let url = Url::parse("https://gist.github.com/user/abc123#comments").unwrap();
+        assert_eq!(Paste::from_url(&url), Some(Paste::Gist("user", "abc123", None)));
+        assert_eq!(paste("https://gist.github.com/user/abc123").unwrap().1,
+            "/user/abc123/raw");
+        assert_eq!(paste("https://gist.github.com/user"), None);
+        assert_eq!(paste("https://gist.github.com/user/abc123/revisions"), None);
+    }
+
+    #[test]
+    fn paste_languages() {
+        assert_eq!(language("rs"), Some("rust"));
+        assert_eq!(language("PY"), Some("python"));
+        assert_eq!(language("txt"), None);
+
+        let lang = |content| content_language(content);
+        assert_eq!(lang("#!/bin/bash\necho hi").unwrap(), "shell");
+        assert_eq!(lang("\n#!/usr/bin/env python3\n").unwrap(), "python");
+        assert_eq!(lang("#!/usr/bin/perl -w\n").unwrap(), "perl");
+        assert_eq!(lang("<?php echo 1; ?>").unwrap(), "php");
+        assert_eq!(lang("<?xml version=\"1.0\"?>").unwrap(), "xml");
+        assert_eq!(lang("# heading"), None);
+        assert_eq!(lang("#!"), None);
+    }
+
+    #[test]
+    fn paste_first_lines() {
+        assert_eq!(first_line("\n  \n\tfn main() {\n}").unwrap(), "fn main() {");
+        assert_eq!(first_line("a\u{1b}[31mb\u{7}\r\n").unwrap(), "a[31mb");
+        assert_eq!(first_line(" \n\u{1}\n"), None);
+
+        let line = first_line(&"x".repeat(200)).unwrap();
+        assert_eq!(line.chars().count(), LINE_MAX);
+        assert!(line.ends_with('…'));
+    }
+
+    #[test]
+    fn paste_titles() {
+        let server_thread = thread::spawn(move || {
+            let server = tiny_http::Server::http("0.0.0.0:28528").unwrap();
+
+            for rq in server.incoming_requests().take(4) {
+                let resp = match rq.url() {
+                    "/raw/AbC123" => Response::from_string("\n#!/bin/sh\nls\n"),
+                    "/user/abc123/raw" => Response::from_string(
+                        "fn main() {\n    println!(\"hi\");\n}\n"),
+                    "/raw/binary" => Response::from_data(vec![0x89, 0, 0]),
+                    _ => Response::from_string("").with_status_code(404),
+                };
+                rq.respond(resp).unwrap();
+            }
+        });
+
+        thread::sleep(Duration::from_millis(100));
+        let rtd = Rtd::default();
+        let raw = "http://0.0.0.0:28528";
+        let title = |url| title(&Url::parse(url).unwrap(), &rtd, raw);
+
+        assert_eq!(title("https://pastebin.com/AbC123").unwrap().unwrap(),
+            "Pastebin (shell): #!/bin/sh");
+        assert_eq!(title("https://gist.github.com/user/abc123#file-main-rs").unwrap().unwrap(),
+            "Gist (rust): fn main() {");
+        assert!(title("https://pastebin.com/binary").unwrap().is_none());
+        assert!(title("https://pastebin.com/missing").is_err());
+        assert!(title("https://pastebin.com/u/someone").unwrap().is_none());
+
+        server_thread.join().unwrap();
+    }
+}
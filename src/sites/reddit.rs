@@ -0,0 +1,165 @@
+/*
+ * Reddit posts, using the public JSON API
+ *
+ */
+use failure::Error;
+use reqwest::header::HeaderMap;
+use url::Url;
+
+use super::super::config::Rtd;
+
+pub const API: &str = "https://www.reddit.com";
+
+#[derive(Deserialize)]
+struct Listing {
+    data: ListingData,
+}
+
+#[derive(Deserialize)]
+struct ListingData {
+    children: Vec<Child>,
+}
+
+#[derive(Deserialize)]
+struct Child {
+    data: Post,
+}
+
+#[derive(Deserialize)]
+struct Post {
+    title: String,
+    subreddit: String,
+    score: i64,
+}
+
+/// the ID of the post a URL refers to, from either a reddit.com comments URL,
+/// or a redd.it short URL
+fn post_id(url: &Url) -> Option<&str> {
+    let segments: Vec<&str> = url.path_segments()?
+        .filter(|s| !s.is_empty())
+        .collect();
+
+    let id = if url.host_str()? == "redd.it" {
+        match segments[..] {
+            [id] => id,
+            _ => return None,
+        }
+    } else {
+        // the ID follows "comments", in /r/<sub>/comments/<id> or
+        // /comments/<id>, optionally followed by a slug
+        let i = match segments.first() {
+            Some(&"r") => 2,
+            _ => 0,
+        };
+        if segments.get(i) != Some(&"comments") {
+            return None;
+        }
+        segments.get(i + 1)?
+    };
+
+    if id.chars().all(|c| c.is_ascii_alphanumeric()) {
+        Some(id)
+    } else {
+        None
+    }
+}
+
+/// get a title for a reddit URL, from the API at `api`, or `None` if the URL
+/// isn't one of a post
+pub fn title(url: &Url, rtd: &Rtd, api: &str) -> Result<Option<String>, Error> {
+    let id = match post_id(url) {
+        Some(id) => id,
+        None => return Ok(None),
+    };
+
+    let url = format!("{}/by_id/t3_{}.json", api, id);
+    let listing: Listing = super::json(super::get(&url, rtd, HeaderMap::new())?)?;
+
+    match listing.data.children.first() {
+        Some(child) => Ok(Some(post_title(&child.data))),
+        None => bail!("post {} not found", id),
+    }
+}
+
+fn post_title(post: &Post) -> String {
+    // titles are HTML escaped
+    let title = post.title
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&");
+
+    format!("[r/{}] {} (↑{})", post.subreddit, title, post.score)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tiny_http;
+
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+    use self::tiny_http::{Response, Header};
+
+    fn id(url: &str) -> Option<String> {
+        post_id(&Url::parse(url).unwrap()).map(String::from)
+    }
+
+    #[test]
+    fn reddit_post_ids() {
+        for url in &[
+            "https://www.reddit.com/r/rust/comments/abc123/a_post/",
+            "https://old.reddit.com/r/rust/comments/abc123/",
+            "https://reddit.com/comments/abc123",
+            "https://redd.it/abc123",
+        ] {
+            assert_eq!(id(url).unwrap(), "abc123");
+        }
+
+        assert!(id("https://www.reddit.com/").is_none());
+        assert!(id("https://www.reddit.com/r/rust/").is_none());
+        assert!(id("https://www.reddit.com/user/someone").is_none());
+        assert!(id("https://redd.it/").is_none());
+        assert!(id("https://redd.it/abc/def").is_none());
+        assert!(id("https://reddit.com/comments/a%20b").is_none());
+    }
+
+    #[test]
+    fn reddit_titles() {
+        let server_thread = thread::spawn(move || {
+            let server = tiny_http::Server::http("0.0.0.0:28499").unwrap();
+
+            for rq in server.incoming_requests().take(3) {
+                let body = match rq.url() {
+                    "/by_id/t3_abc123.json" => r#"{"kind": "Listing",
+                        "data": {"children": [{"kind": "t3", "data": {
+                        "title": "Fish &amp; chips", "subreddit": "food",
+                        "score": 1234}}]}}"#,
+                    "/by_id/t3_gone.json" => r#"{"kind": "Listing",
+                        "data": {"children": []}}"#,
+                    _ => {
+                        rq.respond(Response::empty(500)).unwrap();
+                        continue;
+                    },
+                };
+
+                let header = Header::from_bytes(&b"Content-Type"[..],
+                    &b"application/json"[..]).unwrap();
+                rq.respond(Response::from_string(body).with_header(header))
+                    .unwrap();
+            }
+        });
+
+        thread::sleep(Duration::from_millis(100));
+        let rtd = Rtd::default();
+        let api = "http://0.0.0.0:28499";
+        let title = |url| title(&Url::parse(url).unwrap(), &rtd, api);
+
+        assert_eq!(title("https://redd.it/abc123").unwrap().unwrap(),
+            "[r/food] Fish & chips (↑1234)");
+        assert!(title("https://redd.it/gone").is_err());
+        assert!(title("https://redd.it/error").is_err());
+        assert!(title("https://www.reddit.com/r/food/").unwrap().is_none());
+
+        server_thread.join().unwrap();
+    }
+}
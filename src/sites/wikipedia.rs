@@ -0,0 +1,192 @@
+/*
+ * Wikipedia articles, using the REST API page summary
+ * https://www.mediawiki.org/wiki/REST_API
+ *
+ */
+use failure::Error;
+use reqwest::header::HeaderMap;
+use url::Url;
+use url::percent_encoding::percent_decode;
+
+use super::super::config::Rtd;
+
+/// namespaces of pages which aren't articles, in English
+const NAMESPACES: &[&str] = &[
+    "Talk", "User", "Wikipedia", "File", "Image", "MediaWiki", "Template",
+    "Help", "Category", "Portal", "Draft", "TimedText", "Module", "Special",
+    "Media", "WP", "WT",
+];
+
+#[derive(Deserialize)]
+struct Summary {
+    title: String,
+    extract: String,
+    #[serde(rename = "type")]
+    page_type: String,
+}
+
+/// the title of the article a URL refers to, as it appears in the URL, and
+/// the section, if any
+fn article(url: &Url) -> Option<(&str, Option<String>)> {
+    let segments: Vec<&str> = url.path_segments()?.collect();
+
+    let title = match segments[..] {
+        ["wiki", title] if !title.is_empty() => title,
+        _ => return None,
+    };
+
+    // pages in other namespaces, e.g. Talk:Foo or User_talk:Foo
+    let decoded = percent_decode(title.as_bytes()).decode_utf8().ok()?;
+    if let Some(i) = decoded.find(':') {
+        let namespace = decoded[..i].trim_end_matches("_talk");
+        if NAMESPACES.iter().any(|n| n.eq_ignore_ascii_case(namespace)) {
+            return None;
+        }
+    }
+
+    let section = url.fragment()
+        .and_then(|s| percent_decode(s.as_bytes()).decode_utf8().ok())
+        .map(|s| s.replace('_', " "))
+        .filter(|s| !s.is_empty());
+
+    Some((title, section))
+}
+
+/// get a title for a Wikipedia URL, from the API of the wiki at `base`, or
+/// `None` if the URL isn't one of an article
+pub fn title(url: &Url, rtd: &Rtd, base: &str) -> Result<Option<String>, Error> {
+    let (title, section) = match article(url) {
+        Some(a) => a,
+        None => return Ok(None),
+    };
+
+    let url = format!("{}/api/rest_v1/page/summary/{}", base, title);
+    let summary: Summary = super::json(super::get(&url, rtd, HeaderMap::new())?)?;
+
+    // disambiguation pages have no useful summary
+    if summary.page_type != "standard" {
+        debug!("not summarising {} page", summary.page_type);
+        return Ok(None);
+    }
+
+    Ok(Some(summary_title(&summary, section)))
+}
+
+fn summary_title(summary: &Summary, section: Option<String>) -> String {
+    let mut title = summary.title.clone();
+
+    if let Some(section) = section {
+        title.push_str(&format!(" § {}", section));
+    }
+
+    match first_sentence(&summary.extract) {
+        Some(s) => format!("{} — {}", title, s),
+        None => title,
+    }
+}
+
+/// the first sentence of some text, ending in an ellipsis in place of the full
+/// stop if there is more
+fn first_sentence(text: &str) -> Option<String> {
+    let text = text.trim();
+    if text.is_empty() {
+        return None;
+    }
+
+    match text.find(". ") {
+        Some(i) => Some(format!("{}…", &text[..i])),
+        None => Some(text.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tiny_http;
+
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+    use self::tiny_http::{Response, Header};
+
+    fn article_of(url: &str) -> Option<(String, Option<String>)> {
+        let url = Url::parse(url).unwrap();
+        article(&url).map(|(t, s)| (t.to_string(), s))
+    }
+
+    #[test]
+    fn wikipedia_articles() {
+        assert_eq!(
+            article_of("https://en.wikipedia.org/wiki/Rust_(programming_language)"),
+            Some(("Rust_(programming_language)".to_string(), None))
+        );
+        assert_eq!(
+            article_of("https://en.wikipedia.org/wiki/Ferris#Early_life"),
+            Some(("Ferris".to_string(), Some("Early life".to_string())))
+        );
+        assert_eq!(
+            article_of("https://en.m.wikipedia.org/wiki/Star_Wars:_Episode_IV"),
+            Some(("Star_Wars:_Episode_IV".to_string(), None))
+        );
+        assert_eq!(
+            article_of("https://de.wikipedia.org/wiki/K%C3%A4se"),
+            Some(("K%C3%A4se".to_string(), None))
+        );
+
+        assert!(article_of("https://en.wikipedia.org/").is_none());
+        assert!(article_of("https://en.wikipedia.org/wiki/").is_none());
+        assert!(article_of("https://en.wikipedia.org/wiki/Special:Random").is_none());
+        assert!(article_of("https://en.wikipedia.org/wiki/Talk:Ferris").is_none());
+        assert!(article_of("https://en.wikipedia.org/wiki/User_talk:Someone").is_none());
+        assert!(article_of("https://en.wikipedia.org/wiki/File:Ferris.png").is_none());
+        assert!(article_of("https://en.wikipedia.org/w/index.php?title=Ferris").is_none());
+    }
+
+    #[test]
+    fn wikipedia_first_sentence() {
+        assert_eq!(first_sentence(""), None);
+        assert_eq!(first_sentence("One."), Some("One.".to_string()));
+        assert_eq!(first_sentence("One. Two."), Some("One…".to_string()));
+    }
+
+    #[test]
+    fn wikipedia_titles() {
+        let server_thread = thread::spawn(move || {
+            let server = tiny_http::Server::http("0.0.0.0:28504").unwrap();
+
+            for rq in server.incoming_requests().take(3) {
+                let body = match rq.url() {
+                    "/api/rest_v1/page/summary/Ferris" => r#"{"type": "standard",
+                        "title": "Ferris",
+                        "extract": "Ferris is a crab. He is a mascot."}"#,
+                    "/api/rest_v1/page/summary/Crab" => r#"{"type":
+                        "disambiguation", "title": "Crab", "extract":
+                        "Crab may refer to:"}"#,
+                    _ => {
+                        rq.respond(Response::empty(404)).unwrap();
+                        continue;
+                    },
+                };
+
+                let header = Header::from_bytes(&b"Content-Type"[..],
+                    &b"application/json"[..]).unwrap();
+                rq.respond(Response::from_string(body).with_header(header))
+                    .unwrap();
+            }
+        });
+
+        thread::sleep(Duration::from_millis(100));
+        let rtd = Rtd::default();
+        let base = "http://0.0.0.0:28504";
+        let title = |url| title(&Url::parse(url).unwrap(), &rtd, base);
+
+        assert_eq!(
+            title("https://en.wikipedia.org/wiki/Ferris#Early_life").unwrap().unwrap(),
+            "Ferris § Early life — Ferris is a crab…"
+        );
+        assert!(title("https://en.wikipedia.org/wiki/Crab").unwrap().is_none());
+        assert!(title("https://en.wikipedia.org/wiki/Missing").is_err());
+        assert!(title("https://en.wikipedia.org/wiki/Special:Random").unwrap().is_none());
+
+        server_thread.join().unwrap();
+    }
+}
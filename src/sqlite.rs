@@ -1,17 +1,47 @@
 use rusqlite::Connection;
 use failure::{Error, SyncFailure};
 use std::path::Path;
+use std::time::Duration;
 use serde_rusqlite::{from_rows, to_params_named};
 use time;
 use std::collections::HashMap;
 
+use super::config;
+
+/// the posts table, with the host of each URL, being the URL between the
+/// scheme and the path
+const POST_HOSTS: &str = "(
+    SELECT CASE WHEN instr(rest, '/') > 0
+        THEN substr(rest, 1, instr(rest, '/') - 1)
+        ELSE rest
+        END AS host,
+        user, channel, duration_ms
+    FROM (
+        SELECT substr(url, instr(url, '://') + 3) AS rest,
+            user, channel, duration_ms
+        FROM posts
+    )
+)";
+
 pub struct Database {
     db: Connection,
 }
 
 impl Database {
-    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+    /// open a database file, with the busy timeout and journal mode of the
+    /// `[database]` configuration
+    pub fn open(path: impl AsRef<Path>, conf: &config::Database) -> Result<Self, Error> {
         let db = Connection::open(path)?;
+        db.busy_timeout(Duration::from_millis(conf.busy_timeout_ms))?;
+
+        if conf.wal {
+            let mode: String = db.query_row("PRAGMA journal_mode = WAL", &[],
+                |row| row.get(0))?;
+            if !mode.eq_ignore_ascii_case("wal") {
+                warn!("unable to enable WAL mode, using journal mode {}", mode);
+            }
+        }
+
         Self::from_connection(db)
     }
 
@@ -27,7 +57,9 @@ impl Database {
             url             TEXT NOT NULL,
             user            TEXT NOT NULL,
             channel         TEXT NOT NULL,
-            time_created    TEXT NOT NULL
+            time_created    TEXT NOT NULL,
+            duration_ms     INTEGER,
+            redirect_chain  TEXT
             )",
             &[]
         )?;
@@ -38,10 +70,54 @@ impl Database {
             )",
             &[]
         )?;
+        db.execute("CREATE TABLE IF NOT EXISTS cookies (
+            domain          TEXT NOT NULL,
+            path            TEXT NOT NULL,
+            name            TEXT NOT NULL,
+            cookie          TEXT NOT NULL,
+            expires         INTEGER NOT NULL,
+            PRIMARY KEY (domain, path, name)
+            )",
+            &[]
+        )?;
+
+        db.execute("CREATE TABLE IF NOT EXISTS validators (
+            url             TEXT PRIMARY KEY,
+            page_url        TEXT NOT NULL,
+            etag            TEXT,
+            last_modified   TEXT,
+            title           TEXT NOT NULL,
+            canonical       TEXT,
+            mime            TEXT
+            )",
+            &[]
+        )?;
+
+        Self::migrate(&db)?;
 
         Ok(Self { db })
     }
 
+    /// bring databases created by earlier versions up to date
+    fn migrate(db: &Connection) -> Result<(), Error> {
+        let columns = {
+            let mut st = db.prepare("PRAGMA table_info(posts)")?;
+            let names = st.query_map(&[], |row| row.get::<_, String>(1))?;
+            names.collect::<Result<Vec<_>, _>>()?
+        };
+
+        if !columns.iter().any(|c| c == "duration_ms") {
+            info!("adding duration_ms column to posts table");
+            db.execute("ALTER TABLE posts ADD COLUMN duration_ms INTEGER", &[])?;
+        }
+        if !columns.iter().any(|c| c == "redirect_chain") {
+            info!("adding redirect_chain column to posts table");
+            db.execute("ALTER TABLE posts ADD COLUMN redirect_chain TEXT", &[])?;
+        }
+
+        Ok(())
+    }
+
     pub fn add_log(&self, entry: &NewLogEntry) -> Result<(), Error> {
         let time_created = time::now().to_local().ctime().to_string();
         let params = to_params_named(entry).map_err(SyncFailure::new)?;
@@ -49,8 +125,10 @@ impl Database {
         params.push((":time_created", &time_created));
 
         self.db.execute_named("
-            INSERT INTO posts ( title,  url,  user,  channel,  time_created)
-            VALUES            (:title, :url, :user, :channel, :time_created)",
+            INSERT INTO posts ( title,  url,  user,  channel,  time_created,  duration_ms,
+                                redirect_chain)
+            VALUES            (:title, :url, :user, :channel, :time_created, :duration_ms,
+                               :redirect_chain)",
             &params
         )?;
 
@@ -59,7 +137,7 @@ impl Database {
 
     pub fn check_prepost(&self, url: &str) -> Result<Option<PrevPost>, Error> {
         let mut st = self.db.prepare("
-            SELECT user, time_created, channel
+            SELECT user, time_created, channel, redirect_chain
             FROM posts
             WHERE url LIKE :url
         ")?;
@@ -82,6 +160,149 @@ impl Database {
 
         Ok(())
     }
+
+    /// hosts which took longest to resolve on average, slowest first
+    pub fn slowest_hosts(&self, limit: u32) -> Result<Vec<HostDuration>, Error> {
+        let mut st = self.db.prepare(&format!("
+            SELECT host,
+                COUNT(*) AS count,
+                CAST(AVG(duration_ms) AS INTEGER) AS mean_ms,
+                MAX(duration_ms) AS max_ms
+            FROM {}
+            WHERE duration_ms IS NOT NULL
+            GROUP BY host
+            ORDER BY mean_ms DESC
+            LIMIT :limit
+        ", POST_HOSTS))?;
+        let rows = st.query_named(&[(":limit", &limit)])?;
+
+        let hosts = from_rows::<HostDuration>(rows).collect();
+
+        Ok(hosts)
+    }
+
+    /// numbers of URLs posted, in total, and in a channel, with the user who
+    /// posted most URLs in the channel, and the host linked to most
+    pub fn channel_stats(&self, channel: &str) -> Result<ChannelStats, Error> {
+        let total = self.db.query_row("SELECT COUNT(*) FROM posts", &[], |row| row.get(0))?;
+        let in_channel = self.db.query_row_named(
+            "SELECT COUNT(*) FROM posts WHERE channel = :channel",
+            &[(":channel", &channel)],
+            |row| row.get(0)
+        )?;
+
+        let top = |sql: &str| -> Result<Option<(String, i64)>, Error> {
+            let mut st = self.db.prepare(sql)?;
+            let mut rows = st.query_named(&[(":channel", &channel)])?;
+            match rows.next() {
+                Some(row) => {
+                    let row = row?;
+                    Ok(Some((row.get(0), row.get(1))))
+                },
+                None => Ok(None),
+            }
+        };
+
+        let top_poster = top("
+            SELECT user, COUNT(*) AS count
+            FROM posts
+            WHERE channel = :channel
+            GROUP BY user
+            ORDER BY count DESC, user
+            LIMIT 1
+        ")?;
+        let top_host = top(&format!("
+            SELECT host, COUNT(*) AS count
+            FROM {}
+            WHERE channel = :channel
+            GROUP BY host
+            ORDER BY count DESC, host
+            LIMIT 1
+        ", POST_HOSTS))?;
+
+        Ok(ChannelStats { total, channel: in_channel, top_poster, top_host })
+    }
+
+    /// store a cookie, replacing any with the same domain, path and name
+    pub fn save_cookie(&self, cookie: &StoredCookie) -> Result<(), Error> {
+        let params = to_params_named(cookie).map_err(SyncFailure::new)?;
+        let params = params.to_slice();
+
+        self.db.execute_named("
+            INSERT OR REPLACE INTO cookies ( domain,  path,  name,  cookie,  expires)
+            VALUES                         (:domain, :path, :name, :cookie, :expires)",
+            &params
+        )?;
+
+        Ok(())
+    }
+
+    /// get unexpired cookies whose domain matches the given host, removing any
+    /// cookies which have expired
+    pub fn get_cookies(&self, host: &str) -> Result<Vec<StoredCookie>, Error> {
+        let now = time::now_utc().to_timespec().sec;
+
+        self.db.execute_named("
+            DELETE FROM cookies WHERE expires <= :now",
+            &[(":now", &now)]
+        )?;
+
+        let mut st = self.db.prepare("
+            SELECT domain, path, name, cookie, expires
+            FROM cookies
+            WHERE domain = :host
+            OR substr(:host, -length(domain) - 1) = '.' || domain
+        ")?;
+        let rows = st.query_named(&[(":host", &host)])?;
+
+        let cookies = from_rows::<StoredCookie>(rows).collect();
+
+        Ok(cookies)
+    }
+
+    /// store the validators of a page, replacing any for the same URL
+    pub fn save_validator(&self, validator: &StoredValidator) -> Result<(), Error> {
+        let params = to_params_named(validator).map_err(SyncFailure::new)?;
+        let params = params.to_slice();
+
+        self.db.execute_named("
+            INSERT OR REPLACE INTO validators ( url,  page_url,  etag,  last_modified,
+                                                title,  canonical,  mime)
+            VALUES                            (:url, :page_url, :etag, :last_modified,
+                                               :title, :canonical, :mime)",
+            &params
+        )?;
+
+        Ok(())
+    }
+
+    /// get the validators stored for a URL, if any
+    pub fn get_validator(&self, url: &str) -> Result<Option<StoredValidator>, Error> {
+        let mut st = self.db.prepare("
+            SELECT url, page_url, etag, last_modified, title, canonical, mime
+            FROM validators
+            WHERE url = :url
+        ")?;
+        let rows = st.query_named(&[(":url", &url)])?;
+        let mut rows = from_rows::<StoredValidator>(rows);
+
+        Ok(rows.next())
+    }
+
+    /// the post logged most recently in a channel, if any
+    pub fn last_entry(&self, channel: &str) -> Result<Option<LogEntry>, Error> {
+        let mut st = self.db.prepare("
+            SELECT title, url, user, time_created
+            FROM posts
+            WHERE channel = :channel
+            ORDER BY id DESC
+            LIMIT 1
+        ")?;
+        let rows = st.query_named(&[(":channel", &channel)])?;
+        let mut rows = from_rows::<LogEntry>(rows);
+
+        Ok(rows.next())
+    }
 }
 
 #[derive(Debug, Serialize)]
@@ -90,13 +311,49 @@ pub struct NewLogEntry<'a> {
     pub url: &'a str,
     pub user: &'a str,
     pub channel: &'a str,
+    /// time taken to resolve the title
+    pub duration_ms: Option<i64>,
+    /// URLs requested, as a JSON array, if logged
+    pub redirect_chain: Option<&'a str>,
+}
+
+/// a post logged, URLs posted before not being logged again
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct LogEntry {
+    pub title: String,
+    pub url: String,
+    pub user: String,
+    pub time_created: String,
 }
 
 #[derive(Debug, Default, Deserialize)]
 pub struct PrevPost {
     pub user: String,
     pub time_created: String,
-    pub channel: String
+    pub channel: String,
+    /// URLs requested, as a JSON array, if logged
+    pub redirect_chain: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct HostDuration {
+    pub host: String,
+    /// number of posts of URLs on the host
+    pub count: i64,
+    pub mean_ms: i64,
+    pub max_ms: i64,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct ChannelStats {
+    /// number of URLs posted, in all channels
+    pub total: i64,
+    /// number of URLs posted in the channel
+    pub channel: i64,
+    /// the user who has posted most URLs in the channel, and how many
+    pub top_poster: Option<(String, i64)>,
+    /// the host linked to most in the channel, and how many times
+    pub top_host: Option<(String, i64)>,
 }
 
 #[derive(Default, Serialize)]
@@ -107,8 +364,181 @@ pub struct ErrorInfo<'a> {
     pub headers: HashMap<&'a str, &'a str>,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StoredCookie {
+    pub domain: String,
+    pub path: String,
+    pub name: String,
+    /// the cookie, as it would appear in a Set-Cookie header
+    pub cookie: String,
+    /// expiry time, in seconds since the epoch
+    pub expires: i64,
+}
+
+/// the validators of a page, with which it's requested conditionally, and the
+/// title to reuse if it hasn't been modified
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StoredValidator {
+    /// the URL resolved
+    pub url: String,
+    /// the URL of the page, after any redirections
+    pub page_url: String,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    pub title: String,
+    pub canonical: Option<String>,
+    pub mime: Option<String>,
+}
+
 #[derive(Debug, Serialize)]
 pub struct UrlError<'a> {
     pub url: &'a str,
     pub error_info: &'a str,
 }
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use super::*;
+
+    fn entry(url: &str, duration_ms: Option<i64>) -> NewLogEntry {
+        NewLogEntry {
+            title: "title", url, user: "user", channel: "#chan", duration_ms,
+            redirect_chain: None,
+        }
+    }
+
+    #[test]
+    fn apply_pragmas() {
+        let dir = tempfile::tempdir().unwrap();
+        let busy_timeout = |db: &Database| db.db
+            .query_row("PRAGMA busy_timeout", &[], |row| row.get::<_, i64>(0))
+            .unwrap();
+        let journal_mode = |db: &Database| db.db
+            .query_row("PRAGMA journal_mode", &[], |row| row.get::<_, String>(0))
+            .unwrap();
+
+        let conf = config::Database::default();
+        let db = Database::open(dir.path().join("default.db"), &conf).unwrap();
+        assert_eq!(busy_timeout(&db), 5000);
+        assert_eq!(journal_mode(&db), "delete");
+
+        let conf = config::Database {
+            busy_timeout_ms: 1234,
+            wal: true,
+            ..config::Database::default()
+        };
+        let db = Database::open(dir.path().join("wal.db"), &conf).unwrap();
+        assert_eq!(busy_timeout(&db), 1234);
+        assert_eq!(journal_mode(&db), "wal");
+        db.add_log(&entry("http://z.zzz/", None)).unwrap();
+        assert!(db.check_prepost("http://z.zzz/").unwrap().is_some());
+    }
+
+    #[test]
+    fn slowest_hosts() {
+        let db = Database::open_in_memory().unwrap();
+
+        for e in &[
+            entry("https://fast.zzz/a", Some(10)),
+            entry("https://fast.zzz/b", Some(30)),
+            entry("https://slow.zzz/a?b=c", Some(1000)),
+            entry("http://slower.zzz", Some(2000)),
+            entry("http://untimed.zzz/", None),
+        ] {
+            db.add_log(e).unwrap();
+        }
+
+        let host = |host: &str, count, mean_ms, max_ms| HostDuration {
+            host: host.to_string(), count, mean_ms, max_ms
+        };
+        assert_eq!(db.slowest_hosts(10).unwrap(), vec![
+            host("slower.zzz", 1, 2000, 2000),
+            host("slow.zzz", 1, 1000, 1000),
+            host("fast.zzz", 2, 20, 30),
+        ]);
+        assert_eq!(db.slowest_hosts(1).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn channel_stats() {
+        let db = Database::open_in_memory().unwrap();
+        let post = |url, user, channel| NewLogEntry {
+            title: "title", url, user, channel, duration_ms: None, redirect_chain: None,
+        };
+
+        assert_eq!(db.channel_stats("#chan").unwrap(), ChannelStats::default());
+
+        for e in &[
+            post("https://b.zzz/3", "alice", "#chan"),
+            post("https://a.zzz/2", "bob", "#chan"),
+            post("https://b.zzz/1", "bob", "#chan"),
+            post("http://b.zzz", "carol", "#chan"),
+            post("https://b.zzz/2?c=d", "alice", "#other"),
+            post("https://c.zzz/", "carol", "#other"),
+        ] {
+            db.add_log(e).unwrap();
+        }
+
+        // ties are broken alphabetically
+        assert_eq!(db.channel_stats("#chan").unwrap(), ChannelStats {
+            total: 6,
+            channel: 4,
+            top_poster: Some(("bob".to_string(), 2)),
+            top_host: Some(("b.zzz".to_string(), 3)),
+        });
+        assert_eq!(db.channel_stats("#other").unwrap(), ChannelStats {
+            total: 6,
+            channel: 2,
+            top_poster: Some(("alice".to_string(), 1)),
+            top_host: Some(("b.zzz".to_string(), 1)),
+        });
+        assert_eq!(db.channel_stats("#none").unwrap().top_poster, None);
+    }
+
+    #[test]
+    fn last_entry() {
+        let db = Database::open_in_memory().unwrap();
+        let post = |title, url, channel| NewLogEntry {
+            title, url, user: "user", channel, duration_ms: None, redirect_chain: None,
+        };
+
+        assert_eq!(db.last_entry("#chan").unwrap(), None);
+
+        for e in &[
+            post("first", "https://a.zzz/1", "#chan"),
+            post("second", "https://a.zzz/2", "#chan"),
+            post("other", "https://b.zzz/", "#other"),
+        ] {
+            db.add_log(e).unwrap();
+        }
+
+        let last = |channel| db.last_entry(channel).unwrap().map(|e| (e.title, e.url));
+        assert_eq!(last("#chan"), Some(("second".to_string(), "https://a.zzz/2".to_string())));
+        assert_eq!(last("#other"), Some(("other".to_string(), "https://b.zzz/".to_string())));
+        assert_eq!(last("#none"), None);
+    }
+
+    #[test]
+    fn migrate_posts_without_durations() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute("CREATE TABLE posts (
+            id              INTEGER PRIMARY KEY,
+            title           TEXT NOT NULL,
+            url             TEXT NOT NULL,
+            user            TEXT NOT NULL,
+            channel         TEXT NOT NULL,
+            time_created    TEXT NOT NULL
+            )",
+            &[]
+        ).unwrap();
+
+        let db = Database::from_connection(conn).unwrap();
+        db.add_log(&entry("https://z.zzz/", Some(5))).unwrap();
+        assert_eq!(db.slowest_hosts(10).unwrap()[0].mean_ms, 5);
+
+        // migration is idempotent
+        Database::migrate(&db.db).unwrap();
+    }
+}
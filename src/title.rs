@@ -1,52 +1,397 @@
 use itertools::Itertools;
 use image::{gif, jpeg, png, ImageDecoder};
-use mime::Mime;
-use scraper::{Html, Selector};
+use mime::{Mime, TEXT, XML};
+use humansize::{FileSize, file_size_opts as options};
+use scraper::{ElementRef, Html, Selector};
+use url::Url;
+use url::percent_encoding::percent_decode;
 
 use super::config::Rtd;
 
-/// Format a mime string
-pub fn get_mime(rtd: &Rtd, mime: &Mime, size: &str) -> Option<String> {
-    if rtd.conf.features.report_mime {
-        Some(format!("{} {}", mime, size.replace(" ", "")))
+/// separators between the title of a page, and the name of the site
+const SITE_SEPARATORS: &[&str] = &[" - ", " | ", " – ", " — ", " · "];
+
+/// Format a mime string, with the human-readable size of the content of length
+/// `len`, unless smaller than `params.min_size_report_bytes`. The size is
+/// omitted where the length isn't known, e.g. for a chunked response without a
+/// Content-Length header, since only part of the content may be downloaded.
+/// The name of a downloaded file is given first, if known.
+pub fn get_mime(rtd: &Rtd, mime: &Mime, len: Option<u64>, filename: Option<&str>)
+    -> Option<String>
+{
+    if !rtd.conf.features.report_mime {
+        return None;
+    }
+
+    let report = match len {
+        Some(len) if len >= rtd.conf.params.min_size_report_bytes => {
+            let size = len.file_size(options::CONVENTIONAL).unwrap_or_default();
+            format!("{} {}", mime, size.replace(" ", ""))
+        },
+        _ => mime.to_string(),
+    };
+
+    match filename {
+        Some(name) => Some(format!("{} ({})", name, report)),
+        None => Some(report),
+    }
+}
+
+/// Find the name of a file in a Content-Disposition header, preferring the
+/// RFC 5987 encoded `filename*` parameter to `filename`. Only the last
+/// component of a path is kept.
+pub fn disposition_filename(header: &str) -> Option<String> {
+    let mut plain = None;
+    let mut encoded = None;
+
+    for param in split_params(header).iter().skip(1) {
+        let i = match param.find('=') {
+            Some(i) => i,
+            None => continue,
+        };
+        let (name, value) = (param[..i].trim(), param[i + 1..].trim());
+
+        if name.eq_ignore_ascii_case("filename*") {
+            encoded = decode_ext_value(value);
+        } else if name.eq_ignore_ascii_case("filename") {
+            plain = Some(unquote(value));
+        }
+    }
+
+    let name = encoded.or(plain)?;
+    let name = name.rsplit(|c| c == '/' || c == '\\').next()?;
+    let name = strip_unsafe_chars(name).replace('\n', " ");
+    let name = name.trim();
+
+    if name.is_empty() {
+        None
+    } else {
+        Some(name.to_string())
+    }
+}
+
+/// split a header value into `;` separated parameters, outside quoted strings
+fn split_params(header: &str) -> Vec<String> {
+    let mut params = vec![String::new()];
+    let (mut quoted, mut escaped) = (false, false);
+
+    for c in header.chars() {
+        match c {
+            ';' if !quoted => params.push(String::new()),
+            '"' if !escaped => quoted = !quoted,
+            _ => (),
+        }
+        escaped = quoted && !escaped && c == '\\';
+        if c != ';' || quoted {
+            params.last_mut().unwrap().push(c);
+        }
+    }
+
+    params
+}
+
+/// the content of a possibly quoted string
+fn unquote(value: &str) -> String {
+    if value.len() < 2 || !value.starts_with('"') || !value.ends_with('"') {
+        return value.to_string();
+    }
+
+    let mut unquoted = String::new();
+    let mut escaped = false;
+    for c in value[1..value.len() - 1].chars() {
+        if c == '\\' && !escaped {
+            escaped = true;
+            continue;
+        }
+        unquoted.push(c);
+        escaped = false;
+    }
+
+    unquoted
+}
+
+/// decode an RFC 5987 extended value, e.g. `UTF-8''%E2%82%AC%20rates`, in
+/// either of the UTF-8 or ISO-8859-1 charsets
+fn decode_ext_value(value: &str) -> Option<String> {
+    let mut parts = value.splitn(3, '\'');
+    let charset = parts.next()?;
+    let bytes: Vec<u8> = percent_decode(parts.nth(1)?.as_bytes()).collect();
+
+    if charset.eq_ignore_ascii_case("utf-8") {
+        String::from_utf8(bytes).ok()
+    } else if charset.eq_ignore_ascii_case("iso-8859-1") {
+        Some(bytes.iter().map(|&b| char::from(b)).collect())
     } else {
         None
     }
 }
 
-/// Attempt to get metadata from an image
+/// Whether a title may be found in content of a mime type, i.e. text, or XML,
+/// e.g. `application/xhtml+xml`, or where the type isn't given, in content
+/// which appears to be text, rather than binary
+pub fn is_textual(mime: Option<&Mime>, body: &[u8]) -> bool {
+    match mime {
+        Some(m) => m.type_() == TEXT || m.subtype() == XML || m.suffix() == Some(XML),
+        None => !body.contains(&0),
+    }
+}
+
+/// Whether metadata and mime types are reported for a mime type, reporting is
+/// enabled for all types if `features.report_types` is empty, otherwise for
+/// types matching an entry, e.g. `image/png`, `image/*` or `image`
+pub fn report_type(rtd: &Rtd, mime: &Mime) -> bool {
+    let types = &rtd.conf.features.report_types;
+
+    types.is_empty() || type_matches(types, mime)
+}
+
+/// Whether responses of a mime type are downloaded, all types are downloaded
+/// if `features.content_type_allowlist` is empty
+pub fn allowed_type(rtd: &Rtd, mime: &Mime) -> bool {
+    let types = &rtd.conf.features.content_type_allowlist;
+
+    types.is_empty() || type_matches(types, mime)
+}
+
+/// does a mime type match any of a list of types, e.g. `image/png`, `image/*`
+/// or `image`
+fn type_matches(types: &[String], mime: &Mime) -> bool {
+    types.iter().any(|t| {
+        let mut parts = t.trim().splitn(2, '/');
+        let type_ = parts.next().unwrap_or_default();
+        let subtype = parts.next().unwrap_or("*");
+
+        type_.eq_ignore_ascii_case(mime.type_().as_str())
+            && (subtype == "*" || subtype.eq_ignore_ascii_case(mime.subtype().as_str()))
+    })
+}
+
+/// Attempt to get metadata from an image. Only the headers of images are read,
+/// and images declaring more than `params.max_image_pixels` pixels aren't
+/// passed to a decoder at all, as a crafted header may claim dimensions for
+/// which a decoder would allocate huge buffers.
 pub fn get_image_metadata(rtd: &Rtd, body: &[u8]) -> Option<String> {
     if !rtd.conf.features.report_metadata {
+        return None;
+    }
+
+    let max = rtd.conf.params.max_image_pixels;
+    let within_budget = |(w, h): (u32, u32)| {
+        let fits = u64::from(w) * u64::from(h) <= max;
+        if !fits {
+            debug!("image too large, {}×{}, max {} pixels", w, h, max);
+        }
+        fits
+    };
+
+    if !header_dimensions(body).map_or(true, &within_budget) {
         None
     } else if let Ok((w, h)) = jpeg::JPEGDecoder::new(body).dimensions() {
-        Some(format!("image/jpeg {}×{}", w, h))
+        Some(format!("image/jpeg {}×{}", w, h)).filter(|_| within_budget((w, h)))
     } else if let Ok((w, h)) = png::PNGDecoder::new(body).dimensions() {
         Some(format!("image/png {}×{}", w, h))
     } else if let Ok((w, h)) = gif::Decoder::new(body).dimensions() {
-        Some(format!("image/gif {}×{}", w, h))
+        let frames = match gif_frames(body, rtd.conf.params.max_gif_frames) {
+            (n, true) if n > 1 => format!(" (animated, {} frames)", n),
+            (n, false) if n > 1 => format!(" (animated, {}+ frames)", n),
+            _ => String::new(),
+        };
+        Some(format!("image/gif {}×{}{}", w, h, frames))
+    } else {
+        None
+    }
+}
+
+/// The dimensions declared in the header of a PNG or GIF, read without a
+/// decoder. JPEG dimensions come after variable length segments, which the
+/// JPEG decoder reads without allocating for the image.
+fn header_dimensions(body: &[u8]) -> Option<(u32, u32)> {
+    let be32 = |i: usize| u32::from(body[i]) << 24 | u32::from(body[i + 1]) << 16
+        | u32::from(body[i + 2]) << 8 | u32::from(body[i + 3]);
+    let le16 = |i: usize| u32::from(body[i]) | u32::from(body[i + 1]) << 8;
+
+    if body.len() >= 24 && body.starts_with(b"\x89PNG\r\n\x1a\n") && &body[12..16] == b"IHDR" {
+        Some((be32(16), be32(20)))
+    } else if body.len() >= 10 && (body.starts_with(b"GIF87a") || body.starts_with(b"GIF89a")) {
+        Some((le16(6), le16(8)))
     } else {
         None
     }
 }
 
-/// Attempt to parse HTML for a page title
-fn parse_html_title(page_contents: &str) -> Option<String> {
+/// Attempt to parse HTML for the titles of a page, of which malformed pages
+/// may have several, in document order, those in the head first
+fn parse_html_titles(page_contents: &str) -> Vec<String> {
     let fragment = Html::parse_document(page_contents);
     let title_selector = Selector::parse("title").unwrap();
 
+    // SVG and MathML have title elements of their own, which can come before
+    // the document title, e.g. where an inline SVG in the head ends it early
     fragment
         .select(&title_selector)
+        .filter(|n| !in_foreign_content(n))
+        .map(|n| n.text().collect())
+        .collect()
+}
+
+/// whether an element is within an inline `<svg>` or `<math>` element
+fn in_foreign_content(element: &ElementRef) -> bool {
+    element.ancestors()
+        .filter_map(|n| n.value().as_element())
+        .any(|e| e.name() == "svg" || e.name() == "math")
+}
+
+/// Attempt to find a title in the metadata of a page, from the Open Graph or
+/// Twitter title, or the description
+fn parse_meta_title(page_contents: &str) -> Option<String> {
+    meta_content(page_contents, &[
+        "meta[property=\"og:title\"][content]",
+        "meta[name=\"twitter:title\"][content]",
+        "meta[name=\"description\"][content]",
+    ])
+}
+
+/// Attempt to find the title of a page for sharing, the Open Graph or Twitter
+/// title
+fn parse_social_title(page_contents: &str) -> Option<String> {
+    meta_content(page_contents, &[
+        "meta[property=\"og:title\"][content]",
+        "meta[name=\"twitter:title\"][content]",
+    ])
+}
+
+/// the content of the first of the selected meta elements which isn't empty,
+/// in order of the selectors
+fn meta_content(page_contents: &str, selectors: &[&str]) -> Option<String> {
+    let fragment = Html::parse_document(page_contents);
+
+    selectors.iter()
+        .filter_map(|s| {
+            let selector = Selector::parse(s).unwrap();
+            fragment.select(&selector)
+                .filter_map(|n| n.value().attr("content"))
+                .filter_map(clean_title)
+                .next()
+        })
         .next()
-        .and_then(|n| Some(n.text().collect()))
 }
 
-/// Attempt to extract a page title from downloaded HTML
-pub fn parse_title(page_contents: &str) -> Option<String> {
-    let title_dec = match parse_html_title(page_contents) {
-        Some(t) => t,
-        None => return None,
+/// Append the Open Graph or Twitter title of a page to its title, where they
+/// differ substantially, sharing fewer than half of the words of the shorter
+fn with_social_title(page_contents: &str, title: String) -> String {
+    let social = match parse_social_title(page_contents) {
+        Some(social) => social,
+        None => return title,
     };
 
+    let words = |s: &str| -> Vec<String> {
+        s.split(|c: char| !c.is_alphanumeric())
+            .filter(|w| !w.is_empty())
+            .map(str::to_lowercase)
+            .unique()
+            .collect()
+    };
+    let (a, b) = (words(&title), words(&social));
+    let shared = a.iter().filter(|w| b.contains(w)).count();
+    let shorter = a.len().min(b.len());
+
+    if shorter == 0 || shared * 2 >= shorter {
+        title
+    } else {
+        format!("{} (shared as: {})", title, social)
+    }
+}
+
+/// Append any substantially different Open Graph or Twitter title to the
+/// title of a page, if `features.show_title_mismatch` is enabled
+pub fn title_mismatch(rtd: &Rtd, page_contents: &str, title: String) -> String {
+    if rtd.conf.features.show_title_mismatch {
+        with_social_title(page_contents, title)
+    } else {
+        title
+    }
+}
+
+/// Remove a site name following a separator at the end of the title of a
+/// page, e.g. ` - YouTube` or ` | GitHub`, if `features.trim_site_suffix` is
+/// enabled, and the name matches a label of the host of the page
+pub fn trim_site_suffix(rtd: &Rtd, url: &Url, title: String) -> String {
+    if !rtd.conf.features.trim_site_suffix {
+        return title;
+    }
+
+    let trimmed = url.host_str().and_then(|host| without_site_suffix(host, &title));
+    trimmed.unwrap_or(title)
+}
+
+/// a title without a trailing site name, if it has one matching a label of
+/// the host, other than the top-level domain, ignoring case, spaces, and
+/// punctuation, e.g. `Stack Overflow` on stackoverflow.com
+fn without_site_suffix(host: &str, title: &str) -> Option<String> {
+    let (start, end) = SITE_SEPARATORS.iter()
+        .filter_map(|sep| title.rfind(sep).map(|i| (i, i + sep.len())))
+        .max()?;
+
+    let name: String = title[end..].chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect();
+    let rest = title[..start].trim_end();
+    if name.is_empty() || rest.is_empty() {
+        return None;
+    }
+
+    let host = host.to_lowercase();
+    let labels: Vec<&str> = host.split('.').collect();
+    labels[..labels.len() - 1].iter()
+        .find(|label| label.replace('-', "") == name)
+        .map(|_| rest.to_string())
+}
+
+/// Attempt to extract a page title from downloaded HTML, the first which
+/// isn't empty, where there are several, falling back to the metadata of the
+/// page where there are none
+pub fn parse_title(page_contents: &str) -> Option<String> {
+    parse_html_titles(page_contents)
+        .iter()
+        .filter_map(|t| clean_title(t))
+        .next()
+        .or_else(|| parse_meta_title(page_contents))
+}
+
+/// Attempt to extract a page title from downloaded HTML, as `parse_title`,
+/// but only from the first title, even if it's empty
+pub fn parse_first_title(page_contents: &str) -> Option<String> {
+    parse_html_titles(page_contents)
+        .first()
+        .and_then(|t| clean_title(t))
+        .or_else(|| parse_meta_title(page_contents))
+}
+
+/// Attempt to extract a title from a gemtext document, the first top-level
+/// heading, outside of preformatted blocks
+pub fn parse_gemtext_title(contents: &str) -> Option<String> {
+    let mut preformatted = false;
+
+    for line in contents.lines() {
+        if line.starts_with("```") {
+            preformatted = !preformatted;
+        } else if !preformatted && line.starts_with('#') && !line.starts_with("##") {
+            if let Some(title) = clean_title(&line[1..]) {
+                return Some(title);
+            }
+        }
+    }
+
+    None
+}
+
+/// Make a title safe to display, on a single line, or `None` if there's
+/// nothing left of it
+fn clean_title(title_dec: &str) -> Option<String> {
+    // remove characters which could be used to disguise the title
+    let title_dec = strip_unsafe_chars(title_dec);
+
     // make any multi-line title string into a single line,
     // trim leading and trailing whitespace
     let title_one_line = title_dec
@@ -62,10 +407,167 @@ pub fn parse_title(page_contents: &str) -> Option<String> {
     Some(title_one_line)
 }
 
+/// Remove control characters (other than line breaks, and tabs, which are
+/// replaced with spaces), bidirectional formatting characters, and invisible
+/// zero-width characters. The zero-width (non-)joiners are kept, since they are
+/// used in emoji sequences, and some scripts.
+fn strip_unsafe_chars(s: &str) -> String {
+    s.chars()
+        .map(|c| if c == '\t' { ' ' } else { c })
+        .filter(|&c| c == '\n' || !is_unsafe_char(c))
+        .collect()
+}
+
+fn is_unsafe_char(c: char) -> bool {
+    match c {
+        // C0 and C1 controls, and delete
+        '\u{0}'..='\u{1F}' | '\u{7F}'..='\u{9F}' => true,
+        // bidirectional marks, embeddings, overrides and isolates
+        '\u{61C}' | '\u{200E}' | '\u{200F}' | '\u{202A}'..='\u{202E}'
+            | '\u{2066}'..='\u{2069}' => true,
+        // zero-width space, word joiner, invisible operators, and BOM
+        '\u{200B}' | '\u{2060}'..='\u{2064}' | '\u{FEFF}' => true,
+        _ => false,
+    }
+}
+
+/// Attempt to find the canonical URL of a page in downloaded HTML
+pub fn parse_canonical(page_contents: &str) -> Option<String> {
+    let fragment = Html::parse_document(page_contents);
+    let link_selector = Selector::parse("link[rel][href]").unwrap();
+
+    fragment
+        .select(&link_selector)
+        .find(|n| n.value().attr("rel")
+            .map(|r| r.split_whitespace().any(|r| r.eq_ignore_ascii_case("canonical")))
+            .unwrap_or(false))
+        .and_then(|n| n.value().attr("href"))
+        .map(str::trim)
+        .filter(|h| !h.is_empty())
+        .map(String::from)
+}
+
+/// Attempt to find the language of a page, from the `lang` attribute of the
+/// `<html>` element
+pub fn parse_language(page_contents: &str) -> Option<String> {
+    let fragment = Html::parse_document(page_contents);
+    let html_selector = Selector::parse("html[lang]").unwrap();
+
+    fragment
+        .select(&html_selector)
+        .next()
+        .and_then(|n| n.value().attr("lang"))
+        .and_then(language_tag)
+}
+
+/// Validate a language tag, e.g. `en-GB`, the first of a list of tags is used
+/// (as may be given in the Content-Language header)
+pub fn language_tag(tag: &str) -> Option<String> {
+    let tag = tag.split(',').next()?.trim();
+
+    if tag.is_empty()
+        || tag.len() > 35
+        || !tag.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    {
+        return None;
+    }
+
+    Some(tag.to_string())
+}
+
+/// Attempt to find a meta refresh redirection in downloaded HTML, returning
+/// the delay in seconds, and the target URL
+pub fn parse_meta_refresh(page_contents: &str) -> Option<(u64, String)> {
+    let fragment = Html::parse_document(page_contents);
+    let meta_selector = Selector::parse("meta[http-equiv]").unwrap();
+
+    fragment
+        .select(&meta_selector)
+        .find(|n| n.value().attr("http-equiv")
+            .map(|v| v.trim().eq_ignore_ascii_case("refresh"))
+            .unwrap_or(false))
+        .and_then(|n| n.value().attr("content"))
+        .and_then(parse_refresh_content)
+}
+
+/// Parse the content of a meta refresh, e.g. `0; url=http://z.zzz/`
+fn parse_refresh_content(content: &str) -> Option<(u64, String)> {
+    let mut parts = content.trim().splitn(2, |c| c == ';' || c == ',');
+
+    let delay = parts.next()?.trim().split('.').next()?.parse().ok()?;
+
+    let mut target = parts.next()?.trim();
+    let mut kv = target.splitn(2, '=');
+    if let (Some(k), Some(v)) = (kv.next(), kv.next()) {
+        if k.trim().eq_ignore_ascii_case("url") {
+            target = v;
+        }
+    }
+
+    let target = target.trim().trim_matches(|c| c == '\'' || c == '"').trim();
+
+    if target.is_empty() {
+        None
+    } else {
+        Some((delay, target.to_string()))
+    }
+}
+
+/// Count the frames of a GIF, up to `max`, from the structure of its blocks,
+/// without decoding them, as decoding all frames may be costly. Also returns
+/// whether the count is complete, it isn't if the GIF is truncated, e.g. if
+/// only part of it has been downloaded, or has more than `max` frames.
+fn gif_frames(body: &[u8], max: usize) -> (usize, bool) {
+    // size of a colour table, given the flags of a block which may have one
+    let table_size = |flags: u8| {
+        if flags & 0x80 != 0 { 3 << ((flags & 0x07) + 1) } else { 0 }
+    };
+
+    // position after a sequence of data sub-blocks, if not truncated
+    let skip_sub_blocks = |mut pos: usize| -> Option<usize> {
+        loop {
+            let len = *body.get(pos)? as usize;
+            pos += len + 1;
+            if len == 0 {
+                return Some(pos);
+            }
+        }
+    };
+
+    if body.len() < 13 || !(body.starts_with(b"GIF87a") || body.starts_with(b"GIF89a")) {
+        return (0, false);
+    }
+
+    let mut frames = 0;
+    let mut pos = 13 + table_size(body[10]);
+
+    loop {
+        let next = match body.get(pos) {
+            // a frame beyond the limit
+            Some(0x2C) if frames >= max => return (max, false),
+            // image descriptor, followed by a colour table, and image data
+            Some(0x2C) => body.get(pos + 9)
+                .map(|&flags| pos + 10 + table_size(flags) + 1)
+                .and_then(skip_sub_blocks)
+                .map(|next| { frames += 1; next }),
+            // extension
+            Some(0x21) => skip_sub_blocks(pos + 2),
+            // trailer
+            Some(0x3B) => return (frames, true),
+            _ => None,
+        };
+
+        pos = match next {
+            Some(next) => next,
+            None => return (frames, false),
+        };
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::fs::File;
+    use std::fs::{self, File};
     use std::path::Path;
     use std::io::Read;
 
@@ -134,17 +636,428 @@ mod tests {
         );
     }
 
+    #[test]
+    fn parse_multiple_titles() {
+        let page = "<head><title> </title><title>second</title></head>\
+            <body><title>third</title></body>";
+        assert_eq!(Some(String::from("second")), parse_title(page));
+        assert_eq!(None, parse_first_title(page));
+
+        let page = "<head><title>first</title><title>second</title></head>";
+        assert_eq!(Some(String::from("first")), parse_title(page));
+        assert_eq!(Some(String::from("first")), parse_first_title(page));
+
+        // titles in the head come before any misplaced in the body
+        let page = "<head><title>\n</title></head><body><title>body</title>\
+            <title>later</title></body>";
+        assert_eq!(Some(String::from("body")), parse_title(page));
+
+        // with the metadata used if there is no title
+        let page = "<title></title><title>\u{200B}</title>\
+            <meta name=\"description\" content=\"described\">";
+        assert_eq!(Some(String::from("described")), parse_title(page));
+        assert_eq!(Some(String::from("described")), parse_first_title(page));
+    }
+
+    #[test]
+    fn parse_gemtext_titles() {
+        let doc = "```\n# not a heading\n```\n## Subheading\n#  \n# Gemini  title \n# Second\n";
+        assert_eq!(Some(String::from("Gemini  title")), parse_gemtext_title(doc));
+        assert_eq!(None, parse_gemtext_title("## Subheading\nText\n"));
+        assert_eq!(None, parse_gemtext_title("```\n# Unterminated\n"));
+    }
+
+    #[test]
+    fn parse_meta_titles() {
+        let page = ::std::fs::read_to_string("./test/html/empty-title.html").unwrap();
+        assert_eq!(None, parse_title(&page));
+
+        let meta = |m| format!("<title> </title><meta {}>", m);
+        assert_eq!(
+            Some(String::from("og title")),
+            parse_title(&meta("property=\"og:title\" content=\" og\ntitle\""))
+        );
+        assert_eq!(
+            Some(String::from("twitter title")),
+            parse_title(&meta("name=\"twitter:title\" content=\"twitter title\""))
+        );
+        assert_eq!(
+            Some(String::from("a description")),
+            parse_title(&meta("name=\"description\" content=\"a description\""))
+        );
+        assert_eq!(None, parse_title(&meta("name=\"description\" content=\" \"")));
+        assert_eq!(
+            Some(String::from("title")),
+            parse_title("<title>title</title><meta name=\"description\" content=\"d\">")
+        );
+    }
+
+    #[test]
+    fn show_title_mismatches() {
+        let page = |og| format!("<title>Local man wins lottery | News</title>\
+            <meta property=\"og:title\" content=\"{}\">", og);
+        let title = "Local man wins lottery | News".to_string();
+        let mut rtd = Rtd::default();
+
+        let clickbait = page("You won't believe what happened next");
+        assert_eq!(title_mismatch(&rtd, &clickbait, title.clone()), title);
+
+        rtd.conf.features.show_title_mismatch = true;
+        assert_eq!(
+            title_mismatch(&rtd, &clickbait, title.clone()),
+            "Local man wins lottery | News (shared as: You won't believe what happened next)"
+        );
+
+        // titles differing only by the site name, or case, aren't flagged
+        for same in &["Local man wins lottery", "LOCAL MAN WINS THE LOTTERY!"] {
+            assert_eq!(title_mismatch(&rtd, &page(same), title.clone()), title);
+        }
+        let untitled = "<title>t</title><meta name=\"description\" content=\"d\">";
+        assert_eq!(title_mismatch(&rtd, untitled, "t".to_string()), "t");
+    }
+
+    #[test]
+    fn trim_site_suffixes() {
+        let mut rtd = Rtd::default();
+        let trim = |rtd: &Rtd, url: &str, title: &str| {
+            trim_site_suffix(rtd, &Url::parse(url).unwrap(), title.to_string())
+        };
+
+        let video = "Rick Astley - Never Gonna Give You Up - YouTube";
+        assert_eq!(trim(&rtd, "https://www.youtube.com/watch?v=x", video), video);
+
+        rtd.conf.features.trim_site_suffix = true;
+        assert_eq!(trim(&rtd, "https://www.youtube.com/watch?v=x", video),
+            "Rick Astley - Never Gonna Give You Up");
+        assert_eq!(trim(&rtd, "https://github.com/x/y", "x/y: A tool | GitHub"),
+            "x/y: A tool");
+        assert_eq!(trim(&rtd, "https://stackoverflow.com/q/1", "Why? - Stack Overflow"),
+            "Why?");
+        assert_eq!(trim(&rtd, "https://en.wikipedia.org/wiki/x", "Spider-Man – Wikipedia"),
+            "Spider-Man");
+
+        // dashes which aren't followed by the name of the site are kept
+        for title in &[
+            "Rust 1.35 - what's new",
+            "Spider-Man",
+            "Man bites dog - local news",
+            " - GitHub",
+            "Title - ",
+        ] {
+            assert_eq!(trim(&rtd, "https://github.com/x", title), *title);
+        }
+        // as are names matching only the top-level domain
+        assert_eq!(trim(&rtd, "https://example.com/", "A - Com"), "A - Com");
+    }
+
+    #[test]
+    fn ignore_svg_and_mathml_titles() {
+        let page = ::std::fs::read_to_string("./test/html/svg.html").unwrap();
+        assert_eq!(Some(String::from("svg")), parse_title(&page));
+
+        assert_eq!(
+            Some(String::from("document")),
+            parse_title("<body><math><title>x</title></math><title>document</title>")
+        );
+        assert_eq!(None, parse_title("<svg><title>icon</title></svg>"));
+    }
+
+    #[test]
+    fn parse_canonical_urls() {
+        assert_eq!(None, parse_canonical(""));
+        assert_eq!(None, parse_canonical("<link rel=\"stylesheet\" href=\"/a\">"));
+        assert_eq!(None, parse_canonical("<link rel=\"canonical\" href=\" \">"));
+        assert_eq!(
+            Some(String::from("https://z.zzz/a")),
+            parse_canonical("<link rel=\"canonical\" href=\"https://z.zzz/a\">")
+        );
+        assert_eq!(
+            Some(String::from("/a?b=c")),
+            parse_canonical(
+                "<title>x</title>\n<LINK REL=\"Canonical\" HREF=\" /a?b=c \">")
+        );
+    }
+
+    #[test]
+    fn report_types() {
+        let mut rtd = Rtd::default();
+        let png: Mime = "image/png".parse().unwrap();
+        let pdf: Mime = "application/pdf".parse().unwrap();
+        assert!(report_type(&rtd, &png));
+        assert!(report_type(&rtd, &pdf));
+
+        for types in &[vec!["image/*"], vec!["image"], vec!["image/png", "text/plain"]] {
+            rtd.conf.features.report_types = types.iter().map(|t| t.to_string()).collect();
+            assert!(report_type(&rtd, &png));
+            assert!(!report_type(&rtd, &pdf));
+        }
+
+        rtd.conf.features.report_types = vec!["Application/PDF".to_string()];
+        assert!(!report_type(&rtd, &png));
+        assert!(report_type(&rtd, &pdf));
+    }
+
+    #[test]
+    fn textual_types() {
+        let textual = |t: &str| is_textual(Some(&t.parse().unwrap()), b"");
+
+        for t in &["text/html", "text/plain", "application/xhtml+xml",
+            "application/xml", "image/svg+xml"]
+        {
+            assert!(textual(t));
+        }
+        for t in &["application/octet-stream", "application/pdf", "image/png"] {
+            assert!(!textual(t));
+        }
+
+        // content of an unknown type
+        assert!(is_textual(None, b"<title>text</title>"));
+        assert!(!is_textual(None, b"\x89PNG\x00\x00<title>binary</title>"));
+    }
+
+    #[test]
+    fn allowed_types() {
+        let mut rtd = Rtd::default();
+        let allowed = |rtd: &Rtd, t: &str| allowed_type(rtd, &t.parse().unwrap());
+
+        for t in &["text/html", "text/plain", "image/webp", "application/pdf"] {
+            assert!(allowed(&rtd, t));
+        }
+        for t in &["application/octet-stream", "video/mp4", "audio/ogg"] {
+            assert!(!allowed(&rtd, t));
+        }
+
+        rtd.conf.features.content_type_allowlist = vec![];
+        assert!(allowed(&rtd, "video/mp4"));
+    }
+
+    #[test]
+    fn report_mime_and_size() {
+        let mut rtd = Rtd::default();
+        let mime: Mime = "application/pdf".parse().unwrap();
+
+        assert_eq!(None, get_mime(&rtd, &mime, Some(16), None));
+
+        rtd.conf.features.report_mime = true;
+        assert_eq!(Some(String::from("application/pdf 16B")), get_mime(&rtd, &mime, Some(16), None));
+        assert_eq!(
+            Some(String::from("application/pdf 1.91MB")),
+            get_mime(&rtd, &mime, Some(2_000_000), None)
+        );
+
+        // unknown sizes aren't reported
+        assert_eq!(Some(String::from("application/pdf")), get_mime(&rtd, &mime, None, None));
+
+        // small sizes aren't reported
+        rtd.conf.params.min_size_report_bytes = 1024;
+        assert_eq!(Some(String::from("application/pdf")), get_mime(&rtd, &mime, Some(16), None));
+        assert_eq!(
+            Some(String::from("application/pdf 1KB")),
+            get_mime(&rtd, &mime, Some(1024), None)
+        );
+        assert_eq!(
+            Some(String::from("application/pdf 1.91MB")),
+            get_mime(&rtd, &mime, Some(2_000_000), None)
+        );
+
+        // with the name of a downloaded file
+        assert_eq!(
+            Some(String::from("report.pdf (application/pdf 1KB)")),
+            get_mime(&rtd, &mime, Some(1024), Some("report.pdf"))
+        );
+        assert_eq!(
+            Some(String::from("report.pdf (application/pdf)")),
+            get_mime(&rtd, &mime, None, Some("report.pdf"))
+        );
+    }
+
+    #[test]
+    fn parse_disposition_filenames() {
+        let name = |h| disposition_filename(h);
+
+        assert_eq!(name("attachment; filename=\"report.pdf\""), Some("report.pdf".to_string()));
+        assert_eq!(name("attachment; filename=report.pdf"), Some("report.pdf".to_string()));
+        assert_eq!(name("inline;FILENAME=\"a; \\\"b\\\".pdf\""), Some("a; \"b\".pdf".to_string()));
+        assert_eq!(
+            name("attachment; filename*=UTF-8''%E2%82%AC%20rates.pdf"),
+            Some("€ rates.pdf".to_string())
+        );
+        assert_eq!(
+            name("attachment; filename*=iso-8859-1'en'%A3%20rates.pdf"),
+            Some("£ rates.pdf".to_string())
+        );
+
+        // the encoded name is preferred, wherever it appears
+        assert_eq!(
+            name("attachment; filename*=UTF-8''%C3%A9t%C3%A9.pdf; filename=\"ete.pdf\""),
+            Some("été.pdf".to_string())
+        );
+        assert_eq!(
+            name("attachment; filename=\"ete.pdf\"; filename*=UTF-8''%C3%A9t%C3%A9.pdf"),
+            Some("été.pdf".to_string())
+        );
+
+        // paths and unsafe characters are removed
+        assert_eq!(name("attachment; filename=\"../../etc/passwd\""), Some("passwd".to_string()));
+        assert_eq!(name("attachment; filename=\"C:\\\\x\\\\y.exe\""), Some("y.exe".to_string()));
+        assert_eq!(
+            name("attachment; filename*=UTF-8''invoice%E2%80%AEfdp.exe"),
+            Some("invoicefdp.exe".to_string())
+        );
+
+        assert_eq!(name("attachment"), None);
+        assert_eq!(name("attachment; filename=\"\""), None);
+        assert_eq!(name("attachment; filename*=UTF-16''%00a"), None);
+    }
+
+    #[test]
+    fn strip_unsafe_chars_from_titles() {
+        // right-to-left override, e.g. disguising a file extension
+        assert_eq!(
+            Some(String::from("invoicefdp.exe")),
+            parse_title("<title>invoice\u{202E}fdp.exe</title>")
+        );
+        assert_eq!(
+            Some(String::from("zero width")),
+            parse_title("<title>zero\u{200B} width\u{FEFF}</title>")
+        );
+        assert_eq!(
+            Some(String::from("bell tab")),
+            parse_title("<title>\u{7}bell\ttab\u{85}</title>")
+        );
+        assert_eq!(None, parse_title("<title>\u{200B}\u{2066}\u{2069}</title>"));
+
+        // combining characters, and joiners, are kept
+        assert_eq!(
+            Some(String::from("e\u{0301} \u{1F468}\u{200D}\u{1F469} \u{645}\u{200C}\u{6CC}")),
+            parse_title("<title>e\u{0301} \u{1F468}\u{200D}\u{1F469} \u{645}\u{200C}\u{6CC}</title>")
+        );
+    }
+
+    #[test]
+    fn parse_languages() {
+        assert_eq!(None, parse_language(""));
+        assert_eq!(None, parse_language("<html><title>x</title></html>"));
+        assert_eq!(None, parse_language("<html lang=\"\"></html>"));
+        assert_eq!(None, parse_language("<html lang=\"de DE\"></html>"));
+        assert_eq!(None, parse_language("<div lang=\"de\"></div>"));
+        assert_eq!(
+            Some(String::from("de")),
+            parse_language("<html lang=\"de\"><title>Titel</title></html>")
+        );
+        assert_eq!(
+            Some(String::from("en-GB")),
+            parse_language("<HTML LANG=\" en-GB \"></HTML>")
+        );
+
+        assert_eq!(Some(String::from("mi")), language_tag("mi, en"));
+        assert_eq!(None, language_tag("\u{3}4red"));
+    }
+
+    #[test]
+    fn parse_meta_refreshes() {
+        let page = |c| format!("<meta http-equiv=\"refresh\" content=\"{}\">", c);
+
+        assert_eq!(None, parse_meta_refresh(""));
+        assert_eq!(None, parse_meta_refresh("<title>x</title>"));
+        assert_eq!(None, parse_meta_refresh(&page("5")));
+        assert_eq!(None, parse_meta_refresh(&page("x; url=/a")));
+        assert_eq!(None, parse_meta_refresh(&page("0; url=")));
+        assert_eq!(
+            None,
+            parse_meta_refresh("<meta http-equiv=\"x\" content=\"0; url=/a\">")
+        );
+
+        for c in &[
+            "0; url=/a",
+            "0;URL=/a",
+            "0; url = '/a'",
+            "0,/a",
+            "0.5; url=/a",
+        ] {
+            assert_eq!(
+                Some((0, String::from("/a"))),
+                parse_meta_refresh(&page(c)),
+                "{}", c
+            );
+        }
+
+        assert_eq!(
+            Some((10, String::from("http://z.zzz/?a=b"))),
+            parse_meta_refresh(
+                "<META HTTP-EQUIV=\"Refresh\" CONTENT=\"10;url=http://z.zzz/?a=b\">")
+        );
+    }
+
     #[test]
     fn get_metadata_from_local_images() {
         for test in vec!(
             ("./test/img/test.png", "image/png 800×400"),
             ("./test/img/test.jpg", "image/jpeg 400×200"),
-            ("./test/img/test.gif", "image/gif 1920×1080")
+            ("./test/img/test.gif", "image/gif 1920×1080"),
+            ("./test/img/animated.gif", "image/gif 48×27 (animated, 3 frames)")
         ) {
             get_local_image_metadata(test.0, test.1);
         }
     }
 
+    #[test]
+    fn count_gif_frames() {
+        let gif = fs::read("./test/img/animated.gif").unwrap();
+
+        assert_eq!(gif_frames(&gif, 10), (3, true));
+        assert_eq!(gif_frames(&gif, 2), (2, false));
+        // exactly the limit
+        assert_eq!(gif_frames(&gif, 3), (3, true));
+        assert_eq!(gif_frames(&fs::read("./test/img/test.gif").unwrap(), 10), (1, true));
+
+        // truncated
+        assert_eq!(gif_frames(&gif[..gif.len() - 1], 10), (3, false));
+        assert_eq!(gif_frames(&gif[..gif.len() / 2], 10), (1, false));
+        assert_eq!(gif_frames(&gif[..12], 10), (0, false));
+        assert_eq!(gif_frames(b"not a gif", 10), (0, false));
+
+        let mut rtd = Rtd::default();
+        rtd.conf.features.report_metadata = true;
+        rtd.conf.params.max_gif_frames = 2;
+        assert_eq!(get_image_metadata(&rtd, &gif).unwrap(),
+            "image/gif 48×27 (animated, 2+ frames)");
+        rtd.conf.params.max_gif_frames = 3;
+        assert_eq!(get_image_metadata(&rtd, &gif).unwrap(),
+            "image/gif 48×27 (animated, 3 frames)");
+        assert_eq!(get_image_metadata(&rtd, &gif[..gif.len() / 2]).unwrap(),
+            "image/gif 48×27");
+    }
+
+    #[test]
+    fn refuse_images_too_large() {
+        // a GIF declaring 65535×65535 pixels, with no image data
+        let gif = b"GIF89a\xff\xff\xff\xff\x00\x00\x00\x3b";
+        // a PNG header declaring 100000×100000 pixels
+        let mut png = b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0dIHDR".to_vec();
+        png.extend(&[0, 1, 0x86, 0xa0, 0, 1, 0x86, 0xa0, 8, 6, 0, 0, 0]);
+
+        assert_eq!(header_dimensions(gif), Some((65535, 65535)));
+        assert_eq!(header_dimensions(&png), Some((100_000, 100_000)));
+        assert_eq!(header_dimensions(&png[..20]), None);
+        assert_eq!(header_dimensions(b"GIF89a"), None);
+
+        let mut rtd = Rtd::default();
+        rtd.conf.features.report_metadata = true;
+        assert_eq!(get_image_metadata(&rtd, gif), None);
+        assert_eq!(get_image_metadata(&rtd, &png), None);
+
+        rtd.conf.params.max_image_pixels = u64::max_value();
+        assert_eq!(get_image_metadata(&rtd, gif).unwrap(), "image/gif 65535×65535");
+
+        // the budget applies to images of each type
+        rtd.conf.params.max_image_pixels = 100;
+        for file in &["./test/img/test.gif", "./test/img/test.jpg", "./test/img/test.png"] {
+            let body = fs::read(file).unwrap();
+            assert_eq!(get_image_metadata(&rtd, &body), None);
+        }
+    }
+
     fn get_local_image_metadata(file: impl AsRef<Path>, result: &str) {
         let mut rtd: Rtd = Rtd::default();
 
@@ -0,0 +1,284 @@
+/*
+ * Requests to local services listening on Unix domain sockets, mapped to host
+ * names by `unix_sockets`
+ *
+ * Requests are made with HTTP/1.0, so that responses aren't chunked, the body
+ * of a response being the remainder of the connection.
+ *
+ */
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use failure::Error;
+use hyper;
+use reqwest::{header, Request, Response, Version};
+use reqwest::async::ResponseBuilderExt;
+
+/// max size of a response body read, of which only the first few chunks are
+/// read for a title
+const BODY_MAX: u64 = 4 * 1024 * 1024;
+
+/// Make a request over the socket at `path`, within `timeout`, reading a
+/// response with headers of up to `header_limit` bytes
+pub fn send(path: &Path, request: &Request, timeout: Duration, header_limit: usize)
+    -> Result<Response, Error>
+{
+    let mut stream = Connection {
+        stream: UnixStream::connect(path).map_err(|err| {
+            format_err!("can't connect to {}: {}", path.display(), err)
+        })?,
+        deadline: Instant::now() + timeout,
+    };
+
+    stream.write_all(&request_head(request))?;
+    read_response(stream, request, header_limit)
+}
+
+/// a connection, each read or write on which is limited to the time left
+/// before the deadline
+struct Connection {
+    stream: UnixStream,
+    deadline: Instant,
+}
+
+impl Connection {
+    fn time_left(&self) -> io::Result<Duration> {
+        let now = Instant::now();
+        if now >= self.deadline {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "deadline reached"));
+        }
+        Ok(self.deadline - now)
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.set_read_timeout(Some(self.time_left()?))?;
+        self.stream.read(buf)
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.set_write_timeout(Some(self.time_left()?))?;
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// the request line and headers of a request
+fn request_head(request: &Request) -> Vec<u8> {
+    let url = request.url();
+    let target = match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    };
+
+    let mut head = format!("{} {} HTTP/1.0\r\n", request.method(), target).into_bytes();
+
+    if !request.headers().contains_key(header::HOST) {
+        let host = url.host_str().unwrap_or_default();
+        let host = match url.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_string(),
+        };
+        head.extend(format!("Host: {}\r\n", host).bytes());
+    }
+
+    for (name, value) in request.headers() {
+        head.extend(name.as_str().bytes());
+        head.extend(b": ");
+        head.extend(value.as_bytes());
+        head.extend(b"\r\n");
+    }
+    head.extend(b"\r\n");
+
+    head
+}
+
+/// read the status line, headers and body of a response
+fn read_response(stream: impl Read, request: &Request, header_limit: usize)
+    -> Result<Response, Error>
+{
+    let mut reader = BufReader::new(stream);
+    let mut builder = hyper::Response::builder();
+    builder.url(request.url().clone());
+
+    // the status line, followed by headers, up to a blank line
+    {
+        let mut head = reader.by_ref().take(header_limit as u64 + 2);
+        let mut line = String::new();
+        head.read_line(&mut line)?;
+
+        let (version, status) = status_line(line.trim_end())?;
+        builder.version(version).status(status);
+
+        loop {
+            line.clear();
+            if head.read_line(&mut line)? == 0 {
+                bail!("Response headers too large, max {}B", header_limit);
+            }
+
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+
+            match line.find(':') {
+                Some(i) => builder.header(&line[..i], line[i + 1..].trim()),
+                None => bail!("Invalid response header: {}", line),
+            };
+        }
+    }
+
+    // servers may close the connection without a clean shutdown, so an error
+    // after some content is ignored
+    let mut body = Vec::new();
+    if let Err(err) = reader.take(BODY_MAX).read_to_end(&mut body) {
+        if body.is_empty() {
+            return Err(err.into());
+        }
+        debug!("error reading response: {}", err);
+    }
+
+    Ok(Response::from(builder.body(body)?))
+}
+
+/// the HTTP version and status code of a status line, e.g. HTTP/1.0 200 OK
+fn status_line(line: &str) -> Result<(Version, u16), Error> {
+    let mut parts = line.split_whitespace();
+    let version = match parts.next() {
+        Some("HTTP/1.0") => Version::HTTP_10,
+        Some("HTTP/1.1") => Version::HTTP_11,
+        _ => bail!("Invalid response status line: {}", line),
+    };
+    let status = parts.next()
+        .and_then(|s| s.parse::<u16>().ok())
+        .ok_or_else(|| format_err!("Invalid response status: {}", line))?;
+
+    Ok((version, status))
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use super::*;
+    use std::os::unix::net::UnixListener;
+    use std::sync::mpsc;
+    use std::thread;
+    use reqwest::{Client, Method};
+    use url::Url;
+    use config::Rtd;
+    use http::{resolve_url, ResolveError, Session};
+
+    fn request(url: &str) -> Request {
+        Request::new(Method::GET, Url::parse(url).unwrap())
+    }
+
+    #[test]
+    fn unix_socket_request_head() {
+        assert_eq!(request_head(&request("http://app.local/a/b?c=d#e")),
+            b"GET /a/b?c=d HTTP/1.0\r\nHost: app.local\r\n\r\n".to_vec());
+
+        let head = Client::new()
+            .get("http://app.local:8080/")
+            .header(header::USER_AGENT, "url-bot-rs")
+            .build()
+            .unwrap();
+        assert_eq!(String::from_utf8(request_head(&head)).unwrap(),
+            "GET / HTTP/1.0\r\nHost: app.local:8080\r\nuser-agent: url-bot-rs\r\n\r\n");
+    }
+
+    #[test]
+    fn unix_socket_responses() {
+        let rq = request("http://app.local/");
+        let response = |data: &'static [u8]| read_response(data, &rq, 64);
+
+        let mut resp = response(b"HTTP/1.0 200 OK\r\n\
+            Content-Type: text/html\r\n\r\n<title>App</title>").unwrap();
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.version(), Version::HTTP_10);
+        assert_eq!(resp.url().as_str(), "http://app.local/");
+        assert_eq!(resp.headers()[header::CONTENT_TYPE], "text/html");
+        assert_eq!(resp.text().unwrap(), "<title>App</title>");
+
+        let resp = response(b"HTTP/1.1 301 Moved\r\nLocation: /b\r\n\r\n").unwrap();
+        assert_eq!(resp.status(), 301);
+        assert_eq!(resp.headers()[header::LOCATION], "/b");
+
+        assert!(response(b"").is_err());
+        assert!(response(b"SSH-2.0-OpenSSH\r\n\r\n").is_err());
+        assert!(response(b"HTTP/1.0 OK\r\n\r\n").is_err());
+        assert!(response(b"HTTP/1.0 200 OK\r\nInvalid\r\n\r\n").is_err());
+        let large = format!("HTTP/1.0 200 OK\r\nX-Large: {}\r\n\r\n", "x".repeat(100));
+        assert!(read_response(large.as_bytes(), &rq, 64).is_err());
+    }
+
+    #[test]
+    fn resolve_over_unix_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("app.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+
+        let server_thread = thread::spawn(move || {
+            for stream in listener.incoming().take(2) {
+                let mut stream = stream.unwrap();
+                let mut request = String::new();
+                BufReader::new(&mut stream).read_line(&mut request).unwrap();
+
+                let resp: &[u8] = match request.as_str() {
+                    "GET / HTTP/1.0\r\n" => b"HTTP/1.0 302 Found\r\n\
+                        Location: http://app.invalid/page\r\n\r\n",
+                    _ => b"HTTP/1.0 200 OK\r\nContent-Type: text/html\r\n\r\n\
+                        <html><head><title>Local app</title></head></html>",
+                };
+                stream.write_all(resp).unwrap();
+            }
+        });
+
+        let mut rtd = Rtd::default();
+        rtd.conf.params.unix_sockets.insert("app.invalid".to_string(),
+            path.to_str().unwrap().to_string());
+
+        // sockets are only used if enabled
+        assert!(resolve_url("http://app.invalid/", &rtd, None).is_err());
+
+        rtd.conf.features.unix_sockets = true;
+        let resolved = resolve_url("http://app.invalid/", &rtd, None).unwrap();
+        assert_eq!(resolved.title, "Local app");
+        assert_eq!(resolved.redirects, vec!["http://app.invalid/", "http://app.invalid/page"]);
+
+        server_thread.join().unwrap();
+    }
+
+    #[test]
+    fn unix_socket_total_timeout() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("slow.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+
+        // a server which accepts a connection, but never responds
+        let (tx, rx) = mpsc::channel::<()>();
+        let server_thread = thread::spawn(move || {
+            let (_stream, _) = listener.accept().unwrap();
+            rx.recv().ok();
+        });
+
+        let mut session = Session::new();
+        session.params.unix_sockets.insert("slow.invalid".to_string(), path);
+        session.params.total_timeout_s = 1;
+        session.params.timeout_s = 10;
+        let start = Instant::now();
+        let err = session.request("http://slow.invalid/").unwrap_err();
+        assert_eq!(err.downcast::<ResolveError>().unwrap(), ResolveError::Timeout(1));
+        assert!(start.elapsed() < Duration::from_secs(3));
+
+        drop(tx);
+        server_thread.join().unwrap();
+    }
+}
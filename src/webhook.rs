@@ -0,0 +1,242 @@
+/*
+ * Webhook notifications
+ *
+ * When enabled, a JSON object describing each URL resolved is posted to a
+ * configured URL, and an embed to a Discord webhook, from a separate thread,
+ * so that a slow or failing webhook doesn't hold up the handling of IRC
+ * messages.
+ *
+ */
+use std::thread;
+use failure::Error;
+use reqwest::{header, Client};
+use serde::Serialize;
+use time;
+
+use super::config::Rtd;
+use super::http::{RequestParams, Session};
+use super::sqlite::NewLogEntry;
+
+/// the payload posted to the webhook
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct Resolution {
+    pub url: String,
+    pub title: String,
+    pub user: String,
+    pub channel: String,
+    /// time of resolution, in seconds since the epoch
+    pub timestamp: i64,
+}
+
+impl<'a> From<&'a NewLogEntry<'a>> for Resolution {
+    fn from(entry: &NewLogEntry) -> Self {
+        Self {
+            url: entry.url.to_string(),
+            title: entry.title.to_string(),
+            user: entry.user.to_string(),
+            channel: entry.channel.to_string(),
+            timestamp: time::now_utc().to_timespec().sec,
+        }
+    }
+}
+
+/// max length of the title of a Discord embed
+const DISCORD_TITLE_MAX: usize = 256;
+
+/// the payload posted to a Discord webhook, a message with a single embed
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct DiscordMessage {
+    pub embeds: Vec<Embed>,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct Embed {
+    pub title: String,
+    pub url: String,
+    pub footer: Footer,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct Footer {
+    pub text: String,
+}
+
+impl<'a> From<&'a NewLogEntry<'a>> for DiscordMessage {
+    fn from(entry: &NewLogEntry) -> Self {
+        let mut title: String = entry.title.chars().take(DISCORD_TITLE_MAX).collect();
+        if title.len() < entry.title.len() {
+            title.pop();
+            title.push('…');
+        }
+
+        Self {
+            embeds: vec![Embed {
+                title,
+                url: entry.url.to_string(),
+                footer: Footer {
+                    text: format!("{} in {}", entry.user, entry.channel),
+                },
+            }],
+        }
+    }
+}
+
+/// post a resolution to `params.webhook_url`, and to
+/// `params.discord_webhook_url`, in the background, if enabled
+pub fn notify(rtd: &Rtd, entry: &NewLogEntry) {
+    if rtd.conf.features.dry_run {
+        return;
+    }
+
+    if rtd.conf.features.webhook {
+        send(rtd, &rtd.conf.params.webhook_url, Resolution::from(entry));
+    }
+    if rtd.conf.features.discord_webhook {
+        send(rtd, &rtd.conf.params.discord_webhook_url, DiscordMessage::from(entry));
+    }
+}
+
+fn send<T: Serialize + Send + 'static>(rtd: &Rtd, url: &str, payload: T) {
+    let mut session = Session::new();
+    session.params = RequestParams::from(rtd);
+    let client = match session.client() {
+        Ok(client) => client,
+        Err(err) => {
+            error!("webhook client: {}", err);
+            return;
+        },
+    };
+
+    let url = url.to_string();
+    let user_agent = session.params.user_agent;
+
+    thread::spawn(move || {
+        if let Err(err) = post(&client, &url, &user_agent, &payload) {
+            warn!("failed to post to webhook <{}>: {}", url, err);
+        }
+    });
+}
+
+pub fn post<T: Serialize>(client: &Client, url: &str, user_agent: &str, payload: &T)
+    -> Result<(), Error>
+{
+    client.post(url)
+        .header(header::USER_AGENT, user_agent)
+        .json(payload)
+        .send()?
+        .error_for_status()?;
+
+    debug!("posted to webhook <{}>", url);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tiny_http;
+
+    use super::*;
+    use std::time::Duration;
+    use serde_json;
+    use self::tiny_http::Response;
+
+    #[test]
+    fn post_to_webhook() {
+        let server_thread = thread::spawn(move || {
+            let server = tiny_http::Server::http("0.0.0.0:28511").unwrap();
+            let mut bodies = vec![];
+
+            for (mut rq, status) in server.incoming_requests().take(2).zip(&[200, 500]) {
+                let json = rq.headers().iter().any(|h| {
+                    h.field.equiv("Content-Type")
+                        && h.value.as_str() == "application/json"
+                });
+                assert!(json);
+                assert_eq!(rq.method().as_str(), "POST");
+
+                let mut body = String::new();
+                rq.as_reader().read_to_string(&mut body).unwrap();
+                bodies.push(body);
+                rq.respond(Response::empty(*status)).unwrap();
+            }
+
+            bodies
+        });
+
+        thread::sleep(Duration::from_millis(100));
+        let entry = NewLogEntry {
+            title: "title",
+            url: "http://z.zzz/",
+            user: "user",
+            channel: "#chan",
+            duration_ms: Some(1),
+            redirect_chain: None,
+        };
+        let payload = Resolution::from(&entry);
+        let client = Client::new();
+        let url = "http://127.0.0.1:28511/hook";
+
+        assert!(post(&client, url, "ua", &payload).is_ok());
+        assert!(post(&client, url, "ua", &payload).is_err());
+
+        let bodies = server_thread.join().unwrap();
+        let received: Resolution = serde_json::from_str(&bodies[0]).unwrap();
+        assert_eq!(received, payload);
+        assert_eq!(received.channel, "#chan");
+        assert!(received.timestamp > 1_500_000_000);
+    }
+
+    #[test]
+    fn post_to_discord_webhook() {
+        let server_thread = thread::spawn(move || {
+            let server = tiny_http::Server::http("0.0.0.0:28521").unwrap();
+            let mut rq = server.incoming_requests().next().unwrap();
+
+            let path = rq.url().to_string();
+            let mut body = String::new();
+            rq.as_reader().read_to_string(&mut body).unwrap();
+            rq.respond(Response::empty(204)).unwrap();
+            (path, body)
+        });
+
+        thread::sleep(Duration::from_millis(100));
+        let mut rtd = Rtd::default();
+        rtd.conf.features.discord_webhook = true;
+        rtd.conf.params.discord_webhook_url =
+            "http://127.0.0.1:28521/api/webhooks/1/token".to_string();
+        let entry = NewLogEntry {
+            title: "title",
+            url: "http://z.zzz/",
+            user: "user",
+            channel: "#chan",
+            duration_ms: None,
+            redirect_chain: None,
+        };
+        notify(&rtd, &entry);
+
+        let (path, body) = server_thread.join().unwrap();
+        assert_eq!(path, "/api/webhooks/1/token");
+        let received: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(received, serde_json::json!({"embeds": [{
+            "title": "title",
+            "url": "http://z.zzz/",
+            "footer": {"text": "user in #chan"},
+        }]}));
+    }
+
+    #[test]
+    fn truncate_discord_titles() {
+        let title = "é".repeat(300);
+        let entry = NewLogEntry {
+            title: &title,
+            url: "http://z.zzz/",
+            user: "user",
+            channel: "#chan",
+            duration_ms: None,
+            redirect_chain: None,
+        };
+        let embed = &DiscordMessage::from(&entry).embeds[0];
+
+        assert_eq!(embed.title.chars().count(), DISCORD_TITLE_MAX);
+        assert!(embed.title.ends_with("é…"));
+    }
+}